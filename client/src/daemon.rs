@@ -0,0 +1,110 @@
+//! How often `innernet up --daemon` fetches from the server.
+
+use std::time::{Duration, SystemTime};
+use wireguard_control::PeerInfo;
+
+/// The shortest interval `up --adaptive-interval` fetches at.
+const ADAPTIVE_INTERVAL_FLOOR: Duration = Duration::from_secs(10);
+
+/// WireGuard rejects any communication this long after the last handshake, so a peer we haven't
+/// had one with since then has gone quiet.
+const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
+
+/// Peers quiet for longer than this have most likely gone offline rather than lost track of us
+/// after a network change, and fetching more often won't bring them back.
+const QUIET_PEER_CUTOFF: Duration = Duration::from_secs(60 * 60);
+
+/// Halve the daemon's interval (down to [`ADAPTIVE_INTERVAL_FLOOR`]) while more than a quarter of
+/// the peers we've recently had a handshake with have gone quiet, which usually means the
+/// network changed and they need new endpoints, and go back to the configured interval once
+/// they're healthy. Peers we've never had a handshake with, or not for over
+/// [`QUIET_PEER_CUTOFF`], are most likely just offline, so they don't count.
+pub fn next_interval(
+    current: Duration,
+    configured: Duration,
+    peers: &[PeerInfo],
+    now: SystemTime,
+) -> Duration {
+    let quiet_for = peers.iter().filter_map(|peer| {
+        let last_handshake = peer.stats.last_handshake_time?;
+        let elapsed = now.duration_since(last_handshake).unwrap_or_default();
+        (elapsed <= QUIET_PEER_CUTOFF).then_some(elapsed)
+    });
+    let (total, stale) = quiet_for.fold((0, 0), |(total, stale), elapsed| {
+        (total + 1, stale + usize::from(elapsed > REJECT_AFTER_TIME))
+    });
+
+    if stale * 4 > total {
+        (current / 2).max(ADAPTIVE_INTERVAL_FLOOR).min(configured)
+    } else {
+        configured
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wireguard_control::{Key, PeerConfigBuilder, PeerStats};
+
+    const CONFIGURED: Duration = Duration::from_secs(60);
+
+    /// Peers whose last handshakes were this long before `now`, or never for `None`.
+    fn peers(now: SystemTime, handshakes_ago: &[Option<u64>]) -> Vec<PeerInfo> {
+        handshakes_ago
+            .iter()
+            .map(|ago| PeerInfo {
+                config: PeerConfigBuilder::new(&Key::generate_private().get_public())
+                    .into_peer_config(),
+                stats: PeerStats {
+                    last_handshake_time: ago.map(|ago| now - Duration::from_secs(ago)),
+                    ..Default::default()
+                },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_next_interval() {
+        let now = SystemTime::now();
+
+        // Healthy peers keep the configured interval, no matter where it was.
+        let healthy = peers(now, &[Some(10), Some(20), Some(30), Some(200)]);
+        assert_eq!(
+            next_interval(CONFIGURED, CONFIGURED, &healthy, now),
+            CONFIGURED
+        );
+        assert_eq!(
+            next_interval(ADAPTIVE_INTERVAL_FLOOR, CONFIGURED, &healthy, now),
+            CONFIGURED
+        );
+
+        // Half of them going quiet halves the interval, down to the floor.
+        let quiet = peers(now, &[Some(10), Some(20), Some(300), Some(400)]);
+        let halved = next_interval(CONFIGURED, CONFIGURED, &quiet, now);
+        assert_eq!(halved, CONFIGURED / 2);
+        let quartered = next_interval(halved, CONFIGURED, &quiet, now);
+        assert_eq!(quartered, CONFIGURED / 4);
+        assert_eq!(
+            next_interval(quartered, CONFIGURED, &quiet, now),
+            ADAPTIVE_INTERVAL_FLOOR
+        );
+        assert_eq!(
+            next_interval(ADAPTIVE_INTERVAL_FLOOR, CONFIGURED, &quiet, now),
+            ADAPTIVE_INTERVAL_FLOOR
+        );
+    }
+
+    #[test]
+    fn test_next_interval_ignores_offline_peers() {
+        let now = SystemTime::now();
+
+        // Peers never seen, or quiet for hours, are offline rather than in need of a new
+        // endpoint.
+        let offline = peers(now, &[Some(10), None, None, Some(2 * 60 * 60), Some(86400)]);
+        assert_eq!(
+            next_interval(CONFIGURED, CONFIGURED, &offline, now),
+            CONFIGURED
+        );
+        assert_eq!(next_interval(CONFIGURED, CONFIGURED, &[], now), CONFIGURED);
+    }
+}
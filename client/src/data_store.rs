@@ -1,8 +1,9 @@
-use crate::Error;
+use crate::{nat::NatTraversalResult, Error};
 use anyhow::bail;
 use serde::{Deserialize, Serialize};
-use shared::{chmod, ensure_dirs_exist, Cidr, IoErrorContext, Peer, WrappedIoError};
+use shared::{chmod, ensure_dirs_exist, Cidr, IoErrorContext, KeyRotation, Peer, WrappedIoError};
 use std::{
+    collections::HashMap,
     fs::{File, OpenOptions},
     io::{self, Read, Seek, Write},
     path::{Path, PathBuf},
@@ -19,7 +20,16 @@ pub struct DataStore {
 #[serde(tag = "version")]
 pub enum Contents {
     #[serde(rename = "1")]
-    V1 { peers: Vec<Peer>, cidrs: Vec<Cidr> },
+    V1 {
+        peers: Vec<Peer>,
+        cidrs: Vec<Cidr>,
+        /// The last NAT traversal outcome of each peer, keyed by public key.
+        #[serde(default)]
+        nat_traversal: HashMap<String, NatTraversalResult>,
+        /// The ID of the newest key rotation the server has sent, so it only sends newer ones.
+        #[serde(default)]
+        key_rotations_seen: i64,
+    },
 }
 
 impl DataStore {
@@ -48,6 +58,8 @@ impl DataStore {
         let contents = serde_json::from_str(&json).unwrap_or_else(|_| Contents::V1 {
             peers: vec![],
             cidrs: vec![],
+            nat_traversal: HashMap::new(),
+            key_rotations_seen: 0,
         });
 
         Ok(Self { file, contents })
@@ -91,18 +103,36 @@ impl DataStore {
     ///
     /// Note, however, that this does not prevent a compromised server from adding a new
     /// peer under its control, of course.
-    pub fn update_peers(&mut self, current_peers: &[Peer]) -> Result<(), Error> {
-        let peers = match &mut self.contents {
-            Contents::V1 { ref mut peers, .. } => peers,
+    ///
+    /// A pinned key may only change by following `key_rotations`, the server's record of
+    /// peers that rotated their keypair, and only through rotations signed by the old key, so
+    /// the server can't vouch for a new key on its own.
+    pub fn update_peers(
+        &mut self,
+        current_peers: &[Peer],
+        key_rotations: &[KeyRotation],
+    ) -> Result<(), Error> {
+        let (peers, key_rotations_seen) = match &mut self.contents {
+            Contents::V1 {
+                ref mut peers,
+                ref mut key_rotations_seen,
+                ..
+            } => (peers, key_rotations_seen),
         };
 
         for new_peer in current_peers.iter() {
             if let Some(existing_peer) = peers.iter_mut().find(|p| p.ip == new_peer.ip) {
                 if existing_peer.public_key != new_peer.public_key {
-                    bail!("PINNING ERROR: New peer has same IP but different public key.");
-                } else {
-                    *existing_peer = new_peer.clone();
+                    if !is_rotated(
+                        &existing_peer.public_key,
+                        &new_peer.public_key,
+                        key_rotations,
+                    ) {
+                        bail!("PINNING ERROR: New peer has same IP but different public key.");
+                    }
+                    log::info!("peer {} rotated its public key.", new_peer.name);
                 }
+                *existing_peer = new_peer.clone();
             } else {
                 peers.push(new_peer.clone());
             }
@@ -117,9 +147,23 @@ impl DataStore {
             }
         }
 
+        if let Some(newest) = key_rotations.iter().map(|rotation| rotation.id).max() {
+            *key_rotations_seen = newest.max(*key_rotations_seen);
+        }
+
         Ok(())
     }
 
+    /// The ID of the newest key rotation already taken into account, to ask the server for
+    /// only newer ones.
+    pub fn key_rotations_seen(&self) -> i64 {
+        match &self.contents {
+            Contents::V1 {
+                key_rotations_seen, ..
+            } => *key_rotations_seen,
+        }
+    }
+
     pub fn cidrs(&self) -> &[Cidr] {
         match &self.contents {
             Contents::V1 { cidrs, .. } => cidrs,
@@ -132,6 +176,23 @@ impl DataStore {
         }
     }
 
+    pub fn nat_traversal_result(&self, public_key: &str) -> Option<NatTraversalResult> {
+        match &self.contents {
+            Contents::V1 { nat_traversal, .. } => nat_traversal.get(public_key).copied(),
+        }
+    }
+
+    /// Record the latest NAT traversal outcomes, keeping the results of peers that
+    /// weren't part of this traversal.
+    pub fn update_nat_traversal_results(&mut self, results: &HashMap<String, NatTraversalResult>) {
+        match &mut self.contents {
+            Contents::V1 {
+                ref mut nat_traversal,
+                ..
+            } => nat_traversal.extend(results.iter().map(|(k, v)| (k.clone(), *v))),
+        }
+    }
+
     pub fn write(&mut self) -> Result<(), io::Error> {
         self.file.rewind()?;
         self.file.set_len(0)?;
@@ -141,27 +202,36 @@ impl DataStore {
     }
 }
 
+/// Whether following the signed rotations in `key_rotations` from `old_public_key` leads to
+/// `new_public_key`.
+fn is_rotated(old_public_key: &str, new_public_key: &str, key_rotations: &[KeyRotation]) -> bool {
+    let mut public_key = old_public_key;
+    for rotation in key_rotations {
+        if rotation.old_public_key == public_key && rotation.is_signed() {
+            public_key = &rotation.new_public_key;
+        }
+    }
+    public_key == new_public_key
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use once_cell::sync::Lazy;
     use shared::{Cidr, CidrContents, Peer, PeerContents};
+    use wireguard_control::KeyPair;
     static BASE_PEERS: Lazy<Vec<Peer>> = Lazy::new(|| {
         vec![Peer {
             id: 0,
-            contents: PeerContents {
-                name: "blah".parse().unwrap(),
-                ip: "10.0.0.1".parse().unwrap(),
-                cidr_id: 1,
-                public_key: "abc".to_string(),
-                endpoint: None,
-                is_admin: false,
-                is_disabled: false,
-                is_redeemed: true,
-                persistent_keepalive_interval: None,
-                invite_expires: None,
-                candidates: vec![],
-            },
+            contents: PeerContents::builder(
+                "blah".parse().unwrap(),
+                "10.0.0.1".parse().unwrap(),
+                1,
+                "abc".to_string(),
+            )
+            .set_persistent_keepalive_interval(None)
+            .set_redeemed(true)
+            .build(),
         }]
     });
     static BASE_CIDRS: Lazy<Vec<Cidr>> = Lazy::new(|| {
@@ -171,6 +241,8 @@ mod tests {
                 name: "cidr".to_string(),
                 cidr: "10.0.0.0/24".parse().unwrap(),
                 parent: None,
+                default_keepalive: None,
+                is_disabled: false,
             },
         }]
     });
@@ -182,7 +254,7 @@ mod tests {
         assert_eq!(0, store.peers().len());
         assert_eq!(0, store.cidrs().len());
 
-        store.update_peers(&BASE_PEERS).unwrap();
+        store.update_peers(&BASE_PEERS, &[]).unwrap();
         store.set_cidrs(BASE_CIDRS.to_owned());
         store.write().unwrap();
     }
@@ -204,13 +276,58 @@ mod tests {
             DataStore::open_with_path(dir.path().join("peer_store.json"), false).unwrap();
 
         // Should work, since peer is unmodified.
-        store.update_peers(&BASE_PEERS).unwrap();
+        store.update_peers(&BASE_PEERS, &[]).unwrap();
 
         let mut modified = BASE_PEERS.clone();
         modified[0].contents.public_key = "foo".to_string();
 
         // Should NOT work, since peer is unmodified.
-        assert!(store.update_peers(&modified).is_err());
+        assert!(store.update_peers(&modified, &[]).is_err());
+    }
+
+    #[test]
+    fn test_pinning_follows_key_rotations() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store =
+            DataStore::open_with_path(dir.path().join("peer_store.json"), true).unwrap();
+        let keys = [(); 3].map(|_| KeyPair::generate());
+        let with_key = |key: &KeyPair| {
+            let mut peers = BASE_PEERS.clone();
+            peers[0].contents.public_key = key.public.to_base64();
+            peers
+        };
+        let rotation = |id: i64, old: &KeyPair, new: &KeyPair, signer: &KeyPair| KeyRotation {
+            id,
+            old_public_key: old.public.to_base64(),
+            new_public_key: new.public.to_base64(),
+            signature: KeyRotation::sign(&signer.private, &new.public.to_base64()),
+        };
+        store.update_peers(&with_key(&keys[0]), &[]).unwrap();
+        let rotated = with_key(&keys[2]);
+
+        // A rotation for some other key doesn't vouch for this one.
+        assert!(store
+            .update_peers(&rotated, &[rotation(1, &keys[1], &keys[2], &keys[1])])
+            .is_err());
+
+        // Nor does one the old key didn't sign, e.g. made up by the server.
+        assert!(store
+            .update_peers(&rotated, &[rotation(1, &keys[0], &keys[2], &keys[2])])
+            .is_err());
+        assert_eq!(store.key_rotations_seen(), 0);
+
+        // Rotating twice since the last fetch is followed all the way through.
+        store
+            .update_peers(
+                &rotated,
+                &[
+                    rotation(1, &keys[0], &keys[1], &keys[0]),
+                    rotation(2, &keys[1], &keys[2], &keys[1]),
+                ],
+            )
+            .unwrap();
+        assert_eq!(store.peers(), &rotated);
+        assert_eq!(store.key_rotations_seen(), 2);
     }
 
     #[test]
@@ -221,7 +338,7 @@ mod tests {
             DataStore::open_with_path(dir.path().join("peer_store.json"), false).unwrap();
 
         // Should work, since peer is unmodified.
-        store.update_peers(&[]).unwrap();
+        store.update_peers(&[], &[]).unwrap();
         let new_peers = BASE_PEERS
             .iter()
             .cloned()
@@ -232,4 +349,24 @@ mod tests {
             .collect::<Vec<_>>();
         assert_eq!(store.peers(), &new_peers);
     }
+
+    #[test]
+    fn test_nat_traversal_persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        setup_basic_store(dir.path());
+        let path = dir.path().join("peer_store.json");
+        let mut store = DataStore::open_with_path(&path, false).unwrap();
+        assert_eq!(store.nat_traversal_result("abc"), None);
+
+        let results = HashMap::from([("abc".to_string(), NatTraversalResult::Failed)]);
+        store.update_nat_traversal_results(&results);
+        store.write().unwrap();
+
+        let store = DataStore::open_with_path(&path, false).unwrap();
+        assert_eq!(
+            store.nat_traversal_result("abc"),
+            Some(NatTraversalResult::Failed)
+        );
+        assert_eq!(store.peers(), &*BASE_PEERS);
+    }
 }
@@ -0,0 +1,137 @@
+//! Exit codes for failures a supervisor may want to restart differently, following sysexits(3).
+
+use shared::{interface_config::InvalidInvitationError, Error, WrappedIoError};
+use std::{io, path::Path};
+
+/// Anything not covered by a more specific code.
+pub const FAILURE: i32 = 1;
+/// The server couldn't be reached or failed to respond, which is usually transient.
+pub const UNAVAILABLE: i32 = 69;
+/// The WireGuard interface couldn't be queried or configured.
+pub const INTERFACE: i32 = 71;
+/// The server refused this peer, e.g. because it was disabled. Retrying won't help until an
+/// admin lets it back in.
+pub const UNAUTHORIZED: i32 = 77;
+/// The interface's config is missing or invalid. Retrying won't help.
+pub const CONFIG: i32 = 78;
+
+/// Pick the exit code describing why innernet failed with `e`.
+pub fn for_error(e: &Error, config_dir: &Path) -> i32 {
+    let config_dir = config_dir.to_string_lossy();
+    for cause in e.chain() {
+        if let Some(e) = cause.downcast_ref::<ureq::Error>() {
+            return match e {
+                ureq::Error::Status(401 | 403, _) => UNAUTHORIZED,
+                _ => UNAVAILABLE,
+            };
+        }
+        if cause.is::<InvalidInvitationError>() {
+            return CONFIG;
+        }
+        if let Some(e) = cause.downcast_ref::<WrappedIoError>() {
+            return if is_network_error(e) {
+                UNAVAILABLE
+            } else if e.context().starts_with(&*config_dir) {
+                CONFIG
+            } else {
+                INTERFACE
+            };
+        }
+        if let Some(e) = cause.downcast_ref::<io::Error>() {
+            return if is_network_error(e) {
+                UNAVAILABLE
+            } else {
+                INTERFACE
+            };
+        }
+    }
+    FAILURE
+}
+
+/// Whether `e` came from trying to reach something over the network, such as resolving the
+/// server's endpoint, rather than from the local system.
+fn is_network_error(e: &io::Error) -> bool {
+    use io::ErrorKind::*;
+
+    matches!(
+        e.kind(),
+        AddrNotAvailable
+            | ConnectionAborted
+            | ConnectionRefused
+            | ConnectionReset
+            | HostUnreachable
+            | NetworkDown
+            | NetworkUnreachable
+            | NotConnected
+            | TimedOut
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use shared::IoErrorContext;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_for_error() {
+        let config_dir = Path::new("/etc/innernet");
+        let io_error = |kind| io::Error::new(kind, "test");
+        let status = |code| ureq::Error::Status(code, ureq::Response::new(code, "", "").unwrap());
+        let refused = ureq::get("http://127.0.0.1:1/").call().unwrap_err();
+
+        let cases: Vec<(Error, i32)> = vec![
+            (anyhow!("something else"), FAILURE),
+            (refused.into(), UNAVAILABLE),
+            (status(500).into(), UNAVAILABLE),
+            (status(401).into(), UNAUTHORIZED),
+            (status(403).into(), UNAUTHORIZED),
+            (
+                Error::from(status(403)).context("while fetching"),
+                UNAUTHORIZED,
+            ),
+            (
+                InvalidInvitationError {
+                    path: PathBuf::from("/etc/innernet/evilcorp.conf"),
+                    line: Some(1),
+                    message: "missing field".into(),
+                }
+                .into(),
+                CONFIG,
+            ),
+            (
+                Err::<(), _>(io_error(io::ErrorKind::NotFound))
+                    .with_path("/etc/innernet/evilcorp.conf")
+                    .unwrap_err()
+                    .into(),
+                CONFIG,
+            ),
+            (
+                Err::<(), _>(io_error(io::ErrorKind::PermissionDenied))
+                    .with_str("evilcorp")
+                    .unwrap_err()
+                    .into(),
+                INTERFACE,
+            ),
+            (io_error(io::ErrorKind::NotFound).into(), INTERFACE),
+            // A server endpoint that fails to resolve or can't be reached is worth retrying,
+            // however it's wrapped.
+            (
+                Err::<(), _>(io_error(io::ErrorKind::AddrNotAvailable))
+                    .with_str("vpn.example.com:51820")
+                    .unwrap_err()
+                    .into(),
+                UNAVAILABLE,
+            ),
+            (io_error(io::ErrorKind::TimedOut).into(), UNAVAILABLE),
+            (
+                io_error(io::ErrorKind::NetworkUnreachable).into(),
+                UNAVAILABLE,
+            ),
+        ];
+        for (e, expected) in cases {
+            assert_eq!(for_error(&e, config_dir), expected, "{e:?}");
+        }
+    }
+}
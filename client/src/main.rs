@@ -4,45 +4,119 @@ use colored::*;
 use dialoguer::{Confirm, Input};
 use hostsfile::HostsBuilder;
 use indoc::eprintdoc;
+use publicip::Preference;
+use serde::Serialize;
 use shared::{
     get_local_addrs,
     interface_config::InterfaceConfig,
     prompts,
     wg::{DeviceExt, PeerInfoExt},
     AddCidrOpts, AddDeleteAssociationOpts, AddPeerOpts, Association, AssociationContents, Cidr,
-    CidrTree, DeleteCidrOpts, EnableDisablePeerOpts, Endpoint, EndpointContents, InstallOpts,
-    Interface, IoErrorContext, ListenPortOpts, NatOpts, NetworkOpts, OverrideEndpointOpts, Peer,
-    RedeemContents, RenameCidrOpts, RenamePeerOpts, State, WrappedIoError, REDEEM_TRANSITION_WAIT,
+    CidrTree, DeleteCidrOpts, DeletePeerOpts, EnableDisablePeerOpts, Endpoint, EndpointContents,
+    HostnameFormat, InstallOpts, Interface, IoErrorContext, KeepaliveOpts, KeyRotation,
+    ListenPortOpts, NatOpts, NetworkOpts, OverrideEndpointOpts, Peer, PeerDiff, Reachability,
+    RedeemContents, RenameCidrOpts, RenamePeerOpts, RotateKeyContents, State, WrappedIoError,
+    REDEEM_TRANSITION_WAIT,
 };
 use std::{
-    io,
-    net::SocketAddr,
+    collections::HashMap,
+    fs::File,
+    io::{self, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
     path::{Path, PathBuf},
     thread,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
+};
+use wireguard_control::{
+    Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder, PeerInfo,
 };
-use wireguard_control::{Device, DeviceUpdate, InterfaceName, PeerConfigBuilder, PeerInfo};
 
+mod daemon;
 mod data_store;
+mod exit_code;
 mod nat;
+mod proxy;
 mod util;
 
 use data_store::DataStore;
-use nat::NatTraverse;
+use nat::{NatTraversalResult, NatTraverse};
+use proxy::ProxySettings;
 use shared::{wg, Error};
-use util::{human_duration, human_size, Api};
+use ureq::Proxy;
+use util::{human_duration, human_size, Api, EndpointCache, InviteLocation};
 
 use crate::util::all_installed;
 
+/// How long a resolved server endpoint is reused before it's looked up again.
+const SERVER_ENDPOINT_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// A server we haven't completed a handshake with in this long may have moved, so its
+/// endpoint is re-resolved regardless of the cache. Matches WireGuard's REJECT_AFTER_TIME.
+const SERVER_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(180);
+
 struct PeerState<'a> {
     peer: &'a Peer,
     info: Option<&'a PeerInfo>,
+    nat_traversal: Option<NatTraversalResult>,
+    /// Why this interface's peer can reach this one, filled in for `show --tree`.
+    reachability: Option<Reachability>,
+}
+
+/// The JSON representation of an interface printed by `innernet show --json`.
+#[derive(Serialize)]
+struct InterfaceStatus<'a> {
+    interface: String,
+    listen_port: Option<u16>,
+    peers: Vec<PeerStatus<'a>>,
 }
 
-macro_rules! println_pad {
-    ($pad:expr, $($arg:tt)*) => {
-        print!("{:pad$}", "", pad = $pad);
-        println!($($arg)*);
+#[derive(Serialize)]
+struct PeerStatus<'a> {
+    ip: IpAddr,
+    name: &'a str,
+    public_key: &'a str,
+    endpoint: Option<SocketAddr>,
+    /// Seconds since the UNIX epoch.
+    last_handshake_time: Option<u64>,
+    rx_bytes: u64,
+    tx_bytes: u64,
+    connected: bool,
+    is_you: bool,
+    nat_traversal: Option<NatTraversalResult>,
+}
+
+impl<'a> From<&PeerState<'a>> for PeerStatus<'a> {
+    fn from(state: &PeerState<'a>) -> Self {
+        let PeerState {
+            peer,
+            info,
+            nat_traversal,
+            ..
+        } = *state;
+        Self {
+            ip: peer.ip,
+            name: &peer.name,
+            public_key: &peer.public_key,
+            endpoint: info.and_then(|info| info.config.endpoint),
+            last_handshake_time: info
+                .and_then(|info| info.stats.last_handshake_time)
+                .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs()),
+            rx_bytes: info.map(|info| info.stats.rx_bytes).unwrap_or_default(),
+            tx_bytes: info.map(|info| info.stats.tx_bytes).unwrap_or_default(),
+            connected: info
+                .map(|info| info.is_recently_connected())
+                .unwrap_or_default(),
+            is_you: info.is_none(),
+            nat_traversal,
+        }
+    }
+}
+
+macro_rules! writeln_pad {
+    ($out:expr, $pad:expr, $($arg:tt)*) => {
+        write!($out, "{:pad$}", "", pad = $pad)
+            .and_then(|_| writeln!($out, $($arg)*))
     }
 }
 
@@ -62,10 +136,49 @@ struct Opts {
     #[clap(short, long, default_value = "/var/lib/innernet")]
     data_dir: PathBuf,
 
+    /// Never prompt, failing instead if a required value wasn't supplied as a flag
+    #[clap(long, global = true)]
+    non_interactive: bool,
+
+    /// How long to wait for each request to the server's API, in seconds
+    #[clap(long, global = true, default_value = "5", value_parser = parse_seconds)]
+    api_timeout: Duration,
+
+    /// An HTTP proxy to reach the server's API through (ex: http://proxy.example.com:3128).
+    /// WireGuard traffic is never proxied
+    #[clap(long, global = true, value_parser = proxy::parse_proxy)]
+    proxy: Option<Proxy>,
+
+    /// Reach the server's API through the proxy set in $ALL_PROXY or $HTTPS_PROXY, except for
+    /// the hosts listed in $NO_PROXY
+    #[clap(long, global = true, conflicts_with = "proxy")]
+    env_proxy: bool,
+
     #[clap(flatten)]
     network: NetworkOpts,
 }
 
+impl Opts {
+    /// The backend `interface` is configured to use, falling back to `--backend`.
+    fn backend(&self, interface: &InterfaceName) -> Backend {
+        InterfaceConfig::from_file(InterfaceConfig::get_path(&self.config_dir, interface))
+            .ok()
+            .and_then(|config| config.interface.backend)
+            .unwrap_or(self.network.backend)
+    }
+
+    /// The proxy to make API requests through: `--proxy`, or the environment's with
+    /// `--env-proxy`. Requests go directly to the server otherwise, since a system-wide proxy
+    /// rarely has a route into the network.
+    fn proxy(&self) -> ProxySettings {
+        if self.env_proxy {
+            ProxySettings::from_env()
+        } else {
+            ProxySettings::new(self.proxy.clone())
+        }
+    }
+}
+
 #[derive(Clone, Debug, Args)]
 struct HostsOpt {
     /// The path to write hosts to
@@ -75,11 +188,64 @@ struct HostsOpt {
     /// Don't write to any hosts files
     #[clap(long = "no-write-hosts", conflicts_with = "hosts_path")]
     no_write_hosts: bool,
+
+    /// The hostname to write each peer under, where {name}, {interface} and {cidr} are replaced
+    /// with the peer's name, the interface and the name of the peer's CIDR
+    #[clap(
+        long,
+        value_name = "FORMAT",
+        default_value_t,
+        conflicts_with = "no_write_hosts"
+    )]
+    hostname_format: HostnameFormat,
+}
+
+/// Where and how to write peers to a hosts file.
+#[derive(Clone, Debug)]
+struct HostsFile {
+    path: PathBuf,
+    hostname_format: HostnameFormat,
 }
 
-impl From<HostsOpt> for Option<PathBuf> {
+impl From<HostsOpt> for Option<HostsFile> {
     fn from(opt: HostsOpt) -> Self {
-        (!opt.no_write_hosts).then_some(opt.hosts_path)
+        (!opt.no_write_hosts).then_some(HostsFile {
+            path: opt.hosts_path,
+            hostname_format: opt.hostname_format,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, Args)]
+struct FetchOpts {
+    /// A TOML file mapping peer public keys to endpoints that take precedence over the
+    /// endpoints reported by the server
+    #[clap(long)]
+    endpoint_overrides: Option<PathBuf>,
+
+    /// Continue even if the server peer on the interface doesn't match the server public key
+    /// in the config file
+    #[clap(long)]
+    force: bool,
+
+    /// Print the changes that would be made to the interface without applying them
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Replace all of the interface's peers with exactly the ones the server authorizes,
+    /// instead of only applying the differences. Existing sessions are dropped and re-established
+    #[clap(long, conflicts_with = "dry_run")]
+    reconcile: bool,
+}
+
+impl FetchOpts {
+    fn load_endpoint_overrides(&self) -> Result<HashMap<String, SocketAddr>, Error> {
+        match &self.endpoint_overrides {
+            Some(path) => Ok(toml::from_str(
+                &std::fs::read_to_string(path).with_path(path)?,
+            )?),
+            None => Ok(HashMap::new()),
+        }
     }
 }
 
@@ -88,8 +254,10 @@ enum Command {
     /// Install a new innernet config
     #[clap(alias = "redeem")]
     Install {
-        /// Path to the invitation file
-        invite: PathBuf,
+        /// Paths to invitation files, or directories containing them (*.toml). Use "-" to read
+        /// an invitation from stdin, or an https:// URL to download one
+        #[clap(value_name = "INVITE", required = true, num_args = 1..)]
+        invites: Vec<PathBuf>,
 
         #[clap(flatten)]
         hosts: HostsOpt,
@@ -112,39 +280,107 @@ enum Command {
         #[clap(short, long)]
         tree: bool,
 
+        /// Print machine-readable JSON instead of the human-friendly listing
+        #[clap(long, conflicts_with_all = ["short", "tree"])]
+        json: bool,
+
+        /// Only show the peer with this name or IP, in detail
+        #[clap(long, value_name = "NAME_OR_IP", conflicts_with_all = ["short", "tree"])]
+        peer: Option<String>,
+
+        /// Keep refreshing the display every SECONDS (2 by default) until interrupted
+        #[clap(
+            long,
+            value_name = "SECONDS",
+            num_args = 0..=1,
+            default_missing_value = "2",
+            value_parser = clap::value_parser!(u64).range(1..),
+            conflicts_with = "json"
+        )]
+        watch: Option<u64>,
+
+        /// Write the output to PATH as plain text, without colors, instead of to stdout
+        #[clap(long, value_name = "PATH", conflicts_with = "watch")]
+        output: Option<PathBuf>,
+
+        /// Select the interface by the name of the network it was installed from
+        #[clap(long, value_name = "NAME", conflicts_with = "interface")]
+        network: Option<String>,
+
         interface: Option<Interface>,
     },
 
+    /// Check whether the enabled peers of a network are reachable
+    Check { interface: Interface },
+
     /// Bring up your local interface, and update it with latest peer list
+    ///
+    /// By default this fetches once and exits. Pass --daemon to keep running and fetch
+    /// periodically instead.
     Up {
         /// Enable daemon mode i.e. keep the process running, while fetching
         /// the latest peer list periodically
-        #[clap(short, long)]
+        #[clap(short, long, conflicts_with = "dry_run")]
         daemon: bool,
 
+        /// Fetch the latest peer list once and exit. This is the default without --daemon, and
+        /// only exists to make scripts and cron jobs explicit about it
+        #[clap(long, conflicts_with = "daemon")]
+        once: bool,
+
         /// Keep fetching the latest peer list at the specified interval in
         /// seconds. Valid only in daemon mode
         #[clap(long, default_value = "60")]
         interval: u64,
 
+        /// Add a random delay of up to this many seconds to each interval, to
+        /// avoid many peers fetching at the same time. Valid only in daemon mode
+        #[clap(long, default_value = "0")]
+        jitter: u64,
+
+        /// Fetch more often, down to every 10 seconds, while many of the peers we've had a
+        /// handshake with have gone quiet, e.g. after a network change. Valid only in daemon mode
+        #[clap(long)]
+        adaptive_interval: bool,
+
         #[clap(flatten)]
         hosts: HostsOpt,
 
         #[clap(flatten)]
         nat: NatOpts,
 
+        #[clap(flatten)]
+        fetch_opts: FetchOpts,
+
+        /// Skip this interface when bringing up every installed one. Can be repeated
+        #[clap(long, value_name = "INTERFACE", conflicts_with = "interface")]
+        exclude: Vec<Interface>,
+
         interface: Option<Interface>,
     },
 
     /// Fetch and update your local interface with the latest peer list
     Fetch {
-        interface: Interface,
+        #[clap(required_unless_present = "network")]
+        interface: Option<Interface>,
+
+        /// Select the interface by the name of the network it was installed from
+        #[clap(long, value_name = "NAME", conflicts_with = "interface")]
+        network: Option<String>,
 
         #[clap(flatten)]
         hosts: HostsOpt,
 
         #[clap(flatten)]
         nat: NatOpts,
+
+        #[clap(flatten)]
+        fetch_opts: FetchOpts,
+
+        /// Only update the local peer list used by 'innernet show', without
+        /// touching the WireGuard interface
+        #[clap(long, conflicts_with = "dry_run")]
+        no_apply: bool,
     },
 
     /// Uninstall an innernet network.
@@ -157,7 +393,17 @@ enum Command {
     },
 
     /// Bring down the interface (equivalent to 'wg-quick down <interface>')
-    Down { interface: Interface },
+    Down {
+        interface: Interface,
+
+        #[clap(flatten)]
+        hosts: HostsOpt,
+
+        /// Remove every route innernet added through this network's interface, even if its
+        /// WireGuard device is already gone (e.g. after wireguard-go crashed)
+        #[clap(long)]
+        flush_routes: bool,
+    },
 
     /// Add a new peer
     ///
@@ -239,6 +485,14 @@ enum Command {
         sub_opts: EnableDisablePeerOpts,
     },
 
+    /// Delete a peer, keeping its name and IP reserved until it's purged on the server
+    DeletePeer {
+        interface: Interface,
+
+        #[clap(flatten)]
+        sub_opts: DeletePeerOpts,
+    },
+
     /// Add an association between CIDRs
     AddAssociation {
         interface: Interface,
@@ -266,6 +520,29 @@ enum Command {
         sub_opts: ListenPortOpts,
     },
 
+    /// Generate a new keypair for this peer and register it with the server
+    ///
+    /// Other peers accept the new public key in place of the one they pinned
+    /// the next time they fetch, since the server records the rotation.
+    RotateKey {
+        interface: Interface,
+
+        /// Bypass confirmation
+        #[clap(long)]
+        yes: bool,
+    },
+
+    /// Set the persistent keepalive interval of the local interface's peers
+    ///
+    /// This only changes your local view of the network, and is reset to the
+    /// server-provided value the next time the interface is fetched.
+    SetKeepalive {
+        interface: Interface,
+
+        #[clap(flatten)]
+        sub_opts: KeepaliveOpts,
+    },
+
     /// Override your external endpoint that the server sends to other peers
     OverrideEndpoint {
         interface: Interface,
@@ -274,24 +551,63 @@ enum Command {
         sub_opts: OverrideEndpointOpts,
     },
 
+    /// Print the interface's config, with secrets redacted, e.g. for a bug report
+    ExportConfig {
+        interface: Interface,
+
+        /// Include the private key and join token instead of redacting them
+        #[clap(long)]
+        include_private: bool,
+    },
+
     /// Generate shell completion scripts
     Completions {
         #[clap(value_enum)]
         shell: clap_complete::Shell,
     },
+
+    /// Print dynamic values for shell completion scripts, one per line
+    #[clap(name = "__complete", hide = true)]
+    Complete {
+        #[clap(subcommand)]
+        kind: CompletionKind,
+    },
+}
+
+#[derive(Clone, Debug, Subcommand)]
+enum CompletionKind {
+    /// Names of the installed interfaces
+    Interfaces,
+
+    /// Names of the enabled peers of an interface
+    Peers { interface: Interface },
 }
 
 fn update_hosts_file(
     interface: &InterfaceName,
-    hosts_path: PathBuf,
+    hosts_file: HostsFile,
     peers: &[Peer],
+    cidrs: &[Cidr],
 ) -> Result<(), WrappedIoError> {
+    let HostsFile {
+        path: hosts_path,
+        hostname_format,
+    } = hosts_file;
+    let interface_name = interface.to_string();
     let mut hosts_builder = HostsBuilder::new(format!("innernet {interface}"));
     for peer in peers {
-        hosts_builder.add_hostname(
-            peer.contents.ip,
-            format!("{}.{}.wg", peer.contents.name, interface),
-        );
+        let cidr = cidrs
+            .iter()
+            .find(|cidr| cidr.id == peer.contents.cidr_id)
+            .map(|cidr| cidr.contents.name.as_str());
+        match hostname_format.hostname(&peer.contents.name, &interface_name, cidr) {
+            Some(hostname) => hosts_builder.add_hostname(peer.contents.ip, hostname),
+            None => log::warn!(
+                "skipping peer {} in the hosts file: \"{}\" doesn't give it a valid hostname.",
+                peer.contents.name,
+                hostname_format
+            ),
+        }
     }
     match hosts_builder.write_to(&hosts_path).with_path(&hosts_path) {
         Ok(has_written) if has_written => {
@@ -307,20 +623,98 @@ fn update_hosts_file(
     Ok(())
 }
 
+/// Expand the given paths into a list of invitation files, replacing any directories
+/// with the `.toml` files they contain.
+fn collect_invites(paths: &[PathBuf]) -> Result<Vec<PathBuf>, Error> {
+    if paths.iter().filter(|path| path.as_os_str() == "-").count() > 1 {
+        bail!("\"-\" can only be given once, since stdin holds a single invitation.");
+    }
+    let mut invites = vec![];
+    for path in paths {
+        if path.is_dir() {
+            let mut entries = std::fs::read_dir(path)
+                .with_path(path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()
+                .with_path(path)?;
+            entries.retain(|entry| {
+                entry.is_file() && entry.extension().is_some_and(|ext| ext == "toml")
+            });
+            entries.sort();
+            if entries.is_empty() {
+                log::warn!("no invitation files found in {}", path.display());
+            }
+            invites.extend(entries);
+        } else {
+            invites.push(path.clone());
+        }
+    }
+    Ok(invites)
+}
+
+/// Install every given invitation as its own interface, continuing past failures and
+/// reporting which ones didn't succeed at the end.
+fn install_all(
+    opts: &Opts,
+    paths: &[PathBuf],
+    hosts_file: Option<HostsFile>,
+    install_opts: InstallOpts,
+    nat: &NatOpts,
+) -> Result<(), Error> {
+    let invites = collect_invites(paths)?;
+    match invites.as_slice() {
+        [] => bail!("no invitation files to install."),
+        [invite] => return install(opts, invite, hosts_file, install_opts, nat),
+        _ => {},
+    }
+    if install_opts.name.is_some() {
+        bail!("--name can't be used when installing more than one invitation.");
+    }
+
+    let mut failures = vec![];
+    for invite in &invites {
+        log::info!("installing {}", invite.display());
+        if let Err(e) = install(opts, invite, hosts_file.clone(), install_opts.clone(), nat) {
+            log::error!("failed to install {}: {}", invite.display(), e);
+            failures.push((invite, e));
+        }
+    }
+
+    eprintln!(
+        "\n{} Installed {} of {} invitations.",
+        "[*]".dimmed(),
+        invites.len() - failures.len(),
+        invites.len()
+    );
+    for (invite, e) in &failures {
+        eprintln!("    {} {}: {}", "failed".red(), invite.display(), e);
+    }
+    if !failures.is_empty() {
+        bail!("{} invitation(s) failed to install.", failures.len());
+    }
+    Ok(())
+}
+
 fn install(
     opts: &Opts,
     invite: &Path,
-    hosts_file: Option<PathBuf>,
+    hosts_file: Option<HostsFile>,
     install_opts: InstallOpts,
     nat: &NatOpts,
 ) -> Result<(), Error> {
     shared::ensure_dirs_exist(&[&opts.config_dir])?;
-    let config = InterfaceConfig::from_file(invite)?;
+    let location = InviteLocation::new(invite);
+    let mut config = location.read(opts.api_timeout, &opts.proxy())?;
+    if let Some(join_token) = install_opts.join_token {
+        config.server.join_token = Some(join_token);
+    }
 
     let iface = if install_opts.default_name {
         config.interface.network_name.clone()
     } else if let Some(ref iface) = install_opts.name {
         iface.clone()
+    } else if !prompts::is_interactive() {
+        config.interface.network_name.clone()
     } else {
         Input::with_theme(&*prompts::THEME)
             .with_prompt("Interface name")
@@ -335,22 +729,49 @@ fn install(
             iface
         );
     }
-    let iface = iface.parse()?;
+    if let Some((other, other_network)) =
+        util::overlapping_installed(&opts.config_dir, config.interface.address)?
+    {
+        let message = format!(
+            "This network ({}) overlaps with the network of the installed interface \"{}\" ({}), \
+             so routes to one would shadow the other.",
+            config.interface.address.trunc(),
+            other,
+            other_network
+        );
+        if !install_opts.allow_overlap {
+            bail!("{} Pass --allow-overlap to install it anyway.", message);
+        }
+        log::warn!("{}", message);
+    }
+
+    let iface: Interface = iface.parse()?;
     if Device::list(opts.network.backend)
         .iter()
         .flatten()
-        .any(|name| name == &iface)
+        .any(|name| name == &*iface)
     {
         bail!(
             "An existing WireGuard interface with the name \"{}\" already exists.",
             iface
         );
     }
-
-    redeem_invite(&iface, config, target_conf, opts.network).map_err(|e| {
+    iface.check_reserved()?;
+
+    let redeem_wait = Duration::from_secs(install_opts.redeem_wait);
+    redeem_invite(
+        &iface,
+        config,
+        target_conf,
+        opts.network,
+        redeem_wait,
+        opts.api_timeout,
+        opts.proxy(),
+    )
+    .map_err(|e| {
         log::error!("failed to start the interface: {}.", e);
         log::info!("bringing down the interface.");
-        if let Err(e) = wg::down(&iface, opts.network.backend) {
+        if let Err(e) = wg::down(&iface, opts.backend(&iface)) {
             log::warn!("failed to bring down interface: {}.", e.to_string());
         };
         log::error!("Failed to redeem invite. Now's a good time to make sure the server is started and accessible!");
@@ -358,8 +779,19 @@ fn install(
     })?;
 
     let mut fetch_success = false;
+    let mut endpoint_cache = EndpointCache::new(SERVER_ENDPOINT_TTL);
     for _ in 0..3 {
-        if fetch(&iface, opts, true, hosts_file.clone(), nat).is_ok() {
+        if fetch(
+            &iface,
+            opts,
+            true,
+            hosts_file.clone(),
+            nat,
+            &FetchOpts::default(),
+            &mut endpoint_cache,
+        )
+        .is_ok()
+        {
             fetch_success = true;
             break;
         }
@@ -371,15 +803,29 @@ fn install(
         );
     }
 
-    if install_opts.delete_invite
-        || Confirm::with_theme(&*prompts::THEME)
-            .wait_for_newline(true)
-            .with_prompt(format!(
-                "Delete invitation file \"{}\" now? (It's no longer needed)",
-                invite.to_string_lossy().yellow()
-            ))
-            .default(true)
-            .interact()?
+    if !install_opts.no_self_test {
+        match self_test(&iface, opts) {
+            Ok(peers) => eprintln!(
+                "{} connected to server, {} peers visible",
+                "✓".green().bold(),
+                peers
+            ),
+            Err(e) => log::warn!("self-test failed: {}", e),
+        }
+    }
+
+    // Only a local file is left behind to clean up.
+    if matches!(location, InviteLocation::File(_))
+        && (install_opts.delete_invite
+            || (prompts::is_interactive()
+                && Confirm::with_theme(&*prompts::THEME)
+                    .wait_for_newline(true)
+                    .with_prompt(format!(
+                        "Delete invitation file \"{}\" now? (It's no longer needed)",
+                        invite.to_string_lossy().yellow()
+                    ))
+                    .default(true)
+                    .interact()?))
     {
         std::fs::remove_file(invite).with_path(invite)?;
     }
@@ -402,6 +848,24 @@ fn install(
         interface = iface.to_string().yellow(),
         installed = "installed".green(),
     );
+    let install_service = util::running_under_systemd()
+        && !util::systemd_unit_installed()
+        && (install_opts.install_service
+            || (prompts::is_interactive()
+                && Confirm::with_theme(&*prompts::THEME)
+                    .wait_for_newline(true)
+                    .with_prompt(format!(
+                        "Install the {} systemd unit?",
+                        "innernet@.service".yellow()
+                    ))
+                    .default(true)
+                    .interact()?));
+    if install_service {
+        if let Some(path) = util::install_systemd_unit(install_opts.service_interval)? {
+            log::info!("installed systemd unit {}", path.display());
+        }
+    }
+
     if cfg!(target_os = "linux") {
         eprintdoc!(
             "
@@ -440,12 +904,48 @@ fn install(
     Ok(())
 }
 
+/// Check that the server is reachable through a freshly installed interface, returning how
+/// many peers it reports. The error says which step failed, since a missing handshake and an
+/// unresponsive API point at different problems.
+fn self_test(interface: &InterfaceName, opts: &Opts) -> Result<usize, Error> {
+    let config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let server_key = Key::from_base64(&config.server.public_key)?;
+    let handshaked = Device::peers_iter(interface, opts.backend(interface))
+        .with_str(interface.to_string())?
+        .find_by_key(&server_key)
+        .with_str(interface.to_string())?
+        .is_some_and(|info| info.is_recently_connected());
+    if !handshaked {
+        bail!(
+            "no recent WireGuard handshake with the server at {}, check that UDP traffic to it \
+             isn't blocked by a firewall",
+            config.server.external_endpoint
+        );
+    }
+
+    let State { peers, .. } = Api::new(&config.server, opts.api_timeout, &opts.proxy())
+        .http("GET", "/user/state")
+        .map_err(|e| {
+            anyhow!(
+                "the WireGuard tunnel is up, but the server's API at {} didn't answer ({}), \
+                 check that innernet-server is running",
+                config.server.internal_endpoint,
+                e
+            )
+        })?;
+    Ok(peers.len())
+}
+
 fn redeem_invite(
     iface: &InterfaceName,
     mut config: InterfaceConfig,
     target_conf: PathBuf,
     network: NetworkOpts,
+    redeem_wait: Duration,
+    api_timeout: Duration,
+    proxy: ProxySettings,
 ) -> Result<(), Error> {
+    let network = config.interface.network_opts(network);
     log::info!("bringing up interface {}.", iface.as_str_lossy().yellow());
     let resolved_endpoint = config
         .server
@@ -461,10 +961,12 @@ fn redeem_invite(
             &config.server.public_key,
             config.server.internal_endpoint.ip(),
             resolved_endpoint,
+            config.server.network_preshared_key()?.as_ref(),
         )),
         network,
     )
     .with_str(iface.to_string())?;
+    select_server_endpoint(iface, &config, network.backend)?;
 
     log::info!("Generating new keypair.");
     let keypair = wireguard_control::KeyPair::generate();
@@ -473,14 +975,18 @@ fn redeem_invite(
         "Registering keypair with server (at {}).",
         &config.server.internal_endpoint
     );
-    Api::new(&config.server).http_form::<_, ()>(
+    Api::new(&config.server, api_timeout, &proxy).http_form::<_, ()>(
         "POST",
         "/user/redeem",
         RedeemContents {
             public_key: keypair.public.to_base64(),
+            join_token: config.server.join_token.clone(),
         },
     )?;
 
+    // The token is only needed for redemption, so don't keep it around.
+    config.server.join_token = None;
+
     config.interface.private_key = keypair.private.to_base64();
     config.write_to_path(&target_conf, false, Some(0o600))?;
     log::info!(
@@ -488,37 +994,155 @@ fn redeem_invite(
         target_conf.to_string_lossy().yellow()
     );
 
-    log::info!("Changing keys and waiting 5s for server's WireGuard interface to transition.",);
+    log::info!(
+        "Changing keys and waiting up to {}s for server's WireGuard interface to transition.",
+        redeem_wait.as_secs()
+    );
+    let key_changed_at = SystemTime::now();
     DeviceUpdate::new()
         .set_private_key(keypair.private)
         .apply(iface, network.backend)
         .with_str(iface.to_string())?;
-    thread::sleep(REDEEM_TRANSITION_WAIT);
 
+    // WireGuard won't handshake with the new key until there's something to send, and keeps
+    // retrying until the server has transitioned, so a single packet is enough to start it off.
+    let server_key = Key::from_base64(&config.server.public_key)?;
+    let _ = UdpSocket::bind((config.interface.address.addr(), 0))
+        .and_then(|socket| socket.send_to(&[], config.server.internal_endpoint));
+    if !wg::wait_for_handshake(
+        iface,
+        &server_key,
+        key_changed_at,
+        redeem_wait,
+        network.backend,
+    )
+    .with_str(iface.to_string())?
+    {
+        log::warn!("no handshake with the server yet, continuing anyway.");
+    }
+
+    Ok(())
+}
+
+/// How long to wait for a handshake through each of the server's endpoints. WireGuard only
+/// starts a new handshake every 5 seconds, so anything shorter can miss the endpoint's turn.
+const SERVER_ENDPOINT_WAIT: Duration = Duration::from_secs(6);
+
+/// For servers with alternate endpoints, point the freshly brought up server peer at each of
+/// them in turn, primary first, until one handshakes. If none do, the primary is left in place.
+fn select_server_endpoint(
+    iface: &InterfaceName,
+    config: &InterfaceConfig,
+    backend: Backend,
+) -> Result<(), Error> {
+    let server = &config.server;
+    if server.alternate_endpoints.is_empty() {
+        return Ok(());
+    }
+
+    let server_key = Key::from_base64(&server.public_key)?;
+    let mut primary = None;
+    for endpoint in server.endpoints() {
+        let resolved = match endpoint.resolve() {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                log::warn!("failed to resolve server endpoint {}: {}", endpoint, e);
+                continue;
+            },
+        };
+        primary.get_or_insert(resolved);
+
+        log::info!("trying server endpoint {}.", endpoint);
+        let since = SystemTime::now();
+        DeviceUpdate::new()
+            .add_peer(PeerConfigBuilder::new(&server_key).set_endpoint(resolved))
+            .apply(iface, backend)
+            .with_str(iface.to_string())?;
+        let _ = UdpSocket::bind((config.interface.address.addr(), 0))
+            .and_then(|socket| socket.send_to(&[], server.internal_endpoint));
+        if wg::wait_for_handshake(iface, &server_key, since, SERVER_ENDPOINT_WAIT, backend)
+            .with_str(iface.to_string())?
+        {
+            log::info!("reached the server at {}.", endpoint);
+            return Ok(());
+        }
+    }
+
+    log::warn!("no handshake through any of the server's endpoints, continuing anyway.");
+    if let Some(primary) = primary {
+        DeviceUpdate::new()
+            .add_peer(PeerConfigBuilder::new(&server_key).set_endpoint(primary))
+            .apply(iface, backend)
+            .with_str(iface.to_string())?;
+    }
     Ok(())
 }
 
+/// How often `up` fetches in daemon mode.
+#[derive(Clone, Copy, Debug)]
+struct DaemonInterval {
+    interval: Duration,
+    jitter: Duration,
+    /// Fetch more often while peers have gone quiet, see [`daemon::next_interval`].
+    adaptive: bool,
+}
+
 fn up(
     interface: Option<Interface>,
     opts: &Opts,
-    loop_interval: Option<Duration>,
-    hosts_path: Option<PathBuf>,
+    daemon: Option<DaemonInterval>,
+    hosts_path: Option<HostsFile>,
     nat: &NatOpts,
+    fetch_opts: &FetchOpts,
+    exclude: &[Interface],
 ) -> Result<(), Error> {
+    let mut endpoint_cache = EndpointCache::new(SERVER_ENDPOINT_TTL);
+    let mut interval = daemon.map(|daemon| daemon.interval).unwrap_or_default();
     loop {
         let interfaces = match &interface {
             Some(iface) => vec![iface.clone()],
-            None => all_installed(&opts.config_dir)?,
+            None => {
+                let mut interfaces = all_installed(&opts.config_dir)?;
+                interfaces.retain(|iface| {
+                    let excluded = exclude.contains(iface);
+                    if excluded {
+                        log::debug!("skipping excluded interface {}", iface);
+                    }
+                    !excluded
+                });
+                interfaces
+            },
         };
 
+        let mut peers = vec![];
         for iface in interfaces {
-            fetch(&iface, opts, true, hosts_path.clone(), nat)?;
+            fetch(
+                &iface,
+                opts,
+                true,
+                hosts_path.clone(),
+                nat,
+                fetch_opts,
+                &mut endpoint_cache,
+            )?;
+            if daemon.is_some_and(|daemon| daemon.adaptive) {
+                if let Ok(device) = Device::get(&iface, opts.backend(&iface)) {
+                    peers.extend(device.peers);
+                }
+            }
         }
 
-        match loop_interval {
-            Some(interval) => thread::sleep(interval),
-            None => break,
+        let Some(daemon) = daemon else {
+            break;
+        };
+        if daemon.adaptive {
+            let next = daemon::next_interval(interval, daemon.interval, &peers, SystemTime::now());
+            if next != interval {
+                log::info!("fetching every {}s from now on.", next.as_secs());
+            }
+            interval = next;
         }
+        thread::sleep(interval + util::jitter(daemon.jitter));
     }
 
     Ok(())
@@ -528,11 +1152,18 @@ fn fetch(
     interface: &InterfaceName,
     opts: &Opts,
     bring_up_interface: bool,
-    hosts_path: Option<PathBuf>,
+    hosts_path: Option<HostsFile>,
     nat: &NatOpts,
+    fetch_opts: &FetchOpts,
+    endpoint_cache: &mut EndpointCache,
 ) -> Result<(), Error> {
+    if fetch_opts.dry_run {
+        return fetch_dry_run(interface, opts, fetch_opts);
+    }
+
     let config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
-    let interface_up = match Device::list(opts.network.backend) {
+    let network = config.interface.network_opts(opts.network);
+    let interface_up = match Device::list(network.backend) {
         Ok(interfaces) => interfaces.iter().any(|name| name == interface),
         _ => false,
     };
@@ -549,10 +1180,8 @@ fn fetch(
             "bringing up interface {}.",
             interface.as_str_lossy().yellow()
         );
-        let resolved_endpoint = config
-            .server
-            .external_endpoint
-            .resolve()
+        let resolved_endpoint = endpoint_cache
+            .resolve(&config.server.external_endpoint)
             .with_str(config.server.external_endpoint.to_string())?;
         wg::up(
             interface,
@@ -563,10 +1192,20 @@ fn fetch(
                 &config.server.public_key,
                 config.server.internal_endpoint.ip(),
                 resolved_endpoint,
+                config.server.network_preshared_key()?.as_ref(),
             )),
-            opts.network,
+            network,
         )
         .with_str(interface.to_string())?;
+        select_server_endpoint(interface, &config, network.backend)?;
+    }
+
+    if interface_up {
+        verify_server_peer(interface, opts, &config, fetch_opts.force)?;
+        if let Err(e) = refresh_server_endpoint(interface, &config, network.backend, endpoint_cache)
+        {
+            log::warn!("failed to refresh the server's endpoint: {}", e);
+        }
     }
 
     log::info!(
@@ -574,27 +1213,69 @@ fn fetch(
         interface.as_str_lossy().yellow()
     );
     let mut store = DataStore::open_or_create(&opts.data_dir, interface)?;
-    let api = Api::new(&config.server);
-    let State { peers, cidrs } = api.http("GET", "/user/state")?;
+    let api = Api::new(&config.server, opts.api_timeout, &opts.proxy());
+    let State {
+        mut peers,
+        cidrs,
+        key_rotations,
+    } = api.http("GET", &state_path(&store))?;
+
+    let endpoint_overrides = fetch_opts.load_endpoint_overrides()?;
+    for peer in &mut peers {
+        if let Some(endpoint) = endpoint_overrides.get(&peer.public_key) {
+            log::debug!("overriding endpoint of {} with {}", peer.name, endpoint);
+            peer.contents.endpoint = Some((*endpoint).into());
+        }
+    }
 
-    let device = Device::get(interface, opts.network.backend)?;
-    let modifications = device.diff(&peers);
+    let device = Device::get(interface, network.backend)?;
+    let preshared_key = config.server.network_preshared_key()?;
+    let modifications = device.diff(&peers, preshared_key.as_ref());
 
+    // Removals are handled separately below, so that peers missing from the server's state are
+    // removed by key even if the diff doesn't account for them.
     let updates = modifications
         .iter()
+        .filter(|diff| diff.new.is_some())
         .inspect(|diff| util::print_peer_diff(&store, diff))
         .cloned()
         .map(PeerConfigBuilder::from)
         .collect::<Vec<_>>();
-
-    if !updates.is_empty() || !interface_up {
-        DeviceUpdate::new()
-            .add_peers(&updates)
-            .apply(interface, opts.network.backend)
+    let stale = wg::stale_peers(&device.peers, &peers);
+
+    if fetch_opts.reconcile || !updates.is_empty() || !stale.is_empty() || !interface_up {
+        let update = if fetch_opts.reconcile {
+            let own_public_key = config.interface.public_key()?;
+            let authorized = new_peer_diffs(&peers, &own_public_key, preshared_key.as_ref())?
+                .into_iter()
+                .map(PeerConfigBuilder::from)
+                .collect::<Vec<_>>();
+            log::info!(
+                "replacing the peers of {} with {} authorized peer{}",
+                interface.as_str_lossy().yellow(),
+                authorized.len(),
+                if authorized.len() == 1 { "" } else { "s" },
+            );
+            DeviceUpdate::new().set_peers(&authorized)
+        } else {
+            let mut update = DeviceUpdate::new().add_peers(&updates);
+            for info in &stale {
+                let public_key = info.config.public_key.to_base64();
+                log::info!(
+                    "  peer {} ({}...) isn't in the server's state any more, removing it.",
+                    util::stored_peer_name(&store, &public_key).yellow(),
+                    &public_key[..10].dimmed(),
+                );
+                update = update.remove_peer_by_key(&info.config.public_key);
+            }
+            update
+        };
+        update
+            .apply(interface, network.backend)
             .with_str(interface.to_string())?;
 
-        if let Some(path) = hosts_path {
-            update_hosts_file(interface, path, &peers)?;
+        if let Some(hosts_file) = hosts_path {
+            update_hosts_file(interface, hosts_file, &peers, &cidrs)?;
         }
 
         println!();
@@ -602,13 +1283,14 @@ fn fetch(
     } else {
         log::info!("{}", "peers are already up to date".green());
     }
+    verify_server_peer(interface, opts, &config, fetch_opts.force)?;
     let interface_updated_time = Instant::now();
 
     store.set_cidrs(cidrs);
-    store.update_peers(&peers)?;
+    store.update_peers(&peers, &key_rotations)?;
     store.write().with_str(interface.to_string())?;
 
-    let candidates: Vec<Endpoint> = get_local_addrs()?
+    let candidates: Vec<Endpoint> = get_local_addrs(nat.candidates_family)?
         .filter(|ip| !nat.is_excluded(*ip))
         .map(|addr| SocketAddr::from((addr, device.listen_port.unwrap_or(51820))).into())
         .collect::<Vec<Endpoint>>();
@@ -617,42 +1299,321 @@ fn fetch(
         candidates.len(),
         if candidates.len() == 1 { "" } else { "es" },
     );
-    for candidate in &candidates {
-        log::debug!("  candidate: {}", candidate);
+    for candidate in &candidates {
+        log::debug!("  candidate: {}", candidate);
+    }
+    // The peer state has already been applied at this point, so a failure to report candidates
+    // shouldn't fail the whole fetch.
+    match api.http_form::<_, ()>("PUT", "/user/candidates", &candidates) {
+        Err(ureq::Error::Status(404, _)) => {
+            log::warn!("your network is using an old version of innernet-server that doesn't support NAT traversal candidate reporting.")
+        },
+        Err(e) => log::warn!("failed to report NAT traversal candidates: {}", e),
+        _ => log::debug!("candidates successfully reported"),
+    }
+
+    if nat.no_nat_traversal {
+        log::debug!("NAT traversal explicitly disabled, not attempting.");
+    } else {
+        let step_interval = Duration::from_secs(nat.nat_step_interval);
+        let deadline = nat
+            .nat_traversal_timeout
+            .map(|timeout| Instant::now() + Duration::from_secs(timeout));
+        let mut nat_traverse =
+            NatTraverse::new(interface, network.backend, step_interval, &modifications)?;
+
+        // Give time for handshakes with recently changed endpoints to complete before attempting traversal.
+        if !nat_traverse.is_finished() {
+            thread::sleep(step_interval.saturating_sub(interface_updated_time.elapsed()));
+        }
+        loop {
+            if nat_traverse.is_finished() {
+                break;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                log::warn!(
+                    "NAT traversal timed out, leaving {} unconnected until the next fetch.",
+                    nat_traverse
+                        .remaining_peers()
+                        .iter()
+                        .map(|peer| peer.name.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                break;
+            }
+            log::info!(
+                "Attempting to establish connection with {} remaining unconnected peers...",
+                nat_traverse.remaining()
+            );
+            nat_traverse.step()?;
+        }
+
+        store.update_nat_traversal_results(nat_traverse.results());
+        store.write().with_str(interface.to_string())?;
+    }
+
+    Ok(())
+}
+
+fn down(
+    interface: &InterfaceName,
+    opts: &Opts,
+    hosts_path: Option<HostsFile>,
+    flush_routes: bool,
+) -> Result<(), Error> {
+    if flush_routes {
+        let config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+        // The routes can only be found while the interface still exists.
+        for cidr in
+            wg::flush_routes(interface, config.interface.address).with_str(interface.to_string())?
+        {
+            log::info!("removed route {}", cidr);
+        }
+    }
+    let result = if !flush_routes || Device::get(interface, opts.backend(interface)).is_ok() {
+        wg::down(interface, opts.backend(interface))
+    } else {
+        log::info!("interface {} is already down", interface);
+        Ok(())
+    };
+
+    // The peers' hostnames are stale whether or not the interface came down cleanly.
+    if let Some(HostsFile { path, .. }) = hosts_path {
+        HostsBuilder::remove_from(format!("innernet {interface}"), &path).with_path(&path)?;
+        log::info!("removed {} peers from {}", interface, path.display());
+    }
+
+    result
+}
+
+/// The device's peer for the server: like WireGuard itself, the one with the most specific
+/// allowed IP covering the server's internal address.
+fn find_server_peer<'a>(device: &'a Device, config: &InterfaceConfig) -> Option<&'a PeerInfo> {
+    let server_ip = config.server.internal_endpoint.ip();
+    device
+        .peers
+        .iter()
+        .filter_map(|peer| {
+            peer.config
+                .allowed_ips
+                .iter()
+                .filter(|allowed_ip| {
+                    ipnet::IpNet::new(allowed_ip.address, allowed_ip.cidr)
+                        .is_ok_and(|net| net.contains(&server_ip))
+                })
+                .map(|allowed_ip| allowed_ip.cidr)
+                .max()
+                .map(|prefix| (prefix, peer))
+        })
+        .max_by_key(|(prefix, _)| *prefix)
+        .map(|(_, peer)| peer)
+}
+
+/// If we haven't heard from the server in a while, look its endpoint up again in case its
+/// address changed (e.g. a dynamic DNS name), and point the interface at the new address.
+/// While handshakes keep succeeding, the interface is left alone.
+fn refresh_server_endpoint(
+    interface: &InterfaceName,
+    config: &InterfaceConfig,
+    backend: Backend,
+    endpoint_cache: &mut EndpointCache,
+) -> Result<(), Error> {
+    let device = Device::get(interface, backend).with_str(interface.to_string())?;
+    let Some(server_peer) = find_server_peer(&device, config) else {
+        return Ok(());
+    };
+
+    let handshake_recent = server_peer
+        .stats
+        .last_handshake_time
+        .and_then(|time| time.elapsed().ok())
+        .is_some_and(|elapsed| elapsed < SERVER_HANDSHAKE_TIMEOUT);
+    if handshake_recent {
+        return Ok(());
+    }
+
+    let endpoint = &config.server.external_endpoint;
+    endpoint_cache.invalidate(endpoint);
+    let resolved = endpoint_cache
+        .resolve(endpoint)
+        .with_str(endpoint.to_string())?;
+    if server_peer.config.endpoint != Some(resolved) {
+        log::info!(
+            "no recent handshake with the server, updating its endpoint to {}",
+            resolved
+        );
+        DeviceUpdate::new()
+            .add_peer(PeerConfigBuilder::new(&server_peer.config.public_key).set_endpoint(resolved))
+            .apply(interface, backend)
+            .with_str(interface.to_string())?;
+    }
+
+    Ok(())
+}
+
+/// Make sure the peer that the interface routes the server's internal IP to is the server from
+/// the config file, and not something another process has reconfigured the interface with.
+fn verify_server_peer(
+    interface: &InterfaceName,
+    opts: &Opts,
+    config: &InterfaceConfig,
+    force: bool,
+) -> Result<(), Error> {
+    let device = Device::get(interface, opts.backend(interface)).with_str(interface.to_string())?;
+    let server_ip = config.server.internal_endpoint.ip();
+
+    let Some(server_peer) = find_server_peer(&device, config) else {
+        log::debug!(
+            "no peer on {} routes to the server at {}",
+            interface,
+            server_ip
+        );
+        return Ok(());
+    };
+
+    let actual_key = server_peer.config.public_key.to_base64();
+    if actual_key == config.server.public_key {
+        return Ok(());
+    }
+
+    eprintdoc!(
+        "
+        {warning}: the peer for the server ({server_ip}) on interface {interface} has public key
+            {actual_key}
+        but the config file expects
+            {expected_key}
+
+        Another process may have reconfigured the interface, and innernet could be talking
+        to an imposter.
+        ",
+        warning = "WARNING".red().bold(),
+        interface = interface.to_string().yellow(),
+        expected_key = config.server.public_key,
+    );
+    if force {
+        log::warn!("continuing anyway because --force was passed.");
+        Ok(())
+    } else {
+        bail!("server public key mismatch on interface {interface} (pass --force to ignore)")
+    }
+}
+
+/// Print what `fetch` would change on the interface, without touching the interface, the
+/// hosts file or the local peer list.
+/// The API path to fetch the network's state, asking only for the key rotations `store` hasn't
+/// seen yet.
+fn state_path(store: &DataStore) -> String {
+    format!(
+        "/user/state?key-rotations-after={}",
+        store.key_rotations_seen()
+    )
+}
+
+fn fetch_dry_run(
+    interface: &InterfaceName,
+    opts: &Opts,
+    fetch_opts: &FetchOpts,
+) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    // A down interface has no server peer that could have been tampered with.
+    let device = Device::get(interface, opts.backend(interface)).ok();
+    if device.is_some() {
+        verify_server_peer(interface, opts, &config, fetch_opts.force)?;
+    }
+
+    log::info!(
+        "fetching state for {} from server...",
+        interface.as_str_lossy().yellow()
+    );
+    // Before the first fetch there's no store yet. It starts out empty either way, and the
+    // dry run never writes to it.
+    let mut store = DataStore::open_or_create(&opts.data_dir, interface)?;
+    let State {
+        mut peers,
+        key_rotations,
+        ..
+    } = Api::new(&config.server, opts.api_timeout, &opts.proxy())
+        .http("GET", &state_path(&store))?;
+
+    let endpoint_overrides = fetch_opts.load_endpoint_overrides()?;
+    for peer in &mut peers {
+        if let Some(endpoint) = endpoint_overrides.get(&peer.public_key) {
+            peer.contents.endpoint = Some((*endpoint).into());
+        }
     }
-    match api.http_form::<_, ()>("PUT", "/user/candidates", &candidates) {
-        Err(ureq::Error::Status(404, _)) => {
-            log::warn!("your network is using an old version of innernet-server that doesn't support NAT traversal candidate reporting.")
+
+    let preshared_key = config.server.network_preshared_key()?;
+    let modifications = match &device {
+        Some(device) => device.diff(&peers, preshared_key.as_ref()),
+        None => {
+            // A down interface has no peers yet, so every peer `up` would add is new.
+            let own_public_key = config.interface.public_key()?;
+            new_peer_diffs(&peers, &own_public_key, preshared_key.as_ref())?
         },
-        Err(e) => return Err(e.into()),
-        _ => {},
+    };
+    for diff in &modifications {
+        util::print_peer_diff(&store, diff);
     }
-    log::debug!("candidates successfully reported");
+    // Catch peers that would fail the pinning check, but leave the store on disk alone.
+    store.update_peers(&peers, &key_rotations)?;
 
-    if nat.no_nat_traversal {
-        log::debug!("NAT traversal explicitly disabled, not attempting.");
+    if modifications.is_empty() {
+        log::info!("{}", "peers are already up to date".green());
     } else {
-        let mut nat_traverse = NatTraverse::new(interface, opts.network.backend, &modifications)?;
-
-        // Give time for handshakes with recently changed endpoints to complete before attempting traversal.
-        if !nat_traverse.is_finished() {
-            thread::sleep(nat::STEP_INTERVAL - interface_updated_time.elapsed());
-        }
-        loop {
-            if nat_traverse.is_finished() {
-                break;
-            }
-            log::info!(
-                "Attempting to establish connection with {} remaining unconnected peers...",
-                nat_traverse.remaining()
-            );
-            nat_traverse.step()?;
-        }
+        log::info!(
+            "dry run: {} peer change{} not applied to {}",
+            modifications.len(),
+            if modifications.len() == 1 { "" } else { "s" },
+            interface.as_str_lossy().yellow()
+        );
     }
 
     Ok(())
 }
 
+/// The diffs that add every enabled peer in `peers` other than ourselves to an interface that
+/// has none.
+fn new_peer_diffs<'a>(
+    peers: &'a [Peer],
+    own_public_key: &str,
+    preshared_key: Option<&Key>,
+) -> Result<Vec<PeerDiff<'a>>, Error> {
+    peers
+        .iter()
+        .filter(|peer| !peer.is_disabled && peer.public_key != own_public_key)
+        .filter_map(|peer| {
+            PeerDiff::with_preshared_key(None, Some(peer), preshared_key).transpose()
+        })
+        .collect()
+}
+
+/// Fetch the latest network state into the data store without applying it to the interface.
+fn fetch_state(interface: &InterfaceName, opts: &Opts) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    log::info!(
+        "fetching state for {} from server...",
+        interface.as_str_lossy().yellow()
+    );
+    let mut store = DataStore::open_or_create(&opts.data_dir, interface)?;
+    let State {
+        peers,
+        cidrs,
+        key_rotations,
+    } = Api::new(&config.server, opts.api_timeout, &opts.proxy())
+        .http("GET", &state_path(&store))?;
+
+    store.set_cidrs(cidrs);
+    store.update_peers(&peers, &key_rotations)?;
+    store.write().with_str(interface.to_string())?;
+    log::info!(
+        "updated local state for {}",
+        interface.as_str_lossy().yellow()
+    );
+
+    Ok(())
+}
+
 fn uninstall(interface: &InterfaceName, opts: &Opts, yes: bool) -> Result<(), Error> {
     let config = InterfaceConfig::get_path(&opts.config_dir, interface);
     let data = DataStore::get_path(&opts.data_dir, interface);
@@ -663,6 +1624,7 @@ fn uninstall(interface: &InterfaceName, opts: &Opts, yes: bool) -> Result<(), Er
             interface.as_str_lossy().yellow()
         );
     }
+    prompts::ensure_flags(&[("--yes", yes)])?;
 
     if yes
         || Confirm::with_theme(&*prompts::THEME)
@@ -675,7 +1637,7 @@ fn uninstall(interface: &InterfaceName, opts: &Opts, yes: bool) -> Result<(), Er
             .interact()?
     {
         log::info!("bringing down interface (if up).");
-        wg::down(interface, opts.network.backend).ok();
+        wg::down(interface, opts.backend(interface)).ok();
         std::fs::remove_file(&config)
             .with_path(&config)
             .map_err(|e| log::warn!("{}", e.to_string().yellow()))
@@ -696,7 +1658,7 @@ fn add_cidr(interface: &InterfaceName, opts: &Opts, sub_opts: AddCidrOpts) -> Re
     let InterfaceConfig { server, .. } =
         InterfaceConfig::from_interface(&opts.config_dir, interface)?;
     log::info!("Fetching CIDRs");
-    let api = Api::new(&server);
+    let api = Api::new(&server, opts.api_timeout, &opts.proxy());
     let cidrs: Vec<Cidr> = api.http("GET", "/admin/cidrs")?;
 
     if let Some(cidr_request) = prompts::add_cidr(&cidrs, &sub_opts)? {
@@ -728,7 +1690,7 @@ fn rename_cidr(
 ) -> Result<(), Error> {
     let InterfaceConfig { server, .. } =
         InterfaceConfig::from_interface(&opts.config_dir, interface)?;
-    let api = Api::new(&server);
+    let api = Api::new(&server, opts.api_timeout, &opts.proxy());
 
     log::info!("Fetching CIDRs");
     let cidrs: Vec<Cidr> = api.http("GET", "/admin/cidrs")?;
@@ -759,7 +1721,7 @@ fn delete_cidr(
     let InterfaceConfig { server, .. } =
         InterfaceConfig::from_interface(&opts.config_dir, interface)?;
     println!("Fetching eligible CIDRs");
-    let api = Api::new(&server);
+    let api = Api::new(&server, opts.api_timeout, &opts.proxy());
     let cidrs: Vec<Cidr> = api.http("GET", "/admin/cidrs")?;
     let peers: Vec<Peer> = api.http("GET", "/admin/peers")?;
 
@@ -773,13 +1735,34 @@ fn delete_cidr(
     Ok(())
 }
 
+/// The CIDR tree from the local copy of the network's state, which is empty until the
+/// interface has been fetched at least once.
+fn local_cidr_tree<'a>(
+    store: &'a DataStore,
+    interface: &InterfaceName,
+) -> Result<CidrTree<'a>, Error> {
+    if store.cidrs().is_empty() {
+        return Err(not_fetched_yet(interface));
+    }
+    CidrTree::try_new(store.cidrs())
+}
+
+fn not_fetched_yet(interface: &InterfaceName) -> Error {
+    anyhow!(
+        "no local state for {} yet, run 'innernet fetch {}' first",
+        interface,
+        interface
+    )
+}
+
 fn list_cidrs(interface: &InterfaceName, opts: &Opts, tree: bool) -> Result<(), Error> {
     let data_store = DataStore::open(&opts.data_dir, interface)?;
     if tree {
-        let cidr_tree = CidrTree::new(data_store.cidrs());
+        let cidr_tree = local_cidr_tree(&data_store, interface)?;
         colored::control::set_override(false);
-        print_tree(&cidr_tree, &[], 0);
+        let result = print_tree(&mut io::stdout().lock(), &cidr_tree, &[], 0);
         colored::control::unset_override();
+        result?;
     } else {
         for cidr in data_store.cidrs() {
             println!("{} {}", cidr.cidr, cidr.name);
@@ -791,28 +1774,32 @@ fn list_cidrs(interface: &InterfaceName, opts: &Opts, tree: bool) -> Result<(),
 fn add_peer(interface: &InterfaceName, opts: &Opts, sub_opts: AddPeerOpts) -> Result<(), Error> {
     let InterfaceConfig { server, .. } =
         InterfaceConfig::from_interface(&opts.config_dir, interface)?;
-    let api = Api::new(&server);
+    let api = Api::new(&server, opts.api_timeout, &opts.proxy());
 
     log::info!("Fetching CIDRs");
     let cidrs: Vec<Cidr> = api.http("GET", "/admin/cidrs")?;
     log::info!("Fetching peers");
-    let peers: Vec<Peer> = api.http("GET", "/admin/peers")?;
-    let cidr_tree = CidrTree::new(&cidrs[..]);
+    let mut peers: Vec<Peer> = api.http("GET", "/admin/peers")?;
+    // Deleted peers' IPs stay taken until they're purged.
+    peers.extend(api.http::<Vec<Peer>>("GET", "/admin/peers/deleted")?);
+    let cidr_tree = CidrTree::try_new(&cidrs[..])?;
 
     if let Some(result) = prompts::add_peer(&peers, &cidr_tree, &sub_opts)? {
         let (peer_request, keypair, target_path, mut target_file) = result;
         log::info!("Creating peer...");
         let peer: Peer = api.http_form("POST", "/admin/peers", peer_request)?;
         let server_peer = peers.iter().find(|p| p.id == 1).unwrap();
-        prompts::write_peer_invitation(
-            (&mut target_file, &target_path),
+        let invitation = prompts::peer_invitation(
             interface,
             &peer,
             server_peer,
+            &server.alternate_endpoints,
             &cidr_tree,
             keypair,
             &server.internal_endpoint,
+            server.preshared_key.clone(),
         )?;
+        prompts::write_peer_invitation((&mut target_file, &target_path), &peer, invitation)?;
     } else {
         log::info!("Exited without creating peer.");
     }
@@ -827,7 +1814,7 @@ fn rename_peer(
 ) -> Result<(), Error> {
     let InterfaceConfig { server, .. } =
         InterfaceConfig::from_interface(&opts.config_dir, interface)?;
-    let api = Api::new(&server);
+    let api = Api::new(&server, opts.api_timeout, &opts.proxy());
 
     log::info!("Fetching peers");
     let peers: Vec<Peer> = api.http("GET", "/admin/peers")?;
@@ -859,7 +1846,7 @@ fn enable_or_disable_peer(
 ) -> Result<(), Error> {
     let InterfaceConfig { server, .. } =
         InterfaceConfig::from_interface(&opts.config_dir, interface)?;
-    let api = Api::new(&server);
+    let api = Api::new(&server, opts.api_timeout, &opts.proxy());
 
     log::info!("Fetching peers.");
     let peers: Vec<Peer> = api.http("GET", "/admin/peers")?;
@@ -875,6 +1862,28 @@ fn enable_or_disable_peer(
     Ok(())
 }
 
+fn delete_peer(
+    interface: &InterfaceName,
+    opts: &Opts,
+    sub_opts: DeletePeerOpts,
+) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } =
+        InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let api = Api::new(&server, opts.api_timeout, &opts.proxy());
+
+    log::info!("Fetching peers.");
+    let peers: Vec<Peer> = api.http("GET", "/admin/peers")?;
+
+    if let Some(peer) = prompts::delete_peer(&peers, &sub_opts)? {
+        api.http::<()>("DELETE", &format!("/admin/peers/{}", peer.id))?;
+        log::info!("peer {} deleted.", peer.name.yellow());
+    } else {
+        log::info!("exiting without deleting peer.");
+    }
+
+    Ok(())
+}
+
 fn add_association(
     interface: &InterfaceName,
     opts: &Opts,
@@ -882,7 +1891,7 @@ fn add_association(
 ) -> Result<(), Error> {
     let InterfaceConfig { server, .. } =
         InterfaceConfig::from_interface(&opts.config_dir, interface)?;
-    let api = Api::new(&server);
+    let api = Api::new(&server, opts.api_timeout, &opts.proxy());
 
     log::info!("Fetching CIDRs");
     let cidrs: Vec<Cidr> = api.http("GET", "/admin/cidrs")?;
@@ -924,7 +1933,7 @@ fn delete_association(
 ) -> Result<(), Error> {
     let InterfaceConfig { server, .. } =
         InterfaceConfig::from_interface(&opts.config_dir, interface)?;
-    let api = Api::new(&server);
+    let api = Api::new(&server, opts.api_timeout, &opts.proxy());
 
     log::info!("Fetching CIDRs");
     let cidrs: Vec<Cidr> = api.http("GET", "/admin/cidrs")?;
@@ -945,7 +1954,7 @@ fn delete_association(
 fn list_associations(interface: &InterfaceName, opts: &Opts) -> Result<(), Error> {
     let InterfaceConfig { server, .. } =
         InterfaceConfig::from_interface(&opts.config_dir, interface)?;
-    let api = Api::new(&server);
+    let api = Api::new(&server, opts.api_timeout, &opts.proxy());
 
     log::info!("Fetching CIDRs");
     let cidrs: Vec<Cidr> = api.http("GET", "/admin/cidrs")?;
@@ -983,7 +1992,7 @@ fn set_listen_port(
 
     let listen_port = prompts::set_listen_port(&config.interface, sub_opts)?;
     if let Some(listen_port) = listen_port {
-        wg::set_listen_port(interface, listen_port, opts.network.backend)?;
+        wg::set_listen_port(interface, listen_port, opts.backend(interface))?;
         log::info!("the interface is updated");
 
         config.interface.listen_port = listen_port;
@@ -996,6 +2005,77 @@ fn set_listen_port(
     Ok(listen_port.flatten())
 }
 
+fn rotate_key(interface: &InterfaceName, opts: &Opts, yes: bool) -> Result<(), Error> {
+    let mut config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    prompts::ensure_flags(&[("--yes", yes)])?;
+
+    if !yes
+        && !prompts::confirm(&format!(
+            "Rotate the keypair of this peer on \"{}\"?",
+            interface.as_str_lossy().yellow()
+        ))?
+    {
+        log::info!("exiting without rotating the keypair.");
+        return Ok(());
+    }
+
+    log::info!("Generating new keypair.");
+    let keypair = wireguard_control::KeyPair::generate();
+
+    log::info!(
+        "Registering keypair with server (at {}).",
+        &config.server.internal_endpoint
+    );
+    Api::new(&config.server, opts.api_timeout, &opts.proxy()).http_form::<_, ()>(
+        "POST",
+        "/user/rotate-key",
+        RotateKeyContents {
+            public_key: keypair.public.to_base64(),
+            signature: KeyRotation::sign(
+                &Key::from_base64(&config.interface.private_key)?,
+                &keypair.public.to_base64(),
+            ),
+        },
+    )?;
+
+    config.interface.private_key = keypair.private.to_base64();
+    config.write_to_interface(&opts.config_dir, interface)?;
+    log::info!("New keypair registered and saved to config.");
+
+    log::info!("Changing keys and waiting 5s for server's WireGuard interface to transition.",);
+    DeviceUpdate::new()
+        .set_private_key(keypair.private)
+        .apply(interface, opts.backend(interface))
+        .with_str(interface.to_string())?;
+    thread::sleep(REDEEM_TRANSITION_WAIT);
+
+    Ok(())
+}
+
+fn set_keepalive(
+    interface: &InterfaceName,
+    opts: &Opts,
+    sub_opts: KeepaliveOpts,
+) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let device = Device::get(interface, opts.backend(interface)).with_str(interface.to_string())?;
+    let peers = device
+        .peers
+        .iter()
+        .map(|peer| peer.config.public_key.clone())
+        .filter(|key| sub_opts.all || key.to_base64() == config.server.public_key)
+        .collect::<Vec<_>>();
+
+    if let Some(seconds) = prompts::set_keepalive(&sub_opts)? {
+        wg::set_persistent_keepalive(interface, &peers, seconds, opts.backend(interface))?;
+        log::info!("updated keepalive for {} peer(s).", peers.len());
+    } else {
+        log::info!("exiting without updating the keepalive interval.");
+    }
+
+    Ok(())
+}
+
 fn override_endpoint(
     interface: &InterfaceName,
     opts: &Opts,
@@ -1010,13 +2090,22 @@ fn override_endpoint(
             Some(port) => port,
             None => bail!("you need to set a listen port with set-listen-port before overriding the endpoint (otherwise port randomization on the interface would make it useless).")
         };
-        let endpoint = prompts::override_endpoint(&sub_opts, port)?;
+        // Prefer an address of the same family we reach the server over.
+        let preference = match config.server.external_endpoint.resolve() {
+            Ok(addr) if addr.is_ipv6() => Preference::Ipv6,
+            _ => Preference::Ipv4,
+        };
+        let endpoint = prompts::override_endpoint(&sub_opts, port, preference)?;
         endpoint.map(EndpointContents::Set)
     };
 
     if let Some(contents) = endpoint_contents {
         log::info!("requesting endpoint update...");
-        Api::new(&config.server).http_form::<_, ()>("PUT", "/user/endpoint", contents)?;
+        Api::new(&config.server, opts.api_timeout, &opts.proxy()).http_form::<_, ()>(
+            "PUT",
+            "/user/endpoint",
+            contents,
+        )?;
         log::info!(
             "endpoint override {}",
             if sub_opts.unset { "unset" } else { "set" }
@@ -1028,11 +2117,80 @@ fn override_endpoint(
     Ok(())
 }
 
-fn show(opts: &Opts, short: bool, tree: bool, interface: Option<Interface>) -> Result<(), Error> {
-    let interfaces = interface.map_or_else(
-        || Device::list(opts.network.backend),
-        |interface| Ok(vec![*interface]),
-    )?;
+fn export_config(
+    interface: &InterfaceName,
+    opts: &Opts,
+    include_private: bool,
+) -> Result<(), Error> {
+    let mut config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    if !include_private {
+        const REDACTED: &str = "[redacted]";
+        config.interface.private_key = REDACTED.into();
+        if let Some(join_token) = &mut config.server.join_token {
+            *join_token = REDACTED.into();
+        }
+    }
+
+    print!("{}", toml::to_string(&config)?);
+    Ok(())
+}
+
+/// How `innernet show` lays out what it prints.
+struct ShowOpts {
+    short: bool,
+    tree: bool,
+    json: bool,
+    /// Only show the peer with this name or IP.
+    peer: Option<String>,
+}
+
+fn show(
+    opts: &Opts,
+    show_opts: &ShowOpts,
+    interface: Option<Interface>,
+    output: Option<&Path>,
+) -> Result<(), Error> {
+    match output {
+        Some(path) => {
+            let mut file = File::create(path).with_path(path)?;
+            colored::control::set_override(false);
+            let result = write_show(&mut file, opts, show_opts, interface);
+            colored::control::unset_override();
+            result
+        },
+        None => write_show(&mut io::stdout().lock(), opts, show_opts, interface),
+    }
+}
+
+fn write_show(
+    out: &mut dyn Write,
+    opts: &Opts,
+    show_opts: &ShowOpts,
+    interface: Option<Interface>,
+) -> Result<(), Error> {
+    let ShowOpts {
+        short,
+        tree,
+        json,
+        ref peer,
+    } = *show_opts;
+    let interfaces = match interface {
+        Some(interface) => vec![*interface],
+        None => {
+            let mut interfaces = Device::list(opts.network.backend)?;
+            // Interfaces configured with their own backend aren't listed by `--backend`'s.
+            for interface in all_installed(&opts.config_dir).unwrap_or_default() {
+                let backend = opts.backend(&interface);
+                if backend != opts.network.backend
+                    && !interfaces.contains(&interface)
+                    && Device::get(&interface, backend).is_ok()
+                {
+                    interfaces.push(*interface);
+                }
+            }
+            interfaces
+        },
+    };
 
     let devices = interfaces
         .into_iter()
@@ -1040,7 +2198,7 @@ fn show(opts: &Opts, short: bool, tree: bool, interface: Option<Interface>) -> R
             match DataStore::open(&opts.data_dir, &name) {
                 Ok(store) => {
                     let device =
-                        Device::get(&name, opts.network.backend).with_str(name.as_str_lossy());
+                        Device::get(&name, opts.backend(&name)).with_str(name.as_str_lossy());
                     Some(device.map(|device| (device, store)))
                 },
                 // Skip WireGuard interfaces that aren't managed by innernet.
@@ -1051,12 +2209,14 @@ fn show(opts: &Opts, short: bool, tree: bool, interface: Option<Interface>) -> R
         })
         .collect::<Result<Vec<_>, _>>()?;
 
-    if devices.is_empty() {
+    if devices.is_empty() && !json {
         log::info!("No innernet networks currently running.");
         return Ok(());
     }
 
-    for (device_info, store) in devices {
+    let mut json_output = vec![];
+    let mut matched_peer = false;
+    for (device_info, store) in &devices {
         let public_key = match &device_info.public_key {
             Some(key) => key.to_base64(),
             None => {
@@ -1069,7 +2229,9 @@ fn show(opts: &Opts, short: bool, tree: bool, interface: Option<Interface>) -> R
         };
 
         let peers = store.peers();
-        let cidrs = store.cidrs();
+        if peers.is_empty() {
+            return Err(not_fetched_yet(&device_info.name));
+        }
         let me = peers
             .iter()
             .find(|p| p.public_key == public_key)
@@ -1084,6 +2246,8 @@ fn show(opts: &Opts, short: bool, tree: bool, interface: Option<Interface>) -> R
                     Some(peer) => Ok(PeerState {
                         peer,
                         info: Some(info),
+                        nat_traversal: store.nat_traversal_result(&public_key),
+                        reachability: None,
                     }),
                     None => Err(anyhow!("peer {} isn't an innernet peer.", public_key)),
                 }
@@ -1092,69 +2256,231 @@ fn show(opts: &Opts, short: bool, tree: bool, interface: Option<Interface>) -> R
         peer_states.push(PeerState {
             peer: me,
             info: None,
+            nat_traversal: None,
+            reachability: None,
         });
 
-        print_interface(&device_info, short || tree)?;
         peer_states.sort_by_key(|peer| peer.peer.ip);
+        if let Some(filter) = peer {
+            peer_states.retain(|state| {
+                &*state.peer.name == filter || state.peer.ip.to_string() == *filter
+            });
+            if peer_states.is_empty() {
+                continue;
+            }
+            matched_peer = true;
+        }
 
+        if json {
+            json_output.push(InterfaceStatus {
+                interface: device_info.name.to_string(),
+                listen_port: device_info.listen_port,
+                peers: peer_states.iter().map(PeerStatus::from).collect(),
+            });
+            continue;
+        }
+
+        print_interface(out, device_info, short || tree)?;
         if tree {
-            let cidr_tree = CidrTree::new(cidrs);
-            print_tree(&cidr_tree, &peer_states, 1);
+            let cidr_tree = local_cidr_tree(store, &device_info.name)?;
+            for state in &mut peer_states {
+                if state.info.is_some() {
+                    state.reachability = cidr_tree.reachability(None, me, state.peer);
+                }
+            }
+            print_tree(out, &cidr_tree, &peer_states, 1)?;
         } else {
             for peer_state in peer_states {
-                print_peer(&peer_state, short, 1);
+                print_peer(out, &peer_state, short, 1)?;
             }
         }
     }
+
+    if let Some(filter) = peer {
+        if !matched_peer {
+            bail!("no peer named \"{}\" or with that IP was found.", filter);
+        }
+    }
+    if json {
+        writeln!(out, "{}", serde_json::to_string_pretty(&json_output)?)?;
+    }
+    Ok(())
+}
+
+/// Re-render `show` every `interval` until interrupted. Only local state is re-read between
+/// refreshes; nothing is fetched from the server.
+fn show_watch(
+    opts: &Opts,
+    show_opts: &ShowOpts,
+    interface: Option<Interface>,
+    interval: Duration,
+) -> Result<(), Error> {
+    let (interrupt_tx, interrupt_rx) = std::sync::mpsc::channel();
+    ctrlc::set_handler(move || {
+        let _ = interrupt_tx.send(());
+    })?;
+
+    loop {
+        // Clear the screen and move the cursor to the top-left corner.
+        print!("\x1B[2J\x1B[H");
+        println!(
+            "{}\n",
+            format!("Every {}s: innernet show", interval.as_secs()).dimmed()
+        );
+        show(opts, show_opts, interface.clone(), None)?;
+
+        match interrupt_rx.recv_timeout(interval) {
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => continue,
+            _ => break,
+        }
+    }
+
+    Ok(())
+}
+
+fn check(interface: &InterfaceName, opts: &Opts) -> Result<(), Error> {
+    // How long to give WireGuard to complete handshakes triggered by the probes.
+    const HANDSHAKE_WAIT: Duration = Duration::from_secs(5);
+
+    let store = DataStore::open(&opts.data_dir, interface)?;
+    let device = Device::get(interface, opts.backend(interface)).with_str(interface.to_string())?;
+    let my_public_key = device
+        .public_key
+        .as_ref()
+        .map(|key| key.to_base64())
+        .ok_or_else(|| anyhow!("interface {} is missing a public key", interface))?;
+    let peers: Vec<_> = store
+        .peers()
+        .iter()
+        .filter(|peer| !peer.is_disabled && peer.public_key != my_public_key)
+        .collect();
+
+    // Sending any traffic through the tunnel makes WireGuard initiate a handshake with
+    // peers that don't have a fresh session, so fire a single UDP datagram at each one.
+    let probe_v4 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok();
+    let probe_v6 = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok();
+    for peer in &peers {
+        let socket = if peer.ip.is_ipv4() {
+            &probe_v4
+        } else {
+            &probe_v6
+        };
+        if let Some(socket) = socket {
+            // The discard port; nothing needs to answer for the handshake to happen.
+            let _ = socket.send_to(&[0], (peer.ip, 9));
+        }
+    }
+    log::info!(
+        "probed {} peers, waiting for handshakes to complete...",
+        peers.len()
+    );
+    thread::sleep(HANDSHAKE_WAIT);
+
+    let device = Device::get(interface, opts.backend(interface)).with_str(interface.to_string())?;
+    let mut unreachable = 0;
+    for peer in &peers {
+        let info = device
+            .peers
+            .iter()
+            .find(|info| info.config.public_key.to_base64() == peer.public_key);
+        let last_handshake = info
+            .and_then(|info| info.stats.last_handshake_time)
+            .map(|time| time.elapsed().unwrap_or_default());
+        let status = match (info, last_handshake) {
+            (None, _) => {
+                unreachable += 1;
+                "not on interface (try 'innernet fetch')".red().to_string()
+            },
+            (Some(info), Some(elapsed)) if info.is_recently_connected() => {
+                format!("{} (handshake {})", "OK".green(), human_duration(elapsed))
+            },
+            (Some(_), Some(elapsed)) => {
+                unreachable += 1;
+                format!(
+                    "{} (stale handshake {})",
+                    "unreachable".red(),
+                    human_duration(elapsed)
+                )
+            },
+            (Some(_), None) => {
+                unreachable += 1;
+                format!("{} (never handshaked)", "unreachable".red())
+            },
+        };
+        println!(
+            "{} {}: {}",
+            peer.ip.to_string().yellow().bold(),
+            peer.name.yellow(),
+            status
+        );
+    }
+
+    if unreachable > 0 {
+        bail!("{} of {} peers unreachable", unreachable, peers.len());
+    }
     Ok(())
 }
 
-fn print_tree(cidr: &CidrTree, peers: &[PeerState], level: usize) {
-    println_pad!(
+fn print_tree(
+    out: &mut dyn Write,
+    cidr: &CidrTree,
+    peers: &[PeerState],
+    level: usize,
+) -> io::Result<()> {
+    writeln_pad!(
+        out,
         level * 2,
         "{} {}",
         cidr.cidr.to_string().bold().blue(),
         cidr.name.blue(),
-    );
+    )?;
 
     let mut children: Vec<_> = cidr.children().collect();
     children.sort();
-    children
-        .iter()
-        .for_each(|child| print_tree(child, peers, level + 1));
+    for child in children {
+        print_tree(out, &child, peers, level + 1)?;
+    }
 
     for peer in peers.iter().filter(|p| p.peer.cidr_id == cidr.id) {
-        print_peer(peer, true, level);
+        print_peer(out, peer, true, level)?;
     }
+    Ok(())
 }
 
-fn print_interface(device_info: &Device, short: bool) -> Result<(), Error> {
+fn print_interface(out: &mut dyn Write, device_info: &Device, short: bool) -> io::Result<()> {
     if short {
         let listen_port_str = device_info
             .listen_port
             .map(|p| format!("(:{p}) "))
             .unwrap_or_default();
-        println!(
+        writeln!(
+            out,
             "{} {}",
             device_info.name.to_string().green().bold(),
             listen_port_str.dimmed(),
-        );
+        )?;
     } else {
-        println!(
+        writeln!(
+            out,
             "{}: {}",
             "network".green().bold(),
             device_info.name.to_string().green(),
-        );
+        )?;
         if let Some(listen_port) = device_info.listen_port {
-            println!("  {}: {}", "listening port".bold(), listen_port);
+            writeln!(out, "  {}: {}", "listening port".bold(), listen_port)?;
         }
     }
     Ok(())
 }
 
-fn print_peer(peer: &PeerState, short: bool, level: usize) {
+fn print_peer(out: &mut dyn Write, peer: &PeerState, short: bool, level: usize) -> io::Result<()> {
     let pad = level * 2;
-    let PeerState { peer, info } = peer;
+    let PeerState {
+        peer,
+        info,
+        nat_traversal,
+        reachability,
+    } = peer;
     if short {
         let connected = info
             .map(|info| info.is_recently_connected())
@@ -1162,9 +2488,10 @@ fn print_peer(peer: &PeerState, short: bool, level: usize) {
 
         let is_you = info.is_none();
 
-        println_pad!(
+        writeln_pad!(
+            out,
             pad,
-            "| {} {}: {} ({}{}…)",
+            "| {} {}: {} ({}{}…){}",
             if connected || is_you {
                 "◉".bold()
             } else {
@@ -1174,45 +2501,83 @@ fn print_peer(peer: &PeerState, short: bool, level: usize) {
             peer.name.yellow(),
             if is_you { "you, " } else { "" },
             &peer.public_key[..6].dimmed(),
-        );
+            reachability
+                .map(|reachability| format!(" [{reachability}]").dimmed().to_string())
+                .unwrap_or_default(),
+        )?;
     } else {
-        println_pad!(
+        writeln_pad!(
+            out,
             pad,
             "{}: {} ({}...)",
             "peer".yellow().bold(),
             peer.name.yellow(),
             &peer.public_key[..10].yellow(),
-        );
-        println_pad!(pad, "  {}: {}", "ip".bold(), peer.ip);
+        )?;
+        writeln_pad!(out, pad, "  {}: {}", "ip".bold(), peer.ip)?;
         if let Some(info) = info {
             if let Some(endpoint) = info.config.endpoint {
-                println_pad!(pad, "  {}: {}", "endpoint".bold(), endpoint);
+                writeln_pad!(out, pad, "  {}: {}", "endpoint".bold(), endpoint)?;
             }
             if let Some(last_handshake) = info.stats.last_handshake_time {
-                let duration = last_handshake.elapsed().expect("horrible clock problem");
-                println_pad!(
-                    pad,
-                    "  {}: {}",
-                    "last handshake".bold(),
-                    human_duration(duration),
-                );
+                let elapsed = match last_handshake.elapsed() {
+                    Ok(duration) => human_duration(duration),
+                    Err(e) => {
+                        log::warn!(
+                            "the last handshake with {} is {}s in the future, check that the \
+                             system clock is correct.",
+                            peer.name,
+                            e.duration().as_secs()
+                        );
+                        "clock skew detected".red().to_string()
+                    },
+                };
+                writeln_pad!(out, pad, "  {}: {}", "last handshake".bold(), elapsed)?;
             }
             if info.stats.tx_bytes > 0 || info.stats.rx_bytes > 0 {
-                println_pad!(
+                writeln_pad!(
+                    out,
                     pad,
                     "  {}: {} received, {} sent",
                     "transfer".bold(),
                     human_size(info.stats.rx_bytes),
                     human_size(info.stats.tx_bytes),
-                );
+                )?;
+            }
+            if let Some(result) = nat_traversal {
+                let result = match result {
+                    NatTraversalResult::Succeeded => "succeeded".green(),
+                    NatTraversalResult::Failed => "failed, no direct path found".red(),
+                };
+                writeln_pad!(out, pad, "  {}: {}", "nat traversal".bold(), result)?;
             }
         }
     }
+    Ok(())
+}
+
+/// Completion scripts call this on every keypress, so any errors are silently ignored.
+fn print_completions(opts: &Opts, kind: CompletionKind) {
+    match kind {
+        CompletionKind::Interfaces => {
+            for interface in all_installed(&opts.config_dir).unwrap_or_default() {
+                println!("{interface}");
+            }
+        },
+        CompletionKind::Peers { interface } => {
+            if let Ok(store) = DataStore::open(&opts.data_dir, &interface) {
+                for peer in store.peers().iter().filter(|peer| !peer.is_disabled) {
+                    println!("{}", peer.name);
+                }
+            }
+        },
+    }
 }
 
 fn main() {
     let opts = Opts::parse();
     util::init_logger(opts.verbose);
+    prompts::set_non_interactive(opts.non_interactive);
 
     if let Err(e) = run(&opts) {
         println!();
@@ -1223,7 +2588,47 @@ fn main() {
         if let Some(e) = e.downcast_ref::<io::Error>() {
             util::permissions_helptext(&opts.config_dir, &opts.data_dir, e);
         }
-        std::process::exit(1);
+        std::process::exit(exit_code::for_error(&e, &opts.config_dir));
+    }
+}
+
+fn parse_seconds(seconds: &str) -> Result<Duration, std::num::ParseIntError> {
+    seconds.parse().map(Duration::from_secs)
+}
+
+impl Command {
+    /// Whether the command changes WireGuard interfaces, which requires elevated privileges.
+    fn configures_interface(&self) -> bool {
+        match self {
+            Command::Install { .. }
+            | Command::Down { .. }
+            | Command::Uninstall { .. }
+            | Command::SetListenPort { .. }
+            | Command::RotateKey { .. }
+            | Command::SetKeepalive { .. } => true,
+            Command::Fetch {
+                no_apply,
+                fetch_opts,
+                ..
+            } => !no_apply && !fetch_opts.dry_run,
+            Command::Up { fetch_opts, .. } => !fetch_opts.dry_run,
+            _ => false,
+        }
+    }
+}
+
+/// Resolve an interface given either directly or by `--network`.
+fn select_interface(
+    opts: &Opts,
+    interface: Option<Interface>,
+    network: Option<&str>,
+) -> Result<Option<Interface>, Error> {
+    match network {
+        Some(network) => Ok(Some(util::installed_by_network_name(
+            &opts.config_dir,
+            network,
+        )?)),
+        None => Ok(interface),
     }
 }
 
@@ -1231,40 +2636,106 @@ fn run(opts: &Opts) -> Result<(), Error> {
     let command = opts.command.clone().unwrap_or(Command::Show {
         short: false,
         tree: false,
+        json: false,
+        peer: None,
+        watch: None,
+        output: None,
+        network: None,
         interface: None,
     });
 
+    // Fail up front rather than with an EPERM from deep within netlink, possibly after having
+    // already talked to the server. `main` turns the EPERM into instructions.
+    if command.configures_interface() && !util::can_configure_interfaces() {
+        return Err(io::Error::from_raw_os_error(libc::EPERM))
+            .with_str("configuring WireGuard interfaces requires root or CAP_NET_ADMIN")?;
+    }
+
     match command {
         Command::Install {
-            invite,
+            invites,
             hosts,
             install_opts,
             nat,
-        } => install(opts, &invite, hosts.into(), install_opts, &nat)?,
+        } => install_all(opts, &invites, hosts.into(), install_opts, &nat)?,
         Command::Show {
             short,
             tree,
+            json,
+            peer,
+            watch,
+            output,
+            network,
             interface,
-        } => show(opts, short, tree, interface)?,
+        } => {
+            let interface = select_interface(opts, interface, network.as_deref())?;
+            let show_opts = ShowOpts {
+                short,
+                tree,
+                json,
+                peer,
+            };
+            match watch {
+                Some(interval) => {
+                    show_watch(opts, &show_opts, interface, Duration::from_secs(interval))?
+                },
+                None => show(opts, &show_opts, interface, output.as_deref())?,
+            }
+        },
+        Command::Check { interface } => check(&interface, opts)?,
         Command::Fetch {
             interface,
+            network,
             hosts,
             nat,
-        } => fetch(&interface, opts, false, hosts.into(), &nat)?,
+            fetch_opts,
+            no_apply,
+        } => {
+            let interface = select_interface(opts, interface, network.as_deref())?
+                .expect("clap requires an interface or --network");
+            if no_apply {
+                fetch_state(&interface, opts)?
+            } else {
+                fetch(
+                    &interface,
+                    opts,
+                    false,
+                    hosts.into(),
+                    &nat,
+                    &fetch_opts,
+                    &mut EndpointCache::new(SERVER_ENDPOINT_TTL),
+                )?
+            }
+        },
         Command::Up {
             interface,
             daemon,
+            once: _,
             hosts,
             nat,
             interval,
+            jitter,
+            adaptive_interval,
+            fetch_opts,
+            exclude,
         } => up(
             interface,
             opts,
-            daemon.then(|| Duration::from_secs(interval)),
+            daemon.then(|| DaemonInterval {
+                interval: Duration::from_secs(interval),
+                jitter: Duration::from_secs(jitter),
+                adaptive: adaptive_interval,
+            }),
             hosts.into(),
             &nat,
+            &fetch_opts,
+            &exclude,
         )?,
-        Command::Down { interface } => wg::down(&interface, opts.network.backend)?,
+        Command::Down {
+            interface,
+            hosts,
+            flush_routes,
+        } => down(&interface, opts, hosts.into(), flush_routes)?,
         Command::Uninstall { interface, yes } => uninstall(&interface, opts, yes)?,
         Command::AddPeer {
             interface,
@@ -1295,6 +2766,10 @@ fn run(opts: &Opts) -> Result<(), Error> {
             interface,
             sub_opts,
         } => enable_or_disable_peer(&interface, opts, sub_opts, true)?,
+        Command::DeletePeer {
+            interface,
+            sub_opts,
+        } => delete_peer(&interface, opts, sub_opts)?,
         Command::AddAssociation {
             interface,
             sub_opts,
@@ -1310,12 +2785,22 @@ fn run(opts: &Opts) -> Result<(), Error> {
         } => {
             set_listen_port(&interface, opts, sub_opts)?;
         },
+        Command::RotateKey { interface, yes } => rotate_key(&interface, opts, yes)?,
+        Command::SetKeepalive {
+            interface,
+            sub_opts,
+        } => set_keepalive(&interface, opts, sub_opts)?,
         Command::OverrideEndpoint {
             interface,
             sub_opts,
         } => {
             override_endpoint(&interface, opts, sub_opts)?;
         },
+        Command::ExportConfig {
+            interface,
+            include_private,
+        } => export_config(&interface, opts, include_private)?,
+        Command::Complete { kind } => print_completions(opts, kind),
         Command::Completions { shell } => {
             use clap::CommandFactory;
             let mut app = Opts::command();
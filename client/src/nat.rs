@@ -3,27 +3,42 @@
 //! Doesn't follow the specific ICE protocol, but takes great inspiration from RFC 8445
 //! and applies it to a protocol more specific to innernet.
 
-use std::time::{Duration, Instant};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 
 use anyhow::Error;
+use serde::{Deserialize, Serialize};
 use shared::{
     wg::{DeviceExt, PeerInfoExt},
     Endpoint, Peer, PeerDiff,
 };
 use wireguard_control::{Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder};
 
-pub const STEP_INTERVAL: Duration = Duration::from_secs(5);
+/// The outcome of the last NAT traversal attempt for a peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NatTraversalResult {
+    /// A handshake completed while traversal was in progress.
+    Succeeded,
+    /// Every candidate endpoint was tried without completing a handshake.
+    Failed,
+}
 
 pub struct NatTraverse<'a> {
     interface: &'a InterfaceName,
     backend: Backend,
+    step_interval: Duration,
     remaining: Vec<Peer>,
+    results: HashMap<String, NatTraversalResult>,
 }
 
 impl<'a> NatTraverse<'a> {
     pub fn new(
         interface: &'a InterfaceName,
         backend: Backend,
+        step_interval: Duration,
         diffs: &[PeerDiff],
     ) -> Result<Self, Error> {
         // Filter out removed peers from diffs list.
@@ -50,7 +65,9 @@ impl<'a> NatTraverse<'a> {
         let mut nat_traverse = Self {
             interface,
             backend,
+            step_interval,
             remaining,
+            results: HashMap::new(),
         };
 
         nat_traverse.refresh_remaining()?;
@@ -66,10 +83,21 @@ impl<'a> NatTraverse<'a> {
         self.remaining.len()
     }
 
+    /// The peers still being traversed.
+    pub fn remaining_peers(&self) -> &[Peer] {
+        &self.remaining
+    }
+
+    /// The traversal outcome of each peer (by public key) that has finished traversal.
+    pub fn results(&self) -> &HashMap<String, NatTraversalResult> {
+        &self.results
+    }
+
     /// Refreshes the current state of candidate traversal attempts, filtering out
     /// the peers that have been exhausted of all endpoint options.
     fn refresh_remaining(&mut self) -> Result<(), Error> {
         let device = Device::get(self.interface, self.backend)?;
+        let results = &mut self.results;
         // Remove connected and missing peers
         self.remaining.retain(|peer| {
             if let Some(peer_info) = device.get_peer(&peer.public_key) {
@@ -79,6 +107,7 @@ impl<'a> NatTraverse<'a> {
                         "peer {} removed from NAT traverser (connected!).",
                         peer.name
                     );
+                    results.insert(peer.public_key.clone(), NatTraversalResult::Succeeded);
                 }
                 !recently_connected
             } else {
@@ -90,7 +119,14 @@ impl<'a> NatTraverse<'a> {
             }
         });
 
-        self.remaining.retain(|peer| !peer.candidates.is_empty());
+        self.remaining.retain(|peer| {
+            if peer.candidates.is_empty() {
+                results.insert(peer.public_key.clone(), NatTraversalResult::Failed);
+                false
+            } else {
+                true
+            }
+        });
 
         Ok(())
     }
@@ -114,7 +150,7 @@ impl<'a> NatTraverse<'a> {
             .apply(self.interface, self.backend)?;
 
         let start = Instant::now();
-        while start.elapsed() < STEP_INTERVAL {
+        while start.elapsed() < self.step_interval {
             self.refresh_remaining()?;
 
             if self.is_finished() {
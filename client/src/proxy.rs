@@ -0,0 +1,209 @@
+//! Picking the HTTP proxy, if any, that API requests and invitation downloads go through.
+//!
+//! WireGuard traffic is never proxied, so only requests made with ureq are affected.
+
+use ipnet::IpNet;
+use std::net::IpAddr;
+use ureq::Proxy;
+
+/// The variables a proxy is taken from with `--env-proxy`, in order of preference. Unlike
+/// ureq's own detection, `$HTTP_PROXY` is left alone, since it's often set for web browsing
+/// through a proxy that has no route to the server's internal address.
+const PROXY_VARS: [&str; 4] = ["ALL_PROXY", "all_proxy", "HTTPS_PROXY", "https_proxy"];
+
+/// The variables listing hosts to reach directly with `--env-proxy`.
+const NO_PROXY_VARS: [&str; 2] = ["NO_PROXY", "no_proxy"];
+
+/// Parse a `--proxy` URL. Only HTTP proxies are supported, since this build of ureq can't
+/// speak SOCKS.
+pub fn parse_proxy(proxy: &str) -> Result<Proxy, String> {
+    if proxy.starts_with("socks") {
+        return Err("SOCKS proxies aren't supported, use an HTTP proxy".into());
+    }
+    Proxy::new(proxy).map_err(|e| e.to_string())
+}
+
+/// A proxy to make requests through, along with the hosts that should be reached directly
+/// instead.
+#[derive(Clone, Debug, Default)]
+pub struct ProxySettings {
+    proxy: Option<Proxy>,
+    no_proxy: Vec<String>,
+}
+
+impl ProxySettings {
+    /// Send every request through `proxy`, if one was given.
+    pub fn new(proxy: Option<Proxy>) -> Self {
+        Self {
+            proxy,
+            no_proxy: vec![],
+        }
+    }
+
+    /// The proxy set in `$ALL_PROXY` or `$HTTPS_PROXY` (or their lowercase forms), bypassed for
+    /// the hosts in `$NO_PROXY` (or `$no_proxy`).
+    pub fn from_env() -> Self {
+        Self::from_vars(|var| std::env::var(var).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let var = |name: &str| var(name).filter(|value| !value.is_empty());
+        let proxy = PROXY_VARS.into_iter().find_map(|name| {
+            let value = var(name)?;
+            match parse_proxy(&value) {
+                Ok(proxy) => Some(proxy),
+                Err(e) => {
+                    log::warn!("ignoring ${}: {}", name, e);
+                    None
+                },
+            }
+        });
+        let no_proxy = NO_PROXY_VARS
+            .into_iter()
+            .find_map(var)
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(|entry| entry.trim().to_string())
+                    .filter(|entry| !entry.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self { proxy, no_proxy }
+    }
+
+    /// The proxy to reach `host` (a hostname or IP address) through, or `None` if it should be
+    /// reached directly.
+    pub fn for_host(&self, host: &str) -> Option<Proxy> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if self.no_proxy.iter().any(|entry| bypasses(entry, host)) {
+            None
+        } else {
+            self.proxy.clone()
+        }
+    }
+
+    /// The proxy to download `url` through, or `None` if it should be downloaded directly.
+    pub fn for_url(&self, url: &str) -> Option<Proxy> {
+        self.for_host(url_host(url))
+    }
+}
+
+/// Whether a `$NO_PROXY` entry covers `host`. Entries are `*`, an IP address or CIDR, or a
+/// domain name that also covers its subdomains (with or without a leading dot).
+fn bypasses(entry: &str, host: &str) -> bool {
+    if entry == "*" {
+        return true;
+    }
+    if let Ok(host) = host.parse::<IpAddr>() {
+        let entry = entry.trim_start_matches('[').trim_end_matches(']');
+        return match (entry.parse::<IpNet>(), entry.parse::<IpAddr>()) {
+            (Ok(net), _) => net.contains(&host),
+            (_, Ok(ip)) => ip == host,
+            _ => false,
+        };
+    }
+    let domain = entry.trim_start_matches('.');
+    host.eq_ignore_ascii_case(domain)
+        || host.len() > domain.len()
+            && host.is_char_boundary(host.len() - domain.len() - 1)
+            && host[host.len() - domain.len() - 1..].eq_ignore_ascii_case(&format!(".{domain}"))
+}
+
+/// The host part of a URL, without its scheme, credentials, port, or path.
+fn url_host(url: &str) -> &str {
+    let authority = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = authority.split(['/', '?', '#']).next().unwrap_or(authority);
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    match host.strip_prefix('[') {
+        Some(v6) => v6.split(']').next().unwrap_or(v6),
+        None => host.split(':').next().unwrap_or(host),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn from_vars(vars: &[(&str, &str)]) -> ProxySettings {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        ProxySettings::from_vars(|name| vars.get(name).map(|value| value.to_string()))
+    }
+
+    #[test]
+    fn test_parse_proxy() {
+        assert!(parse_proxy("http://proxy.example.com:3128").is_ok());
+        assert!(parse_proxy("socks5://proxy.example.com:1080").is_err());
+        assert!(parse_proxy("ftp://proxy.example.com:3128").is_err());
+    }
+
+    #[test]
+    fn test_proxy_from_env() {
+        let proxy = parse_proxy("http://all.example.com:3128").unwrap();
+        let settings = from_vars(&[
+            ("HTTP_PROXY", "http://ignored.example.com:3128"),
+            ("https_proxy", "http://https.example.com:3128"),
+            ("ALL_PROXY", "http://all.example.com:3128"),
+        ]);
+        assert_eq!(settings.for_host("10.80.0.1"), Some(proxy));
+
+        assert_eq!(from_vars(&[]).for_host("10.80.0.1"), None);
+        assert_eq!(
+            from_vars(&[("HTTP_PROXY", "http://proxy.example.com:3128")]).for_host("10.80.0.1"),
+            None
+        );
+        // An unusable proxy is skipped in favor of the next variable.
+        let proxy = parse_proxy("http://https.example.com:3128").unwrap();
+        let settings = from_vars(&[
+            ("ALL_PROXY", "socks5://proxy.example.com:1080"),
+            ("HTTPS_PROXY", "http://https.example.com:3128"),
+        ]);
+        assert_eq!(settings.for_host("10.80.0.1"), Some(proxy));
+    }
+
+    #[test]
+    fn test_no_proxy() {
+        let settings = from_vars(&[
+            ("HTTPS_PROXY", "http://proxy.example.com:3128"),
+            (
+                "no_proxy",
+                "10.80.0.0/16, fd00::1,.internal.example.com,example.org",
+            ),
+        ]);
+        assert!(settings.for_host("10.80.1.1").is_none());
+        assert!(settings.for_host("10.81.1.1").is_some());
+        assert!(settings.for_host("[fd00::1]").is_none());
+        assert!(settings.for_host("fd00::2").is_some());
+        assert!(settings.for_host("a.internal.example.com").is_none());
+        assert!(settings.for_host("internal.example.com").is_none());
+        assert!(settings.for_host("EXAMPLE.org").is_none());
+        assert!(settings.for_host("www.example.org").is_none());
+        assert!(settings.for_host("notexample.org").is_some());
+
+        let settings = from_vars(&[
+            ("HTTPS_PROXY", "http://proxy.example.com:3128"),
+            ("NO_PROXY", "*"),
+        ]);
+        assert!(settings.for_host("10.80.1.1").is_none());
+
+        // An explicit --proxy isn't subject to $NO_PROXY.
+        let proxy = parse_proxy("http://proxy.example.com:3128").unwrap();
+        assert!(ProxySettings::new(Some(proxy))
+            .for_host("10.80.1.1")
+            .is_some());
+    }
+
+    #[test]
+    fn test_url_host() {
+        assert_eq!(url_host("https://example.com/invite.toml"), "example.com");
+        assert_eq!(
+            url_host("https://user:pw@example.com:8443/a?b"),
+            "example.com"
+        );
+        assert_eq!(url_host("https://[fd00::1]:8443/invite.toml"), "fd00::1");
+        assert_eq!(url_host("https://example.com"), "example.com");
+    }
+}
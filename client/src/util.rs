@@ -1,14 +1,89 @@
-use crate::data_store::DataStore;
+use crate::{data_store::DataStore, proxy::ProxySettings};
+use anyhow::{anyhow, bail};
 use colored::*;
 use indoc::eprintdoc;
+use ipnet::IpNet;
 use log::{Level, LevelFilter};
 use serde::{de::DeserializeOwned, Serialize};
 use shared::{
-    interface_config::ServerInfo, Interface, PeerChange, PeerDiff, INNERNET_PUBKEY_HEADER,
+    interface_config::{InterfaceConfig, ServerInfo},
+    Endpoint, Error, Interface, IoErrorContext, PeerChange, PeerDiff, INNERNET_PUBKEY_HEADER,
+};
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    io::{self, Read},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use std::{ffi::OsStr, io, path::Path, time::Duration};
 use ureq::{Agent, AgentBuilder};
 
+/// Where an `install` argument says to read an invitation from.
+pub enum InviteLocation<'a> {
+    File(&'a Path),
+    Stdin,
+    Url(&'a str),
+}
+
+impl<'a> InviteLocation<'a> {
+    pub fn new(invite: &'a Path) -> Self {
+        match invite.to_str() {
+            Some("-") => Self::Stdin,
+            Some(url) if url.starts_with("https://") || url.starts_with("http://") => {
+                Self::Url(url)
+            },
+            _ => Self::File(invite),
+        }
+    }
+
+    /// Read and parse the invitation. URLs must use HTTPS, since invitations carry a
+    /// private key, and redirects aren't followed so the download can't be bounced anywhere
+    /// else.
+    pub fn read(&self, timeout: Duration, proxy: &ProxySettings) -> Result<InterfaceConfig, Error> {
+        let (path, contents) = match self {
+            Self::File(path) => return InterfaceConfig::from_file(path),
+            Self::Stdin => {
+                let mut contents = String::new();
+                io::stdin()
+                    .read_to_string(&mut contents)
+                    .with_str("stdin")?;
+                (Path::new("<stdin>"), contents)
+            },
+            Self::Url(url) => {
+                if !url.starts_with("https://") {
+                    bail!(
+                        "refusing to download an invitation over plain HTTP, use an https:// URL."
+                    );
+                }
+                if !cfg!(feature = "invite-https") {
+                    bail!(
+                        "this build of innernet can't download invitations, since it was built \
+                        without the \"invite-https\" feature."
+                    );
+                }
+                let mut agent = AgentBuilder::new()
+                    .timeout(timeout)
+                    .https_only(true)
+                    .redirects(0);
+                if let Some(proxy) = proxy.for_url(url) {
+                    agent = agent.proxy(proxy);
+                }
+                let contents = agent
+                    .build()
+                    .get(url)
+                    .call()
+                    .map_err(|e| anyhow!("failed to download invitation: {}", e))?
+                    .into_string()
+                    .with_str(*url)?;
+                (Path::new(*url), contents)
+            },
+        };
+        InterfaceConfig::from_toml(path, &contents)
+    }
+}
+
 static LOGGER: Logger = Logger;
 struct Logger;
 
@@ -90,6 +165,27 @@ pub fn human_duration(duration: Duration) -> String {
     }
 }
 
+/// A random duration in `[0, max]` for spreading out periodic work, seeded from the clock.
+///
+/// This isn't cryptographically random, it only needs to differ between hosts that started
+/// at roughly the same time.
+pub fn jitter(max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+        ^ u64::from(std::process::id()).rotate_left(32);
+    // splitmix64 finalizer to spread the low-entropy seed across all bits.
+    let mut x = seed.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^= x >> 31;
+    Duration::from_millis(x % (max.as_millis() as u64 + 1))
+}
+
 pub fn human_size(bytes: u64) -> String {
     const KB: u64 = 1024;
     const MB: u64 = 1024 * KB;
@@ -104,8 +200,36 @@ pub fn human_size(bytes: u64) -> String {
     }
 }
 
+/// Linux capability number of CAP_NET_ADMIN, from linux/capability.h.
+#[cfg(target_os = "linux")]
+const CAP_NET_ADMIN: u32 = 12;
+
+/// Whether this process may configure network interfaces, i.e. runs as root or (on Linux) has
+/// the CAP_NET_ADMIN capability.
+pub fn can_configure_interfaces() -> bool {
+    if unsafe { libc::geteuid() } == 0 {
+        return true;
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        let effective = std::fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|status| {
+                let caps = status
+                    .lines()
+                    .find_map(|line| line.strip_prefix("CapEff:"))?;
+                u64::from_str_radix(caps.trim(), 16).ok()
+            });
+        effective.is_some_and(|caps| caps & (1 << CAP_NET_ADMIN) != 0)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    false
+}
+
 pub fn permissions_helptext(config_dir: &Path, data_dir: &Path, e: &io::Error) {
-    if e.raw_os_error() == Some(1) {
+    if e.raw_os_error() == Some(libc::EPERM) {
         let current_exe = std::env::current_exe()
             .ok()
             .map(|s| s.to_string_lossy().to_string())
@@ -156,26 +280,33 @@ impl ChangeAction {
     }
 }
 
+/// The name the data store last saw for the peer with `public_key`, for peers that are no longer
+/// in the server's state.
+pub fn stored_peer_name(store: &DataStore, public_key: &str) -> String {
+    store
+        .peers()
+        .iter()
+        .find(|p| p.public_key == public_key)
+        .map(|p| p.name.to_string())
+        .unwrap_or_else(|| "[unknown]".into())
+}
+
 pub fn print_peer_diff(store: &DataStore, diff: &PeerDiff) {
     let public_key = diff.public_key().to_base64();
 
     let change_action = match (diff.old, diff.new) {
         (None, Some(_)) => ChangeAction::Added,
+        (Some(_), Some(new)) if new.is_disabled => ChangeAction::Removed,
         (Some(_), Some(_)) => ChangeAction::Modified,
         (Some(_), None) => ChangeAction::Removed,
         _ => unreachable!("PeerDiff can't be None -> None"),
     };
 
     // Grab the peer name from either the new data, or the historical data (if the peer is removed).
-    let peer_hostname = match diff.new {
-        Some(peer) => Some(peer.name.clone()),
-        None => store
-            .peers()
-            .iter()
-            .find(|p| p.public_key == public_key)
-            .map(|p| p.name.clone()),
+    let peer_name = match diff.new {
+        Some(peer) => peer.name.to_string(),
+        None => stored_peer_name(store, &public_key),
     };
-    let peer_name = peer_hostname.as_deref().unwrap_or("[unknown]");
 
     if change_action == ChangeAction::Modified
         && diff
@@ -196,7 +327,16 @@ pub fn print_peer_diff(store: &DataStore, diff: &PeerDiff) {
     );
 
     for change in diff.changes() {
-        if let PeerChange::Endpoint { .. } = change {
+        // A newly added peer's allowed IPs and preshared key are just its initial setup, but
+        // changing them on an existing peer is worth pointing out.
+        let notable = match change {
+            PeerChange::Endpoint { .. } => true,
+            PeerChange::AllowedIPs { .. } | PeerChange::PresharedKey { .. } => {
+                change_action == ChangeAction::Modified
+            },
+            _ => false,
+        };
+        if notable {
             log::info!("    {}", change);
         } else {
             log::debug!("    {}", change);
@@ -229,18 +369,152 @@ pub fn all_installed(config_dir: &Path) -> Result<Vec<Interface>, std::io::Error
     Ok(installed)
 }
 
+const SYSTEMD_UNIT_NAME: &str = "innernet@.service";
+const SYSTEMD_UNIT_DIRS: &[&str] = &[
+    "/etc/systemd/system",
+    "/usr/lib/systemd/system",
+    "/lib/systemd/system",
+];
+
+/// Whether systemd is the running init system, the same check `sd_booted(3)` does.
+pub fn running_under_systemd() -> bool {
+    cfg!(target_os = "linux") && Path::new("/run/systemd/system").is_dir()
+}
+
+/// Whether the `innernet@.service` unit is already in one of the directories systemd loads from.
+pub fn systemd_unit_installed() -> bool {
+    SYSTEMD_UNIT_DIRS
+        .iter()
+        .any(|dir| Path::new(dir).join(SYSTEMD_UNIT_NAME).exists())
+}
+
+/// Write the templated `innernet@.service` unit to /etc/systemd/system, pointed at this binary
+/// and refreshing every `interval_secs`. Returns the path written, or `None` if systemd isn't
+/// running or the unit is already installed somewhere systemd looks.
+pub fn install_systemd_unit(interval_secs: u64) -> Result<Option<PathBuf>, Error> {
+    if !running_under_systemd() || systemd_unit_installed() {
+        return Ok(None);
+    }
+
+    let exe = std::env::current_exe()?;
+    let unit = include_str!("../innernet@.service")
+        .replace("/usr/bin/innernet", &exe.to_string_lossy())
+        .replace("--interval 60", &format!("--interval {interval_secs}"));
+    let path = Path::new(SYSTEMD_UNIT_DIRS[0]).join(SYSTEMD_UNIT_NAME);
+    std::fs::write(&path, unit).with_path(&path)?;
+
+    match Command::new("systemctl").arg("daemon-reload").status() {
+        Ok(status) if status.success() => {},
+        Ok(status) => log::warn!("systemctl daemon-reload exited with {}", status),
+        Err(e) => log::warn!("failed to run systemctl daemon-reload: {}", e),
+    }
+    Ok(Some(path))
+}
+
+/// Find an installed interface whose network overlaps `network`, along with that network.
+pub fn overlapping_installed(
+    config_dir: &Path,
+    network: IpNet,
+) -> Result<Option<(Interface, IpNet)>, Error> {
+    let network = network.trunc();
+    for interface in all_installed(config_dir)? {
+        match InterfaceConfig::from_file(InterfaceConfig::get_path(config_dir, &interface)) {
+            Ok(config) => {
+                let other = config.interface.address.trunc();
+                if network.contains(&other.network()) || other.contains(&network.network()) {
+                    return Ok(Some((interface, other)));
+                }
+            },
+            Err(e) => log::warn!("skipping {}: {}", interface, e),
+        }
+    }
+    Ok(None)
+}
+
+/// Find the installed interface whose config belongs to the network named `network_name`,
+/// which may differ from the name of the interface itself.
+pub fn installed_by_network_name(
+    config_dir: &Path,
+    network_name: &str,
+) -> Result<Interface, Error> {
+    let mut matches = vec![];
+    for interface in all_installed(config_dir)? {
+        match InterfaceConfig::from_file(InterfaceConfig::get_path(config_dir, &interface)) {
+            Ok(config) if config.interface.network_name == network_name => matches.push(interface),
+            Ok(_) => {},
+            Err(e) => log::warn!("skipping {}: {}", interface, e),
+        }
+    }
+
+    match &matches[..] {
+        [interface] => Ok(interface.clone()),
+        [] => Err(anyhow!(
+            "no installed interface belongs to the network \"{}\"",
+            network_name
+        )),
+        _ => bail!(
+            "the network \"{}\" is installed as more than one interface ({}), pick one by its interface name",
+            network_name,
+            matches
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Remembers resolved endpoints for a while, so a daemon refreshing every few seconds doesn't
+/// query DNS for a server hostname on every cycle.
+pub struct EndpointCache {
+    ttl: Duration,
+    entries: HashMap<Endpoint, (SocketAddr, Instant)>,
+}
+
+impl EndpointCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Resolve `endpoint`, reusing the previous result if it hasn't expired yet.
+    pub fn resolve(&mut self, endpoint: &Endpoint) -> Result<SocketAddr, io::Error> {
+        match self.entries.get(endpoint) {
+            Some((addr, resolved_at)) if resolved_at.elapsed() < self.ttl => Ok(*addr),
+            _ => {
+                let addr = endpoint.resolve()?;
+                log::debug!("resolved {} to {}", endpoint, addr);
+                self.entries
+                    .insert(endpoint.clone(), (addr, Instant::now()));
+                Ok(addr)
+            },
+        }
+    }
+
+    /// Forget the resolved address of `endpoint`, so the next lookup goes to DNS.
+    pub fn invalidate(&mut self, endpoint: &Endpoint) {
+        self.entries.remove(endpoint);
+    }
+}
+
 pub struct Api<'a> {
     agent: Agent,
     server: &'a ServerInfo,
 }
 
 impl<'a> Api<'a> {
-    pub fn new(server: &'a ServerInfo) -> Self {
-        let agent = AgentBuilder::new()
-            .timeout(Duration::from_secs(5))
-            .redirects(0)
-            .build();
-        Self { agent, server }
+    /// `timeout` bounds each request as a whole, from connecting to reading the response.
+    pub fn new(server: &'a ServerInfo, timeout: Duration, proxy: &ProxySettings) -> Self {
+        let mut agent = AgentBuilder::new().timeout(timeout).redirects(0);
+        if let Some(proxy) = proxy.for_host(&server.internal_endpoint.ip().to_string()) {
+            agent = agent.proxy(proxy);
+        }
+        Self {
+            agent: agent.build(),
+            server,
+        }
     }
 
     #[allow(clippy::result_large_err)]
@@ -1,5 +1,5 @@
 use std::{
-    collections::BTreeMap,
+    collections::HashMap,
     fmt,
     fs::OpenOptions,
     io::{self, BufRead, BufReader, ErrorKind, Write},
@@ -82,7 +82,23 @@ impl std::error::Error for Error {
 /// ```
 pub struct HostsBuilder {
     tag: String,
-    hostname_map: BTreeMap<IpAddr, Vec<String>>,
+    /// Each IP's hostnames, in the order the IPs were first added.
+    hostname_map: Vec<(IpAddr, Vec<String>)>,
+    /// Index into `hostname_map` for each IP.
+    index: HashMap<IpAddr, usize>,
+    order: EntryOrder,
+}
+
+/// The order in which a [`HostsBuilder`] writes its entries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EntryOrder {
+    /// Sorted by IP address.
+    #[default]
+    ByIp,
+    /// Sorted by each IP's first hostname.
+    ByHostname,
+    /// In the order the IPs were first added.
+    Insertion,
 }
 
 impl HostsBuilder {
@@ -91,27 +107,45 @@ impl HostsBuilder {
     pub fn new<S: Into<String>>(tag: S) -> Self {
         Self {
             tag: tag.into(),
-            hostname_map: BTreeMap::new(),
+            hostname_map: vec![],
+            index: HashMap::new(),
+            order: EntryOrder::default(),
         }
     }
 
+    /// Sets the order the entries are written in, sorted by IP by default.
+    pub fn set_order(&mut self, order: EntryOrder) {
+        self.order = order;
+    }
+
+    fn hostnames_mut(&mut self, ip: IpAddr) -> &mut Vec<String> {
+        let hostname_map = &mut self.hostname_map;
+        let i = *self.index.entry(ip).or_insert_with(|| {
+            hostname_map.push((ip, vec![]));
+            hostname_map.len() - 1
+        });
+        &mut self.hostname_map[i].1
+    }
+
     /// Adds a mapping of `ip` to `hostname`. If there hostnames associated with the IP already,
-    /// the hostname will be appended to the list.
+    /// the hostname will be appended to the list, unless it's already in it.
     pub fn add_hostname<S: ToString>(&mut self, ip: IpAddr, hostname: S) {
-        let hostnames_dest = self.hostname_map.entry(ip).or_default();
-        hostnames_dest.push(hostname.to_string());
+        self.add_hostnames(ip, [hostname]);
     }
 
     /// Adds a mapping of `ip` to a list of `hostname`s. If there hostnames associated with the IP
-    /// already, the new hostnames will be appended to the list.
+    /// already, the new hostnames will be appended to the list, skipping any already in it.
     pub fn add_hostnames<I: IntoIterator<Item = impl ToString>>(
         &mut self,
         ip: IpAddr,
         hostnames: I,
     ) {
-        let hostnames_dest = self.hostname_map.entry(ip).or_default();
+        let hostnames_dest = self.hostnames_mut(ip);
         for hostname in hostnames.into_iter() {
-            hostnames_dest.push(hostname.to_string());
+            let hostname = hostname.to_string();
+            if !hostnames_dest.contains(&hostname) {
+                hostnames_dest.push(hostname);
+            }
         }
     }
 
@@ -122,6 +156,12 @@ impl HostsBuilder {
         self.write_to(Self::default_path()?)
     }
 
+    /// Removes the section with the given tag name from the specified hosts file, if present.
+    /// Returns true if the hosts file has changed.
+    pub fn remove_from<S: Into<String>, P: AsRef<Path>>(tag: S, hosts_path: P) -> io::Result<bool> {
+        Self::new(tag).write_to(hosts_path)
+    }
+
     /// Returns the default hosts path based on the current OS.
     pub fn default_path() -> io::Result<PathBuf> {
         let hosts_file = if cfg!(unix) {
@@ -152,6 +192,18 @@ impl HostsBuilder {
         Ok(hosts_file)
     }
 
+    /// The entries in the order they should be written.
+    fn ordered_entries(&self) -> Vec<&(IpAddr, Vec<String>)> {
+        let mut entries: Vec<_> = self.hostname_map.iter().collect();
+        match self.order {
+            EntryOrder::ByIp => entries.sort_by_key(|(ip, _)| *ip),
+            EntryOrder::ByHostname => entries
+                .sort_by(|(a_ip, a), (b_ip, b)| a.first().cmp(&b.first()).then(a_ip.cmp(b_ip))),
+            EntryOrder::Insertion => {},
+        }
+        entries
+    }
+
     pub fn get_temp_path(hosts_path: &Path) -> io::Result<PathBuf> {
         let hosts_dir = hosts_path.parent().ok_or_else(|| {
             io::Error::new(
@@ -214,7 +266,7 @@ impl HostsBuilder {
         let mut lines_to_insert = vec![];
         if !self.hostname_map.is_empty() {
             lines_to_insert.push(begin_marker);
-            for (ip, hostnames) in &self.hostname_map {
+            for (ip, hostnames) in self.ordered_entries() {
                 if cfg!(windows) {
                     // windows only allows one hostname per line
                     for hostname in hostnames {
@@ -238,6 +290,7 @@ impl HostsBuilder {
 
                 begin
             },
+            (None, None) if lines_to_insert.is_empty() => return Ok(false),
             (None, None) => {
                 // Insert a blank line before a new section.
                 if let Some(last_line) = lines.iter().last() {
@@ -384,4 +437,71 @@ mod tests {
         assert!(contents.contains("# DO NOT EDIT foo BEGIN"));
         assert!(contents.contains("1.1.1.1 whatever"));
     }
+
+    #[test]
+    fn test_entry_order() {
+        if cfg!(windows) {
+            return;
+        }
+        let section = |order| {
+            let (_, temp_path) = tempfile::NamedTempFile::new().unwrap().into_parts();
+            let mut builder = HostsBuilder::new("foo");
+            builder.set_order(order);
+            builder.add_hostname([10, 0, 0, 2].into(), "alpha");
+            builder.add_hostname([10, 0, 0, 3].into(), "charlie");
+            builder.add_hostname([10, 0, 0, 1].into(), "bravo");
+            builder.add_hostname([10, 0, 0, 2].into(), "alpha2");
+            builder.write_to(&temp_path).unwrap();
+            std::fs::read_to_string(&temp_path)
+                .unwrap()
+                .lines()
+                .filter(|line| !line.starts_with('#'))
+                .map(|line| line.split(' ').nth(1).unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(section(EntryOrder::ByIp), ["bravo", "alpha", "charlie"]);
+        assert_eq!(
+            section(EntryOrder::ByHostname),
+            ["alpha", "bravo", "charlie"]
+        );
+        assert_eq!(
+            section(EntryOrder::Insertion),
+            ["alpha", "charlie", "bravo"]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_hostnames() {
+        let (_, temp_path) = tempfile::NamedTempFile::new().unwrap().into_parts();
+        let mut builder = HostsBuilder::new("foo");
+        builder.add_hostname([10, 0, 0, 1].into(), "alpha");
+        builder.add_hostnames([10, 0, 0, 1].into(), ["bravo", "alpha"]);
+        builder.add_hostname([10, 0, 0, 1].into(), "bravo");
+        builder.write_to(&temp_path).unwrap();
+
+        let contents = std::fs::read_to_string(&temp_path).unwrap();
+        let hostnames = contents
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .flat_map(|line| line.split_whitespace().skip(1))
+            .collect::<Vec<_>>();
+        assert_eq!(hostnames, ["alpha", "bravo"]);
+    }
+
+    #[test]
+    fn test_remove() {
+        let (mut temp_file, temp_path) = tempfile::NamedTempFile::new().unwrap().into_parts();
+        temp_file.write_all(b"preexisting\ncontent\n").unwrap();
+        let mut builder = HostsBuilder::new("foo");
+        builder.add_hostname([1, 1, 1, 1].into(), "whatever");
+        assert!(builder.write_to(&temp_path).unwrap());
+        assert!(HostsBuilder::remove_from("foo", &temp_path).unwrap());
+        assert!(!HostsBuilder::remove_from("foo", &temp_path).unwrap());
+
+        let contents = std::fs::read_to_string(&temp_path).unwrap();
+        assert!(!contents.contains("# DO NOT EDIT foo BEGIN"));
+        assert!(!contents.contains("1.1.1.1 whatever"));
+        assert!(contents.starts_with("preexisting\ncontent"));
+    }
 }
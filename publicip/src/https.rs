@@ -0,0 +1,50 @@
+//! A fallback for networks that block outbound DNS, asking Cloudflare's trace
+//! endpoint over HTTPS instead.
+
+use crate::{CLOUDFLARE_IPV4, CLOUDFLARE_IPV6, TIMEOUT};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    thread,
+};
+use ureq::{Agent, AgentBuilder};
+
+pub fn get_both() -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
+    let agent = AgentBuilder::new().timeout(TIMEOUT).redirects(0).build();
+    thread::scope(|scope| {
+        let ipv4 = scope.spawn(|| lookup(&agent, CLOUDFLARE_IPV4.into()));
+        let ipv6 = scope.spawn(|| lookup(&agent, CLOUDFLARE_IPV6.into()));
+        (ipv4.join().ok().flatten(), ipv6.join().ok().flatten())
+    })
+}
+
+/// Connecting to the resolver by address rather than by name pins the
+/// request, and so the reported address, to that address family.
+fn lookup<T: FromStr>(agent: &Agent, resolver: IpAddr) -> Option<T> {
+    let url = format!("https://{}/cdn-cgi/trace", SocketAddr::new(resolver, 443));
+    let body = agent.get(&url).call().ok()?.into_string().ok()?;
+    parse_trace(&body)
+}
+
+/// The trace is a list of `key=value` lines, one of which is the client's IP.
+fn parse_trace<T: FromStr>(body: &str) -> Option<T> {
+    body.lines()
+        .find_map(|line| line.strip_prefix("ip="))
+        .and_then(|ip| ip.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_trace() {
+        let body = "fl=123f45\nh=1.1.1.1\nip=203.0.113.7\nts=1700000000.123\nvisit_scheme=https\n";
+        assert_eq!(
+            parse_trace::<Ipv4Addr>(body),
+            Some(Ipv4Addr::new(203, 0, 113, 7))
+        );
+        assert_eq!(parse_trace::<Ipv6Addr>(body), None);
+        assert_eq!(parse_trace::<Ipv4Addr>("h=1.1.1.1\n"), None);
+    }
+}
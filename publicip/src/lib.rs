@@ -1,7 +1,8 @@
 //! Get your public IP address(es) as fast as possible, with no dependencies.
 //!
 //! Currently uses Cloudflare's DNS as it's the simplest, but that could change
-//! in the future.
+//! in the future. With the `https` feature, Cloudflare is asked over HTTPS instead
+//! on networks that block outbound DNS.
 
 use std::{
     fs::File,
@@ -27,6 +28,11 @@ static CLOUDFLARE_QNAME: &[&str] = &["whoami", "cloudflare"];
 const CLOUDFLARE_IPV4: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
 const CLOUDFLARE_IPV6: Ipv6Addr = Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111);
 
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+#[cfg(feature = "https")]
+mod https;
+
 pub enum Preference {
     Ipv4,
     Ipv6,
@@ -35,10 +41,18 @@ pub enum Preference {
 pub fn get_both() -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
     let ipv4 = Request::start(CLOUDFLARE_IPV4).ok();
     let ipv6 = Request::start(CLOUDFLARE_IPV6).ok();
-    (
+    let both = (
         ipv4.and_then(|req| req.read_response().ok()),
         ipv6.and_then(|req| req.read_response().ok()),
-    )
+    );
+
+    #[cfg(feature = "https")]
+    let both = match both {
+        (None, None) => https::get_both(),
+        both => both,
+    };
+
+    both
 }
 
 pub fn get_any(preference: Preference) -> Option<IpAddr> {
@@ -68,7 +82,7 @@ impl<T: Into<IpAddr> + FromStr<Err = AddrParseError>> Request<T> {
             },
             0,
         ))?;
-        socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+        socket.set_read_timeout(Some(TIMEOUT))?;
         let endpoint = SocketAddr::new(resolver_ip, 53);
 
         let id = get_id()?;
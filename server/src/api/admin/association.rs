@@ -5,7 +5,7 @@
 use std::collections::VecDeque;
 
 use crate::{
-    db::DatabaseAssociation,
+    db::{AuditAction, AuditLog, DatabaseAssociation, DatabaseCidr},
     util::{form_body, json_response, status_response},
     ServerError, Session,
 };
@@ -33,14 +33,29 @@ pub async fn routes(
 
 mod handlers {
     use super::*;
+    use rusqlite::Connection;
+
+    fn describe(conn: &Connection, contents: &AssociationContents) -> Result<String, ServerError> {
+        let cidr_1 = DatabaseCidr::get(conn, contents.cidr_id_1)?;
+        let cidr_2 = DatabaseCidr::get(conn, contents.cidr_id_2)?;
+        Ok(format!("{} <-> {}", cidr_1.name, cidr_2.name))
+    }
 
     pub async fn create(
         contents: AssociationContents,
         session: Session,
     ) -> Result<Response<Body>, ServerError> {
-        let conn = session.context.db.lock();
+        let mut conn = session.context.db.lock();
+        let tx = conn.transaction()?;
 
-        DatabaseAssociation::create(&conn, contents)?;
+        let association = DatabaseAssociation::create(&tx, contents)?;
+        AuditLog::record(
+            &tx,
+            &session.peer,
+            AuditAction::CreateAssociation,
+            &describe(&tx, &association)?,
+        )?;
+        tx.commit()?;
 
         status_response(StatusCode::CREATED)
     }
@@ -53,8 +68,20 @@ mod handlers {
     }
 
     pub async fn delete(id: i64, session: Session) -> Result<Response<Body>, ServerError> {
-        let conn = session.context.db.lock();
-        DatabaseAssociation::delete(&conn, id)?;
+        let mut conn = session.context.db.lock();
+        let tx = conn.transaction()?;
+        let association = DatabaseAssociation::list(&tx)?
+            .into_iter()
+            .find(|association| association.id == id)
+            .ok_or(ServerError::NotFound)?;
+        DatabaseAssociation::delete(&tx, id)?;
+        AuditLog::record(
+            &tx,
+            &session.peer,
+            AuditAction::DeleteAssociation,
+            &describe(&tx, &association)?,
+        )?;
+        tx.commit()?;
 
         status_response(StatusCode::NO_CONTENT)
     }
@@ -0,0 +1,95 @@
+//! Read access to the log of changes admins have made through the API.
+
+use std::collections::VecDeque;
+
+use crate::{db::AuditLog, util::json_response, ServerError, Session};
+use hyper::{Body, Method, Request, Response};
+
+pub async fn routes(
+    req: Request<Body>,
+    mut components: VecDeque<String>,
+    session: Session,
+) -> Result<Response<Body>, ServerError> {
+    match (req.method(), components.pop_front().as_deref()) {
+        (&Method::GET, None) => handlers::list(session).await,
+        _ => Err(ServerError::NotFound),
+    }
+}
+
+mod handlers {
+    use super::*;
+
+    pub async fn list(session: Session) -> Result<Response<Body>, ServerError> {
+        let conn = session.context.db.lock();
+        let entries = AuditLog::list(&conn)?;
+
+        json_response(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test;
+    use bytes::Buf;
+    use hyper::StatusCode;
+    use shared::{AuditEntry, Error};
+
+    #[tokio::test]
+    async fn test_audit_log_records_admin_changes() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        let ip = if cfg!(feature = "v6-test") {
+            "fd00:1337::2:0:0:3"
+        } else {
+            "10.80.64.4"
+        };
+        let peer = test::developer_peer_contents("developer3", ip)?;
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/peers", &peer)
+            .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = server
+            .request(test::ADMIN_PEER_IP, "DELETE", "/v1/admin/cidrs/9999")
+            .await;
+        assert!(!res.status().is_success());
+
+        let res = server
+            .request(test::ADMIN_PEER_IP, "GET", "/v1/admin/audit")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let entries: Vec<AuditEntry> =
+            serde_json::from_reader(hyper::body::aggregate(res).await?.reader())?;
+
+        // Only the successful change is recorded.
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].action, "create-peer");
+        assert_eq!(entries[0].target, format!("developer3 ({ip})"));
+        assert_eq!(
+            entries[0].actor_ip,
+            test::ADMIN_PEER_IP.parse::<std::net::IpAddr>()?
+        );
+
+        // The log can't be rewritten after the fact.
+        let conn = server.db();
+        let conn = conn.lock();
+        assert!(conn.execute("DELETE FROM audit_log", []).is_err());
+        assert!(conn
+            .execute("UPDATE audit_log SET target = 'nothing'", [])
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_admin_only() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        let res = server
+            .request(test::DEVELOPER1_PEER_IP, "GET", "/v1/admin/audit")
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+}
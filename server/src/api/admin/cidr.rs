@@ -1,7 +1,7 @@
 use std::collections::VecDeque;
 
 use crate::{
-    db::DatabaseCidr,
+    db::{AuditAction, AuditLog, DatabaseCidr},
     util::{form_body, json_response, status_response},
     ServerError, Session,
 };
@@ -24,6 +24,14 @@ pub async fn routes(
             let form = form_body(req).await?;
             handlers::update(id, form, session).await
         },
+        (&Method::POST, Some(id)) => {
+            let id: i64 = id.parse().map_err(|_| ServerError::NotFound)?;
+            match components.pop_front().as_deref() {
+                Some("disable") => handlers::set_disabled(id, true, session).await,
+                Some("enable") => handlers::set_disabled(id, false, session).await,
+                _ => Err(ServerError::NotFound),
+            }
+        },
         (&Method::DELETE, Some(id)) => {
             let id: i64 = id.parse().map_err(|_| ServerError::NotFound)?;
             handlers::delete(id, session).await
@@ -41,9 +49,17 @@ mod handlers {
         contents: CidrContents,
         session: Session,
     ) -> Result<Response<Body>, ServerError> {
-        let conn = session.context.db.lock();
-
-        let cidr = DatabaseCidr::create(&conn, contents)?;
+        let mut conn = session.context.db.lock();
+        let tx = conn.transaction()?;
+
+        let cidr = DatabaseCidr::create(&tx, contents)?;
+        AuditLog::record(
+            &tx,
+            &session.peer,
+            AuditAction::CreateCidr,
+            &format!("{} ({})", cidr.name, cidr.cidr),
+        )?;
+        tx.commit()?;
 
         json_status_response(cidr, StatusCode::CREATED)
     }
@@ -53,9 +69,48 @@ mod handlers {
         form: CidrContents,
         session: Session,
     ) -> Result<Response<Body>, ServerError> {
-        let conn = session.context.db.lock();
-        let cidr = DatabaseCidr::get(&conn, id)?;
-        DatabaseCidr::from(cidr).update(&conn, form)?;
+        let mut conn = session.context.db.lock();
+        let tx = conn.transaction()?;
+        let cidr = DatabaseCidr::get(&tx, id)?;
+        let old_name = cidr.name.clone();
+        let mut cidr = DatabaseCidr::from(cidr);
+        cidr.update(&tx, form)?;
+        AuditLog::record(
+            &tx,
+            &session.peer,
+            AuditAction::UpdateCidr,
+            &format!(
+                "{} ({}), previously named {}",
+                cidr.name, cidr.cidr, old_name
+            ),
+        )?;
+        tx.commit()?;
+
+        status_response(StatusCode::NO_CONTENT)
+    }
+
+    /// Disabling a CIDR hides its peers, and those of its children, from every peer's
+    /// `/user/state` without deleting them.
+    pub async fn set_disabled(
+        id: i64,
+        is_disabled: bool,
+        session: Session,
+    ) -> Result<Response<Body>, ServerError> {
+        let mut conn = session.context.db.lock();
+        let tx = conn.transaction()?;
+        let mut cidr = DatabaseCidr::from(DatabaseCidr::get(&tx, id)?);
+        cidr.set_disabled(&tx, is_disabled)?;
+        AuditLog::record(
+            &tx,
+            &session.peer,
+            if is_disabled {
+                AuditAction::DisableCidr
+            } else {
+                AuditAction::EnableCidr
+            },
+            &format!("{} ({})", cidr.name, cidr.cidr),
+        )?;
+        tx.commit()?;
 
         status_response(StatusCode::NO_CONTENT)
     }
@@ -68,8 +123,17 @@ mod handlers {
     }
 
     pub async fn delete(id: i64, session: Session) -> Result<Response<Body>, ServerError> {
-        let conn = session.context.db.lock();
-        DatabaseCidr::delete(&conn, id)?;
+        let mut conn = session.context.db.lock();
+        let tx = conn.transaction()?;
+        let cidr = DatabaseCidr::get(&tx, id)?;
+        DatabaseCidr::delete(&tx, id)?;
+        AuditLog::record(
+            &tx,
+            &session.peer,
+            AuditAction::DeleteCidr,
+            &format!("{} ({})", cidr.name, cidr.cidr),
+        )?;
+        tx.commit()?;
 
         status_response(StatusCode::NO_CONTENT)
     }
@@ -93,6 +157,8 @@ mod tests {
             name: "experimental".to_string(),
             cidr: test::EXPERIMENTAL_CIDR.parse()?,
             parent: Some(test::ROOT_CIDR_ID),
+            default_keepalive: None,
+            is_disabled: false,
         };
 
         let res = server
@@ -119,6 +185,8 @@ mod tests {
             name: "experimental".to_string(),
             cidr: test::EXPERIMENTAL_CIDR.parse()?,
             parent: Some(test::ROOT_CIDR_ID),
+            default_keepalive: None,
+            is_disabled: false,
         };
 
         let res = server
@@ -132,6 +200,8 @@ mod tests {
             name: "experimental".to_string(),
             cidr: test::EXPERIMENTAL_SUBCIDR.parse()?,
             parent: Some(cidr_res.id),
+            default_keepalive: None,
+            is_disabled: false,
         };
         let res = server
             .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/cidrs", &contents)
@@ -149,6 +219,8 @@ mod tests {
             name: "experimental".to_string(),
             cidr: test::EXPERIMENTAL_CIDR.parse()?,
             parent: Some(test::ROOT_CIDR_ID),
+            default_keepalive: None,
+            is_disabled: false,
         };
 
         let res = server
@@ -167,6 +239,8 @@ mod tests {
             name: "experimental".to_string(),
             cidr: test::EXPERIMENTAL_CIDR.parse()?,
             parent: Some(test::ROOT_CIDR_ID),
+            default_keepalive: None,
+            is_disabled: false,
         };
         let res = server
             .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/cidrs", &contents)
@@ -177,6 +251,8 @@ mod tests {
             name: "experimental".to_string(),
             cidr: test::EXPERIMENTAL_SUBCIDR.parse()?,
             parent: Some(test::ROOT_CIDR_ID),
+            default_keepalive: None,
+            is_disabled: false,
         };
 
         let res = server
@@ -195,6 +271,8 @@ mod tests {
             name: "experimental".to_string(),
             cidr: "10.80.1.0/21".parse()?,
             parent: Some(test::ROOT_CIDR_ID),
+            default_keepalive: None,
+            is_disabled: false,
         };
         let res = server
             .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/cidrs", &contents)
@@ -204,6 +282,82 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_cidr_same_as_parent() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        let contents = CidrContents {
+            name: "experimental".to_string(),
+            cidr: test::EXPERIMENTAL_CIDR.parse()?,
+            parent: Some(test::ROOT_CIDR_ID),
+            default_keepalive: None,
+            is_disabled: false,
+        };
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/cidrs", &contents)
+            .await;
+        assert!(res.status().is_success());
+        let experimental_cidr: Cidr =
+            serde_json::from_reader(hyper::body::aggregate(res).await?.reader())?;
+
+        let contents = CidrContents {
+            name: "experimental-copy".to_string(),
+            cidr: test::EXPERIMENTAL_CIDR.parse()?,
+            parent: Some(experimental_cidr.id),
+            default_keepalive: None,
+            is_disabled: false,
+        };
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/cidrs", &contents)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cidr_default_keepalive() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        let contents = CidrContents {
+            name: "experimental".to_string(),
+            cidr: test::EXPERIMENTAL_CIDR.parse()?,
+            parent: Some(test::ROOT_CIDR_ID),
+            default_keepalive: Some(0),
+            is_disabled: false,
+        };
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/cidrs", &contents)
+            .await;
+        assert!(res.status().is_success());
+        let experimental_cidr: Cidr =
+            serde_json::from_reader(hyper::body::aggregate(res).await?.reader())?;
+        assert_eq!(experimental_cidr.default_keepalive, Some(0));
+
+        let db = server.db();
+        let db = db.lock();
+        let peer = DatabasePeer::create(
+            &db,
+            test::peer_contents(
+                "experiment-peer",
+                test::EXPERIMENT_SUBCIDR_PEER_IP,
+                experimental_cidr.id,
+                false,
+            )?,
+        )?;
+        let peer = DatabasePeer::get(&db, peer.id)?;
+        assert_eq!(peer.persistent_keepalive_interval, None);
+
+        // Peers in CIDRs without a default fall back to the global interval.
+        let developer = DatabasePeer::get(&db, test::DEVELOPER1_PEER_ID)?;
+        assert_eq!(
+            developer.persistent_keepalive_interval,
+            Some(shared::PERSISTENT_KEEPALIVE_INTERVAL_SECS)
+        );
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_cidr_delete_fail_with_child_cidr() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -214,6 +368,8 @@ mod tests {
                 name: "experimental".to_string(),
                 cidr: test::EXPERIMENTAL_CIDR.parse()?,
                 parent: Some(test::ROOT_CIDR_ID),
+                default_keepalive: None,
+                is_disabled: false,
             },
         )?;
         let experimental_subcidr = DatabaseCidr::create(
@@ -222,6 +378,8 @@ mod tests {
                 name: "experimental subcidr".to_string(),
                 cidr: test::EXPERIMENTAL_SUBCIDR.parse()?,
                 parent: Some(experimental_cidr.id),
+                default_keepalive: None,
+                is_disabled: false,
             },
         )?;
 
@@ -267,6 +425,8 @@ mod tests {
                 name: "experimental".to_string(),
                 cidr: test::EXPERIMENTAL_CIDR.parse()?,
                 parent: Some(test::ROOT_CIDR_ID),
+                default_keepalive: None,
+                is_disabled: false,
             },
         )?;
 
@@ -292,4 +452,55 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_cidr_disable() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let visible_peers = |server: &test::Server| {
+            let conn = server.db();
+            let conn = conn.lock();
+            let peers = DatabasePeer::get(&conn, test::DEVELOPER1_PEER_ID)?
+                .get_all_allowed_peers(&conn)?
+                .into_iter()
+                .map(|peer| peer.id)
+                .collect::<Vec<_>>();
+            Ok::<_, ServerError>(peers)
+        };
+        assert!(visible_peers(&server)?.contains(&test::DEVELOPER2_PEER_ID));
+
+        let res = server
+            .request(
+                test::ADMIN_PEER_IP,
+                "POST",
+                &format!("/v1/admin/cidrs/{}/disable", test::DEVELOPER_CIDR_ID),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let peers = visible_peers(&server)?;
+        assert!(!peers.contains(&test::DEVELOPER2_PEER_ID));
+        // The server is in the infra CIDR, which is still enabled.
+        assert!(peers.contains(&1));
+
+        let res = server
+            .request(
+                test::ADMIN_PEER_IP,
+                "POST",
+                &format!("/v1/admin/cidrs/{}/enable", test::DEVELOPER_CIDR_ID),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(visible_peers(&server)?.contains(&test::DEVELOPER2_PEER_ID));
+
+        // Disabling the server's own CIDR would cut everyone off from it.
+        let res = server
+            .request(
+                test::ADMIN_PEER_IP,
+                "POST",
+                &format!("/v1/admin/cidrs/{}/disable", test::ROOT_CIDR_ID),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
 }
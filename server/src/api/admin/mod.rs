@@ -1,10 +1,11 @@
 use std::collections::VecDeque;
 
-use hyper::{Body, Request, Response};
+use hyper::{Body, Method, Request, Response};
 
 use crate::{ServerError, Session};
 
 pub mod association;
+pub mod audit;
 pub mod cidr;
 pub mod peer;
 
@@ -13,12 +14,18 @@ pub async fn routes(
     mut components: VecDeque<String>,
     session: Session,
 ) -> Result<Response<Body>, ServerError> {
-    if !session.admin_capable() {
+    let authorized = if req.method() == Method::GET {
+        session.viewer_capable()
+    } else {
+        session.admin_capable()
+    };
+    if !authorized {
         return Err(ServerError::Unauthorized);
     }
 
     match components.pop_front().as_deref() {
         Some("associations") => association::routes(req, components, session).await,
+        Some("audit") => audit::routes(req, components, session).await,
         Some("cidrs") => cidr::routes(req, components, session).await,
         Some("peers") => peer::routes(req, components, session).await,
         _ => Err(ServerError::NotFound),
@@ -2,13 +2,15 @@ use std::collections::VecDeque;
 
 use crate::{
     api::inject_endpoints,
-    db::DatabasePeer,
+    db::{peer::SERVER_PEER_ID, AuditAction, AuditLog, DatabasePeer},
+    peer_config,
     util::{form_body, json_response, json_status_response, status_response},
     ServerError, Session,
 };
 use hyper::{Body, Method, Request, Response, StatusCode};
-use shared::PeerContents;
-use wireguard_control::{DeviceUpdate, PeerConfigBuilder};
+use shared::{PeerContents, PeerUsage, MAX_RECOMMENDED_INVITE_EXPIRATION};
+use std::time::SystemTime;
+use wireguard_control::{Device, DeviceUpdate, Key};
 
 pub async fn routes(
     req: Request<Body>,
@@ -17,6 +19,15 @@ pub async fn routes(
 ) -> Result<Response<Body>, ServerError> {
     match (req.method(), components.pop_front().as_deref()) {
         (&Method::GET, None) => handlers::list(session).await,
+        (&Method::GET, Some("deleted")) => handlers::list_deleted(session).await,
+        (&Method::GET, Some(id)) => {
+            let id: i64 = id.parse().map_err(|_| ServerError::NotFound)?;
+            match components.pop_front().as_deref() {
+                Some("reachable") => handlers::reachable(id, session).await,
+                Some("usage") => handlers::usage(id, session).await,
+                _ => Err(ServerError::NotFound),
+            }
+        },
         (&Method::POST, None) => {
             let form = form_body(req).await?;
             handlers::create(form, session).await
@@ -28,7 +39,11 @@ pub async fn routes(
         },
         (&Method::DELETE, Some(id)) => {
             let id: i64 = id.parse().map_err(|_| ServerError::NotFound)?;
-            handlers::delete(id, session).await
+            match components.pop_front().as_deref() {
+                None => handlers::delete(id, session).await,
+                Some("purge") => handlers::purge(id, session).await,
+                _ => Err(ServerError::NotFound),
+            }
         },
         _ => Err(ServerError::NotFound),
     }
@@ -42,17 +57,45 @@ mod handlers {
         form: PeerContents,
         session: Session,
     ) -> Result<Response<Body>, ServerError> {
-        let conn = session.context.db.lock();
+        if !form.is_redeemed
+            && form
+                .invite_expires
+                .is_some_and(|time| time <= SystemTime::now())
+        {
+            log::warn!("refusing to create an invitation that has already expired.");
+            return Err(ServerError::InvalidQuery);
+        }
+        if let Some(lifetime) = form
+            .invite_expires
+            .and_then(|time| time.duration_since(SystemTime::now()).ok())
+            .filter(|lifetime| *lifetime > MAX_RECOMMENDED_INVITE_EXPIRATION)
+        {
+            log::warn!(
+                "creating an invitation for {} that's valid for {} days.",
+                form.name,
+                lifetime.as_secs() / (24 * 60 * 60)
+            );
+        }
+
+        let mut conn = session.context.db.lock();
+        let tx = conn.transaction()?;
 
-        let peer = DatabasePeer::create(&conn, form)?;
+        let peer = DatabasePeer::create(&tx, form)?;
         log::info!("adding peer {}", &*peer);
+        AuditLog::record(
+            &tx,
+            &session.peer,
+            AuditAction::CreatePeer,
+            &format!("{} ({})", peer.name, peer.ip),
+        )?;
+        tx.commit()?;
 
         if cfg!(not(test)) {
             // Update the current WireGuard interface with the new peers.
             DeviceUpdate::new()
-                .add_peer(PeerConfigBuilder::from(&*peer))
+                .add_peer(peer_config(&peer, session.context.preshared_key.as_ref()))
                 .apply(&session.context.interface, session.context.backend)
-                .map_err(|_| ServerError::WireGuard)?;
+                .map_err(ServerError::WireGuard)?;
             log::info!("updated WireGuard interface, adding {}", &*peer);
         }
 
@@ -64,9 +107,17 @@ mod handlers {
         form: PeerContents,
         session: Session,
     ) -> Result<Response<Body>, ServerError> {
-        let conn = session.context.db.lock();
-        let mut peer = DatabasePeer::get(&conn, id)?;
-        peer.update(&conn, form)?;
+        let mut conn = session.context.db.lock();
+        let tx = conn.transaction()?;
+        let mut peer = DatabasePeer::get(&tx, id)?;
+        peer.update(&tx, form)?;
+        AuditLog::record(
+            &tx,
+            &session.peer,
+            AuditAction::UpdatePeer,
+            &format!("{} ({})", peer.name, peer.ip),
+        )?;
+        tx.commit()?;
 
         status_response(StatusCode::NO_CONTENT)
     }
@@ -82,9 +133,106 @@ mod handlers {
         json_response(&peers)
     }
 
-    pub async fn delete(id: i64, session: Session) -> Result<Response<Body>, ServerError> {
+    /// List the tombstones of deleted peers, whose names and IPs stay taken until they're
+    /// purged.
+    pub async fn list_deleted(session: Session) -> Result<Response<Body>, ServerError> {
+        let conn = session.context.db.lock();
+        let peers = DatabasePeer::list_deleted(&conn)?
+            .into_iter()
+            .map(|peer| peer.inner)
+            .collect::<Vec<_>>();
+        json_response(&peers)
+    }
+
+    /// List the peers that the given peer is allowed to reach, as it would see them
+    /// in its own `/user/state` response. This is an admin-only endpoint.
+    pub async fn reachable(id: i64, session: Session) -> Result<Response<Body>, ServerError> {
         let conn = session.context.db.lock();
-        DatabasePeer::disable(&conn, id)?;
+        let mut peers = DatabasePeer::get(&conn, id)?
+            .get_all_allowed_peers(&conn)?
+            .into_iter()
+            .map(|peer| peer.inner)
+            .collect::<Vec<_>>();
+        inject_endpoints(&session, &mut peers);
+        json_response(&peers)
+    }
+
+    /// Report a peer's traffic from the server's live WireGuard interface. This is an
+    /// admin-only endpoint.
+    pub async fn usage(id: i64, session: Session) -> Result<Response<Body>, ServerError> {
+        let peer = DatabasePeer::get(&session.context.db.lock(), id)?;
+        let info = if cfg!(not(test)) {
+            let public_key =
+                Key::from_base64(&peer.public_key).map_err(ServerError::invalid_key)?;
+            Device::peers_iter(&session.context.interface, session.context.backend)
+                .and_then(|mut peers| peers.find_by_key(&public_key))
+                .map_err(ServerError::WireGuard)?
+        } else {
+            None
+        };
+
+        json_response(&PeerUsage {
+            id: peer.id,
+            public_key: peer.public_key.clone(),
+            rx_bytes: info
+                .as_ref()
+                .map(|info| info.stats.rx_bytes)
+                .unwrap_or_default(),
+            tx_bytes: info
+                .as_ref()
+                .map(|info| info.stats.tx_bytes)
+                .unwrap_or_default(),
+            last_handshake_time: info
+                .and_then(|info| info.stats.last_handshake_time)
+                .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs()),
+        })
+    }
+
+    /// Delete a peer, leaving a tombstone that keeps its IP from being reused until it's purged.
+    pub async fn delete(id: i64, session: Session) -> Result<Response<Body>, ServerError> {
+        if id == SERVER_PEER_ID {
+            return Err(ServerError::Conflict(
+                "the innernet server's own peer can't be deleted".into(),
+            ));
+        }
+        let mut conn = session.context.db.lock();
+        let tx = conn.transaction()?;
+        let peer = DatabasePeer::get(&tx, id)?;
+        DatabasePeer::delete(&tx, id)?;
+        AuditLog::record(
+            &tx,
+            &session.peer,
+            AuditAction::DeletePeer,
+            &format!("{} ({})", peer.name, peer.ip),
+        )?;
+        tx.commit()?;
+
+        if cfg!(not(test)) {
+            let public_key =
+                Key::from_base64(&peer.public_key).map_err(ServerError::invalid_key)?;
+            DeviceUpdate::new()
+                .remove_peer_by_key(&public_key)
+                .apply(&session.context.interface, session.context.backend)
+                .map_err(ServerError::WireGuard)?;
+            log::info!("updated WireGuard interface, removing {}", &*peer);
+        }
+
+        status_response(StatusCode::NO_CONTENT)
+    }
+
+    /// Permanently remove an already-deleted peer, freeing its name and IP.
+    pub async fn purge(id: i64, session: Session) -> Result<Response<Body>, ServerError> {
+        let mut conn = session.context.db.lock();
+        let tx = conn.transaction()?;
+        let peer = DatabasePeer::purge(&tx, id)?;
+        AuditLog::record(
+            &tx,
+            &session.peer,
+            AuditAction::PurgePeer,
+            &format!("{} ({})", peer.name, peer.ip),
+        )?;
+        tx.commit()?;
 
         status_response(StatusCode::NO_CONTENT)
     }
@@ -93,10 +241,50 @@ mod handlers {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::test;
+    use crate::{
+        db::{NetworkEpoch, PeerLimits},
+        test,
+    };
     use bytes::Buf;
     use shared::{Error, Peer};
 
+    #[tokio::test]
+    async fn test_add_peer_over_limit() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let peer = if cfg!(feature = "v6-test") {
+            test::developer_peer_contents("developer3", "fd00:1337::2:0:0:3")?
+        } else {
+            test::developer_peer_contents("developer3", "10.80.64.4")?
+        };
+
+        // The developer CIDR already holds developer1 and developer2.
+        PeerLimits::set(&server.db().lock(), test::DEVELOPER_CIDR_ID, Some(2))?;
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/peers", &peer)
+            .await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        // A limit on the root CIDR counts every peer in the network.
+        PeerLimits::set(&server.db().lock(), test::DEVELOPER_CIDR_ID, None)?;
+        let peer_count = DatabasePeer::list(&server.db().lock())?.len() as u32;
+        PeerLimits::set(&server.db().lock(), test::ROOT_CIDR_ID, Some(peer_count))?;
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/peers", &peer)
+            .await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        PeerLimits::set(
+            &server.db().lock(),
+            test::ROOT_CIDR_ID,
+            Some(peer_count + 1),
+        )?;
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/peers", &peer)
+            .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_add_peer() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -127,6 +315,53 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_add_peer_with_expired_invite() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        let mut peer = if cfg!(feature = "v6-test") {
+            test::developer_peer_contents("developer3", "fd00:1337::2:0:0:3")?
+        } else {
+            test::developer_peer_contents("developer3", "10.80.64.4")?
+        };
+        peer.is_redeemed = false;
+        peer.invite_expires = Some(SystemTime::now() - std::time::Duration::from_secs(1));
+
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/peers", &peer)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expired_invites_are_removed() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let conn = server.db();
+        let conn = conn.lock();
+
+        let mut contents = if cfg!(feature = "v6-test") {
+            test::developer_peer_contents("developer3", "fd00:1337::2:0:0:3")?
+        } else {
+            test::developer_peer_contents("developer3", "10.80.64.4")?
+        };
+        contents.is_redeemed = false;
+        let peer = DatabasePeer::create(&conn, contents)?;
+        conn.execute(
+            "UPDATE peers SET invite_expires = 1 WHERE id = ?1",
+            rusqlite::params![peer.id],
+        )?;
+
+        assert_eq!(DatabasePeer::delete_expired_invites(&conn)?, 1);
+        // Nobody ever used the invite, so no tombstone is left holding its name and IP.
+        assert!(DatabasePeer::list_including_deleted(&conn)?
+            .iter()
+            .all(|p| p.id != peer.id));
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_add_peer_with_invalid_name() -> Result<(), Error> {
         assert!(test::developer_peer_contents("devel oper", "10.80.64.4").is_err());
@@ -162,13 +397,13 @@ mod tests {
         let old_peers = DatabasePeer::list(&server.db().lock())?;
 
         // Try to add a peer with an IP that is already taken.
-        let peer = test::developer_peer_contents("developer3", "10.80.64.3")?;
+        let peer = test::developer_peer_contents("developer3", test::DEVELOPER2_PEER_IP)?;
 
         let res = server
             .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/peers", &peer)
             .await;
 
-        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.status(), StatusCode::CONFLICT);
 
         // The number of peer entries in the database should not change.
         let new_peers = DatabasePeer::list(&server.db().lock())?;
@@ -315,6 +550,98 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_viewer_is_read_only() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        {
+            let db = server.db.lock();
+            let mut viewer = DatabasePeer::get(&db, test::USER1_PEER_ID)?;
+            let contents = PeerContents {
+                is_viewer: true,
+                ..viewer.contents.clone()
+            };
+            viewer.update(&db, contents)?;
+        }
+
+        let res = server
+            .request(test::USER1_PEER_IP, "GET", "/v1/admin/peers")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let peer = test::developer_peer_contents("developer3", "10.80.64.4")?;
+        let res = server
+            .form_request(test::USER1_PEER_IP, "POST", "/v1/admin/peers", &peer)
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reachable_peers_from_admin() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let res = server
+            .request(
+                test::ADMIN_PEER_IP,
+                "GET",
+                &format!("/v1/admin/peers/{}/reachable", test::DEVELOPER1_PEER_ID),
+            )
+            .await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let whole_body = hyper::body::aggregate(res).await?;
+        let peers: Vec<Peer> = serde_json::from_reader(whole_body.reader())?;
+        let mut peer_names = peers.iter().map(|p| &*p.contents.name).collect::<Vec<_>>();
+        peer_names.sort_unstable();
+        // Same view as developer1 gets from /v1/user/state.
+        assert_eq!(
+            &["developer1", "developer2", "innernet-server"],
+            &peer_names[..]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reachable_peers_unknown_id() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let res = server
+            .request(
+                test::ADMIN_PEER_IP,
+                "GET",
+                &format!("/v1/admin/peers/{}/reachable", test::USER1_PEER_ID + 100),
+            )
+            .await;
+
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_peer_usage() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let path = format!("/v1/admin/peers/{}/usage", test::DEVELOPER1_PEER_ID);
+
+        let res = server.request(test::ADMIN_PEER_IP, "GET", &path).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let whole_body = hyper::body::aggregate(res).await?;
+        let usage: PeerUsage = serde_json::from_reader(whole_body.reader())?;
+        let peer = DatabasePeer::get(&server.db.lock(), test::DEVELOPER1_PEER_ID)?;
+        assert_eq!(usage.id, test::DEVELOPER1_PEER_ID);
+        assert_eq!(usage.public_key, peer.public_key);
+        // There's no WireGuard interface in tests, so the peer has never been seen.
+        assert_eq!((usage.rx_bytes, usage.tx_bytes), (0, 0));
+        assert_eq!(usage.last_handshake_time, None);
+
+        let res = server.request(test::DEVELOPER1_PEER_IP, "GET", &path).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_delete() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -338,6 +665,22 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_delete_server_peer() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        let res = server
+            .request(
+                test::ADMIN_PEER_IP,
+                "DELETE",
+                &format!("/v1/admin/peers/{SERVER_PEER_ID}"),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        assert!(DatabasePeer::get(&server.db().lock(), SERVER_PEER_ID).is_ok());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_delete_from_non_admin() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -378,4 +721,59 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_delete_leaves_tombstone_until_purged() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let path = format!("/v1/admin/peers/{}", test::USER1_PEER_ID);
+        let epoch = NetworkEpoch::get(&server.db().lock())?;
+
+        let res = server.request(test::ADMIN_PEER_IP, "DELETE", &path).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        // Other peers learn about the delete on their next fetch.
+        let epoch_after_delete = NetworkEpoch::get(&server.db().lock())?;
+        assert!(epoch_after_delete > epoch);
+
+        // The peer is gone from listings and can no longer reach the API...
+        let res = server
+            .request(test::USER1_PEER_IP, "GET", "/v1/user/state")
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = server
+            .request(test::ADMIN_PEER_IP, "GET", "/v1/admin/peers/deleted")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let whole_body = hyper::body::aggregate(res).await?;
+        let deleted: Vec<Peer> = serde_json::from_reader(whole_body.reader())?;
+        assert_eq!(deleted.len(), 1);
+        assert!(!DatabasePeer::list(&server.db().lock())?
+            .iter()
+            .any(|peer| peer.id == test::USER1_PEER_ID));
+
+        // ...but its IP can't be handed to a new peer yet.
+        let peer = test::user_peer_contents("user3", &deleted[0].ip.to_string())?;
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/peers", &peer)
+            .await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        // Only deleted peers can be purged.
+        let res = server
+            .request(
+                test::ADMIN_PEER_IP,
+                "DELETE",
+                &format!("/v1/admin/peers/{}/purge", test::DEVELOPER1_PEER_ID),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = server
+            .request(test::ADMIN_PEER_IP, "DELETE", &format!("{path}/purge"))
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(DatabasePeer::list_deleted(&server.db().lock())?.is_empty());
+        assert!(NetworkEpoch::get(&server.db().lock())? > epoch_after_delete);
+
+        Ok(())
+    }
 }
@@ -1,4 +1,5 @@
 use shared::Peer;
+use std::net::SocketAddr;
 
 use crate::Session;
 
@@ -10,10 +11,22 @@ pub mod user;
 /// already has an endpoint specified (by calling the override-endpoint) API,
 /// the relatively recent wireguard endpoint will be added to the list of NAT
 /// candidates, so other peers have a better chance of connecting.
+///
+/// This is skipped entirely when the server runs with `--disable-endpoint-injection`.
 pub fn inject_endpoints(session: &Session, peers: &mut Vec<Peer>) {
+    if session.context.disable_endpoint_injection {
+        return;
+    }
+
     for peer in peers {
         let endpoints = session.context.endpoints.read();
         if let Some(wg_endpoint) = endpoints.get(&peer.public_key) {
+            // Dual-stack sockets report IPv4 peers as IPv4-mapped IPv6 addresses, which
+            // IPv4-only peers can't use, and an unspecified address is useless to anyone.
+            let wg_endpoint = SocketAddr::new(wg_endpoint.ip().to_canonical(), wg_endpoint.port());
+            if wg_endpoint.ip().is_unspecified() {
+                continue;
+            }
             if peer.contents.endpoint.is_none() {
                 peer.contents.endpoint = Some(wg_endpoint.to_owned().into());
             } else {
@@ -2,13 +2,18 @@ use std::collections::VecDeque;
 
 use crate::{
     api::inject_endpoints,
-    db::{DatabaseCidr, DatabasePeer},
+    db::{DatabaseCidr, DatabasePeer, KeyRotations, NetworkEpoch},
+    hash_join_token, peer_config,
     util::{form_body, json_response, status_response},
     Context, ServerError, Session,
 };
 use hyper::{Body, Method, Request, Response, StatusCode};
-use shared::{EndpointContents, PeerContents, RedeemContents, State, REDEEM_TRANSITION_WAIT};
-use wireguard_control::{DeviceUpdate, PeerConfigBuilder};
+use shared::{
+    EndpointContents, Info, KeyRotation, PeerContents, PeerSelfContents, RedeemContents,
+    RotateKeyContents, State, REDEEM_TRANSITION_WAIT,
+};
+use subtle::ConstantTimeEq;
+use wireguard_control::{DeviceUpdate, Key};
 
 pub async fn routes(
     req: Request<Body>,
@@ -16,11 +21,28 @@ pub async fn routes(
     session: Session,
 ) -> Result<Response<Body>, ServerError> {
     match (req.method(), components.pop_front().as_deref()) {
+        (&Method::GET, Some("info")) => {
+            if !session.user_capable() {
+                return Err(ServerError::Unauthorized);
+            }
+            handlers::info(session).await
+        },
         (&Method::GET, Some("state")) => {
             if !session.user_capable() {
                 return Err(ServerError::Unauthorized);
             }
-            handlers::state(session).await
+            let key_rotations_after = req
+                .uri()
+                .query()
+                .and_then(|query| {
+                    url::form_urlencoded::parse(query.as_bytes())
+                        .find(|(key, _)| key == "key-rotations-after")
+                })
+                .map(|(_, value)| value.parse())
+                .transpose()
+                .map_err(|_| ServerError::InvalidQuery)?
+                .unwrap_or(0);
+            handlers::state(session, key_rotations_after).await
         },
         (&Method::POST, Some("redeem")) => {
             if !session.redeemable() {
@@ -29,6 +51,13 @@ pub async fn routes(
             let form = form_body(req).await?;
             handlers::redeem(form, session).await
         },
+        (&Method::POST, Some("rotate-key")) => {
+            if !session.user_capable() {
+                return Err(ServerError::Unauthorized);
+            }
+            let form = form_body(req).await?;
+            handlers::rotate_key(form, session).await
+        },
         (&Method::PUT, Some("endpoint")) => {
             if !session.user_capable() {
                 return Err(ServerError::Unauthorized);
@@ -43,6 +72,13 @@ pub async fn routes(
             let form = form_body(req).await?;
             handlers::candidates(form, session).await
         },
+        (&Method::PUT, Some("self")) => {
+            if !session.user_capable() {
+                return Err(ServerError::Unauthorized);
+            }
+            let form = form_body(req).await?;
+            handlers::update_self(form, session).await
+        },
         _ => Err(ServerError::NotFound),
     }
 }
@@ -52,11 +88,25 @@ mod handlers {
 
     use super::*;
 
+    /// Get the server's version and the network's current epoch, letting peers check whether
+    /// anything changed without fetching the whole state.
+    pub async fn info(session: Session) -> Result<Response<Body>, ServerError> {
+        let conn = session.context.db.lock();
+        json_response(Info {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            epoch: NetworkEpoch::get(&conn)?,
+        })
+    }
+
     /// Get the current state of the network, in the eyes of the current peer.
     ///
     /// This endpoint returns the visible CIDRs and Peers, providing all the necessary
-    /// information for the peer to create connections to all of them.
-    pub async fn state(session: Session) -> Result<Response<Body>, ServerError> {
+    /// information for the peer to create connections to all of them, along with the key
+    /// rotations newer than `key_rotations_after`.
+    pub async fn state(
+        session: Session,
+        key_rotations_after: i64,
+    ) -> Result<Response<Body>, ServerError> {
         let conn = session.context.db.lock();
         let selected_peer = DatabasePeer::get(&conn, session.peer.id)?;
 
@@ -68,7 +118,13 @@ mod handlers {
             .map(|p| p.inner)
             .collect();
         inject_endpoints(&session, &mut peers);
-        json_response(State { peers, cidrs })
+        let peer_ids: Vec<_> = peers.iter().map(|peer| peer.id).collect();
+        let key_rotations = KeyRotations::list_for(&conn, &peer_ids, key_rotations_after)?;
+        json_response(State {
+            peers,
+            cidrs,
+            key_rotations,
+        })
     }
 
     /// Redeems an invitation. An invitation includes a WireGuard keypair generated by either the server
@@ -83,25 +139,78 @@ mod handlers {
         form: RedeemContents,
         session: Session,
     ) -> Result<Response<Body>, ServerError> {
+        if let Some(join_token_hash) = &session.context.join_token_hash {
+            let valid = form.join_token.as_deref().is_some_and(|token| {
+                hash_join_token(token)
+                    .as_bytes()
+                    .ct_eq(join_token_hash.as_bytes())
+                    .into()
+            });
+            if !valid {
+                return Err(ServerError::Forbidden);
+            }
+        }
+
         let conn = session.context.db.lock();
         let mut selected_peer = DatabasePeer::get(&conn, session.peer.id)?;
 
         let old_public_key = wireguard_control::Key::from_base64(&selected_peer.public_key)
-            .map_err(|_| ServerError::WireGuard)?;
+            .map_err(ServerError::invalid_key)?;
 
         selected_peer.redeem(&conn, &form.public_key)?;
+        swap_public_key(&session.context, old_public_key, selected_peer);
+
+        status_response(StatusCode::NO_CONTENT)
+    }
+
+    /// Replaces the public key of an already-redeemed peer, allowing it to rotate its keypair
+    /// without being re-invited. The request has to be signed with the old key, which other
+    /// peers check before accepting the new key.
+    ///
+    /// Like with redemption, the client is expected to wait `REDEEM_TRANSITION_WAIT` after a
+    /// success response before switching to its new private key.
+    pub async fn rotate_key(
+        form: RotateKeyContents,
+        session: Session,
+    ) -> Result<Response<Body>, ServerError> {
+        let mut conn = session.context.db.lock();
+        let tx = conn.transaction()?;
+        let mut selected_peer = DatabasePeer::get(&tx, session.peer.id)?;
+
+        let old_public_key = wireguard_control::Key::from_base64(&selected_peer.public_key)
+            .map_err(ServerError::invalid_key)?;
+        wireguard_control::Key::from_base64(&form.public_key)
+            .map_err(|_| ServerError::InvalidQuery)?;
+        let rotation = KeyRotation {
+            id: 0,
+            old_public_key: selected_peer.public_key.clone(),
+            new_public_key: form.public_key,
+            signature: form.signature,
+        };
+        if !rotation.is_signed() {
+            return Err(ServerError::InvalidQuery);
+        }
+
+        selected_peer.set_public_key(&tx, &rotation.new_public_key, &rotation.signature)?;
+        tx.commit()?;
+        swap_public_key(&session.context, old_public_key, selected_peer);
+
+        status_response(StatusCode::NO_CONTENT)
+    }
 
+    fn swap_public_key(context: &Context, old_public_key: Key, peer: DatabasePeer) {
         if cfg!(not(test)) {
             let Context {
                 interface, backend, ..
-            } = session.context;
+            } = *context;
+            let peer_config = peer_config(&peer, context.preshared_key.as_ref());
 
             // If we were to modify the WireGuard interface immediately, the HTTP response wouldn't
             // get through. Instead, we need to wait a reasonable amount for the HTTP response to
             // flush, then update the interface.
             //
             // The client is also expected to wait the same amount of time after receiving a success
-            // response from /redeem.
+            // response from /redeem or /rotate-key.
             //
             // This might be avoidable if we were able to run code after we were certain the response
             // had flushed over the TCP socket, but that isn't easily accessible from this high-level
@@ -112,18 +221,17 @@ mod handlers {
                 tokio::time::sleep(REDEEM_TRANSITION_WAIT).await;
                 log::info!(
                     "WireGuard: adding new peer {}, removing old pubkey {}",
-                    &*selected_peer,
+                    &*peer,
                     old_public_key.to_base64()
                 );
                 DeviceUpdate::new()
                     .remove_peer_by_key(&old_public_key)
-                    .add_peer(PeerConfigBuilder::from(&*selected_peer))
+                    .add_peer(peer_config)
                     .apply(&interface, backend)
                     .map_err(|e| log::error!("{:?}", e))
                     .ok();
             });
         }
-        status_response(StatusCode::NO_CONTENT)
     }
 
     /// Report any other endpoint candidates that can be tried by peers to connect.
@@ -165,6 +273,24 @@ mod handlers {
 
         status_response(StatusCode::NO_CONTENT)
     }
+
+    /// Update the few fields a peer is allowed to change about itself without admin rights.
+    pub async fn update_self(
+        contents: PeerSelfContents,
+        session: Session,
+    ) -> Result<Response<Body>, ServerError> {
+        let conn = session.context.db.lock();
+        let mut selected_peer = DatabasePeer::get(&conn, session.peer.id)?;
+        selected_peer.update(
+            &conn,
+            PeerContents {
+                description: Some(contents.description.unwrap_or_default()),
+                ..selected_peer.contents.clone()
+            },
+        )?;
+
+        status_response(StatusCode::NO_CONTENT)
+    }
 }
 
 #[cfg(test)]
@@ -174,7 +300,11 @@ mod tests {
     use super::*;
     use crate::{db::DatabaseAssociation, test};
     use bytes::Buf;
-    use shared::{AssociationContents, CidrContents, Endpoint, EndpointContents, Error};
+    use shared::{
+        AssociationContents, CidrContents, Endpoint, EndpointContents, Error,
+        MAX_PEER_DESCRIPTION_LEN,
+    };
+    use wireguard_control::KeyPair;
 
     #[tokio::test]
     async fn test_get_state_from_developer1() -> Result<(), Error> {
@@ -267,6 +397,8 @@ mod tests {
                     name: "experiment cidr".to_string(),
                     cidr: test::EXPERIMENTAL_CIDR.parse()?,
                     parent: Some(test::ROOT_CIDR_ID),
+                    default_keepalive: None,
+                    is_disabled: false,
                 },
             )?;
             let subcidr = DatabaseCidr::create(
@@ -275,6 +407,8 @@ mod tests {
                     name: "experiment subcidr".to_string(),
                     cidr: test::EXPERIMENTAL_SUBCIDR.parse()?,
                     parent: Some(cidr.id),
+                    default_keepalive: None,
+                    is_disabled: false,
                 },
             )?;
             DatabasePeer::create(
@@ -336,6 +470,8 @@ mod tests {
                 name: "experimental".to_string(),
                 cidr: test::EXPERIMENTAL_CIDR.parse()?,
                 parent: Some(test::ROOT_CIDR_ID),
+                default_keepalive: None,
+                is_disabled: false,
             },
         )?;
 
@@ -358,6 +494,7 @@ mod tests {
         // Step 2: Ensure that redemption works.
         let body = RedeemContents {
             public_key: "YBVIgpfLbi/knrMCTEb0L6eVy0daiZnJJQkxBK9s+2I=".into(),
+            join_token: None,
         };
         let res = server
             .form_request(
@@ -388,6 +525,184 @@ mod tests {
         Ok(())
     }
 
+    /// An IPv6-only network should work end to end: an admin invites a peer, the peer redeems
+    /// the invitation, and nothing in the state it then fetches refers to IPv4.
+    #[cfg(feature = "v6-test")]
+    #[tokio::test]
+    async fn test_ipv6_only_invite_and_redeem() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        let mut peer_contents = test::developer_peer_contents("developer3", "fd00:1337::2:0:0:3")?;
+        peer_contents.is_redeemed = false;
+        peer_contents.invite_expires = Some(SystemTime::now() + Duration::from_secs(100));
+        let res = server
+            .form_request(
+                test::ADMIN_PEER_IP,
+                "POST",
+                "/v1/admin/peers",
+                &peer_contents,
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let body = RedeemContents {
+            public_key: "YBVIgpfLbi/knrMCTEb0L6eVy0daiZnJJQkxBK9s+2I=".into(),
+            join_token: None,
+        };
+        let res = server
+            .form_request("fd00:1337::2:0:0:3", "POST", "/v1/user/redeem", &body)
+            .await;
+        assert!(res.status().is_success());
+
+        let res = server
+            .request("fd00:1337::2:0:0:3", "GET", "/v1/user/state")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let State { peers, cidrs, .. } =
+            serde_json::from_reader(hyper::body::aggregate(res).await?.reader())?;
+
+        assert!(peers.iter().any(|peer| &*peer.name == "innernet-server"));
+        assert!(peers.iter().all(|peer| peer.ip.is_ipv6()));
+        assert!(cidrs.iter().all(|cidr| cidr.cidr.addr().is_ipv6()));
+        for endpoint in peers
+            .iter()
+            .flat_map(|peer| peer.endpoint.iter().chain(&peer.candidates))
+        {
+            assert!(endpoint.resolve()?.is_ipv6(), "{endpoint} isn't IPv6");
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_redeem_with_join_token() -> Result<(), Error> {
+        let mut server = test::Server::new()?;
+        server.join_token_hash = Some(hash_join_token("open sesame"));
+
+        let experimental_cidr = DatabaseCidr::create(
+            &server.db().lock(),
+            CidrContents {
+                name: "experimental".to_string(),
+                cidr: test::EXPERIMENTAL_CIDR.parse()?,
+                parent: Some(test::ROOT_CIDR_ID),
+                default_keepalive: None,
+                is_disabled: false,
+            },
+        )?;
+
+        let mut peer_contents = test::peer_contents(
+            "experiment-peer",
+            test::EXPERIMENT_SUBCIDR_PEER_IP,
+            experimental_cidr.id,
+            false,
+        )?;
+        peer_contents.is_redeemed = false;
+        let _experiment_peer = DatabasePeer::create(&server.db().lock(), peer_contents)?;
+
+        for (join_token, status) in [
+            (None, StatusCode::FORBIDDEN),
+            (Some("wrong"), StatusCode::FORBIDDEN),
+            (Some("open sesame"), StatusCode::NO_CONTENT),
+        ] {
+            let body = RedeemContents {
+                public_key: "YBVIgpfLbi/knrMCTEb0L6eVy0daiZnJJQkxBK9s+2I=".into(),
+                join_token: join_token.map(String::from),
+            };
+            let res = server
+                .form_request(
+                    test::EXPERIMENT_SUBCIDR_PEER_IP,
+                    "POST",
+                    "/v1/user/redeem",
+                    &body,
+                )
+                .await;
+            assert_eq!(res.status(), status);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rotate_key() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        // The server only knows developer1's public key, so give it one we hold the private half of.
+        let old_keypair = KeyPair::generate();
+        let old_public_key = old_keypair.public.to_base64();
+        server.db().lock().execute(
+            "UPDATE peers SET public_key = ?1 WHERE id = ?2",
+            rusqlite::params![old_public_key, test::DEVELOPER1_PEER_ID],
+        )?;
+        let new_public_key = "YBVIgpfLbi/knrMCTEb0L6eVy0daiZnJJQkxBK9s+2I=".to_string();
+
+        for body in [
+            RotateKeyContents {
+                public_key: "not a key".into(),
+                signature: KeyRotation::sign(&old_keypair.private, "not a key"),
+            },
+            // Signed by some other key than the peer's current one.
+            RotateKeyContents {
+                public_key: new_public_key.clone(),
+                signature: KeyRotation::sign(&KeyPair::generate().private, &new_public_key),
+            },
+        ] {
+            let res = server
+                .form_request(
+                    test::DEVELOPER1_PEER_IP,
+                    "POST",
+                    "/v1/user/rotate-key",
+                    &body,
+                )
+                .await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        }
+
+        let body = RotateKeyContents {
+            public_key: new_public_key.clone(),
+            signature: KeyRotation::sign(&old_keypair.private, &new_public_key),
+        };
+        let res = server
+            .form_request(
+                test::DEVELOPER1_PEER_IP,
+                "POST",
+                "/v1/user/rotate-key",
+                &body,
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let peer = DatabasePeer::get(&server.db().lock(), test::DEVELOPER1_PEER_ID)?;
+        assert_eq!(peer.public_key, body.public_key);
+        assert!(peer.is_redeemed);
+
+        // Other peers are told about the rotation so they can re-pin the new key.
+        let res = server
+            .request(test::DEVELOPER2_PEER_IP, "GET", "/v1/user/state")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let whole_body = hyper::body::aggregate(res).await?;
+        let State { key_rotations, .. } = serde_json::from_reader(whole_body.reader())?;
+        let [rotation] = &key_rotations[..] else {
+            panic!("expected one rotation, got {key_rotations:?}");
+        };
+        assert_eq!(rotation.old_public_key, old_public_key);
+        assert_eq!(rotation.new_public_key, new_public_key);
+        assert!(rotation.is_signed());
+
+        // ...but only about rotations they haven't seen yet.
+        let res = server
+            .request(
+                test::DEVELOPER2_PEER_IP,
+                "GET",
+                &format!("/v1/user/state?key-rotations-after={}", rotation.id),
+            )
+            .await;
+        let whole_body = hyper::body::aggregate(res).await?;
+        let State { key_rotations, .. } = serde_json::from_reader(whole_body.reader())?;
+        assert!(key_rotations.is_empty());
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_redeem_expired() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -398,6 +713,8 @@ mod tests {
                 name: "experimental".to_string(),
                 cidr: test::EXPERIMENTAL_CIDR.parse()?,
                 parent: Some(test::ROOT_CIDR_ID),
+                default_keepalive: None,
+                is_disabled: false,
             },
         )?;
 
@@ -420,6 +737,7 @@ mod tests {
         // Step 2: Ensure that redemption works.
         let body = RedeemContents {
             public_key: "YBVIgpfLbi/knrMCTEb0L6eVy0daiZnJJQkxBK9s+2I=".into(),
+            join_token: None,
         };
         let res = server
             .form_request(
@@ -465,6 +783,47 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_info_epoch() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let epoch = || async {
+            let res = server
+                .request(test::DEVELOPER1_PEER_IP, "GET", "/v1/user/info")
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let whole_body = hyper::body::aggregate(res).await.unwrap();
+            serde_json::from_reader::<_, Info>(whole_body.reader())
+                .unwrap()
+                .epoch
+        };
+        let report_candidates = || async {
+            let candidates = vec!["1.1.1.1:51820".parse::<Endpoint>().unwrap()];
+            server
+                .form_request(
+                    test::DEVELOPER1_PEER_IP,
+                    "PUT",
+                    "/v1/user/candidates",
+                    &candidates,
+                )
+                .await
+                .status()
+        };
+
+        let initial = epoch().await;
+        assert_eq!(report_candidates().await, StatusCode::NO_CONTENT);
+        let changed = epoch().await;
+        assert!(changed > initial);
+
+        // Reporting the same candidates again doesn't change anything.
+        assert_eq!(report_candidates().await, StatusCode::NO_CONTENT);
+        assert_eq!(epoch().await, changed);
+
+        DatabasePeer::disable(&server.db().lock(), test::USER1_PEER_ID)?;
+        assert!(epoch().await > changed);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_endpoint_in_candidates() -> Result<(), Error> {
         // We want to verify that the current wireguard endpoint always shows up
@@ -553,4 +912,110 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_disable_endpoint_injection() -> Result<(), Error> {
+        let mut server = test::Server::new()?;
+        server.disable_endpoint_injection = true;
+
+        let res = server
+            .request(test::DEVELOPER2_PEER_IP, "GET", "/v1/user/state")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let State { peers, .. } =
+            serde_json::from_reader(hyper::body::aggregate(res).await?.reader())?;
+
+        // developer1 has no configured endpoint, so the observed one isn't handed out.
+        let developer_1 = peers
+            .into_iter()
+            .find(|p| p.id == test::DEVELOPER1_PEER_ID)
+            .unwrap();
+        assert_eq!(developer_1.endpoint, None);
+        assert_eq!(developer_1.candidates, vec![]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_update_self() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let description = |description: Option<&str>| PeerSelfContents {
+            description: description.map(String::from),
+        };
+        let epoch = NetworkEpoch::get(&server.db().lock())?;
+
+        let res = server
+            .form_request(
+                test::USER1_PEER_IP,
+                "PUT",
+                "/v1/user/self",
+                &description(Some("alice's laptop")),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let peer = DatabasePeer::get(&server.db().lock(), test::USER1_PEER_ID)?;
+        assert_eq!(peer.description.as_deref(), Some("alice's laptop"));
+        // Other peers learn about the new description on their next fetch.
+        assert!(NetworkEpoch::get(&server.db().lock())? > epoch);
+
+        // Descriptions are length-limited and single-line.
+        for invalid in [
+            "x".repeat(MAX_PEER_DESCRIPTION_LEN + 1),
+            "two\nlines".into(),
+        ] {
+            let res = server
+                .form_request(
+                    test::USER1_PEER_IP,
+                    "PUT",
+                    "/v1/user/self",
+                    &description(Some(&invalid)),
+                )
+                .await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        }
+
+        // An admin update that doesn't mention the description leaves it alone...
+        let mut contents = peer.contents.clone();
+        contents.description = None;
+        let res = server
+            .form_request(
+                test::ADMIN_PEER_IP,
+                "PUT",
+                &format!("/v1/admin/peers/{}", test::USER1_PEER_ID),
+                &contents,
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let peer = DatabasePeer::get(&server.db().lock(), test::USER1_PEER_ID)?;
+        assert_eq!(peer.description.as_deref(), Some("alice's laptop"));
+
+        // ...but admins can still override it.
+        contents.description = Some("lost and found".into());
+        let res = server
+            .form_request(
+                test::ADMIN_PEER_IP,
+                "PUT",
+                &format!("/v1/admin/peers/{}", test::USER1_PEER_ID),
+                &contents,
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let peer = DatabasePeer::get(&server.db().lock(), test::USER1_PEER_ID)?;
+        assert_eq!(peer.description.as_deref(), Some("lost and found"));
+
+        // Clearing it is up to the peer, too.
+        let res = server
+            .form_request(
+                test::USER1_PEER_IP,
+                "PUT",
+                "/v1/user/self",
+                &description(None),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let peer = DatabasePeer::get(&server.db().lock(), test::USER1_PEER_ID)?;
+        assert_eq!(peer.description, None);
+
+        Ok(())
+    }
 }
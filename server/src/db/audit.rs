@@ -0,0 +1,119 @@
+//! An append-only record of the changes admins make to the network through the API.
+
+use super::DatabasePeer;
+use crate::ServerError;
+use rusqlite::{params, Connection};
+use shared::AuditEntry;
+use std::time::SystemTime;
+
+/// The triggers make the table append-only: entries can never be modified or removed.
+pub static CREATE_TABLE_SQL: &str = "
+    CREATE TABLE audit_log (
+      id                INTEGER PRIMARY KEY,
+      timestamp         INTEGER NOT NULL,   /* The UNIX time the change was made.                 */
+      actor_ip          TEXT NOT NULL,      /* The internal IP of the admin peer who made it.     */
+      actor_public_key  TEXT NOT NULL,      /* The public key of the admin peer who made it.      */
+      action            TEXT NOT NULL,      /* What was done, e.g. 'create-peer'.                 */
+      target            TEXT NOT NULL       /* What it was done to, e.g. the peer's name and IP.  */
+    );
+    CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log
+    BEGIN
+      SELECT RAISE(ABORT, 'the audit log is append-only');
+    END;
+    CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log
+    BEGIN
+      SELECT RAISE(ABORT, 'the audit log is append-only');
+    END;";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditAction {
+    CreatePeer,
+    UpdatePeer,
+    DeletePeer,
+    PurgePeer,
+    CreateCidr,
+    UpdateCidr,
+    DeleteCidr,
+    DisableCidr,
+    EnableCidr,
+    CreateAssociation,
+    DeleteAssociation,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::CreatePeer => "create-peer",
+            Self::UpdatePeer => "update-peer",
+            Self::DeletePeer => "delete-peer",
+            Self::PurgePeer => "purge-peer",
+            Self::CreateCidr => "create-cidr",
+            Self::UpdateCidr => "update-cidr",
+            Self::DeleteCidr => "delete-cidr",
+            Self::DisableCidr => "disable-cidr",
+            Self::EnableCidr => "enable-cidr",
+            Self::CreateAssociation => "create-association",
+            Self::DeleteAssociation => "delete-association",
+        }
+    }
+}
+
+pub struct AuditLog;
+
+impl AuditLog {
+    /// Record that `actor` performed `action` on `target`.
+    pub fn record(
+        conn: &Connection,
+        actor: &DatabasePeer,
+        action: AuditAction,
+        target: &str,
+    ) -> Result<(), ServerError> {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Something is horribly wrong with system time.")
+            .as_secs();
+
+        log::info!(
+            "audit: {} ({}) {} {}",
+            actor.name,
+            actor.ip,
+            action.as_str(),
+            target
+        );
+        conn.execute(
+            "INSERT INTO audit_log (timestamp, actor_ip, actor_public_key, action, target)
+              VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                timestamp,
+                actor.ip.to_string(),
+                actor.public_key,
+                action.as_str(),
+                target
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// List all entries, oldest first.
+    pub fn list(conn: &Connection) -> Result<Vec<AuditEntry>, ServerError> {
+        let mut stmt = conn.prepare_cached(
+            "SELECT id, timestamp, actor_ip, actor_public_key, action, target
+              FROM audit_log ORDER BY id",
+        )?;
+        let entry_iter = stmt.query_map(params![], |row| {
+            let actor_ip: String = row.get(2)?;
+            Ok(AuditEntry {
+                id: row.get(0)?,
+                timestamp: row.get(1)?,
+                actor_ip: actor_ip
+                    .parse()
+                    .map_err(|_| rusqlite::Error::ExecuteReturnedResults)?,
+                actor_public_key: row.get(3)?,
+                action: row.get(4)?,
+                target: row.get(5)?,
+            })
+        })?;
+
+        Ok(entry_iter.collect::<Result<Vec<_>, rusqlite::Error>>()?)
+    }
+}
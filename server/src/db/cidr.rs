@@ -1,3 +1,4 @@
+use super::DatabasePeer;
 use crate::ServerError;
 use ipnet::IpNet;
 use rusqlite::{params, Connection};
@@ -10,6 +11,8 @@ pub static CREATE_TABLE_SQL: &str = "CREATE TABLE cidrs (
       ip               TEXT NOT NULL,
       prefix           INTEGER NOT NULL,
       parent           INTEGER REFERENCES cidrs,
+      default_keepalive INTEGER,
+      is_disabled      INTEGER DEFAULT 0 NOT NULL,
       UNIQUE(ip, prefix),
       FOREIGN KEY (parent)
          REFERENCES cidrs (id)
@@ -43,7 +46,13 @@ impl DerefMut for DatabaseCidr {
 
 impl DatabaseCidr {
     pub fn create(conn: &Connection, contents: CidrContents) -> Result<Cidr, ServerError> {
-        let CidrContents { name, cidr, parent } = &contents;
+        let CidrContents {
+            name,
+            cidr,
+            parent,
+            default_keepalive,
+            is_disabled,
+        } = &contents;
 
         log::debug!("creating {:?}", contents);
 
@@ -68,44 +77,66 @@ impl DatabaseCidr {
             if let Some(closest_parent) = closest_parent {
                 if closest_parent.id != *parent_id {
                     log::warn!("tried to add a CIDR at the incorrect place in the tree (should be added to {}).", closest_parent.name);
-                    return Err(ServerError::InvalidQuery);
+                    return Err(ServerError::InvalidCidr(format!(
+                        "{} belongs under {} ({}), not the requested parent",
+                        cidr, closest_parent.name, closest_parent.cidr
+                    )));
                 }
             } else {
                 log::warn!("tried to add a CIDR outside of the root network range.");
-                return Err(ServerError::InvalidQuery);
+                return Err(ServerError::InvalidCidr(format!(
+                    "{} is outside of the root network range",
+                    cidr
+                )));
             }
 
             let parent_cidr = Self::get(conn, *parent_id)?.cidr;
             if !parent_cidr.contains(&cidr.network()) || !parent_cidr.contains(&cidr.broadcast()) {
                 log::warn!("tried to add a CIDR with a network range outside of its parent.");
-                return Err(ServerError::InvalidQuery);
+                return Err(ServerError::InvalidCidr(format!(
+                    "{} is not contained within its parent {}",
+                    cidr, parent_cidr
+                )));
+            }
+
+            if cidr.prefix_len() <= parent_cidr.prefix_len() {
+                log::warn!("tried to add a CIDR that is not strictly smaller than its parent.");
+                return Err(ServerError::InvalidCidr(format!(
+                    "{} must be a strict subset of its parent {}",
+                    cidr, parent_cidr
+                )));
             }
         }
 
         let overlapping_sibling = Self::list(conn)?
-            .iter()
+            .into_iter()
             .filter(|current| current.parent == *parent)
-            .map(|sibling| sibling.cidr)
-            .any(|sibling| {
+            .find(|sibling| {
+                let sibling = sibling.cidr;
                 cidr.contains(&sibling.network())
                     || cidr.contains(&sibling.broadcast())
                     || sibling.contains(&cidr.network())
                     || sibling.contains(&cidr.broadcast())
             });
 
-        if overlapping_sibling {
+        if let Some(sibling) = overlapping_sibling {
             log::warn!("tried to add a CIDR that overlaps with a sibling.");
-            return Err(ServerError::InvalidQuery);
+            return Err(ServerError::InvalidCidr(format!(
+                "{} overlaps with sibling {} ({})",
+                cidr, sibling.name, sibling.cidr
+            )));
         }
 
         conn.execute(
-            "INSERT INTO cidrs (name, ip, prefix, parent)
-              VALUES (?1, ?2, ?3, ?4)",
+            "INSERT INTO cidrs (name, ip, prefix, parent, default_keepalive, is_disabled)
+              VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
             params![
                 name,
                 cidr.addr().to_string(),
                 cidr.prefix_len() as i32,
-                parent
+                parent,
+                default_keepalive,
+                is_disabled
             ],
         )?;
         let id = conn.last_insert_rowid();
@@ -129,6 +160,31 @@ impl DatabaseCidr {
         Ok(())
     }
 
+    /// Disable or enable a CIDR. The CIDR the server itself is in can't be disabled, since that
+    /// would hide the server from every peer.
+    pub fn set_disabled(
+        &mut self,
+        conn: &Connection,
+        is_disabled: bool,
+    ) -> Result<(), ServerError> {
+        // The server is always the first peer created.
+        let server = DatabasePeer::get(conn, 1)?;
+        if is_disabled && self.cidr.contains(&server.ip) {
+            log::warn!("tried to disable the CIDR the server is in.");
+            return Err(ServerError::InvalidCidr(format!(
+                "{} ({}) contains the server and can't be disabled",
+                self.name, self.cidr
+            )));
+        }
+
+        conn.execute(
+            "UPDATE cidrs SET is_disabled = ?2 WHERE id = ?1",
+            params![self.id, is_disabled],
+        )?;
+        self.contents.is_disabled = is_disabled;
+        Ok(())
+    }
+
     pub fn delete(conn: &Connection, id: i64) -> Result<(), ServerError> {
         conn.execute("DELETE FROM cidrs WHERE id = ?1", params![id])?;
         Ok(())
@@ -144,22 +200,33 @@ impl DatabaseCidr {
             .map_err(|_| rusqlite::Error::ExecuteReturnedResults)?;
         let cidr = IpNet::new(ip, prefix).map_err(|_| rusqlite::Error::ExecuteReturnedResults)?;
         let parent = row.get(4)?;
+        let default_keepalive = row.get(5)?;
+        let is_disabled = row.get(6)?;
         Ok(Cidr {
             id,
-            contents: CidrContents { name, cidr, parent },
+            contents: CidrContents {
+                name,
+                cidr,
+                parent,
+                default_keepalive,
+                is_disabled,
+            },
         })
     }
 
     pub fn get(conn: &Connection, id: i64) -> Result<Cidr, ServerError> {
         Ok(conn.query_row(
-            "SELECT id, name, ip, prefix, parent FROM cidrs WHERE id = ?1",
+            "SELECT id, name, ip, prefix, parent, default_keepalive, is_disabled
+              FROM cidrs WHERE id = ?1",
             params![id],
             Self::from_row,
         )?)
     }
 
     pub fn list(conn: &Connection) -> Result<Vec<Cidr>, ServerError> {
-        let mut stmt = conn.prepare_cached("SELECT id, name, ip, prefix, parent FROM cidrs")?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT id, name, ip, prefix, parent, default_keepalive, is_disabled FROM cidrs",
+        )?;
         let cidr_iter = stmt.query_map(params![], Self::from_row)?;
 
         Ok(cidr_iter.collect::<Result<Vec<_>, rusqlite::Error>>()?)
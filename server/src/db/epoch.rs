@@ -0,0 +1,80 @@
+//! A counter that's bumped whenever the network's peers, CIDRs or associations change, so
+//! clients can cheaply tell whether their view of the network is out of date.
+//!
+//! The counter is maintained by triggers, so every change is counted regardless of whether it
+//! came through the API or the server's CLI.
+
+use rusqlite::{params, Connection};
+
+pub static CREATE_TABLE_SQL: &str = "
+    CREATE TABLE network_epoch (
+      id     INTEGER PRIMARY KEY CHECK (id = 1),
+      epoch  INTEGER NOT NULL
+    );
+    INSERT INTO network_epoch (id, epoch) VALUES (1, 0);
+
+    CREATE TRIGGER epoch_peer_insert AFTER INSERT ON peers
+    BEGIN
+      UPDATE network_epoch SET epoch = epoch + 1;
+    END;
+    CREATE TRIGGER epoch_peer_delete AFTER DELETE ON peers
+    BEGIN
+      UPDATE network_epoch SET epoch = epoch + 1;
+    END;
+
+    CREATE TRIGGER epoch_cidr_insert AFTER INSERT ON cidrs
+    BEGIN
+      UPDATE network_epoch SET epoch = epoch + 1;
+    END;
+    CREATE TRIGGER epoch_cidr_update AFTER UPDATE ON cidrs
+    BEGIN
+      UPDATE network_epoch SET epoch = epoch + 1;
+    END;
+    CREATE TRIGGER epoch_cidr_delete AFTER DELETE ON cidrs
+    BEGIN
+      UPDATE network_epoch SET epoch = epoch + 1;
+    END;
+
+    CREATE TRIGGER epoch_association_insert AFTER INSERT ON associations
+    BEGIN
+      UPDATE network_epoch SET epoch = epoch + 1;
+    END;
+    CREATE TRIGGER epoch_association_delete AFTER DELETE ON associations
+    BEGIN
+      UPDATE network_epoch SET epoch = epoch + 1;
+    END;";
+
+/// The trigger for peer updates, which only counts changes to columns clients see. It's kept
+/// apart from the table so migrations can recreate it as columns are added to `peers`.
+pub static PEER_UPDATE_TRIGGER_SQL: &str = "
+    DROP TRIGGER IF EXISTS epoch_peer_update;
+    /* Peers report their NAT candidates on every fetch, mostly unchanged. */
+    CREATE TRIGGER epoch_peer_update AFTER UPDATE ON peers
+    WHEN OLD.name IS NOT NEW.name
+      OR OLD.ip IS NOT NEW.ip
+      OR OLD.public_key IS NOT NEW.public_key
+      OR OLD.endpoint IS NOT NEW.endpoint
+      OR OLD.cidr_id IS NOT NEW.cidr_id
+      OR OLD.is_admin IS NOT NEW.is_admin
+      OR OLD.is_viewer IS NOT NEW.is_viewer
+      OR OLD.is_disabled IS NOT NEW.is_disabled
+      OR OLD.is_redeemed IS NOT NEW.is_redeemed
+      OR OLD.invite_expires IS NOT NEW.invite_expires
+      OR OLD.candidates IS NOT NEW.candidates
+      OR OLD.deleted_at IS NOT NEW.deleted_at
+      OR OLD.description IS NOT NEW.description
+    BEGIN
+      UPDATE network_epoch SET epoch = epoch + 1;
+    END;";
+
+pub struct NetworkEpoch;
+
+impl NetworkEpoch {
+    pub fn get(conn: &Connection) -> Result<u64, rusqlite::Error> {
+        conn.query_row(
+            "SELECT epoch FROM network_epoch WHERE id = 1",
+            params![],
+            |row| row.get(0),
+        )
+    }
+}
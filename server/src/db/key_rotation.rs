@@ -0,0 +1,73 @@
+//! A record of peers replacing their public key through `rotate-key`, so clients that pinned a
+//! peer's old key can follow it to the new one instead of refusing it. Each rotation is signed
+//! with the old key, so clients don't have to take the server's word for it.
+
+use crate::ServerError;
+use rusqlite::{params, Connection};
+use shared::KeyRotation;
+
+pub static CREATE_TABLE_SQL: &str = "CREATE TABLE key_rotations (
+      id              INTEGER PRIMARY KEY,
+      peer_id         INTEGER NOT NULL,  /* The peer whose key was replaced.       */
+      old_public_key  TEXT NOT NULL,     /* The key the peer had before rotating.  */
+      new_public_key  TEXT NOT NULL,     /* The key the peer rotated to.           */
+      signature       TEXT NOT NULL,     /* The old key's signature (base64).      */
+      FOREIGN KEY (peer_id)
+        REFERENCES peers (id)
+          ON UPDATE RESTRICT
+          ON DELETE CASCADE
+    )";
+
+pub struct KeyRotations;
+
+impl KeyRotations {
+    /// Record that the peer with `peer_id` replaced `old_public_key` with `new_public_key`, as
+    /// signed by the old key.
+    pub fn record(
+        conn: &Connection,
+        peer_id: i64,
+        old_public_key: &str,
+        new_public_key: &str,
+        signature: &str,
+    ) -> Result<(), ServerError> {
+        conn.execute(
+            "INSERT INTO key_rotations (peer_id, old_public_key, new_public_key, signature)
+              VALUES (?1, ?2, ?3, ?4)",
+            params![peer_id, old_public_key, new_public_key, signature],
+        )?;
+        Ok(())
+    }
+
+    /// List the rotations of the peers in `peer_ids` with an ID greater than `after`, oldest
+    /// first.
+    pub fn list_for(
+        conn: &Connection,
+        peer_ids: &[i64],
+        after: i64,
+    ) -> Result<Vec<KeyRotation>, ServerError> {
+        let mut stmt = conn.prepare_cached(
+            "SELECT peer_id, id, old_public_key, new_public_key, signature FROM key_rotations
+              WHERE id > ?1
+              ORDER BY id",
+        )?;
+        let rotations = stmt
+            .query_map(params![after], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    KeyRotation {
+                        id: row.get(1)?,
+                        old_public_key: row.get(2)?,
+                        new_public_key: row.get(3)?,
+                        signature: row.get(4)?,
+                    },
+                ))
+            })?
+            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+
+        Ok(rotations
+            .into_iter()
+            .filter(|(peer_id, _)| peer_ids.contains(peer_id))
+            .map(|(_, rotation)| rotation)
+            .collect())
+    }
+}
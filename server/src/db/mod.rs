@@ -1,16 +1,36 @@
 pub mod association;
+pub mod audit;
 pub mod cidr;
+pub mod epoch;
+pub mod key_rotation;
 pub mod peer;
+pub mod peer_limit;
 
 pub use association::DatabaseAssociation;
+pub use audit::{AuditAction, AuditLog};
 pub use cidr::DatabaseCidr;
+pub use epoch::NetworkEpoch;
+pub use key_rotation::KeyRotations;
 pub use peer::DatabasePeer;
+pub use peer_limit::PeerLimits;
 use rusqlite::params;
 
 const INVITE_EXPIRATION_VERSION: usize = 1;
 const ENDPOINT_CANDIDATES_VERSION: usize = 2;
+const CIDR_DEFAULT_KEEPALIVE_VERSION: usize = 3;
+const AUDIT_LOG_VERSION: usize = 4;
+const VIEWER_ROLE_VERSION: usize = 5;
+const NETWORK_EPOCH_VERSION: usize = 6;
+const KEY_ROTATIONS_VERSION: usize = 7;
+const CIDR_DISABLED_VERSION: usize = 8;
+const PEER_TOMBSTONE_VERSION: usize = 9;
+const PEER_DESCRIPTION_VERSION: usize = 10;
+const PEER_LIMITS_VERSION: usize = 11;
+const KEY_ROTATION_SIGNATURE_VERSION: usize = 12;
+const EPOCH_PEER_DELETE_VERSION: usize = 13;
+const EPOCH_PEER_DESCRIPTION_VERSION: usize = 14;
 
-pub const CURRENT_VERSION: usize = ENDPOINT_CANDIDATES_VERSION;
+pub const CURRENT_VERSION: usize = EPOCH_PEER_DESCRIPTION_VERSION;
 
 pub fn auto_migrate(conn: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
     let old_version: usize = conn.pragma_query_value(None, "user_version", |r| r.get(0))?;
@@ -27,6 +47,68 @@ pub fn auto_migrate(conn: &rusqlite::Connection) -> Result<(), rusqlite::Error>
         conn.execute("ALTER TABLE peers ADD COLUMN candidates TEXT", params![])?;
     }
 
+    if old_version < CIDR_DEFAULT_KEEPALIVE_VERSION {
+        conn.execute(
+            "ALTER TABLE cidrs ADD COLUMN default_keepalive INTEGER",
+            params![],
+        )?;
+    }
+
+    if old_version < AUDIT_LOG_VERSION {
+        conn.execute_batch(audit::CREATE_TABLE_SQL)?;
+    }
+
+    if old_version < VIEWER_ROLE_VERSION {
+        conn.execute(
+            "ALTER TABLE peers ADD COLUMN is_viewer INTEGER DEFAULT 0 NOT NULL",
+            params![],
+        )?;
+    }
+
+    if old_version < NETWORK_EPOCH_VERSION {
+        conn.execute_batch(epoch::CREATE_TABLE_SQL)?;
+    }
+
+    if old_version < KEY_ROTATIONS_VERSION {
+        conn.execute(key_rotation::CREATE_TABLE_SQL, params![])?;
+    }
+
+    if old_version < CIDR_DISABLED_VERSION {
+        conn.execute(
+            "ALTER TABLE cidrs ADD COLUMN is_disabled INTEGER DEFAULT 0 NOT NULL",
+            params![],
+        )?;
+    }
+
+    if old_version < PEER_TOMBSTONE_VERSION {
+        conn.execute("ALTER TABLE peers ADD COLUMN deleted_at INTEGER", params![])?;
+    }
+
+    if old_version < PEER_DESCRIPTION_VERSION {
+        conn.execute("ALTER TABLE peers ADD COLUMN description TEXT", params![])?;
+    }
+
+    if old_version < PEER_LIMITS_VERSION {
+        conn.execute(peer_limit::CREATE_TABLE_SQL, params![])?;
+    }
+
+    if old_version < KEY_ROTATION_SIGNATURE_VERSION {
+        // Clients only follow signed rotations, so the unsigned ones recorded before are of no
+        // use to anyone.
+        conn.execute("DROP TABLE IF EXISTS key_rotations", params![])?;
+        conn.execute(key_rotation::CREATE_TABLE_SQL, params![])?;
+    }
+
+    if old_version < EPOCH_PEER_DELETE_VERSION {
+        // Soft-deleting a peer only sets `deleted_at`, which the old trigger didn't count.
+        conn.execute_batch(epoch::PEER_UPDATE_TRIGGER_SQL)?;
+    }
+
+    if old_version < EPOCH_PEER_DESCRIPTION_VERSION {
+        // Clients show peers' descriptions, so changing one has to bump the epoch too.
+        conn.execute_batch(epoch::PEER_UPDATE_TRIGGER_SQL)?;
+    }
+
     if old_version != CURRENT_VERSION {
         conn.pragma_update(None, "user_version", CURRENT_VERSION)?;
         log::info!(
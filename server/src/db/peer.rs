@@ -1,9 +1,11 @@
-use super::DatabaseCidr;
+use super::{DatabaseCidr, KeyRotations, PeerLimits};
 use crate::ServerError;
 use once_cell::sync::Lazy;
 use regex::Regex;
 use rusqlite::{params, types::Type, Connection};
-use shared::{IpNetExt, Peer, PeerContents, PERSISTENT_KEEPALIVE_INTERVAL_SECS};
+use shared::{
+    persistent_keepalive_interval, IpNetExt, Peer, PeerContents, MAX_PEER_DESCRIPTION_LEN,
+};
 use std::{
     net::IpAddr,
     ops::{Deref, DerefMut},
@@ -22,6 +24,9 @@ pub static CREATE_TABLE_SQL: &str = "CREATE TABLE peers (
       is_redeemed     INTEGER DEFAULT 0 NOT NULL,   /* Has the peer redeemed their invite yet?                          */
       invite_expires  INTEGER,                      /* The UNIX time that an invited peer can no longer redeem.         */
       candidates      TEXT,                         /* A list of additional endpoints that peers can use to connect.    */
+      is_viewer       INTEGER DEFAULT 0 NOT NULL,   /* Read-only admin capabilities.                                    */
+      deleted_at      INTEGER,                      /* The UNIX time the peer was deleted, kept as a tombstone until purged. */
+      description     TEXT,                         /* A free-form note about the peer, settable by the peer itself.    */
       FOREIGN KEY (cidr_id)
          REFERENCES cidrs (id)
             ON UPDATE RESTRICT
@@ -40,8 +45,27 @@ pub static COLUMNS: &[&str] = &[
     "is_redeemed",
     "invite_expires",
     "candidates",
+    "is_viewer",
+    "description",
 ];
 
+/// The innernet server's own peer, which is the first one created along with the network.
+pub const SERVER_PEER_ID: i64 = 1;
+
+/// Peers inherit their persistent keepalive interval from their CIDR, so it's selected
+/// alongside the peer's own columns.
+static CIDR_KEEPALIVE_COLUMN: &str =
+    "(SELECT default_keepalive FROM cidrs WHERE cidrs.id = peers.cidr_id)";
+
+fn select_columns() -> String {
+    COLUMNS
+        .iter()
+        .map(|col| format!("peers.{col}"))
+        .chain(std::iter::once(CIDR_KEEPALIVE_COLUMN.to_string()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 /// Regex to match the requirements of hostname(7), needed to have peers also be reachable hostnames.
 /// Note that the full length also must be maximum 63 characters, which this regex does not check.
 static PEER_NAME_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^([a-z0-9]-?)*[a-z0-9]$").unwrap());
@@ -84,6 +108,8 @@ impl DatabasePeer {
             is_redeemed,
             invite_expires,
             candidates,
+            is_viewer,
+            description,
             ..
         } = &contents;
         log::info!("creating peer {:?}", contents);
@@ -92,6 +118,10 @@ impl DatabasePeer {
             log::warn!("peer name is invalid, must conform to hostname(7) requirements.");
             return Err(ServerError::InvalidQuery);
         }
+        if !Self::is_valid_description(description.as_deref()) {
+            log::warn!("peer description is too long or contains control characters.");
+            return Err(ServerError::InvalidQuery);
+        }
 
         let cidr = DatabaseCidr::get(conn, *cidr_id)?;
         if !cidr.cidr.contains(ip) {
@@ -107,6 +137,21 @@ impl DatabasePeer {
             return Err(ServerError::InvalidQuery);
         }
 
+        shared::ensure_ip_available(Self::list(conn)?.iter().map(|peer| &peer.inner), *ip)
+            .map_err(|e| ServerError::Conflict(e.to_string()))?;
+        // Deleted peers keep their IP until purged, so a fresh peer can't be mistaken for one that
+        // was only just removed.
+        if let Some(deleted) = Self::list_deleted(conn)?
+            .into_iter()
+            .find(|peer| peer.ip == *ip || peer.name == *name)
+        {
+            return Err(ServerError::Conflict(format!(
+                "{} ({}) belongs to a deleted peer, purge it first",
+                deleted.name, deleted.ip
+            )));
+        }
+        PeerLimits::check(conn, *cidr_id)?;
+
         let invite_expires = invite_expires
             .map(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
             .flatten()
@@ -116,7 +161,7 @@ impl DatabasePeer {
 
         conn.execute(
             &format!(
-                "INSERT INTO peers ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                "INSERT INTO peers ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
                 COLUMNS[1..].join(", ")
             ),
             params![
@@ -130,6 +175,8 @@ impl DatabasePeer {
                 is_redeemed,
                 invite_expires,
                 candidates,
+                is_viewer,
+                description,
             ],
         )?;
         let id = conn.last_insert_rowid();
@@ -140,12 +187,23 @@ impl DatabasePeer {
         name.len() < 64 && PEER_NAME_REGEX.is_match(name)
     }
 
+    fn is_valid_description(description: Option<&str>) -> bool {
+        description.is_none_or(|description| {
+            description.chars().count() <= MAX_PEER_DESCRIPTION_LEN
+                && !description.chars().any(char::is_control)
+        })
+    }
+
     /// Update self with new contents, validating them and updating the backend in the process.
     pub fn update(&mut self, conn: &Connection, contents: PeerContents) -> Result<(), ServerError> {
         if !Self::is_valid_name(&contents.name) {
             log::warn!("peer name is invalid, must conform to hostname(7) requirements.");
             return Err(ServerError::InvalidQuery);
         }
+        if !Self::is_valid_description(contents.description.as_deref()) {
+            log::warn!("peer description is too long or contains control characters.");
+            return Err(ServerError::InvalidQuery);
+        }
 
         // We will only allow updates of certain fields at this point, disregarding any requests
         // for changes of IP address, public key, or parent CIDR, for security reasons.
@@ -156,8 +214,16 @@ impl DatabasePeer {
             name: contents.name,
             endpoint: contents.endpoint,
             is_admin: contents.is_admin,
+            is_viewer: contents.is_viewer,
             is_disabled: contents.is_disabled,
             candidates: contents.candidates,
+            // Clients that predate descriptions don't send one, so a missing description is left
+            // as is and an empty one clears it.
+            description: match contents.description {
+                Some(description) if description.is_empty() => None,
+                Some(description) => Some(description),
+                None => self.contents.description.clone(),
+            },
             ..self.contents.clone()
         };
 
@@ -168,7 +234,9 @@ impl DatabasePeer {
                 endpoint = ?3,
                 is_admin = ?4,
                 is_disabled = ?5,
-                candidates = ?6
+                candidates = ?6,
+                is_viewer = ?7,
+                description = ?8
             WHERE id = ?1",
             params![
                 self.id,
@@ -180,6 +248,8 @@ impl DatabasePeer {
                 new_contents.is_admin,
                 new_contents.is_disabled,
                 new_candidates,
+                new_contents.is_viewer,
+                new_contents.description,
             ],
         )?;
 
@@ -197,6 +267,31 @@ impl DatabasePeer {
         }
     }
 
+    /// Tombstone the peer, keeping its row (and so its name and IP) around until it's purged.
+    pub fn delete(conn: &Connection, id: i64) -> Result<(), ServerError> {
+        match conn.execute(
+            "UPDATE peers SET deleted_at = ?2 WHERE id = ?1 AND deleted_at IS NULL",
+            params![id, unix_now()],
+        )? {
+            0 => Err(ServerError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    /// Permanently remove a peer that was already deleted, returning what it was.
+    pub fn purge(conn: &Connection, id: i64) -> Result<Self, ServerError> {
+        let peer = conn.query_row(
+            &format!(
+                "SELECT {} FROM peers WHERE id = ?1 AND deleted_at IS NOT NULL",
+                select_columns()
+            ),
+            params![id],
+            Self::from_row,
+        )?;
+        conn.execute("DELETE FROM peers WHERE id = ?1", params![id])?;
+        Ok(peer)
+    }
+
     pub fn redeem(&mut self, conn: &Connection, pubkey: &str) -> Result<(), ServerError> {
         if self.is_redeemed {
             return Err(ServerError::Gone);
@@ -219,6 +314,34 @@ impl DatabasePeer {
         }
     }
 
+    /// Replace the public key of an already-redeemed peer when its keypair is rotated, recording
+    /// the rotation (with the old key's `signature` over it, from `KeyRotation::sign`) so other
+    /// peers accept the new key in place of the one they pinned. Setting the key the peer
+    /// already has is a no-op.
+    ///
+    /// Both happen in separate statements, so this should be called within a transaction.
+    pub fn set_public_key(
+        &mut self,
+        conn: &Connection,
+        pubkey: &str,
+        signature: &str,
+    ) -> Result<(), ServerError> {
+        if self.contents.public_key == pubkey {
+            return Ok(());
+        }
+        match conn.execute(
+            "UPDATE peers SET public_key = ?1 WHERE id = ?2",
+            params![pubkey, self.id],
+        )? {
+            0 => Err(ServerError::NotFound),
+            _ => {
+                KeyRotations::record(conn, self.id, &self.contents.public_key, pubkey, signature)?;
+                self.contents.public_key = pubkey.into();
+                Ok(())
+            },
+        }
+    }
+
     fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
         let id = row.get(0)?;
         let name = row
@@ -249,7 +372,9 @@ impl DatabasePeer {
             vec![]
         };
 
-        let persistent_keepalive_interval = Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS);
+        let is_viewer = row.get(11)?;
+        let description = row.get(12)?;
+        let persistent_keepalive_interval = persistent_keepalive_interval(row.get(13)?);
 
         Ok(Peer {
             id,
@@ -261,10 +386,12 @@ impl DatabasePeer {
                 endpoint,
                 persistent_keepalive_interval,
                 is_admin,
+                is_viewer,
                 is_disabled,
                 is_redeemed,
                 invite_expires,
                 candidates,
+                description,
             },
         }
         .into())
@@ -272,7 +399,10 @@ impl DatabasePeer {
 
     pub fn get(conn: &Connection, id: i64) -> Result<Self, ServerError> {
         let result = conn.query_row(
-            &format!("SELECT {} FROM peers WHERE id = ?1", COLUMNS.join(", ")),
+            &format!(
+                "SELECT {} FROM peers WHERE id = ?1 AND deleted_at IS NULL",
+                select_columns()
+            ),
             params![id],
             Self::from_row,
         )?;
@@ -282,7 +412,10 @@ impl DatabasePeer {
 
     pub fn get_from_ip(conn: &Connection, ip: IpAddr) -> Result<Self, rusqlite::Error> {
         let result = conn.query_row(
-            &format!("SELECT {} FROM peers WHERE ip = ?1", COLUMNS.join(", ")),
+            &format!(
+                "SELECT {} FROM peers WHERE ip = ?1 AND deleted_at IS NULL",
+                select_columns()
+            ),
             params![ip.to_string()],
             Self::from_row,
         )?;
@@ -297,6 +430,7 @@ impl DatabasePeer {
         // 2. associated: Enumerate all auth associations between any of the above enumerated CIDRs.
         // 3. associated_subcidrs: For each association, list all peers by enumerating down each
         //    associated CIDR's children and listing any peers belonging to them.
+        // 4. disabled: Enumerate all disabled CIDRs and their children, whose peers are left out.
         //
         // NOTE that a forced association is created with the special "infra" CIDR with id 2 (1 being the root).
         let mut stmt = conn.prepare_cached(
@@ -317,12 +451,18 @@ impl DatabasePeer {
                     SELECT cidr_id FROM associated
                     UNION
                     SELECT id FROM cidrs, associated_subcidrs WHERE cidrs.parent=associated_subcidrs.cidr_id
+                ),
+                disabled(id) AS (
+                    SELECT id FROM cidrs WHERE is_disabled = 1
+                    UNION
+                    SELECT cidrs.id FROM cidrs JOIN disabled ON cidrs.parent = disabled.id
                 )
                 SELECT DISTINCT {}
                 FROM peers
                 JOIN associated_subcidrs ON peers.cidr_id=associated_subcidrs.cidr_id
-                WHERE peers.is_disabled = 0 AND peers.is_redeemed = 1;",
-                COLUMNS.iter().map(|col| format!("peers.{col}")).collect::<Vec<_>>().join(", ")
+                WHERE peers.is_disabled = 0 AND peers.is_redeemed = 1 AND peers.deleted_at IS NULL
+                  AND peers.cidr_id NOT IN (SELECT id FROM disabled);",
+                select_columns()
             ),
         )?;
         let peers = stmt
@@ -331,23 +471,53 @@ impl DatabasePeer {
         Ok(peers)
     }
 
+    /// List all peers that haven't been deleted.
     pub fn list(conn: &Connection) -> Result<Vec<Self>, ServerError> {
-        let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM peers", COLUMNS.join(", ")))?;
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {} FROM peers WHERE deleted_at IS NULL",
+            select_columns()
+        ))?;
+        let peer_iter = stmt.query_map(params![], Self::from_row)?;
+
+        Ok(peer_iter.collect::<Result<_, _>>()?)
+    }
+
+    /// List the tombstones of deleted peers that haven't been purged yet.
+    pub fn list_deleted(conn: &Connection) -> Result<Vec<Self>, ServerError> {
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {} FROM peers WHERE deleted_at IS NOT NULL",
+            select_columns()
+        ))?;
         let peer_iter = stmt.query_map(params![], Self::from_row)?;
 
         Ok(peer_iter.collect::<Result<_, _>>()?)
     }
 
+    /// List every peer, including the tombstones of deleted ones, which still hold on to their
+    /// names and IPs.
+    pub fn list_including_deleted(conn: &Connection) -> Result<Vec<Self>, ServerError> {
+        let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM peers", select_columns()))?;
+        let peer_iter = stmt.query_map(params![], Self::from_row)?;
+
+        Ok(peer_iter.collect::<Result<_, _>>()?)
+    }
+
+    /// Delete the invitations that expired without being redeemed. Nobody ever used them, so
+    /// they're removed outright rather than left as tombstones.
     pub fn delete_expired_invites(conn: &Connection) -> Result<usize, ServerError> {
-        let unix_now = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .expect("Something is horribly wrong with system time.");
+        let unix_now = unix_now();
         let deleted = conn.execute(
-            "DELETE FROM peers
-            WHERE is_redeemed = 0 AND invite_expires < ?1",
-            params![unix_now.as_secs()],
+            "DELETE FROM peers WHERE is_redeemed = 0 AND invite_expires < ?1",
+            params![unix_now],
         )?;
 
         Ok(deleted)
     }
 }
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Something is horribly wrong with system time.")
+        .as_secs()
+}
@@ -0,0 +1,90 @@
+//! Optional caps on how many peers a CIDR can hold, to keep runaway automation from filling up
+//! the network. A CIDR's limit counts the peers in the CIDRs nested in it too, so a limit on
+//! the root CIDR caps the whole network.
+
+use super::{DatabaseCidr, DatabasePeer};
+use crate::ServerError;
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+
+pub static CREATE_TABLE_SQL: &str = "CREATE TABLE peer_limits (
+      cidr_id    INTEGER PRIMARY KEY,  /* The CIDR whose peers (including nested ones) are limited. */
+      max_peers  INTEGER NOT NULL,     /* The most peers the CIDR may hold.                         */
+      FOREIGN KEY (cidr_id)
+        REFERENCES cidrs (id)
+          ON UPDATE RESTRICT
+          ON DELETE CASCADE
+    )";
+
+pub struct PeerLimits;
+
+impl PeerLimits {
+    /// Set the limit of the CIDR with `cidr_id`, or remove it if `max_peers` is `None`.
+    pub fn set(conn: &Connection, cidr_id: i64, max_peers: Option<u32>) -> Result<(), ServerError> {
+        match max_peers {
+            Some(max_peers) => conn.execute(
+                "INSERT INTO peer_limits (cidr_id, max_peers) VALUES (?1, ?2)
+                  ON CONFLICT (cidr_id) DO UPDATE SET max_peers = excluded.max_peers",
+                params![cidr_id, max_peers],
+            )?,
+            None => conn.execute(
+                "DELETE FROM peer_limits WHERE cidr_id = ?1",
+                params![cidr_id],
+            )?,
+        };
+        Ok(())
+    }
+
+    /// The limits of every CIDR that has one, by CIDR ID.
+    pub fn list(conn: &Connection) -> Result<HashMap<i64, u32>, ServerError> {
+        let mut stmt = conn.prepare_cached("SELECT cidr_id, max_peers FROM peer_limits")?;
+        let limits = stmt
+            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        Ok(limits)
+    }
+
+    /// Check that a new peer fits in the CIDR with `cidr_id` without going over the limit of it
+    /// or any of the CIDRs it's nested in. Deleted peers no longer count, disabled ones do.
+    pub fn check(conn: &Connection, cidr_id: i64) -> Result<(), ServerError> {
+        let limits = Self::list(conn)?;
+        if limits.is_empty() {
+            return Ok(());
+        }
+        let cidrs = DatabaseCidr::list(conn)?;
+        let parents: HashMap<_, _> = cidrs.iter().map(|cidr| (cidr.id, cidr.parent)).collect();
+        let ancestry = |mut id: i64| {
+            let mut ancestry = vec![id];
+            while let Some(Some(parent)) = parents.get(&id) {
+                if ancestry.contains(parent) {
+                    break;
+                }
+                ancestry.push(*parent);
+                id = *parent;
+            }
+            ancestry
+        };
+
+        let peers = DatabasePeer::list(conn)?;
+        for limited in ancestry(cidr_id) {
+            let Some(&max_peers) = limits.get(&limited) else {
+                continue;
+            };
+            let count = peers
+                .iter()
+                .filter(|peer| ancestry(peer.cidr_id).contains(&limited))
+                .count();
+            if count >= max_peers as usize {
+                let name = cidrs
+                    .iter()
+                    .find(|cidr| cidr.id == limited)
+                    .map(|cidr| cidr.name.as_str())
+                    .unwrap_or_default();
+                return Err(ServerError::Conflict(format!(
+                    "CIDR {name} has reached its limit of {max_peers} peers"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
@@ -8,20 +8,29 @@ pub enum ServerError {
     #[error("unauthorized access")]
     Unauthorized,
 
+    #[error("forbidden")]
+    Forbidden,
+
     #[error("object not found")]
     NotFound,
 
     #[error("invalid query")]
     InvalidQuery,
 
+    #[error("invalid CIDR: {0}")]
+    InvalidCidr(String),
+
     #[error("endpoint gone")]
     Gone,
 
+    #[error("conflict: {0}")]
+    Conflict(String),
+
     #[error("internal database error")]
     Database(#[from] rusqlite::Error),
 
-    #[error("internal WireGuard error")]
-    WireGuard,
+    #[error("internal WireGuard error: {0}")]
+    WireGuard(#[source] std::io::Error),
 
     #[error("internal I/O error")]
     Io(#[from] std::io::Error),
@@ -36,14 +45,24 @@ pub enum ServerError {
     Hyper(#[from] hyper::Error),
 }
 
+impl ServerError {
+    /// A stored WireGuard key that doesn't parse, which is just as much of an internal problem
+    /// as the interface itself failing.
+    pub fn invalid_key(e: wireguard_control::InvalidKey) -> Self {
+        Self::WireGuard(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+}
+
 impl<'a> From<&'a ServerError> for StatusCode {
     fn from(error: &ServerError) -> StatusCode {
         use ServerError::*;
         match error {
             Unauthorized => StatusCode::UNAUTHORIZED,
+            Forbidden => StatusCode::FORBIDDEN,
             NotFound => StatusCode::NOT_FOUND,
             Gone => StatusCode::GONE,
-            InvalidQuery | Json(_) => StatusCode::BAD_REQUEST,
+            Conflict(_) => StatusCode::CONFLICT,
+            InvalidQuery | InvalidCidr(_) | Json(_) => StatusCode::BAD_REQUEST,
             // Special-case the constraint violation situation.
             Database(rusqlite::Error::SqliteFailure(libsqlite3_sys::Error { code, .. }, ..))
                 if *code == libsqlite3_sys::ErrorCode::ConstraintViolation =>
@@ -51,7 +70,7 @@ impl<'a> From<&'a ServerError> for StatusCode {
                 StatusCode::BAD_REQUEST
             },
             Database(rusqlite::Error::QueryReturnedNoRows) => StatusCode::NOT_FOUND,
-            WireGuard | Io(_) | Database(_) | Http(_) | Hyper(_) => {
+            WireGuard(_) | Io(_) | Database(_) | Http(_) | Hyper(_) => {
                 StatusCode::INTERNAL_SERVER_ERROR
             },
         }
@@ -62,8 +81,14 @@ impl TryFrom<ServerError> for Response<Body> {
     type Error = http::Error;
 
     fn try_from(e: ServerError) -> Result<Self, Self::Error> {
-        Response::builder()
-            .status(StatusCode::from(&e))
-            .body(Body::empty())
+        let body = match &e {
+            ServerError::InvalidCidr(_) | ServerError::Conflict(_) => Body::from(e.to_string()),
+            ServerError::WireGuard(_) => {
+                log::error!("{}", e);
+                Body::empty()
+            },
+            _ => Body::empty(),
+        };
+        Response::builder().status(StatusCode::from(&e)).body(body)
     }
 }
@@ -10,11 +10,14 @@ use indoc::printdoc;
 use ipnet::IpNet;
 use publicip::Preference;
 use rusqlite::{params, Connection};
-use shared::{
-    prompts, CidrContents, Endpoint, IpNetExt, PeerContents, PERSISTENT_KEEPALIVE_INTERVAL_SECS,
+use serde::Deserialize;
+use shared::{prompts, CidrContents, Endpoint, IpNetExt, PeerContents};
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
 };
-use std::net::{IpAddr, SocketAddr};
-use wireguard_control::KeyPair;
+use wireguard_control::{Key, KeyPair};
 
 fn create_database<P: AsRef<Path>>(
     database_path: P,
@@ -24,6 +27,11 @@ fn create_database<P: AsRef<Path>>(
     conn.execute(db::peer::CREATE_TABLE_SQL, params![])?;
     conn.execute(db::association::CREATE_TABLE_SQL, params![])?;
     conn.execute(db::cidr::CREATE_TABLE_SQL, params![])?;
+    conn.execute_batch(db::audit::CREATE_TABLE_SQL)?;
+    conn.execute_batch(db::epoch::CREATE_TABLE_SQL)?;
+    conn.execute_batch(db::epoch::PEER_UPDATE_TRIGGER_SQL)?;
+    conn.execute(db::key_rotation::CREATE_TABLE_SQL, params![])?;
+    conn.execute(db::peer_limit::CREATE_TABLE_SQL, params![])?;
     conn.pragma_update(None, "user_version", db::CURRENT_VERSION)?;
     log::debug!("set database version to db::CURRENT_VERSION");
 
@@ -48,9 +56,79 @@ pub struct InitializeOpts {
     #[clap(long = "auto-external-endpoint")]
     pub auto_external_endpoint: bool,
 
+    /// Another external endpoint this server can be reached at, tried by peers if the main one
+    /// doesn't answer. Can be given more than once
+    #[clap(long = "alternate-endpoint", value_name = "ENDPOINT")]
+    pub alternate_endpoints: Vec<Endpoint>,
+
     /// Port to listen on (for the WireGuard interface)
     #[clap(long)]
     pub listen_port: Option<u16>,
+
+    /// Generate a WireGuard preshared key that every peer in the network uses with every other
+    /// peer, and hand it out in invitations
+    #[clap(long)]
+    pub preshared_key: bool,
+
+    /// Read the network settings from a TOML file instead of prompting for them. Flags given
+    /// on the command line take precedence over the file
+    #[clap(long, value_name = "PATH")]
+    pub from_config: Option<PathBuf>,
+}
+
+/// The settings a `new --from-config` file supplies, named like their flags.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct InitializeConfig {
+    network_name: String,
+    network_cidr: IpNet,
+    external_endpoint: Option<Endpoint>,
+    #[serde(default)]
+    auto_external_endpoint: bool,
+    #[serde(default)]
+    alternate_endpoints: Vec<Endpoint>,
+    listen_port: Option<u16>,
+    #[serde(default)]
+    preshared_key: bool,
+}
+
+impl InitializeOpts {
+    /// Fill in anything not given as a flag from the `--from-config` file, if there is one, so
+    /// that nothing is left to prompt for.
+    fn with_config_file(mut self) -> Result<Self, Error> {
+        let Some(path) = &self.from_config else {
+            return Ok(self);
+        };
+        let contents = fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read {}: {}", path.display(), e))?;
+        let config: InitializeConfig = toml::from_str(&contents)
+            .map_err(|e| anyhow!("invalid config file {}: {}", path.display(), e))?;
+
+        if self.network_name.is_none() {
+            let name = config
+                .network_name
+                .parse()
+                .map_err(|e| anyhow!("invalid network-name {:?}: {}", config.network_name, e))?;
+            self.network_name = Some(name);
+        }
+        self.network_cidr = self.network_cidr.or(Some(config.network_cidr));
+        self.listen_port = self.listen_port.or(config.listen_port).or(Some(51820));
+        if self.external_endpoint.is_none() && !self.auto_external_endpoint {
+            self.external_endpoint = config.external_endpoint;
+            self.auto_external_endpoint = config.auto_external_endpoint;
+        }
+        if self.external_endpoint.is_none() && !self.auto_external_endpoint {
+            return Err(anyhow!(
+                "{} must set external-endpoint or auto-external-endpoint",
+                path.display()
+            ));
+        }
+        if self.alternate_endpoints.is_empty() {
+            self.alternate_endpoints = config.alternate_endpoints;
+        }
+        self.preshared_key |= config.preshared_key;
+        Ok(self)
+    }
 }
 
 struct DbInitData {
@@ -71,6 +149,8 @@ fn populate_database(conn: &Connection, db_init_data: DbInitData) -> Result<(),
             name: db_init_data.network_name.clone(),
             cidr: db_init_data.network_cidr,
             parent: None,
+            default_keepalive: None,
+            is_disabled: false,
         },
     )
     .map_err(|_| anyhow!("failed to create root CIDR"))?;
@@ -81,25 +161,24 @@ fn populate_database(conn: &Connection, db_init_data: DbInitData) -> Result<(),
             name: SERVER_NAME.into(),
             cidr: db_init_data.server_cidr,
             parent: Some(root_cidr.id),
+            default_keepalive: None,
+            is_disabled: false,
         },
     )
     .map_err(|_| anyhow!("failed to create innernet-server CIDR"))?;
 
     let _me = DatabasePeer::create(
         conn,
-        PeerContents {
-            name: SERVER_NAME.parse().map_err(|e: &str| anyhow!(e))?,
-            ip: db_init_data.our_ip,
-            cidr_id: server_cidr.id,
-            public_key: db_init_data.public_key_base64,
-            endpoint: Some(db_init_data.endpoint),
-            is_admin: true,
-            is_disabled: false,
-            is_redeemed: true,
-            persistent_keepalive_interval: Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
-            invite_expires: None,
-            candidates: vec![],
-        },
+        PeerContents::builder(
+            SERVER_NAME.parse().map_err(|e: &str| anyhow!(e))?,
+            db_init_data.our_ip,
+            server_cidr.id,
+            db_init_data.public_key_base64,
+        )
+        .set_endpoint(db_init_data.endpoint)
+        .set_admin(true)
+        .set_redeemed(true)
+        .build(),
     )
     .map_err(|_| anyhow!("failed to create innernet peer."))?;
 
@@ -107,7 +186,17 @@ fn populate_database(conn: &Connection, db_init_data: DbInitData) -> Result<(),
 }
 
 pub fn init_wizard(conf: &ServerConfig, opts: InitializeOpts) -> Result<(), Error> {
+    let opts = opts.with_config_file()?;
     let theme = ColorfulTheme::default();
+    prompts::ensure_flags(&[
+        ("--network-name", opts.network_name.is_some()),
+        ("--network-cidr", opts.network_cidr.is_some()),
+        ("--listen-port", opts.listen_port.is_some()),
+        (
+            "--external-endpoint or --auto-external-endpoint",
+            opts.external_endpoint.is_some() || opts.auto_external_endpoint,
+        ),
+    ])?;
 
     shared::ensure_dirs_exist(&[conf.config_dir(), conf.database_dir()]).map_err(|_| {
         anyhow!(
@@ -115,8 +204,9 @@ pub fn init_wizard(conf: &ServerConfig, opts: InitializeOpts) -> Result<(), Erro
             "(are you not running as root?)".bold()
         )
     })?;
-    printdoc!(
-        "\nTime to setup your innernet network.
+    if opts.from_config.is_none() {
+        printdoc!(
+            "\nTime to setup your innernet network.
 
         Your network name can be any hostname-valid string, i.e. \"evilcorp\", and
         your network CIDR should be in the RFC1918 IPv4 (10/8, 172.16/12, or 192.168/16), 
@@ -128,7 +218,8 @@ pub fn init_wizard(conf: &ServerConfig, opts: InitializeOpts) -> Result<(), Erro
 
         For more usage instructions, see https://github.com/tonarino/innernet#usage
         \n"
-    );
+        );
+    }
 
     let name: Interface = if let Some(name) = opts.network_name {
         name
@@ -137,6 +228,7 @@ pub fn init_wizard(conf: &ServerConfig, opts: InitializeOpts) -> Result<(), Erro
             .with_prompt("Network name")
             .interact()?
     };
+    name.check_reserved()?;
 
     let root_cidr: IpNet = if let Some(cidr) = opts.network_cidr {
         cidr
@@ -159,14 +251,19 @@ pub fn init_wizard(conf: &ServerConfig, opts: InitializeOpts) -> Result<(), Erro
 
     log::info!("listen port: {}", listen_port);
 
+    // An IPv6 network is most likely also reached over IPv6, so prefer a public IPv6 address.
+    let preference = match root_cidr {
+        IpNet::V4(_) => Preference::Ipv4,
+        IpNet::V6(_) => Preference::Ipv6,
+    };
     let endpoint: Endpoint = if let Some(endpoint) = opts.external_endpoint {
         endpoint
     } else if opts.auto_external_endpoint {
-        let ip = publicip::get_any(Preference::Ipv4)
-            .ok_or_else(|| anyhow!("couldn't get external IP"))?;
+        let ip =
+            publicip::get_any(preference).ok_or_else(|| anyhow!("couldn't get external IP"))?;
         SocketAddr::new(ip, listen_port).into()
     } else {
-        prompts::ask_endpoint(listen_port)?
+        prompts::ask_endpoint(listen_port, preference)?
     };
 
     let our_ip = root_cidr
@@ -181,6 +278,11 @@ pub fn init_wizard(conf: &ServerConfig, opts: InitializeOpts) -> Result<(), Erro
         listen_port,
         address: our_ip,
         network_cidr_prefix: root_cidr.prefix_len(),
+        join_token_hash: None,
+        preshared_key: opts
+            .preshared_key
+            .then(|| Key::generate_preshared().to_base64()),
+        alternate_endpoints: opts.alternate_endpoints,
     };
     config.write_to_path(config_path)?;
 
@@ -241,3 +343,47 @@ pub fn init_wizard(conf: &ServerConfig, opts: InitializeOpts) -> Result<(), Erro
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_config_file() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("new.toml");
+        fs::write(
+            &path,
+            "network-name = \"evilcorp\"\n\
+             network-cidr = \"10.42.0.0/16\"\n\
+             external-endpoint = \"100.100.100.100:51820\"\n\
+             alternate-endpoints = [\"200.200.200.200:51820\"]\n",
+        )?;
+
+        let opts = InitializeOpts {
+            listen_port: Some(51821),
+            from_config: Some(path.clone()),
+            ..Default::default()
+        }
+        .with_config_file()?;
+        assert_eq!(opts.network_name.unwrap().to_string(), "evilcorp");
+        assert_eq!(opts.network_cidr, Some("10.42.0.0/16".parse()?));
+        assert_eq!(opts.listen_port, Some(51821));
+        assert!(opts.external_endpoint.is_some());
+        assert_eq!(
+            opts.alternate_endpoints,
+            vec!["200.200.200.200:51820".parse::<Endpoint>().unwrap()]
+        );
+
+        fs::write(
+            &path,
+            "network-name = \"evilcorp\"\nnetwork-cidr = \"10.42.0.0/16\"\n",
+        )?;
+        let opts = InitializeOpts {
+            from_config: Some(path),
+            ..Default::default()
+        };
+        assert!(opts.with_config_file().is_err());
+        Ok(())
+    }
+}
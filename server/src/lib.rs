@@ -1,16 +1,18 @@
 use anyhow::{anyhow, bail};
+use clap::{Args, ValueEnum};
 use colored::*;
 use dialoguer::Confirm;
-use hyper::{http, server::conn::AddrStream, Body, Request, Response};
+use hyper::{http, server::conn::AddrStream, Body, Method, Request, Response, StatusCode};
 use indoc::printdoc;
 use ipnet::IpNet;
 use parking_lot::{Mutex, RwLock};
 use rusqlite::Connection;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use shared::{
-    get_local_addrs, AddCidrOpts, AddPeerOpts, DeleteCidrOpts, EnableDisablePeerOpts, Endpoint,
-    IoErrorContext, NetworkOpts, PeerContents, RenameCidrOpts, RenamePeerOpts,
-    INNERNET_PUBKEY_HEADER,
+    get_local_addrs, AddCidrOpts, AddPeerOpts, DeleteCidrOpts, EnableDisableCidrOpts,
+    EnableDisablePeerOpts, Endpoint, IoErrorContext, NetworkOpts, Peer, PeerContents,
+    RenameCidrOpts, RenamePeerOpts, INNERNET_PUBKEY_HEADER,
 };
 use std::{
     collections::{HashMap, VecDeque},
@@ -19,25 +21,31 @@ use std::{
     fs::File,
     io::prelude::*,
     net::{IpAddr, SocketAddr, TcpListener},
-    ops::Deref,
     path::{Path, PathBuf},
     sync::Arc,
     time::Duration,
 };
 use subtle::ConstantTimeEq;
-use wireguard_control::{Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder};
+use wireguard_control::{
+    Backend, Device, DeviceUpdate, InterfaceName, Key, KeyPair, PeerConfigBuilder,
+};
 
 mod api;
 mod db;
 mod error;
 pub mod initialize;
+mod manifest;
+pub mod metrics;
 #[cfg(test)]
 mod test;
 mod util;
 
 use db::{DatabaseCidr, DatabasePeer};
 pub use error::ServerError;
+use manifest::PeerManifest;
+use metrics::{Metrics, MetricsMode};
 use shared::{prompts, wg, CidrTree, Error, Interface};
+use util::status_response;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -51,6 +59,10 @@ pub struct Context {
     pub interface: InterfaceName,
     pub backend: Backend,
     pub public_key: Key,
+    pub join_token_hash: Option<String>,
+    pub preshared_key: Option<Key>,
+    pub disable_endpoint_injection: bool,
+    pub metrics: Arc<Metrics>,
 }
 
 pub struct Session {
@@ -63,6 +75,11 @@ impl Session {
         self.peer.is_admin && self.user_capable()
     }
 
+    /// Whether the peer may use the read-only parts of the admin API.
+    pub fn viewer_capable(&self) -> bool {
+        (self.peer.is_admin || self.peer.is_viewer) && self.user_capable()
+    }
+
     pub fn user_capable(&self) -> bool {
         !self.peer.is_disabled && self.peer.is_redeemed
     }
@@ -86,15 +103,28 @@ pub struct ConfigFile {
 
     /// The CIDR prefix of the WireGuard network
     pub network_cidr_prefix: u8,
+
+    /// The SHA-256 hash (hex) of the token required to redeem invitations, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub join_token_hash: Option<String>,
+
+    /// The WireGuard preshared key (base64) shared by every peer in the network, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preshared_key: Option<String>,
+
+    /// Other external endpoints the server can be reached at, advertised ahead of its local
+    /// addresses in its candidates
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alternate_endpoints: Vec<Endpoint>,
 }
 
 impl ConfigFile {
     pub fn write_to_path<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
-        let mut invitation_file = File::create(&path).with_path(&path)?;
-        shared::chmod(&invitation_file, 0o600)?;
-        invitation_file
-            .write_all(toml::to_string(self).unwrap().as_bytes())
-            .with_path(path)?;
+        let path = path.as_ref();
+        shared::write_atomic(path, 0o600, |file| {
+            file.write_all(toml::to_string(self).unwrap().as_bytes())
+        })
+        .with_path(path)?;
         Ok(())
     }
 
@@ -112,19 +142,131 @@ impl ConfigFile {
             &std::fs::read_to_string(path).with_path(path)?,
         )?)
     }
+
+    pub fn network_preshared_key(&self) -> Result<Option<Key>, Error> {
+        Ok(self
+            .preshared_key
+            .as_deref()
+            .map(Key::from_base64)
+            .transpose()?)
+    }
+}
+
+/// The WireGuard config for `peer`, including the network's preshared key if it has one.
+pub(crate) fn peer_config(peer: &Peer, preshared_key: Option<&Key>) -> PeerConfigBuilder {
+    let builder = PeerConfigBuilder::from(peer);
+    match preshared_key {
+        Some(key) => builder.set_preshared_key(key.clone()),
+        None => builder,
+    }
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct ServeOpts {
+    /// How often (in seconds) to delete expired invitations from the database. This bounds how
+    /// long an expired invitation can linger after its expiration time has passed.
+    #[clap(long, default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    pub invite_sweep_interval: u64,
+
+    /// How often (in seconds) to read peers' observed endpoints off the WireGuard interface.
+    /// This bounds how stale an endpoint handed out to other peers can be.
+    #[clap(long, default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    pub endpoint_refresh_interval: u64,
+
+    /// Leave the WireGuard interface up after the server shuts down
+    #[clap(long)]
+    pub keep_interface: bool,
+
+    /// Only hand out the endpoints peers were explicitly configured with, instead of the
+    /// endpoints observed on the WireGuard interface. Useful when every peer already has a
+    /// stable, routable endpoint.
+    #[clap(long)]
+    pub disable_endpoint_injection: bool,
+
+    /// An additional address to serve the health checks on, e.g. 127.0.0.1:51820 for a
+    /// co-located monitoring tool. Can be given multiple times. The API itself is only served
+    /// over the WireGuard interface, which is what vouches for a peer's IP.
+    #[clap(long = "management-listen")]
+    pub management_listen: Vec<SocketAddr>,
+
+    /// Serve Prometheus metrics on /metrics
+    #[clap(long, value_enum, default_value_t)]
+    pub metrics: MetricsMode,
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct AddPeersFromOpts {
+    /// Path to a TOML manifest of `[[peer]]` entries with a name, cidr, and optionally an ip,
+    /// admin, and invite_expires
+    pub manifest: PathBuf,
+
+    /// Directory to write each peer's invitation (`<name>.toml`) to
+    #[clap(long, default_value = ".")]
+    pub save_dir: PathBuf,
+
+    /// Bypass confirmation
+    #[clap(long)]
+    pub yes: bool,
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct SetPeerLimitOpts {
+    /// The most peers the CIDR may hold, counting those in CIDRs nested in it
+    #[clap(required_unless_present = "unset")]
+    pub max_peers: Option<u32>,
+
+    /// Name of the CIDR to limit, the root CIDR (and so the whole network) if omitted
+    #[clap(long)]
+    pub cidr: Option<String>,
+
+    /// Remove the CIDR's limit
+    #[clap(long, conflicts_with = "max_peers")]
+    pub unset: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum JournalMode {
+    /// Write-ahead logging, letting readers proceed while a write is in progress
+    #[default]
+    Wal,
+    /// SQLite's default rollback journal
+    Delete,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Synchronous {
+    /// Sync less often; in WAL mode the database still can't be corrupted, but the most
+    /// recent changes may be lost on power failure
+    #[default]
+    Normal,
+    /// Sync on every transaction
+    Full,
+}
+
+#[derive(Clone, Copy, Debug, Default, Args)]
+pub struct DatabaseOpts {
+    /// The SQLite journal mode of the network database
+    #[clap(long, value_enum, default_value = "wal")]
+    pub journal_mode: JournalMode,
+
+    /// How often SQLite syncs the network database to disk
+    #[clap(long, value_enum, default_value = "normal")]
+    pub synchronous: Synchronous,
 }
 
 #[derive(Clone, Debug)]
 pub struct ServerConfig {
     pub config_dir: PathBuf,
     pub data_dir: PathBuf,
+    pub database: DatabaseOpts,
 }
 
 impl ServerConfig {
-    pub fn new(config_dir: PathBuf, data_dir: PathBuf) -> Self {
+    pub fn new(config_dir: PathBuf, data_dir: PathBuf, database: DatabaseOpts) -> Self {
         Self {
             config_dir,
             data_dir,
+            database,
         }
     }
 
@@ -166,6 +308,19 @@ fn open_database_connection(
     let conn = Connection::open(&database_path)?;
     // Foreign key constraints aren't on in SQLite by default. Enable.
     conn.pragma_update(None, "foreign_keys", 1)?;
+    let journal_mode = match conf.database.journal_mode {
+        JournalMode::Wal => "WAL",
+        JournalMode::Delete => "DELETE",
+    };
+    conn.pragma_update_and_check(None, "journal_mode", journal_mode, |_| Ok(()))?;
+    let synchronous = match conf.database.synchronous {
+        Synchronous::Normal => "NORMAL",
+        Synchronous::Full => "FULL",
+    };
+    conn.pragma_update(None, "synchronous", synchronous)?;
+    // The server's background tasks use their own connections, so wait for each other's
+    // writes instead of failing with SQLITE_BUSY.
+    conn.busy_timeout(Duration::from_secs(5))?;
     db::auto_migrate(&conn)?;
     Ok(conn)
 }
@@ -178,12 +333,13 @@ pub fn add_peer(
 ) -> Result<(), Error> {
     let config = ConfigFile::from_file(conf.config_path(interface))?;
     let conn = open_database_connection(interface, conf)?;
-    let peers = DatabasePeer::list(&conn)?
+    // Deleted peers' names and IPs stay taken until they're purged.
+    let peers = DatabasePeer::list_including_deleted(&conn)?
         .into_iter()
         .map(|dp| dp.inner)
         .collect::<Vec<_>>();
     let cidrs = DatabaseCidr::list(&conn)?;
-    let cidr_tree = CidrTree::new(&cidrs[..]);
+    let cidr_tree = CidrTree::try_new(&cidrs[..])?;
 
     if let Some(result) = shared::prompts::add_peer(&peers, &cidr_tree, &opts)? {
         let (peer_request, keypair, target_path, mut target_file) = result;
@@ -191,23 +347,25 @@ pub fn add_peer(
         if cfg!(not(test)) && Device::get(interface, network.backend).is_ok() {
             // Update the current WireGuard interface with the new peers.
             DeviceUpdate::new()
-                .add_peer(PeerConfigBuilder::from(&*peer))
+                .add_peer(peer_config(&peer, config.network_preshared_key()?.as_ref()))
                 .apply(interface, network.backend)
-                .map_err(|_| ServerError::WireGuard)?;
+                .map_err(ServerError::WireGuard)?;
 
             println!("adding to WireGuard interface: {}", &*peer);
         }
 
         let server_peer = DatabasePeer::get(&conn, 1)?;
-        prompts::write_peer_invitation(
-            (&mut target_file, &target_path),
+        let invitation = prompts::peer_invitation(
             interface,
             &peer,
             &server_peer,
+            &config.alternate_endpoints,
             &cidr_tree,
             keypair,
             &SocketAddr::new(config.address, config.listen_port),
+            config.preshared_key.clone(),
         )?;
+        prompts::write_peer_invitation((&mut target_file, &target_path), &peer, invitation)?;
     } else {
         println!("exited without creating peer.");
     }
@@ -215,6 +373,111 @@ pub fn add_peer(
     Ok(())
 }
 
+/// Create every peer in a manifest, writing out their invitations. The whole manifest is
+/// validated up front, and the peers are created in a single transaction, so a bad entry
+/// doesn't leave the network half-provisioned.
+pub fn add_peers_from(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    opts: AddPeersFromOpts,
+    network: NetworkOpts,
+) -> Result<(), Error> {
+    let config = ConfigFile::from_file(conf.config_path(interface))?;
+    let mut conn = open_database_connection(interface, conf)?;
+    // Deleted peers' names and IPs stay taken until they're purged.
+    let peers = DatabasePeer::list_including_deleted(&conn)?
+        .into_iter()
+        .map(|dp| dp.inner)
+        .collect::<Vec<_>>();
+    let cidrs = DatabaseCidr::list(&conn)?;
+    let cidr_tree = CidrTree::try_new(&cidrs[..])?;
+    prompts::ensure_flags(&[("--yes", opts.yes)])?;
+
+    let planned =
+        PeerManifest::from_file(&opts.manifest)?.plan(&peers, &cidr_tree, &opts.save_dir)?;
+    if planned.is_empty() {
+        println!("the manifest doesn't contain any peers.");
+        return Ok(());
+    }
+
+    for peer in &planned {
+        println!(
+            "{} ({}) in {}",
+            peer.contents.name.yellow(),
+            peer.contents.ip,
+            cidrs
+                .iter()
+                .find(|cidr| cidr.id == peer.contents.cidr_id)
+                .map(|cidr| cidr.name.as_str())
+                .unwrap_or_default()
+        );
+    }
+    if !opts.yes
+        && !Confirm::with_theme(&*prompts::THEME)
+            .with_prompt(format!("Create {} peers?", planned.len()))
+            .default(false)
+            .interact()?
+    {
+        println!("exited without creating peers.");
+        return Ok(());
+    }
+
+    let tx = conn.transaction()?;
+    let server_peer = DatabasePeer::get(&tx, 1)?;
+    let server_api_addr = SocketAddr::new(config.address, config.listen_port);
+    let mut created = vec![];
+    let mut written = vec![];
+    let result = planned
+        .into_iter()
+        .try_for_each(|planned| -> Result<(), Error> {
+            let peer = DatabasePeer::create(&tx, planned.contents)?;
+            prompts::peer_invitation(
+                interface,
+                &peer,
+                &server_peer,
+                &config.alternate_endpoints,
+                &cidr_tree,
+                planned.keypair,
+                &server_api_addr,
+                config.preshared_key.clone(),
+            )?
+            .write_to_path(&planned.invite_path, true, None)?;
+            written.push(planned.invite_path);
+            created.push(peer);
+            Ok(())
+        });
+    if let Err(e) = result {
+        // Dropping the transaction rolls back the peers, so their invitations are useless.
+        for path in &written {
+            let _ = std::fs::remove_file(path);
+        }
+        bail!("failed to create peers, no changes were made: {}", e);
+    }
+    tx.commit()?;
+
+    if cfg!(not(test)) && Device::get(interface, network.backend).is_ok() {
+        // Update the current WireGuard interface with the new peers.
+        let preshared_key = config.network_preshared_key()?;
+        created
+            .iter()
+            .fold(DeviceUpdate::new(), |update, peer| {
+                update.add_peer(peer_config(peer, preshared_key.as_ref()))
+            })
+            .apply(interface, network.backend)
+            .map_err(ServerError::WireGuard)?;
+    }
+
+    println!(
+        "\n{} peers added, invitations written to {}\n\
+         Please send them to each peer securely (eg. via magic-wormhole) \
+         to bootstrap them onto the network.",
+        created.len(),
+        opts.save_dir.display().to_string().bold()
+    );
+
+    Ok(())
+}
+
 pub fn rename_peer(
     interface: &InterfaceName,
     conf: &ServerConfig,
@@ -263,18 +526,22 @@ pub fn enable_or_disable_peer(
         )?;
 
         if enable {
+            let config = ConfigFile::from_file(conf.config_path(interface))?;
             DeviceUpdate::new()
-                .add_peer(db_peer.deref().into())
+                .add_peer(peer_config(
+                    &db_peer,
+                    config.network_preshared_key()?.as_ref(),
+                ))
                 .apply(interface, network.backend)
-                .map_err(|_| ServerError::WireGuard)?;
+                .map_err(ServerError::WireGuard)?;
         } else {
             let public_key =
-                Key::from_base64(&peer.public_key).map_err(|_| ServerError::WireGuard)?;
+                Key::from_base64(&peer.public_key).map_err(ServerError::invalid_key)?;
 
             DeviceUpdate::new()
                 .remove_peer_by_key(&public_key)
                 .apply(interface, network.backend)
-                .map_err(|_| ServerError::WireGuard)?;
+                .map_err(ServerError::WireGuard)?;
         }
     } else {
         log::info!("exiting without enabling or disabling peer.");
@@ -283,6 +550,30 @@ pub fn enable_or_disable_peer(
     Ok(())
 }
 
+/// Disabling a CIDR hides its peers, and those of its children, from every peer's view of the
+/// network without deleting them. They reappear once it's enabled again.
+pub fn enable_or_disable_cidr(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    enable: bool,
+    opts: EnableDisableCidrOpts,
+) -> Result<(), Error> {
+    let conn = open_database_connection(interface, conf)?;
+    let cidrs = DatabaseCidr::list(&conn)?;
+
+    if let Some(cidr) = prompts::enable_or_disable_cidr(&cidrs, &opts, enable)? {
+        DatabaseCidr::from(cidr).set_disabled(&conn, !enable)?;
+        println!(
+            "CIDR {}abled. Peers will see the change the next time they fetch.",
+            if enable { "en" } else { "dis" }
+        );
+    } else {
+        log::info!("exiting without enabling or disabling CIDR.");
+    }
+
+    Ok(())
+}
+
 pub fn add_cidr(
     interface: &InterfaceName,
     conf: &ServerConfig,
@@ -354,12 +645,169 @@ pub fn delete_cidr(
     Ok(())
 }
 
+/// Print every peer in the network, or dump them as JSON.
+pub fn list_peers(interface: &InterfaceName, conf: &ServerConfig, json: bool) -> Result<(), Error> {
+    let conn = open_database_connection(interface, conf)?;
+    let peers = DatabasePeer::list(&conn)?
+        .into_iter()
+        .map(|peer| peer.inner)
+        .collect::<Vec<_>>();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&peers)?);
+        return Ok(());
+    }
+
+    let cidrs = DatabaseCidr::list(&conn)?;
+    for peer in peers {
+        let cidr = cidrs.iter().find(|cidr| cidr.id == peer.cidr_id);
+        let flags = [
+            (peer.is_admin, "admin"),
+            (peer.is_viewer, "viewer"),
+            (peer.is_disabled, "disabled"),
+            (!peer.is_redeemed, "unredeemed"),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, flag)| *flag)
+        .collect::<Vec<_>>();
+        println!(
+            "{:>4}  {}  {}  {}  {}  {}",
+            peer.id,
+            peer.name.bold(),
+            peer.ip.to_string().yellow(),
+            cidr.map(|cidr| cidr.name.as_str()).unwrap_or("?"),
+            peer.public_key.dimmed(),
+            flags.join(",")
+        );
+    }
+
+    Ok(())
+}
+
+pub fn list_cidrs(interface: &InterfaceName, conf: &ServerConfig, json: bool) -> Result<(), Error> {
+    let conn = open_database_connection(interface, conf)?;
+    let cidrs = DatabaseCidr::list(&conn)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&cidrs)?);
+        return Ok(());
+    }
+
+    let limits = db::PeerLimits::list(&conn)?;
+    for cidr in &cidrs {
+        let parent = cidr
+            .parent
+            .and_then(|parent| cidrs.iter().find(|other| other.id == parent));
+        println!(
+            "{:>4}  {}  {}  {}{}{}",
+            cidr.id,
+            cidr.name.bold(),
+            cidr.cidr.to_string().yellow(),
+            parent
+                .map(|parent| format!("child of {}", parent.name))
+                .unwrap_or_else(|| "root".into()),
+            if cidr.is_disabled { "  disabled" } else { "" },
+            limits
+                .get(&cidr.id)
+                .map(|max_peers| format!("  max {max_peers} peers"))
+                .unwrap_or_default()
+        );
+    }
+
+    Ok(())
+}
+
+pub fn set_peer_limit(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    opts: SetPeerLimitOpts,
+) -> Result<(), Error> {
+    let conn = open_database_connection(interface, conf)?;
+    let cidrs = DatabaseCidr::list(&conn)?;
+    let matches: Vec<_> = match &opts.cidr {
+        Some(name) => cidrs.iter().filter(|cidr| &cidr.name == name).collect(),
+        None => cidrs.iter().filter(|cidr| cidr.parent.is_none()).collect(),
+    };
+    let cidr = match matches.as_slice() {
+        [cidr] => cidr,
+        [] => bail!("no CIDR named {}", opts.cidr.unwrap_or_default()),
+        _ => bail!("more than one CIDR matches, give the --cidr name of one"),
+    };
+
+    let max_peers = if opts.unset { None } else { opts.max_peers };
+    db::PeerLimits::set(&conn, cidr.id, max_peers)?;
+    match max_peers {
+        Some(max_peers) => println!(
+            "{} CIDR {} is now limited to {} peers.",
+            "[*]".dimmed(),
+            cidr.name.yellow(),
+            max_peers
+        ),
+        None => println!(
+            "{} CIDR {} no longer has a peer limit.",
+            "[*]".dimmed(),
+            cidr.name.yellow()
+        ),
+    }
+    Ok(())
+}
+
+/// Print the log of changes admins have made to the network through the API.
+pub fn audit(interface: &InterfaceName, conf: &ServerConfig) -> Result<(), Error> {
+    let conn = open_database_connection(interface, conf)?;
+    let entries = db::AuditLog::list(&conn)?;
+    if entries.is_empty() {
+        println!("No admin changes have been recorded yet.");
+    }
+
+    for entry in entries {
+        println!(
+            "{} UTC  {} ({})  {}  {}",
+            format_utc(entry.timestamp).dimmed(),
+            entry.actor_ip.to_string().yellow(),
+            entry.actor_public_key.dimmed(),
+            entry.action.bold(),
+            entry.target
+        );
+    }
+
+    Ok(())
+}
+
+/// Format seconds since the UNIX epoch as a `YYYY-MM-DD HH:MM:SS` UTC date and time.
+fn format_utc(timestamp: u64) -> String {
+    let (days, seconds) = (timestamp / 86_400, timestamp % 86_400);
+
+    // Howard Hinnant's civil_from_days, shifted so eras start on March 1st of year 0.
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
 pub fn uninstall(
     interface: &InterfaceName,
     conf: &ServerConfig,
     network: NetworkOpts,
     yes: bool,
 ) -> Result<(), Error> {
+    prompts::ensure_flags(&[("--yes", yes)])?;
     if yes
         || Confirm::with_theme(&*prompts::THEME)
             .with_prompt(format!(
@@ -381,6 +829,18 @@ pub fn uninstall(
             .with_path(&data)
             .map_err(|e| println!("[!] {}", e.to_string().yellow()))
             .ok();
+        // In WAL mode, SQLite keeps the write-ahead log and its index next to the database.
+        for suffix in ["-wal", "-shm"] {
+            let mut path = data.clone().into_os_string();
+            path.push(suffix);
+            let path = PathBuf::from(path);
+            if path.exists() {
+                std::fs::remove_file(&path)
+                    .with_path(&path)
+                    .map_err(|e| println!("[!] {}", e.to_string().yellow()))
+                    .ok();
+            }
+        }
         println!(
             "{} network {} is uninstalled.",
             "[*]".dimmed(),
@@ -390,12 +850,215 @@ pub fn uninstall(
     Ok(())
 }
 
-fn spawn_endpoint_refresher(interface: InterfaceName, network: NetworkOpts) -> Endpoints {
+pub(crate) fn hash_join_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Require (or stop requiring) a join token to redeem invitations to the network.
+pub fn set_join_token(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    unset: bool,
+) -> Result<(), Error> {
+    let config_path = conf.config_path(interface);
+    let mut config = ConfigFile::from_file(&config_path)?;
+
+    if unset {
+        config.join_token_hash = None;
+        config.write_to_path(&config_path)?;
+        println!(
+            "{} invitations to {} no longer require a join token.",
+            "[*]".dimmed(),
+            interface.as_str_lossy().yellow()
+        );
+    } else {
+        // A private key is 32 bytes from the OS's CSPRNG, which is as good a token as any.
+        let token = Key::generate_private().to_base64();
+        config.join_token_hash = Some(hash_join_token(&token));
+        config.write_to_path(&config_path)?;
+        printdoc!(
+            "
+            {star} Invitations to {interface} now require this join token to be redeemed:
+
+                {token}
+
+            The token itself is not stored, so keep it somewhere safe. Share it with
+            invitees separately from their invitation file, to be passed to
+            '{install}'.
+
+            Restart the server for the change to take effect.
+            ",
+            star = "[*]".dimmed(),
+            interface = interface.as_str_lossy().yellow(),
+            token = token.yellow(),
+            install = "innernet install --join-token <TOKEN>".yellow(),
+        );
+    }
+
+    Ok(())
+}
+
+/// Move the server's WireGuard interface (and with it, the API) to a new port, updating the
+/// config file, the server peer's endpoint in the database, and the live interface.
+pub fn set_listen_port(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    listen_port: u16,
+    network: NetworkOpts,
+) -> Result<(), Error> {
+    let config_path = conf.config_path(interface);
+    let mut config = ConfigFile::from_file(&config_path)?;
+    let old_port = config.listen_port;
+    if old_port == listen_port {
+        println!("No change necessary - the server already listens on this port.");
+        return Ok(());
+    }
+
+    let conn = open_database_connection(interface, conf)?;
+    let mut server_peer = DatabasePeer::get_from_ip(&conn, config.address)?;
+    // An endpoint on a different port than we listen on is being port-forwarded, and it's up to
+    // the operator to move the forward along with us.
+    let endpoint = server_peer.endpoint.as_ref().map(|endpoint| {
+        if endpoint.port() == old_port {
+            endpoint.with_port(listen_port)
+        } else {
+            println!(
+                "{} leaving external endpoint {} as is, update your port forwarding to {}.",
+                "[!]".yellow(),
+                endpoint,
+                listen_port
+            );
+            endpoint.clone()
+        }
+    });
+    let candidates = server_peer
+        .candidates
+        .iter()
+        .map(|candidate| candidate.with_port(listen_port))
+        .collect();
+    server_peer.update(
+        &conn,
+        PeerContents {
+            endpoint,
+            candidates,
+            ..server_peer.contents.clone()
+        },
+    )?;
+
+    config.listen_port = listen_port;
+    config.write_to_path(&config_path)?;
+
+    if Device::get(interface, network.backend).is_ok() {
+        DeviceUpdate::new()
+            .set_listen_port(listen_port)
+            .apply(interface, network.backend)
+            .map_err(ServerError::WireGuard)?;
+        println!("{} updated WireGuard interface.", "[*]".dimmed());
+    }
+
+    printdoc!(
+        "
+        {star} The server now listens on port {listen_port}.
+
+        Restart the server for its API to move to the new port as well. Peers have the old
+        port in both the external-endpoint and internal-endpoint of their interface config,
+        and need to update them before they can re-fetch the new endpoint from the server.
+        ",
+        star = "[*]".dimmed(),
+        listen_port = listen_port.to_string().yellow(),
+    );
+
+    Ok(())
+}
+
+/// Generate a new keypair for the server and apply it to the config file, the database,
+/// and the live WireGuard interface (if it's up).
+///
+/// Clients keep trusting the old server public key stored in their interface config, so
+/// every peer has to update `public-key` in the `[server]` section of its config and re-fetch
+/// before it can talk to the server again. The rotation is recorded (which also bumps the
+/// network epoch), so that fetch re-pins the server peer instead of failing the pinning check.
+pub fn rotate_key(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    network: NetworkOpts,
+    yes: bool,
+) -> Result<(), Error> {
+    prompts::ensure_flags(&[("--yes", yes)])?;
+    if !yes
+        && !Confirm::with_theme(&*prompts::THEME)
+            .with_prompt(format!(
+                "Rotate the server key for network \"{}\"? All peers will need the new public key.",
+                interface.as_str_lossy().yellow()
+            ))
+            .default(false)
+            .interact()?
+    {
+        println!("exited without rotating key.");
+        return Ok(());
+    }
+
+    let config_path = conf.config_path(interface);
+    let mut config = ConfigFile::from_file(&config_path)?;
+    let mut conn = open_database_connection(interface, conf)?;
+    let tx = conn.transaction()?;
+    let mut server_peer = DatabasePeer::get_from_ip(&tx, config.address)?;
+    let keypair = KeyPair::generate();
+
+    let old_private_key = wireguard_control::Key::from_base64(&config.private_key)?;
+    let new_public_key = keypair.public.to_base64();
+    server_peer.set_public_key(
+        &tx,
+        &new_public_key,
+        &shared::KeyRotation::sign(&old_private_key, &new_public_key),
+    )?;
+    config.private_key = keypair.private.to_base64();
+    config.write_to_path(&config_path)?;
+    tx.commit()?;
+
+    if Device::get(interface, network.backend).is_ok() {
+        DeviceUpdate::new()
+            .set_private_key(keypair.private)
+            .apply(interface, network.backend)
+            .map_err(ServerError::WireGuard)?;
+        println!("{} updated WireGuard interface.", "[*]".dimmed());
+    }
+
+    printdoc!(
+        "
+        {star} Server key rotated. The new server public key is:
+
+            {public_key}
+
+        A running `innernet-server serve` still expects the old key from API requests,
+        since it only reads the key when it starts. Restart it now:
+
+            systemctl restart innernet-server@{interface}
+
+        Peers will not be able to reach the server until they set this as the
+        server public-key in their interface config and re-fetch.
+        ",
+        interface = interface,
+        star = "[*]".dimmed(),
+        public_key = keypair.public.to_base64().yellow(),
+    );
+
+    Ok(())
+}
+
+fn spawn_endpoint_refresher(
+    interface: InterfaceName,
+    network: NetworkOpts,
+    period: Duration,
+) -> Endpoints {
     let endpoints = Arc::new(RwLock::new(HashMap::new()));
     tokio::task::spawn({
         let endpoints = endpoints.clone();
         async move {
-            let mut interval = tokio::time::interval(Duration::from_secs(10));
+            let mut interval = tokio::time::interval(period);
             loop {
                 interval.tick().await;
                 if let Ok(info) = Device::get(&interface, network.backend) {
@@ -413,13 +1076,16 @@ fn spawn_endpoint_refresher(interface: InterfaceName, network: NetworkOpts) -> E
     endpoints
 }
 
-fn spawn_expired_invite_sweeper(db: Db) {
+/// The sweeper gets its own database connection so it doesn't hold up API requests waiting on
+/// the shared one.
+fn spawn_expired_invite_sweeper(conn: Connection, period: Duration, metrics: Arc<Metrics>) {
     tokio::task::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(10));
+        let mut interval = tokio::time::interval(period);
         loop {
             interval.tick().await;
-            match DatabasePeer::delete_expired_invites(&db.lock()) {
+            match DatabasePeer::delete_expired_invites(&conn) {
                 Ok(deleted) if deleted > 0 => {
+                    metrics.record_expired_invites_swept(deleted);
                     log::info!("Deleted {} expired peer invitations.", deleted)
                 },
                 Err(e) => log::error!("Failed to delete expired peer invitations: {}", e),
@@ -433,6 +1099,7 @@ pub async fn serve(
     interface: InterfaceName,
     conf: &ServerConfig,
     network: NetworkOpts,
+    opts: ServeOpts,
 ) -> Result<(), Error> {
     let config = ConfigFile::from_file(conf.config_path(&interface))?;
     log::debug!("opening database connection...");
@@ -440,9 +1107,10 @@ pub async fn serve(
 
     let mut peers = DatabasePeer::list(&conn)?;
     log::debug!("peers listed...");
+    let preshared_key = config.network_preshared_key()?;
     let peer_configs = peers
         .iter()
-        .map(|peer| peer.deref().into())
+        .map(|peer| peer_config(peer, preshared_key.as_ref()))
         .collect::<Vec<PeerConfigBuilder>>();
 
     log::info!("bringing up interface.");
@@ -461,8 +1129,13 @@ pub async fn serve(
 
     log::info!("{} peers added to wireguard interface.", peers.len());
 
-    let candidates: Vec<Endpoint> = get_local_addrs()?
-        .map(|addr| SocketAddr::from((addr, config.listen_port)).into())
+    let candidates: Vec<Endpoint> = config
+        .alternate_endpoints
+        .iter()
+        .cloned()
+        .chain(
+            get_local_addrs(None)?.map(|addr| SocketAddr::from((addr, config.listen_port)).into()),
+        )
         .collect();
     let num_candidates = candidates.len();
     let myself = peers
@@ -484,8 +1157,17 @@ pub async fn serve(
 
     let public_key = wireguard_control::Key::from_base64(&config.private_key)?.get_public();
     let db = Arc::new(Mutex::new(conn));
-    let endpoints = spawn_endpoint_refresher(interface, network);
-    spawn_expired_invite_sweeper(db.clone());
+    let endpoints = spawn_endpoint_refresher(
+        interface,
+        network,
+        Duration::from_secs(opts.endpoint_refresh_interval),
+    );
+    let metrics = Arc::new(Metrics::new(opts.metrics));
+    spawn_expired_invite_sweeper(
+        open_database_connection(&interface, conf)?,
+        Duration::from_secs(opts.invite_sweep_interval),
+        metrics.clone(),
+    );
 
     let context = Context {
         db,
@@ -493,12 +1175,41 @@ pub async fn serve(
         interface,
         public_key,
         backend: network.backend,
+        join_token_hash: config.join_token_hash,
+        preshared_key,
+        disable_endpoint_injection: opts.disable_endpoint_injection,
+        metrics,
     };
 
     log::info!("innernet-server {} starting.", VERSION);
 
     let listener = get_listener((config.address, config.listen_port).into(), &interface)?;
 
+    for addr in opts.management_listen {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let context = context.clone();
+        let make_svc = hyper::service::make_service_fn(move |socket: &AddrStream| {
+            let remote_addr = socket.remote_addr();
+            let context = context.clone();
+            async move {
+                Ok::<_, http::Error>(hyper::service::service_fn(move |req: Request<Body>| {
+                    log::debug!("{} - {} {}", &remote_addr, req.method(), req.uri());
+                    management_service(req, context.clone(), remote_addr)
+                }))
+            }
+        });
+        let server = hyper::Server::from_tcp(listener)?
+            .serve(make_svc)
+            .with_graceful_shutdown(shutdown_signal());
+        log::info!("serving health checks on {}.", addr);
+        tokio::task::spawn(async move {
+            if let Err(e) = server.await {
+                log::error!("management listener on {} failed: {}", addr, e);
+            }
+        });
+    }
+
     let make_svc = hyper::service::make_service_fn(move |socket: &AddrStream| {
         let remote_addr = socket.remote_addr();
         let context = context.clone();
@@ -510,13 +1221,35 @@ pub async fn serve(
         }
     });
 
-    let server = hyper::Server::from_tcp(listener)?.serve(make_svc);
+    let server = hyper::Server::from_tcp(listener)?
+        .serve(make_svc)
+        .with_graceful_shutdown(shutdown_signal());
 
     server.await?;
 
+    if opts.keep_interface {
+        log::info!("leaving interface {} up.", interface);
+    } else {
+        log::info!("bringing down interface {}.", interface);
+        wg::down(&interface, network.backend)?;
+    }
+
     Ok(())
 }
 
+/// Resolves once the process receives SIGINT or SIGTERM, letting in-flight
+/// requests finish before the server exits.
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
+    }
+    log::info!("shutdown signal received, stopping server.");
+}
+
 /// This function differs per OS, because different operating systems have
 /// opposing characteristics when binding to a specific IP address.
 /// On Linux, binding to a specific local IP address does *not* bind it to
@@ -559,9 +1292,31 @@ pub(crate) async fn hyper_service(
         .map(String::from)
         .collect();
 
-    routes(req, context, remote_addr, components)
+    let metrics = context.metrics.clone();
+    let res = routes(req, context, remote_addr, components)
         .await
-        .or_else(TryInto::try_into)
+        .or_else(TryInto::try_into);
+    if let Ok(res) = &res {
+        metrics.record_response(res.status().as_u16());
+    }
+    res
+}
+
+/// Serves requests on the additional management addresses. Unlike on the WireGuard interface,
+/// nothing vouches for the remote address there, so no session can be derived from it and only
+/// the unauthenticated health checks (and public metrics) are available.
+pub(crate) async fn management_service(
+    req: Request<Body>,
+    context: Context,
+    remote_addr: SocketAddr,
+) -> Result<Response<Body>, http::Error> {
+    match req.uri().path().trim_start_matches('/') {
+        "healthz" | "readyz" => hyper_service(req, context, remote_addr).await,
+        "metrics" if context.metrics.mode == MetricsMode::Public => {
+            hyper_service(req, context, remote_addr).await
+        },
+        _ => ServerError::NotFound.try_into(),
+    }
 }
 
 async fn routes(
@@ -570,17 +1325,51 @@ async fn routes(
     remote_addr: SocketAddr,
     mut components: VecDeque<String>,
 ) -> Result<Response<Body>, ServerError> {
-    // Must be "/v1/[something]"
-    if components.pop_front().as_deref() != Some("v1") {
-        Err(ServerError::NotFound)
-    } else {
-        let session = get_session(&req, context, remote_addr.ip())?;
-        let component = components.pop_front();
-        match component.as_deref() {
-            Some("user") => api::user::routes(req, components, session).await,
-            Some("admin") => api::admin::routes(req, components, session).await,
-            _ => Err(ServerError::NotFound),
-        }
+    match components.pop_front().as_deref() {
+        // Unauthenticated liveness and readiness checks for external health checkers.
+        Some("healthz") if req.method() == Method::GET => status_response(StatusCode::OK),
+        Some("readyz") if req.method() == Method::GET => {
+            let ready = context
+                .db
+                .lock()
+                .query_row("SELECT 1", [], |_| Ok(()))
+                .is_ok();
+            status_response(if ready {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            })
+        },
+        Some("metrics") if req.method() == Method::GET => {
+            match context.metrics.mode {
+                MetricsMode::Disabled => return Err(ServerError::NotFound),
+                MetricsMode::Public => {},
+                MetricsMode::Admin => {
+                    if !get_session(&req, context.clone(), remote_addr.ip())?.admin_capable() {
+                        return Err(ServerError::Unauthorized);
+                    }
+                },
+            }
+            let body = context.metrics.render(&context)?;
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header(
+                    hyper::header::CONTENT_TYPE,
+                    "text/plain; version=0.0.4; charset=utf-8",
+                )
+                .body(Body::from(body))?)
+        },
+        // Must be "/v1/[something]"
+        Some("v1") => {
+            let session = get_session(&req, context, remote_addr.ip())?;
+            let component = components.pop_front();
+            match component.as_deref() {
+                Some("user") => api::user::routes(req, components, session).await,
+                Some("admin") => api::admin::routes(req, components, session).await,
+                _ => Err(ServerError::NotFound),
+            }
+        },
+        _ => Err(ServerError::NotFound),
     }
 }
 
@@ -594,7 +1383,10 @@ fn get_session(
         .get(INNERNET_PUBKEY_HEADER)
         .ok_or(ServerError::Unauthorized)?;
     let pubkey = pubkey.to_str().map_err(|_| ServerError::Unauthorized)?;
-    let pubkey = Key::from_base64(pubkey).map_err(|_| ServerError::Unauthorized)?;
+    let pubkey = Key::from_base64(pubkey).map_err(|e| {
+        log::debug!("rejecting request from {}: {}", addr, e);
+        ServerError::Unauthorized
+    })?;
     if pubkey
         .as_bytes()
         .ct_eq(context.public_key.as_bytes())
@@ -618,9 +1410,29 @@ mod tests {
     use super::*;
     use crate::test;
     use anyhow::Result;
-    use hyper::StatusCode;
     use std::path::Path;
 
+    #[test]
+    fn test_peer_config_preshared_key() -> Result<()> {
+        let server = test::Server::new()?;
+        let peer = DatabasePeer::get(&server.db().lock(), test::DEVELOPER1_PEER_ID)?;
+        let psk = Key::generate_preshared();
+
+        let config = peer_config(&peer, Some(&psk)).into_peer_config();
+        assert_eq!(config.preshared_key, Some(psk));
+        let config = peer_config(&peer, None).into_peer_config();
+        assert_eq!(config.preshared_key, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(0), "1970-01-01 00:00:00");
+        assert_eq!(format_utc(951_782_400), "2000-02-29 00:00:00");
+        assert_eq!(format_utc(1_792_276_199), "2026-10-17 22:29:59");
+    }
+
     #[test]
     fn test_init_wizard() -> Result<(), Error> {
         // This runs init_wizard().
@@ -631,6 +1443,75 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_database_wal_mode() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let conn = server.open_database()?;
+
+        let journal_mode: String =
+            conn.pragma_query_value(None, "journal_mode", |row| row.get(0))?;
+        assert_eq!(journal_mode, "wal");
+        // NORMAL
+        let synchronous: i64 = conn.pragma_query_value(None, "synchronous", |row| row.get(0))?;
+        assert_eq!(synchronous, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_listen_port() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        set_listen_port(
+            server.interface(),
+            server.conf(),
+            51999,
+            NetworkOpts::default(),
+        )?;
+
+        let config = ConfigFile::from_file(server.wg_conf_path())?;
+        assert_eq!(config.listen_port, 51999);
+        let conn = server.open_database()?;
+        let server_peer = DatabasePeer::get_from_ip(&conn, config.address)?;
+        assert_eq!(server_peer.endpoint.as_ref().unwrap().port(), 51999);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotate_key() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let config = ConfigFile::from_file(server.wg_conf_path())?;
+        let conn = server.open_database()?;
+        let old_public_key = DatabasePeer::get_from_ip(&conn, config.address)?
+            .public_key
+            .clone();
+        let old_epoch = db::NetworkEpoch::get(&conn)?;
+
+        rotate_key(
+            server.interface(),
+            server.conf(),
+            NetworkOpts::default(),
+            true,
+        )?;
+
+        let config = ConfigFile::from_file(server.wg_conf_path())?;
+        let new_public_key = Key::from_base64(&config.private_key)?
+            .get_public()
+            .to_base64();
+        let server_peer = DatabasePeer::get_from_ip(&conn, config.address)?;
+        assert_eq!(server_peer.public_key, new_public_key);
+        assert!(db::NetworkEpoch::get(&conn)? > old_epoch);
+        let rotations = db::KeyRotations::list_for(&conn, &[server_peer.id], 0)?;
+        let [rotation] = &rotations[..] else {
+            panic!("expected one rotation, got {rotations:?}");
+        };
+        assert_eq!(rotation.old_public_key, old_public_key);
+        assert_eq!(rotation.new_public_key, new_public_key);
+        assert!(rotation.is_signed());
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_with_session_disguised_with_headers() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -663,6 +1544,87 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_health_checks_without_session() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        for path in ["/healthz", "/readyz"] {
+            let uri = if cfg!(feature = "v6-test") {
+                format!("http://[{}]{}", test::WG_MANAGE_PEER_IP, path)
+            } else {
+                format!("http://{}{}", test::WG_MANAGE_PEER_IP, path)
+            };
+            let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+            // No public key header and an unknown IP, but health checks don't require a session.
+            let res = if cfg!(feature = "v6-test") {
+                server.raw_request("fd00:1337::1337", req).await
+            } else {
+                server.raw_request("10.80.80.80", req).await
+            };
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_management_listener_only_serves_health_checks() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let remote_addr = SocketAddr::new(test::ADMIN_PEER_IP.parse()?, 54321);
+
+        let req = Request::get("http://127.0.0.1/healthz").body(Body::empty())?;
+        let res = management_service(req, server.context(), remote_addr).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Even from an admin's IP and with the right key, the API itself isn't served.
+        let req = Request::get("http://127.0.0.1/v1/admin/peers")
+            .header(
+                INNERNET_PUBKEY_HEADER,
+                server.context().public_key.to_base64(),
+            )
+            .body(Body::empty())?;
+        let res = management_service(req, server.context(), remote_addr).await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_metrics() -> Result<(), Error> {
+        let mut server = test::Server::new()?;
+        let res = server.request(test::ADMIN_PEER_IP, "GET", "/metrics").await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        server.metrics = Arc::new(Metrics::new(MetricsMode::Admin));
+        let res = server
+            .request(test::DEVELOPER1_PEER_IP, "GET", "/metrics")
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = server.request(test::ADMIN_PEER_IP, "GET", "/metrics").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        let body = std::str::from_utf8(&body)?;
+        let peers = DatabasePeer::list(&server.db().lock())?.len();
+        assert!(body.contains(&format!("\ninnernet_peers {peers}\n")));
+        // The unauthorized request above was counted.
+        assert!(body.contains("innernet_http_responses_total{status=\"401\"} 1\n"));
+
+        // Admin-only metrics aren't served where nothing vouches for the caller's IP...
+        let remote_addr = SocketAddr::new(test::ADMIN_PEER_IP.parse()?, 54321);
+        let req = Request::get("http://127.0.0.1/metrics").body(Body::empty())?;
+        let res = management_service(req, server.context(), remote_addr).await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        // ...but public ones are.
+        server.metrics = Arc::new(Metrics::new(MetricsMode::Public));
+        let req = Request::get("http://127.0.0.1/metrics").body(Body::empty())?;
+        let res = management_service(req, server.context(), remote_addr).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_incorrect_public_key() -> Result<(), Error> {
         let server = test::Server::new()?;
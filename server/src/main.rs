@@ -1,15 +1,18 @@
 use clap::{Parser, Subcommand};
 use colored::*;
 use shared::{
-    AddCidrOpts, AddPeerOpts, DeleteCidrOpts, EnableDisablePeerOpts, NetworkOpts, RenameCidrOpts,
-    RenamePeerOpts,
+    AddCidrOpts, AddPeerOpts, DeleteCidrOpts, EnableDisableCidrOpts, EnableDisablePeerOpts,
+    NetworkOpts, RenameCidrOpts, RenamePeerOpts,
 };
 use std::{env, path::PathBuf};
 
 use innernet_server::{
-    add_cidr, add_peer, delete_cidr, enable_or_disable_peer,
+    add_cidr, add_peer, add_peers_from, audit, delete_cidr, enable_or_disable_cidr,
+    enable_or_disable_peer,
     initialize::{self, InitializeOpts},
-    rename_cidr, rename_peer, serve, uninstall, ServerConfig,
+    list_cidrs, list_peers, rename_cidr, rename_peer, rotate_key, serve, set_join_token,
+    set_listen_port, set_peer_limit, uninstall, AddPeersFromOpts, DatabaseOpts, ServeOpts,
+    ServerConfig, SetPeerLimitOpts,
 };
 use shared::Interface;
 
@@ -25,8 +28,15 @@ struct Opts {
     #[clap(short, long, default_value = "/var/lib/innernet-server")]
     data_dir: PathBuf,
 
+    /// Never prompt, failing instead if a required value wasn't supplied as a flag
+    #[clap(long, global = true)]
+    non_interactive: bool,
+
     #[clap(flatten)]
     network: NetworkOpts,
+
+    #[clap(flatten)]
+    database: DatabaseOpts,
 }
 
 #[derive(Debug, Subcommand)]
@@ -47,12 +57,42 @@ enum Command {
         yes: bool,
     },
 
+    /// Generate a new keypair for the server. `serve` has to be restarted to use it, and peers
+    /// need the new public key to reconnect.
+    RotateKey {
+        interface: Interface,
+
+        /// Bypass confirmation
+        #[clap(long)]
+        yes: bool,
+    },
+
+    /// Require a join token to redeem invitations, printing the newly generated token.
+    SetJoinToken {
+        interface: Interface,
+
+        /// Stop requiring a join token
+        #[clap(long)]
+        unset: bool,
+    },
+
+    /// Change the port the server listens on.
+    SetListenPort {
+        interface: Interface,
+
+        #[clap(value_parser = clap::value_parser!(u16).range(1..))]
+        listen_port: u16,
+    },
+
     /// Serve the coordinating server for an existing network.
     Serve {
         interface: Interface,
 
         #[clap(flatten)]
         network: NetworkOpts,
+
+        #[clap(flatten)]
+        opts: ServeOpts,
     },
 
     /// Add a peer to an existing network.
@@ -63,6 +103,14 @@ enum Command {
         args: AddPeerOpts,
     },
 
+    /// Add many peers at once from a TOML manifest, writing each invitation to `<name>.toml`.
+    AddPeersFrom {
+        interface: Interface,
+
+        #[clap(flatten)]
+        args: AddPeersFromOpts,
+    },
+
     /// Disable an enabled peer
     DisablePeer {
         interface: Interface,
@@ -111,6 +159,51 @@ enum Command {
         args: DeleteCidrOpts,
     },
 
+    /// Disable a CIDR, hiding its peers from the rest of the network without deleting them.
+    DisableCidr {
+        interface: Interface,
+
+        #[clap(flatten)]
+        args: EnableDisableCidrOpts,
+    },
+
+    /// Enable a disabled CIDR.
+    EnableCidr {
+        interface: Interface,
+
+        #[clap(flatten)]
+        args: EnableDisableCidrOpts,
+    },
+
+    /// List every peer in the network, including disabled ones.
+    ListPeers {
+        interface: Interface,
+
+        /// Print the peers as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// List every CIDR in the network.
+    ListCidrs {
+        interface: Interface,
+
+        /// Print the CIDRs as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Limit how many peers a CIDR (or the whole network) can hold.
+    SetPeerLimit {
+        interface: Interface,
+
+        #[clap(flatten)]
+        args: SetPeerLimitOpts,
+    },
+
+    /// Show the log of changes admins have made through the API.
+    Audit { interface: Interface },
+
     /// Generate shell completion scripts
     Completions {
         #[clap(value_enum)]
@@ -127,12 +220,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     pretty_env_logger::init();
     let opts = Opts::parse();
+    shared::prompts::set_non_interactive(opts.non_interactive);
 
     if unsafe { libc::getuid() } != 0 && !matches!(opts.command, Command::Completions { .. }) {
         return Err("innernet-server must run as root.".into());
     }
 
-    let conf = ServerConfig::new(opts.config_dir, opts.data_dir);
+    let conf = ServerConfig::new(opts.config_dir, opts.data_dir, opts.database);
 
     match opts.command {
         Command::New { opts } => {
@@ -142,11 +236,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         },
         Command::Uninstall { interface, yes } => uninstall(&interface, &conf, opts.network, yes)?,
+        Command::RotateKey { interface, yes } => rotate_key(&interface, &conf, opts.network, yes)?,
+        Command::SetJoinToken { interface, unset } => set_join_token(&interface, &conf, unset)?,
+        Command::SetListenPort {
+            interface,
+            listen_port,
+        } => set_listen_port(&interface, &conf, listen_port, opts.network)?,
         Command::Serve {
             interface,
             network: routing,
-        } => serve(*interface, &conf, routing).await?,
+            opts,
+        } => serve(*interface, &conf, routing, opts).await?,
         Command::AddPeer { interface, args } => add_peer(&interface, &conf, args, opts.network)?,
+        Command::AddPeersFrom { interface, args } => {
+            add_peers_from(&interface, &conf, args, opts.network)?
+        },
         Command::RenamePeer { interface, args } => rename_peer(&interface, &conf, args)?,
         Command::DisablePeer { interface, args } => {
             enable_or_disable_peer(&interface, &conf, false, opts.network, args)?
@@ -157,6 +261,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         Command::AddCidr { interface, args } => add_cidr(&interface, &conf, args)?,
         Command::RenameCidr { interface, args } => rename_cidr(&interface, &conf, args)?,
         Command::DeleteCidr { interface, args } => delete_cidr(&interface, &conf, args)?,
+        Command::DisableCidr { interface, args } => {
+            enable_or_disable_cidr(&interface, &conf, false, args)?
+        },
+        Command::EnableCidr { interface, args } => {
+            enable_or_disable_cidr(&interface, &conf, true, args)?
+        },
+        Command::ListPeers { interface, json } => list_peers(&interface, &conf, json)?,
+        Command::ListCidrs { interface, json } => list_cidrs(&interface, &conf, json)?,
+        Command::SetPeerLimit { interface, args } => set_peer_limit(&interface, &conf, args)?,
+        Command::Audit { interface } => audit(&interface, &conf)?,
         Command::Completions { shell } => {
             use clap::CommandFactory;
             let mut app = Opts::command();
@@ -0,0 +1,318 @@
+//! Bulk creation of peers from a TOML manifest, for bringing up a network with many
+//! predetermined peers at once.
+//!
+//! A manifest is a list of `[[peer]]` tables:
+//!
+//! ```toml
+//! [[peer]]
+//! name = "laptop"
+//! cidr = "humans"
+//! ip = "10.42.1.5"       # optional, the first available IP in the CIDR otherwise
+//! admin = true           # optional, false by default
+//! viewer = false         # optional, read-only admin access, false by default
+//! invite_expires = "30d" # optional, 14 days by default
+//! ```
+
+use anyhow::{anyhow, bail};
+use serde::Deserialize;
+use shared::{
+    persistent_keepalive_interval, CidrTree, Error, Hostname, IoErrorContext, IpNetExt, Peer,
+    PeerContents, Timestring, MAX_RECOMMENDED_INVITE_EXPIRATION,
+};
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use wireguard_control::KeyPair;
+
+const DEFAULT_INVITE_EXPIRES: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeerManifest {
+    #[serde(default, rename = "peer")]
+    pub peers: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestEntry {
+    pub name: String,
+    pub cidr: String,
+    pub ip: Option<IpAddr>,
+    #[serde(default)]
+    pub admin: bool,
+    #[serde(default)]
+    pub viewer: bool,
+    pub invite_expires: Option<String>,
+}
+
+/// A validated manifest entry, ready to be created.
+pub struct PlannedPeer {
+    pub contents: PeerContents,
+    pub keypair: KeyPair,
+    pub invite_path: PathBuf,
+}
+
+impl PeerManifest {
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path).with_path(path)?;
+        toml::from_str(&contents).map_err(|e| anyhow!("invalid manifest {}: {}", path.display(), e))
+    }
+
+    /// Validate every entry against the existing network, assigning IPs to the entries that
+    /// don't specify one. Nothing is returned unless the whole manifest is valid, and the
+    /// error lists every problem found rather than just the first.
+    pub fn plan(
+        &self,
+        peers: &[Peer],
+        cidr_tree: &CidrTree,
+        save_dir: &Path,
+    ) -> Result<Vec<PlannedPeer>, Error> {
+        let leaves = cidr_tree.leaves();
+        let mut names: HashSet<&str> = peers.iter().map(|peer| &*peer.name).collect();
+        let mut taken_ips: HashSet<IpAddr> = peers.iter().map(|peer| peer.ip).collect();
+        let mut errors = vec![];
+
+        // Reserve the explicitly requested IPs first, so that an auto-assigned entry can't
+        // take an IP that a later entry asked for.
+        let conflicting: HashSet<usize> = self
+            .peers
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.ip.is_some_and(|ip| !taken_ips.insert(ip)))
+            .map(|(i, _)| i)
+            .collect();
+
+        let mut planned = vec![];
+        for (i, entry) in self.peers.iter().enumerate() {
+            let mut fail = |message: String| errors.push(format!("{}: {}", entry.name, message));
+
+            let name = match entry.name.parse::<Hostname>() {
+                Ok(name) => name,
+                Err(e) => {
+                    fail(e.to_string());
+                    continue;
+                },
+            };
+            if !names.insert(&entry.name) {
+                fail("a peer with this name already exists".into());
+                continue;
+            }
+
+            if entry.admin && entry.viewer {
+                fail("a peer can't be both an admin and a viewer".into());
+                continue;
+            }
+
+            let Some(cidr) = leaves.iter().find(|cidr| cidr.name == entry.cidr) else {
+                fail(format!("no eligible CIDR named \"{}\" exists", entry.cidr));
+                continue;
+            };
+
+            let ip = match entry.ip {
+                Some(ip) if !cidr.is_assignable(&ip) => {
+                    fail(format!("IP {} is not assignable in {}", ip, cidr.cidr));
+                    continue;
+                },
+                Some(ip) if conflicting.contains(&i) => {
+                    fail(format!("IP {} is already taken", ip));
+                    continue;
+                },
+                Some(ip) => ip,
+                None => match cidr.first_available_ip(|ip| taken_ips.contains(ip)) {
+                    Some(ip) => {
+                        taken_ips.insert(ip);
+                        ip
+                    },
+                    None => {
+                        fail(format!("no IPs left in {}", cidr.cidr));
+                        continue;
+                    },
+                },
+            };
+
+            let invite_expires = match &entry.invite_expires {
+                Some(timestring) => match timestring.parse::<Timestring>() {
+                    Ok(timestring) => timestring.into(),
+                    Err(e) => {
+                        fail(e.to_string());
+                        continue;
+                    },
+                },
+                None => DEFAULT_INVITE_EXPIRES,
+            };
+            if invite_expires.is_zero() {
+                fail("the invitation must expire some time in the future".into());
+                continue;
+            }
+            if invite_expires > MAX_RECOMMENDED_INVITE_EXPIRATION {
+                log::warn!(
+                    "{}: the invitation won't expire for {} days",
+                    entry.name,
+                    invite_expires.as_secs() / (24 * 60 * 60)
+                );
+            }
+
+            let invite_path = save_dir.join(format!("{name}.toml"));
+            if invite_path.exists() {
+                fail(format!("{} already exists", invite_path.display()));
+                continue;
+            }
+
+            let keypair = KeyPair::generate();
+            planned.push(PlannedPeer {
+                contents: PeerContents::builder(name, ip, cidr.id, keypair.public.to_base64())
+                    .set_admin(entry.admin)
+                    .set_viewer(entry.viewer)
+                    .set_persistent_keepalive_interval(persistent_keepalive_interval(
+                        cidr.default_keepalive,
+                    ))
+                    .set_invite_expires(SystemTime::now() + invite_expires)
+                    .build(),
+                keypair,
+                invite_path,
+            });
+        }
+
+        if !errors.is_empty() {
+            bail!(
+                "the manifest has {} invalid entries:\n  {}",
+                errors.len(),
+                errors.join("\n  ")
+            );
+        }
+        Ok(planned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test;
+    use shared::Cidr;
+
+    fn plan(manifest: &str) -> Result<Vec<PlannedPeer>, Error> {
+        plan_on(&test::Server::new()?, manifest)
+    }
+
+    fn plan_on(server: &test::Server, manifest: &str) -> Result<Vec<PlannedPeer>, Error> {
+        let db = server.db();
+        let db = db.lock();
+        let peers = crate::DatabasePeer::list_including_deleted(&db)?
+            .into_iter()
+            .map(|peer| peer.inner)
+            .collect::<Vec<_>>();
+        let cidrs: Vec<Cidr> = crate::DatabaseCidr::list(&db)?;
+        let save_dir = tempfile::tempdir()?;
+
+        toml::from_str::<PeerManifest>(manifest)?.plan(
+            &peers,
+            &CidrTree::try_new(&cidrs[..])?,
+            save_dir.path(),
+        )
+    }
+
+    #[test]
+    fn test_plan_assigns_ips() -> Result<(), Error> {
+        let developer_cidr: ipnet::IpNet = test::DEVELOPER_CIDR.parse()?;
+        let taken: Vec<IpAddr> = vec![
+            test::DEVELOPER1_PEER_IP.parse()?,
+            test::DEVELOPER2_PEER_IP.parse()?,
+        ];
+        let first_free = developer_cidr
+            .first_available_ip(|ip| taken.contains(ip))
+            .unwrap();
+
+        let planned = plan(&format!(
+            r#"
+            [[peer]]
+            name = "auto"
+            cidr = "developer"
+
+            [[peer]]
+            name = "explicit"
+            cidr = "developer"
+            ip = "{first_free}"
+            admin = true
+            "#
+        ))?;
+
+        assert_eq!(planned.len(), 2);
+        // The explicitly requested IP is reserved before "auto" is assigned one.
+        assert_ne!(planned[0].contents.ip, first_free);
+        assert!(!taken.contains(&planned[0].contents.ip));
+        assert!(developer_cidr.contains(&planned[0].contents.ip));
+        assert_eq!(planned[1].contents.ip, first_free);
+        assert!(planned[1].contents.is_admin);
+        assert!(planned[1].invite_path.ends_with("explicit.toml"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_skips_deleted_peer_ips() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let developer_cidr: ipnet::IpNet = test::DEVELOPER_CIDR.parse()?;
+        let taken: Vec<IpAddr> = vec![
+            test::DEVELOPER1_PEER_IP.parse()?,
+            test::DEVELOPER2_PEER_IP.parse()?,
+        ];
+        let first_free = developer_cidr
+            .first_available_ip(|ip| taken.contains(ip))
+            .unwrap();
+        {
+            let db = server.db();
+            let db = db.lock();
+            let contents = test::developer_peer_contents("deleted", &first_free.to_string())?;
+            let peer = crate::DatabasePeer::create(&db, contents)?;
+            crate::DatabasePeer::delete(&db, peer.id)?;
+        }
+
+        let planned = plan_on(
+            &server,
+            r#"
+            [[peer]]
+            name = "auto"
+            cidr = "developer"
+            "#,
+        )?;
+
+        assert_eq!(planned.len(), 1);
+        assert_ne!(planned[0].contents.ip, first_free);
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_rejects_whole_manifest() -> Result<(), Error> {
+        let err = plan(&format!(
+            r#"
+            [[peer]]
+            name = "fine"
+            cidr = "developer"
+
+            [[peer]]
+            name = "developer1"
+            cidr = "developer"
+
+            [[peer]]
+            name = "nowhere"
+            cidr = "does-not-exist"
+
+            [[peer]]
+            name = "outside"
+            cidr = "developer"
+            ip = "{}"
+            "#,
+            test::ADMIN_PEER_IP
+        ))
+        .err()
+        .expect("invalid manifest should be rejected");
+
+        let message = err.to_string();
+        assert!(message.contains("3 invalid entries"), "{}", message);
+        assert!(!message.contains("fine:"));
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+//! Counters and gauges for `/metrics`, rendered in the Prometheus text exposition format.
+
+use crate::{
+    db::{DatabaseCidr, DatabasePeer},
+    Context, ServerError,
+};
+use clap::ValueEnum;
+use parking_lot::Mutex;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::SystemTime,
+};
+use wireguard_control::{Device, Key};
+
+/// Who may read `/metrics`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum MetricsMode {
+    /// Don't serve metrics
+    #[default]
+    Disabled,
+    /// Serve metrics to anyone, including on the --management-listen addresses
+    Public,
+    /// Only serve metrics to admin peers, over the WireGuard interface
+    Admin,
+}
+
+pub struct Metrics {
+    pub mode: MetricsMode,
+    expired_invites_swept: AtomicU64,
+    responses: Mutex<BTreeMap<u16, u64>>,
+}
+
+impl Metrics {
+    pub fn new(mode: MetricsMode) -> Self {
+        Self {
+            mode,
+            expired_invites_swept: AtomicU64::new(0),
+            responses: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn record_response(&self, status: u16) {
+        *self.responses.lock().entry(status).or_default() += 1;
+    }
+
+    pub fn record_expired_invites_swept(&self, count: usize) {
+        self.expired_invites_swept
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, context: &Context) -> Result<String, ServerError> {
+        let (peers, cidrs) = {
+            let conn = context.db.lock();
+            (DatabasePeer::list(&conn)?, DatabaseCidr::list(&conn)?)
+        };
+        let enabled = peers.iter().filter(|peer| !peer.is_disabled).count();
+
+        let mut out = String::new();
+        metric(&mut out, "innernet_peers", "gauge", "Peers in the network.");
+        writeln!(out, "innernet_peers {}", peers.len()).unwrap();
+        metric(
+            &mut out,
+            "innernet_peers_enabled",
+            "gauge",
+            "Peers in the network that aren't disabled.",
+        );
+        writeln!(out, "innernet_peers_enabled {enabled}").unwrap();
+        metric(&mut out, "innernet_cidrs", "gauge", "CIDRs in the network.");
+        writeln!(out, "innernet_cidrs {}", cidrs.len()).unwrap();
+        metric(
+            &mut out,
+            "innernet_expired_invites_swept_total",
+            "counter",
+            "Expired invitations deleted since the server started.",
+        );
+        writeln!(
+            out,
+            "innernet_expired_invites_swept_total {}",
+            self.expired_invites_swept.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        metric(
+            &mut out,
+            "innernet_http_responses_total",
+            "counter",
+            "HTTP responses sent since the server started, by status code.",
+        );
+        for (status, count) in self.responses.lock().iter() {
+            writeln!(
+                out,
+                "innernet_http_responses_total{{status=\"{status}\"}} {count}"
+            )
+            .unwrap();
+        }
+
+        if cfg!(not(test)) {
+            let stats = Device::get_stats(&context.interface, context.backend)
+                .map_err(ServerError::WireGuard)?;
+            metric(
+                &mut out,
+                "innernet_peer_last_handshake_age_seconds",
+                "gauge",
+                "Seconds since the server's last handshake with each peer that has had one.",
+            );
+            for peer in &peers {
+                let Ok(public_key) = Key::from_base64(&peer.public_key) else {
+                    continue;
+                };
+                let Some(age) = stats
+                    .get(&public_key)
+                    .and_then(|stats| stats.last_handshake_time)
+                    .and_then(|time| SystemTime::now().duration_since(time).ok())
+                else {
+                    continue;
+                };
+                writeln!(
+                    out,
+                    "innernet_peer_last_handshake_age_seconds{{peer=\"{}\",ip=\"{}\"}} {}",
+                    peer.name,
+                    peer.ip,
+                    age.as_secs()
+                )
+                .unwrap();
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}").unwrap();
+}
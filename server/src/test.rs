@@ -2,7 +2,8 @@
 use crate::{
     db::{DatabaseCidr, DatabasePeer},
     initialize::{init_wizard, InitializeOpts},
-    Context, Db, Endpoints, ServerConfig,
+    metrics::{Metrics, MetricsMode},
+    Context, DatabaseOpts, Db, Endpoints, ServerConfig,
 };
 use anyhow::anyhow;
 use hyper::{header::HeaderValue, http, Body, Request, Response};
@@ -16,6 +17,7 @@ use wireguard_control::{Backend, InterfaceName, Key, KeyPair};
 
 #[cfg(not(feature = "v6-test"))]
 mod v4 {
+    pub const SERVER_EXTERNAL_ENDPOINT: &str = "155.155.155.155:54321";
     pub const ROOT_CIDR: &str = "10.80.0.0/15";
     pub const SERVER_CIDR: &str = "10.80.0.1/32";
     pub const ADMIN_CIDR: &str = "10.80.1.0/24";
@@ -39,6 +41,7 @@ pub use v4::*;
 
 #[cfg(feature = "v6-test")]
 mod v6 {
+    pub const SERVER_EXTERNAL_ENDPOINT: &str = "[2001:db8::155]:54321";
     pub const ROOT_CIDR: &str = "fd00:1337::/64";
     pub const SERVER_CIDR: &str = "fd00:1337::1/128";
     pub const ADMIN_CIDR: &str = "fd00:1337::1:0:0:0/80";
@@ -78,6 +81,9 @@ pub struct Server {
     interface: InterfaceName,
     conf: ServerConfig,
     public_key: Key,
+    pub join_token_hash: Option<String>,
+    pub disable_endpoint_injection: bool,
+    pub metrics: Arc<Metrics>,
     // The directory will be removed during destruction.
     _test_dir: TempDir,
 }
@@ -94,14 +100,18 @@ impl Server {
         let conf = ServerConfig {
             config_dir: test_dir_path.to_path_buf(),
             data_dir: test_dir_path.to_path_buf(),
+            database: DatabaseOpts::default(),
         };
 
         let opts = InitializeOpts {
             network_name: Some(interface.parse()?),
             network_cidr: Some(ROOT_CIDR.parse()?),
-            external_endpoint: Some("155.155.155.155:54321".parse().unwrap()),
+            external_endpoint: Some(SERVER_EXTERNAL_ENDPOINT.parse().unwrap()),
             listen_port: Some(54321),
             auto_external_endpoint: false,
+            alternate_endpoints: vec![],
+            preshared_key: false,
+            from_config: None,
         };
         init_wizard(&conf, opts).map_err(|_| anyhow!("init_wizard failed"))?;
 
@@ -162,6 +172,9 @@ impl Server {
             endpoints,
             interface,
             public_key,
+            join_token_hash: None,
+            disable_endpoint_injection: false,
+            metrics: Arc::new(Metrics::new(MetricsMode::Disabled)),
             _test_dir: test_dir,
         })
     }
@@ -176,6 +189,10 @@ impl Server {
             interface: self.interface,
             endpoints: self.endpoints.clone(),
             public_key: self.public_key.clone(),
+            join_token_hash: self.join_token_hash.clone(),
+            preshared_key: None,
+            disable_endpoint_injection: self.disable_endpoint_injection,
+            metrics: self.metrics.clone(),
             #[cfg(target_os = "linux")]
             backend: Backend::Kernel,
             #[cfg(not(target_os = "linux"))]
@@ -183,6 +200,18 @@ impl Server {
         }
     }
 
+    pub fn conf(&self) -> &ServerConfig {
+        &self.conf
+    }
+
+    pub fn interface(&self) -> &InterfaceName {
+        &self.interface
+    }
+
+    pub fn open_database(&self) -> Result<Connection, Error> {
+        crate::open_database_connection(&self.interface, &self.conf)
+    }
+
     pub fn wg_conf_path(&self) -> PathBuf {
         self.conf.config_path(&self.interface)
     }
@@ -243,6 +272,8 @@ pub fn create_cidr(db: &Connection, name: &str, cidr_str: &str) -> Result<Cidr,
             name: name.to_string(),
             cidr: cidr_str.parse()?,
             parent: Some(ROOT_CIDR_ID),
+            default_keepalive: None,
+            is_disabled: false,
         },
     )?;
 
@@ -261,19 +292,16 @@ pub fn peer_contents(
 ) -> Result<PeerContents, Error> {
     let public_key = KeyPair::generate().public;
 
-    Ok(PeerContents {
-        name: name.parse().map_err(|e: &str| anyhow!(e))?,
-        ip: ip_str.parse()?,
+    Ok(PeerContents::builder(
+        name.parse().map_err(|e: &str| anyhow!(e))?,
+        ip_str.parse()?,
         cidr_id,
-        public_key: public_key.to_base64(),
-        is_admin,
-        endpoint: None,
-        persistent_keepalive_interval: None,
-        is_disabled: false,
-        is_redeemed: true,
-        invite_expires: None,
-        candidates: vec![],
-    })
+        public_key.to_base64(),
+    )
+    .set_admin(is_admin)
+    .set_persistent_keepalive_interval(None)
+    .set_redeemed(true)
+    .build())
 }
 
 pub fn admin_peer_contents(name: &str, ip_str: &str) -> Result<PeerContents, Error> {
@@ -1,14 +1,18 @@
-use crate::{chmod, ensure_dirs_exist, Endpoint, Error, IoErrorContext, WrappedIoError};
+use crate::{
+    chmod, ensure_dirs_exist, write_atomic, write_atomic_new, Endpoint, Error, IoErrorContext, Mtu,
+    NetworkOpts, WrappedIoError,
+};
 use indoc::writedoc;
 use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 use std::{
-    fs::{File, OpenOptions},
+    fmt::{self, Display, Formatter},
+    fs::File,
     io::{self, Write},
     net::SocketAddr,
     path::{Path, PathBuf},
 };
-use wireguard_control::InterfaceName;
+use wireguard_control::{Backend, InterfaceName};
 
 #[derive(Clone, Deserialize, Serialize, Debug)]
 #[serde(rename_all = "kebab-case")]
@@ -18,6 +22,11 @@ pub struct InterfaceConfig {
 
     /// The necessary contact information for the server.
     pub server: ServerInfo,
+
+    /// Free-form keys and values for operators' own bookkeeping (e.g. an owner or a ticket
+    /// number), which innernet ignores but keeps whenever it rewrites the config.
+    #[serde(default, skip_serializing_if = "toml::Table::is_empty")]
+    pub metadata: toml::Table,
 }
 
 #[derive(Clone, Deserialize, Serialize, Debug)]
@@ -35,6 +44,72 @@ pub struct InterfaceInfo {
 
     /// The local listen port. A random port will be used if `None`.
     pub listen_port: Option<u16>,
+
+    /// The MTU of this interface, or "auto" to probe it, taking precedence over the `--mtu`
+    /// command-line option.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "mtu")]
+    pub mtu: Option<Mtu>,
+
+    /// The WireGuard backend of this interface, taking precedence over the `--backend`
+    /// command-line option.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "backend")]
+    pub backend: Option<Backend>,
+}
+
+/// (De)serializes a [`Backend`] by its name, as accepted by `--backend`.
+mod backend {
+    use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+    use wireguard_control::Backend;
+
+    pub fn serialize<S: Serializer>(
+        backend: &Option<Backend>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match backend {
+            Some(backend) => serializer.collect_str(backend),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Backend>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|name| name.parse().map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+mod mtu {
+    use crate::Mtu;
+    use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+
+    /// An MTU as written in the config file: a number, or "auto".
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawMtu {
+        Fixed(u32),
+        Named(String),
+    }
+
+    pub fn serialize<S: Serializer>(mtu: &Option<Mtu>, serializer: S) -> Result<S::Ok, S::Error> {
+        match mtu {
+            Some(Mtu::Fixed(mtu)) => serializer.serialize_u32(*mtu),
+            Some(Mtu::Auto) => serializer.serialize_str("auto"),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Mtu>, D::Error> {
+        Option::<RawMtu>::deserialize(deserializer)?
+            .map(|mtu| match mtu {
+                RawMtu::Fixed(mtu) => Ok(Mtu::Fixed(mtu)),
+                RawMtu::Named(name) => name.parse().map_err(D::Error::custom),
+            })
+            .transpose()
+    }
 }
 
 #[derive(Clone, Deserialize, Serialize, Debug)]
@@ -46,10 +121,88 @@ pub struct ServerInfo {
     /// The external internet endpoint to reach the server.
     pub external_endpoint: Endpoint,
 
+    /// Other endpoints the server can be reached at (e.g. through another uplink), tried in
+    /// order if the external endpoint doesn't answer.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alternate_endpoints: Vec<Endpoint>,
+
     /// An internal endpoint in the WireGuard network that hosts the coordination API.
     pub internal_endpoint: SocketAddr,
+
+    /// A secret that must be presented to the server to redeem the invitation, for networks
+    /// that require one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub join_token: Option<String>,
+
+    /// The WireGuard preshared key (base64) shared by every peer in the network, for networks
+    /// that use one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preshared_key: Option<String>,
 }
 
+/// The newest layout of config and invitation files this version of innernet understands.
+///
+/// Every layout written so far is version 1, which doesn't record its version: fields added
+/// since the first release are optional, so older files parse as they are. A file only needs a
+/// top-level `version` once a change can't be made that way, and `from_file` is where older
+/// versions would be converted to the current struct.
+pub const CONFIG_VERSION: u32 = 1;
+
+#[derive(Deserialize)]
+struct VersionProbe {
+    version: Option<u32>,
+}
+
+/// A config or invitation file that exists but couldn't be parsed, located as precisely as
+/// the TOML parser allows.
+#[derive(Debug)]
+pub struct InvalidInvitationError {
+    pub path: PathBuf,
+    /// 1-based line the problem was found on.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl InvalidInvitationError {
+    fn new(path: &Path, contents: &str, error: toml::de::Error) -> Self {
+        let start = error.span().map(|span| span.start.min(contents.len()));
+        let line = start.map(|start| contents[..start].matches('\n').count() + 1);
+
+        // A missing field is reported at the table it's missing from, so name it in full.
+        let mut message = error.message().trim().to_string();
+        let table = start.and_then(|start| {
+            let header = contents[start..].lines().next()?.trim();
+            header.strip_prefix('[')?.strip_suffix(']').map(str::trim)
+        });
+        if let (Some(table), Some(field)) = (table, message.strip_prefix("missing field `")) {
+            message = format!("missing field `{table}.{field}");
+        }
+
+        Self {
+            path: path.to_path_buf(),
+            line,
+            message,
+        }
+    }
+}
+
+impl Display for InvalidInvitationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid invitation file {}: {}",
+            self.path.display(),
+            self.message
+        )?;
+        if let Some(line) = self.line {
+            write!(f, " (line {line})")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for InvalidInvitationError {}
+
 impl InterfaceConfig {
     pub fn write_to(
         &self,
@@ -82,6 +235,7 @@ impl InterfaceConfig {
         Ok(())
     }
 
+    /// Writes the config to a new file, failing if one already exists at `path`.
     pub fn write_to_path<P: AsRef<Path>>(
         &self,
         path: P,
@@ -89,13 +243,10 @@ impl InterfaceConfig {
         mode: Option<u32>,
     ) -> Result<(), WrappedIoError> {
         let path = path.as_ref();
-        let mut target_file = OpenOptions::new()
-            .create_new(true)
-            .write(true)
-            .open(path)
-            .with_path(path)?;
-        self.write_to(&mut target_file, comments, mode)
-            .with_path(path)
+        write_atomic_new(path, mode.unwrap_or(0o600), |file| {
+            self.write_to(file, comments, None)
+        })
+        .with_path(path)
     }
 
     /// Overwrites the config file if it already exists.
@@ -105,16 +256,37 @@ impl InterfaceConfig {
         interface: &InterfaceName,
     ) -> Result<PathBuf, Error> {
         let path = Self::build_config_file_path(config_dir, interface)?;
-        File::create(&path)
-            .with_path(&path)?
-            .write_all(toml::to_string(self).unwrap().as_bytes())?;
+        write_atomic(&path, 0o600, |file| self.write_to(file, false, None)).with_path(&path)?;
         Ok(path)
     }
 
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
-        Ok(toml::from_str(
-            &std::fs::read_to_string(&path).with_path(path)?,
-        )?)
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).with_path(path)?;
+        Self::from_toml(path, &contents)
+    }
+
+    /// Parse a config that didn't come from a file, such as an invitation read from stdin.
+    /// `path` is only used to say where it came from in errors.
+    pub fn from_toml(path: &Path, contents: &str) -> Result<Self, Error> {
+        let probe: VersionProbe =
+            toml::from_str(contents).map_err(|e| InvalidInvitationError::new(path, contents, e))?;
+        let message = match probe.version.unwrap_or(1) {
+            1 => {
+                return toml::from_str(contents)
+                    .map_err(|e| InvalidInvitationError::new(path, contents, e).into())
+            },
+            0 => "invalid layout version 0, versions start at 1".to_string(),
+            version => format!(
+                "unsupported layout version {version} (this innernet understands up to {CONFIG_VERSION}), it may have been written by a newer innernet"
+            ),
+        };
+        Err(InvalidInvitationError {
+            path: path.to_path_buf(),
+            line: None,
+            message,
+        }
+        .into())
     }
 
     pub fn from_interface(config_dir: &Path, interface: &InterfaceName) -> Result<Self, Error> {
@@ -144,4 +316,233 @@ impl InterfaceInfo {
             .get_public()
             .to_base64())
     }
+
+    /// Apply this interface's own settings on top of the command-line network options.
+    pub fn network_opts(&self, network: NetworkOpts) -> NetworkOpts {
+        NetworkOpts {
+            mtu: self.mtu.or(network.mtu),
+            backend: self.backend.unwrap_or(network.backend),
+            ..network
+        }
+    }
+}
+
+impl ServerInfo {
+    pub fn network_preshared_key(&self) -> Result<Option<wireguard_control::Key>, Error> {
+        Ok(self
+            .preshared_key
+            .as_deref()
+            .map(wireguard_control::Key::from_base64)
+            .transpose()?)
+    }
+
+    /// Every endpoint the server can be reached at, primary first.
+    pub fn endpoints(&self) -> impl Iterator<Item = &Endpoint> {
+        std::iter::once(&self.external_endpoint).chain(&self.alternate_endpoints)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn config() -> InterfaceConfig {
+        InterfaceConfig {
+            interface: InterfaceInfo {
+                network_name: "test".into(),
+                address: "10.0.0.2/24".parse().unwrap(),
+                private_key: "4CNZorWVtohO64n6AAaH/JyFjIIgBFrfJK2SGtKjzEE=".into(),
+                listen_port: None,
+                mtu: None,
+                backend: None,
+            },
+            server: ServerInfo {
+                public_key: "4CNZorWVtohO64n6AAaH/JyFjIIgBFrfJK2SGtKjzEE=".into(),
+                external_endpoint: "1.2.3.4:51820".parse().unwrap(),
+                alternate_endpoints: vec![],
+                internal_endpoint: "10.0.0.1:51820".parse().unwrap(),
+                join_token: None,
+                preshared_key: None,
+            },
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_interrupted_write_keeps_original() {
+        let dir = tempfile::tempdir().unwrap();
+        let interface: InterfaceName = "test".parse().unwrap();
+        let path = config().write_to_interface(dir.path(), &interface).unwrap();
+        let original = std::fs::read_to_string(&path).unwrap();
+
+        // Simulate a write that fails halfway through, e.g. due to ENOSPC.
+        let result = write_atomic(&path, 0o600, |file| {
+            file.write_all(&original.as_bytes()[..original.len() / 2])?;
+            Err(io::Error::other("disk full"))
+        });
+        assert!(result.is_err());
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), original);
+        InterfaceConfig::from_file(&path).unwrap();
+        // The temporary file has been cleaned up.
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_metadata_survives_rewrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let interface: InterfaceName = "test".parse().unwrap();
+        let path = config().write_to_interface(dir.path(), &interface).unwrap();
+        assert!(!std::fs::read_to_string(&path)
+            .unwrap()
+            .contains("[metadata]"));
+
+        let mut contents = std::fs::read_to_string(&path).unwrap();
+        contents.push_str("\n[metadata]\nowner = \"ops\"\nticket = 1234\n");
+        std::fs::write(&path, contents).unwrap();
+
+        let mut config = InterfaceConfig::from_file(&path).unwrap();
+        config.interface.listen_port = Some(51820);
+        config.write_to_interface(dir.path(), &interface).unwrap();
+
+        let config = InterfaceConfig::from_file(&path).unwrap();
+        assert_eq!(config.metadata["owner"].as_str(), Some("ops"));
+        assert_eq!(config.metadata["ticket"].as_integer(), Some(1234));
+    }
+
+    #[test]
+    fn test_alternate_endpoints() {
+        let contents = toml::to_string(&config()).unwrap();
+        assert!(!contents.contains("alternate-endpoints"));
+
+        let mut config = config();
+        config.server.alternate_endpoints = vec!["5.6.7.8:51820".parse().unwrap()];
+        let config: InterfaceConfig = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+        let endpoints: Vec<String> = config.server.endpoints().map(ToString::to_string).collect();
+        assert_eq!(endpoints, ["1.2.3.4:51820", "5.6.7.8:51820"]);
+    }
+
+    #[test]
+    fn test_mtu() {
+        let contents = toml::to_string(&config()).unwrap();
+        assert!(!contents.contains("mtu"));
+
+        for mtu in [Mtu::Fixed(1420), Mtu::Auto] {
+            let mut config = config();
+            config.interface.mtu = Some(mtu);
+            let config: InterfaceConfig =
+                toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+            assert_eq!(config.interface.mtu, Some(mtu));
+        }
+
+        let contents = contents.replace("[server]", "mtu = \"big\"\n\n[server]");
+        assert!(toml::from_str::<InterfaceConfig>(&contents).is_err());
+    }
+
+    #[test]
+    fn test_write_to_path_never_replaces() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // A fresh path is written, with the requested mode.
+        let path = dir.path().join("invite.toml");
+        config().write_to_path(&path, true, Some(0o640)).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o640
+        );
+
+        // Neither a config written earlier nor some unrelated file is replaced, and no
+        // temporary file is left behind.
+        let other = dir.path().join("other.toml");
+        std::fs::write(&other, "existing").unwrap();
+        for (path, contents) in [(&path, written.as_str()), (&other, "existing")] {
+            let err = config().write_to_path(path, true, None).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+            assert_eq!(std::fs::read_to_string(path).unwrap(), contents);
+        }
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_invalid_invitation_names_field() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("invite.toml");
+        let contents = toml::to_string(&config()).unwrap();
+        let broken: String = contents
+            .lines()
+            .filter(|line| !line.starts_with("public-key"))
+            .map(|line| format!("{line}\n"))
+            .collect();
+        std::fs::write(&path, broken).unwrap();
+
+        let err = InterfaceConfig::from_file(&path).unwrap_err();
+        let err = err.downcast_ref::<InvalidInvitationError>().unwrap();
+        assert_eq!(err.message, "missing field `server.public-key`");
+        assert!(err.line.is_some());
+    }
+
+    #[test]
+    fn test_legacy_layouts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("invite.toml");
+
+        // The layout of the first releases, before mtu, join-token, preshared-key and metadata.
+        std::fs::write(
+            &path,
+            r#"
+                [interface]
+                network-name = "test"
+                address = "10.0.0.2/24"
+                private-key = "4CNZorWVtohO64n6AAaH/JyFjIIgBFrfJK2SGtKjzEE="
+
+                [server]
+                public-key = "4CNZorWVtohO64n6AAaH/JyFjIIgBFrfJK2SGtKjzEE="
+                external-endpoint = "1.2.3.4:51820"
+                internal-endpoint = "10.0.0.1:51820"
+            "#,
+        )
+        .unwrap();
+        let config = InterfaceConfig::from_file(&path).unwrap();
+        assert_eq!(config.interface.listen_port, None);
+        assert_eq!(config.server.join_token, None);
+        assert!(config.metadata.is_empty());
+
+        // The same layout, explicitly marked as version 1.
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("version = 1\n{contents}")).unwrap();
+        InterfaceConfig::from_file(&path).unwrap();
+
+        std::fs::write(&path, format!("version = 2\n{contents}")).unwrap();
+        let e = InterfaceConfig::from_file(&path).unwrap_err();
+        assert!(e.to_string().contains("unsupported layout version 2"));
+
+        std::fs::write(&path, format!("version = 0\n{contents}")).unwrap();
+        let e = InterfaceConfig::from_file(&path).unwrap_err();
+        assert!(e.to_string().contains("invalid layout version 0"));
+        assert!(!e.to_string().contains("newer innernet"));
+    }
+
+    #[test]
+    fn test_backend_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let interface: InterfaceName = "test".parse().unwrap();
+        let mut config = config();
+        config.interface.backend = Some(Backend::Userspace);
+        let path = config.write_to_interface(dir.path(), &interface).unwrap();
+        assert!(std::fs::read_to_string(&path)
+            .unwrap()
+            .contains("backend = \"userspace\""));
+
+        let config = InterfaceConfig::from_file(&path).unwrap();
+        assert_eq!(config.interface.backend, Some(Backend::Userspace));
+        assert_eq!(
+            config
+                .interface
+                .network_opts(NetworkOpts::default())
+                .backend,
+            Backend::Userspace
+        );
+    }
 }
@@ -1,10 +1,11 @@
 pub use anyhow::Error;
 use ipnet::IpNet;
 use std::{
-    fs::{self, File, Permissions},
+    ffi::OsString,
+    fs::{self, File, OpenOptions, Permissions},
     io,
     net::{IpAddr, Ipv6Addr},
-    os::unix::fs::PermissionsExt,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
     path::Path,
     time::Duration,
 };
@@ -21,6 +22,20 @@ pub use types::*;
 pub const REDEEM_TRANSITION_WAIT: Duration = Duration::from_secs(5);
 pub const PERSISTENT_KEEPALIVE_INTERVAL_SECS: u16 = 25;
 pub const INNERNET_PUBKEY_HEADER: &str = "X-Innernet-Server-Key";
+/// Invitations are bearer credentials until redeemed, so creating ones that outlive this asks for
+/// confirmation.
+pub const MAX_RECOMMENDED_INVITE_EXPIRATION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Resolve the persistent keepalive interval for a peer from its CIDR's default, where a
+/// default of 0 disables keepalive and no default falls back to
+/// [`PERSISTENT_KEEPALIVE_INTERVAL_SECS`].
+pub fn persistent_keepalive_interval(cidr_default: Option<u16>) -> Option<u16> {
+    match cidr_default {
+        Some(0) => None,
+        Some(seconds) => Some(seconds),
+        None => Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
+    }
+}
 
 pub fn ensure_dirs_exist(dirs: &[&Path]) -> Result<(), WrappedIoError> {
     for dir in dirs {
@@ -74,6 +89,72 @@ pub fn chmod(file: &File, new_mode: u32) -> Result<bool, io::Error> {
     Ok(updated)
 }
 
+/// Atomically replaces the file at `path` with whatever `write` writes, created with `mode`.
+///
+/// The contents are written to a temporary file in the same directory, which is fsync'd and
+/// then renamed over `path`, so an interrupted write never leaves a truncated file behind.
+pub fn write_atomic<F>(path: &Path, mode: u32, write: F) -> Result<(), io::Error>
+where
+    F: FnOnce(&mut File) -> Result<(), io::Error>,
+{
+    write_atomic_impl(path, mode, true, write)
+}
+
+/// Like [`write_atomic`], but fails with [`io::ErrorKind::AlreadyExists`] instead of replacing
+/// a file that's already at `path`.
+///
+/// The temporary file is hard-linked into place rather than renamed, since `link` never
+/// replaces its target, so a file created at `path` in the meantime is left alone.
+pub fn write_atomic_new<F>(path: &Path, mode: u32, write: F) -> Result<(), io::Error>
+where
+    F: FnOnce(&mut File) -> Result<(), io::Error>,
+{
+    write_atomic_impl(path, mode, false, write)
+}
+
+fn write_atomic_impl<F>(path: &Path, mode: u32, replace: bool, write: F) -> Result<(), io::Error>
+where
+    F: FnOnce(&mut File) -> Result<(), io::Error>,
+{
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path is missing a filename"))?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".tmp{}", std::process::id()));
+    let temp_path = dir.join(temp_name);
+
+    let result = (|| {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(mode)
+            .open(&temp_path)?;
+        // The mode passed to open() is subject to the umask and ignored for existing files.
+        chmod(&file, mode)?;
+        write(&mut file)?;
+        file.sync_all()?;
+        if replace {
+            fs::rename(&temp_path, path)?;
+        } else {
+            fs::hard_link(&temp_path, path)?;
+            fs::remove_file(&temp_path)?;
+        }
+        // Make sure the rename itself is durable.
+        File::open(dir)?.sync_all()
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
 #[cfg(target_os = "macos")]
 pub fn _get_local_addrs() -> Result<impl Iterator<Item = std::net::IpAddr>, io::Error> {
     use std::net::Ipv4Addr;
@@ -106,28 +187,52 @@ pub fn _get_local_addrs() -> Result<impl Iterator<Item = std::net::IpAddr>, io::
 #[cfg(target_os = "linux")]
 pub use netlink::get_local_addrs as _get_local_addrs;
 
-pub fn get_local_addrs() -> Result<impl Iterator<Item = std::net::IpAddr>, io::Error> {
+/// Up to 10 local addresses worth reporting as NAT traversal candidates, only of `family` if
+/// one is given. IPv6 ULAs (fc00::/7) are skipped unless `family` asks for IPv6 specifically,
+/// for peers that share a private IPv6 network.
+pub fn get_local_addrs(
+    family: Option<AddressFamily>,
+) -> Result<impl Iterator<Item = std::net::IpAddr>, io::Error> {
     // TODO(jake): this is temporary pending the stabilization of rust-lang/rust#27709
-    fn is_unicast_global(ip: &Ipv6Addr) -> bool {
+    fn is_unicast_global(ip: &Ipv6Addr, allow_unique_local: bool) -> bool {
         !((ip.segments()[0] & 0xff00) == 0xff00 // multicast
             || ip.is_loopback()
             || ip.is_unspecified()
             || ((ip.segments()[0] == 0x2001) && (ip.segments()[1] == 0xdb8)) // documentation
             || (ip.segments()[0] & 0xffc0) == 0xfe80 // unicast link local
-            || (ip.segments()[0] & 0xfe00) == 0xfc00) // unicast local
+            || (!allow_unique_local && (ip.segments()[0] & 0xfe00) == 0xfc00)) // unicast local
     }
 
+    let allow_unique_local = family == Some(AddressFamily::Ipv6);
     Ok(_get_local_addrs()?
-        .filter(|ip| {
+        .filter(move |ip| family.is_none_or(|family| family.contains(*ip)))
+        .filter(move |ip| {
             ip.is_ipv4()
                 || matches!(ip,
-            IpAddr::V6(v6) if is_unicast_global(v6))
+            IpAddr::V6(v6) if is_unicast_global(v6, allow_unique_local))
         })
         .take(10))
 }
 
+/// Check that no peer in `peers` has been assigned `ip` yet.
+pub fn ensure_ip_available<'a>(
+    peers: impl IntoIterator<Item = &'a Peer>,
+    ip: IpAddr,
+) -> Result<(), IpInUse> {
+    match peers.into_iter().find(|peer| peer.ip == ip) {
+        Some(peer) => Err(IpInUse {
+            ip,
+            peer: peer.name.to_string(),
+        }),
+        None => Ok(()),
+    }
+}
+
 pub trait IpNetExt {
     fn is_assignable(&self, ip: &IpAddr) -> bool;
+
+    /// The first assignable IP in the network for which `is_taken` returns false.
+    fn first_available_ip(&self, is_taken: impl Fn(&IpAddr) -> bool) -> Option<IpAddr>;
 }
 
 impl IpNetExt for IpNet {
@@ -140,4 +245,9 @@ impl IpNetExt for IpNet {
                 IpNet::V6(_) => self.prefix_len() >= 127 || ip != &self.network(),
             }
     }
+
+    fn first_available_ip(&self, is_taken: impl Fn(&IpAddr) -> bool) -> Option<IpAddr> {
+        self.hosts()
+            .find(|ip| self.is_assignable(ip) && !is_taken(ip))
+    }
 }
@@ -51,11 +51,16 @@ pub fn set_addr(interface: &InterfaceName, addr: IpNet) -> Result<(), io::Error>
                 ],
             )
         },
+        // Skip duplicate address detection, which would otherwise leave the address "tentative"
+        // for a while, making anything that binds to it right away (like innernet-server's
+        // listener) fail with EADDRNOTAVAIL. WireGuard interfaces have no link layer to detect
+        // duplicates on anyway.
         IpNet::V6(network) => (
             AddressFamily::Inet6,
-            vec![address::AddressAttribute::Address(IpAddr::V6(
-                network.addr(),
-            ))],
+            vec![
+                address::AddressAttribute::Address(IpAddr::V6(network.addr())),
+                address::AddressAttribute::Flags(address::AddressFlags::Nodad),
+            ],
         ),
     };
     let header = AddressHeader {
@@ -77,8 +82,7 @@ pub fn set_addr(interface: &InterfaceName, addr: IpNet) -> Result<(), io::Error>
     Ok(())
 }
 
-pub fn add_route(interface: &InterfaceName, cidr: IpNet) -> Result<bool, io::Error> {
-    let if_index = if_nametoindex(interface)?;
+fn route_message(cidr: IpNet, if_index: u32) -> RouteMessage {
     let (address_family, dst) = match cidr {
         IpNet::V4(network) => (
             AddressFamily::Inet,
@@ -101,6 +105,12 @@ pub fn add_route(interface: &InterfaceName, cidr: IpNet) -> Result<bool, io::Err
     let mut message = RouteMessage::default();
     message.header = header;
     message.attributes = vec![dst, route::RouteAttribute::Oif(if_index)];
+    message
+}
+
+pub fn add_route(interface: &InterfaceName, cidr: IpNet) -> Result<bool, io::Error> {
+    let if_index = if_nametoindex(interface)?;
+    let message = route_message(cidr, if_index);
 
     match netlink_request_rtnl(RouteNetlinkMessage::NewRoute(message), None) {
         Ok(_) => {
@@ -115,6 +125,67 @@ pub fn add_route(interface: &InterfaceName, cidr: IpNet) -> Result<bool, io::Err
     }
 }
 
+/// Delete a route previously added by [`add_route`]. Only the route through this interface is
+/// removed, so another interface's route to the same CIDR is left alone.
+///
+/// Returns true if a route was removed, false if there was no such route.
+pub fn delete_route(interface: &InterfaceName, cidr: IpNet) -> Result<bool, io::Error> {
+    let if_index = if_nametoindex(interface)?;
+    let message = route_message(cidr, if_index);
+
+    match netlink_request_rtnl(RouteNetlinkMessage::DelRoute(message), None) {
+        Ok(_) => {
+            log::debug!("deleted route {} from interface {}", cidr, interface);
+            Ok(true)
+        },
+        Err(e) if e.raw_os_error() == Some(libc::ESRCH) => {
+            log::debug!("route {} didn't exist.", cidr);
+            Ok(false)
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// List the routes added by [`add_route`] that point to this interface.
+pub fn get_routes(interface: &InterfaceName) -> Result<Vec<IpNet>, io::Error> {
+    let if_index = if_nametoindex(interface)?;
+    let route_responses = netlink_request_rtnl(
+        RouteNetlinkMessage::GetRoute(RouteMessage::default()),
+        Some(NLM_F_DUMP | NLM_F_REQUEST),
+    )?;
+    let routes = route_responses
+        .into_iter()
+        .filter_map(|response| match response {
+            NetlinkMessage {
+                payload: NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewRoute(route)),
+                ..
+            } => Some(route),
+            _ => None,
+        })
+        .filter(|route| {
+            route.header.table == RouteHeader::RT_TABLE_MAIN
+                && route.header.protocol == route::RouteProtocol::Boot
+                && route
+                    .attributes
+                    .contains(&route::RouteAttribute::Oif(if_index))
+        })
+        .filter_map(|route| {
+            let prefix_len = route.header.destination_prefix_length;
+            route.attributes.iter().find_map(|nla| match nla {
+                route::RouteAttribute::Destination(route::RouteAddress::Inet(addr)) => {
+                    IpNet::new(IpAddr::V4(*addr), prefix_len).ok()
+                },
+                route::RouteAttribute::Destination(route::RouteAddress::Inet6(addr)) => {
+                    IpNet::new(IpAddr::V6(*addr), prefix_len).ok()
+                },
+                _ => None,
+            })
+        })
+        .collect();
+
+    Ok(routes)
+}
+
 fn get_links() -> Result<Vec<String>, io::Error> {
     let link_responses = netlink_request_rtnl(
         RouteNetlinkMessage::GetLink(LinkMessage::default()),
@@ -1,11 +1,12 @@
 use crate::{
     interface_config::{InterfaceConfig, InterfaceInfo, ServerInfo},
-    AddCidrOpts, AddDeleteAssociationOpts, AddPeerOpts, Association, Cidr, CidrContents, CidrTree,
-    DeleteCidrOpts, EnableDisablePeerOpts, Endpoint, Error, Hostname, IpNetExt, ListenPortOpts,
-    OverrideEndpointOpts, Peer, PeerContents, RenameCidrOpts, RenamePeerOpts,
+    persistent_keepalive_interval, AddCidrOpts, AddDeleteAssociationOpts, AddPeerOpts, Association,
+    Cidr, CidrContents, CidrTree, DeleteCidrOpts, DeletePeerOpts, EnableDisableCidrOpts,
+    EnableDisablePeerOpts, Endpoint, Error, Hostname, IpNetExt, KeepaliveOpts, ListenPortOpts,
+    OverrideEndpointOpts, Peer, PeerContents, RenameCidrOpts, RenamePeerOpts, Timestring,
     PERSISTENT_KEEPALIVE_INTERVAL_SECS,
 };
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use colored::*;
 use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
 use ipnet::IpNet;
@@ -17,14 +18,55 @@ use std::{
     io,
     net::SocketAddr,
     str::FromStr,
-    time::SystemTime,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime},
 };
 use wireguard_control::{InterfaceName, KeyPair};
 
 pub static THEME: Lazy<ColorfulTheme> = Lazy::new(ColorfulTheme::default);
 
+static NON_INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Never prompt, even when a TTY is connected, so that any value missing from the command
+/// line is an error instead.
+pub fn set_non_interactive(non_interactive: bool) {
+    NON_INTERACTIVE.store(non_interactive, Ordering::Relaxed);
+}
+
+pub fn is_interactive() -> bool {
+    !NON_INTERACTIVE.load(Ordering::Relaxed) && atty::is(atty::Stream::Stdin)
+}
+
+/// Check up front that every flag a flow would otherwise prompt for was supplied when
+/// prompting isn't possible, so the error names all of them at once instead of failing at
+/// the first prompt. Each entry is a flag (or a description of alternatives) and whether
+/// it was supplied.
+pub fn ensure_flags(flags: &[(&str, bool)]) -> Result<(), Error> {
+    check_flags(is_interactive(), flags)
+}
+
+fn check_flags(interactive: bool, flags: &[(&str, bool)]) -> Result<(), Error> {
+    if interactive {
+        return Ok(());
+    }
+
+    let missing: Vec<_> = flags
+        .iter()
+        .filter(|(_, supplied)| !supplied)
+        .map(|(flag, _)| *flag)
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "can't prompt for missing values when running non-interactively, please supply: {}",
+            missing.join(", ")
+        ))
+    }
+}
+
 pub fn ensure_interactive(prompt: &str) -> Result<(), io::Error> {
-    if atty::is(atty::Stream::Stdin) {
+    if is_interactive() {
         Ok(())
     } else {
         Err(io::Error::new(
@@ -76,6 +118,13 @@ where
 
 /// Bring up a prompt to create a new CIDR. Returns the peer request.
 pub fn add_cidr(cidrs: &[Cidr], request: &AddCidrOpts) -> Result<Option<CidrContents>, Error> {
+    ensure_flags(&[
+        ("--parent", request.parent.is_some()),
+        ("--name", request.name.is_some()),
+        ("--cidr", request.cidr.is_some()),
+        ("--yes", request.yes),
+    ])?;
+
     let parent_cidr = if let Some(ref parent_name) = request.parent {
         cidrs
             .iter()
@@ -101,6 +150,8 @@ pub fn add_cidr(cidrs: &[Cidr], request: &AddCidrOpts) -> Result<Option<CidrCont
         name: name.to_string(),
         cidr,
         parent: Some(parent_cidr.id),
+        default_keepalive: request.default_keepalive,
+        is_disabled: false,
     };
 
     Ok(
@@ -117,6 +168,12 @@ pub fn rename_cidr(
     cidrs: &[Cidr],
     args: &RenameCidrOpts,
 ) -> Result<Option<(CidrContents, String)>, Error> {
+    ensure_flags(&[
+        ("--name", args.name.is_some()),
+        ("--new-name", args.new_name.is_some()),
+        ("--yes", args.yes),
+    ])?;
+
     let old_cidr = if let Some(ref name) = args.name {
         cidrs
             .iter()
@@ -157,6 +214,8 @@ pub fn rename_cidr(
 
 /// Bring up a prompt to delete a CIDR. Returns the peer request.
 pub fn delete_cidr(cidrs: &[Cidr], peers: &[Peer], request: &DeleteCidrOpts) -> Result<i64, Error> {
+    ensure_flags(&[("--name", request.name.is_some()), ("--yes", request.yes)])?;
+
     let eligible_cidrs: Vec<_> = cidrs
         .iter()
         .filter(|cidr| {
@@ -257,6 +316,12 @@ pub fn add_association<'a>(
     cidrs: &'a [Cidr],
     args: &AddDeleteAssociationOpts,
 ) -> Result<Option<(&'a Cidr, &'a Cidr)>, Error> {
+    ensure_flags(&[
+        ("<cidr1>", args.cidr1.is_some()),
+        ("<cidr2>", args.cidr2.is_some()),
+        ("--yes", args.yes),
+    ])?;
+
     let cidr1 = find_or_prompt_cidr(cidrs, &args.cidr1, "First CIDR")?;
     let cidr2 = find_or_prompt_cidr(cidrs, &args.cidr2, "Second CIDR")?;
 
@@ -280,6 +345,12 @@ pub fn delete_association<'a>(
     cidrs: &'a [Cidr],
     args: &AddDeleteAssociationOpts,
 ) -> Result<Option<&'a Association>, Error> {
+    ensure_flags(&[
+        ("<cidr1>", args.cidr1.is_some()),
+        ("<cidr2>", args.cidr2.is_some()),
+        ("--yes", args.yes),
+    ])?;
+
     let association = choose_association(associations, cidrs, args)?;
 
     Ok(
@@ -297,6 +368,19 @@ pub fn add_peer(
     cidr_tree: &CidrTree,
     args: &AddPeerOpts,
 ) -> Result<Option<(PeerContents, KeyPair, String, File)>, Error> {
+    // The IP, invite expiration, and invitation path fall back to the defaults the prompts
+    // would have offered when running non-interactively.
+    ensure_flags(&[
+        ("--cidr", args.cidr.is_some()),
+        ("--name", args.name.is_some()),
+        ("--admin or --viewer", args.admin.is_some() || args.viewer),
+        ("--yes", args.yes),
+    ])?;
+    if args.viewer && args.admin == Some(true) {
+        bail!("a peer can't be both an admin and a viewer");
+    }
+    let interactive = is_interactive();
+
     let leaves = cidr_tree.leaves();
 
     let cidr = if let Some(ref parent_name) = args.cidr {
@@ -308,24 +392,18 @@ pub fn add_peer(
         choose_cidr(&leaves[..], "Eligible CIDRs for peer")?
     };
 
-    let mut available_ip = None;
-    let candidate_ips = cidr.hosts().filter(|ip| cidr.is_assignable(ip));
-    for ip in candidate_ips {
-        if !peers.iter().any(|peer| peer.ip == ip) {
-            available_ip = Some(ip);
-            break;
-        }
-    }
-
-    let available_ip = available_ip.expect("No IPs in this CIDR are avavilable");
+    let available_ip = cidr
+        .first_available_ip(|ip| peers.iter().any(|peer| &peer.ip == ip))
+        .expect("No IPs in this CIDR are avavilable");
 
     let ip = if let Some(ip) = args.ip {
         ip
-    } else if args.auto_ip {
+    } else if args.auto_ip || !interactive {
         available_ip
     } else {
         input("IP", Prefill::Default(available_ip))?
     };
+    crate::ensure_ip_available(peers, ip)?;
 
     let name = if let Some(ref name) = args.name {
         name.clone()
@@ -335,42 +413,58 @@ pub fn add_peer(
 
     let is_admin = if let Some(is_admin) = args.admin {
         is_admin
+    } else if args.viewer {
+        false
     } else {
         confirm(&format!("Make {name} an admin?"))?
     };
 
+    let default_invite_expires: Timestring = "14d".parse().map_err(|s: &str| anyhow!(s))?;
     let invite_expires = if let Some(ref invite_expires) = args.invite_expires {
         invite_expires.clone()
+    } else if !interactive {
+        default_invite_expires
     } else {
         input(
             "Invite expires after",
-            Prefill::Default("14d".parse().map_err(|s: &str| anyhow!(s))?),
+            Prefill::Default(default_invite_expires),
         )?
     };
+    let invite_expires = Duration::from(invite_expires);
+    if invite_expires.is_zero() {
+        bail!("the invitation must expire some time in the future.");
+    }
+    if invite_expires > crate::MAX_RECOMMENDED_INVITE_EXPIRATION {
+        let message = format!(
+            "The invitation won't expire for {} days, and anyone holding it can join the network until it's redeemed.",
+            invite_expires.as_secs() / (24 * 60 * 60)
+        );
+        if args.yes {
+            log::warn!("{}", message);
+        } else if !confirm(&format!("{message} Continue?"))? {
+            return Ok(None);
+        }
+    }
 
+    let default_save_path = format!("{name}.toml");
     let invite_save_path = if let Some(ref location) = args.save_config {
         location.clone()
+    } else if !interactive {
+        default_save_path
     } else {
         input(
             "Save peer invitation file to",
-            Prefill::Default(format!("{name}.toml")),
+            Prefill::Default(default_save_path),
         )?
     };
 
     let default_keypair = KeyPair::generate();
-    let peer_request = PeerContents {
-        name,
-        ip,
-        cidr_id: cidr.id,
-        public_key: default_keypair.public.to_base64(),
-        endpoint: None,
-        is_admin,
-        is_disabled: false,
-        is_redeemed: false,
-        persistent_keepalive_interval: Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
-        invite_expires: Some(SystemTime::now() + invite_expires.into()),
-        candidates: vec![],
-    };
+    let peer_request = PeerContents::builder(name, ip, cidr.id, default_keypair.public.to_base64())
+        .set_admin(is_admin)
+        .set_viewer(args.viewer)
+        .set_persistent_keepalive_interval(persistent_keepalive_interval(cidr.default_keepalive))
+        .set_invite_expires(SystemTime::now() + invite_expires)
+        .build();
 
     Ok(
         if args.yes || confirm(&format!("Create peer {}?", peer_request.name.yellow()))? {
@@ -391,6 +485,12 @@ pub fn rename_peer(
     peers: &[Peer],
     args: &RenamePeerOpts,
 ) -> Result<Option<(PeerContents, Hostname)>, Error> {
+    ensure_flags(&[
+        ("--name", args.name.is_some()),
+        ("--new-name", args.new_name.is_some()),
+        ("--yes", args.yes),
+    ])?;
+
     let eligible_peers = peers
         .iter()
         .filter(|p| &*p.name != "innernet-server")
@@ -436,6 +536,96 @@ pub fn rename_peer(
     )
 }
 
+/// Presents a selection and confirmation of eligible CIDRs for either disabling or enabling,
+/// and returns back the selected CIDR.
+pub fn enable_or_disable_cidr(
+    cidrs: &[Cidr],
+    args: &EnableDisableCidrOpts,
+    enable: bool,
+) -> Result<Option<Cidr>, Error> {
+    ensure_flags(&[("--name", args.name.is_some()), ("--yes", args.yes)])?;
+
+    let eligible_cidrs: Vec<_> = cidrs
+        .iter()
+        .filter(|cidr| cidr.is_disabled == enable)
+        .collect();
+
+    let cidr = if let Some(ref name) = args.name {
+        eligible_cidrs
+            .into_iter()
+            .find(|cidr| &cidr.name == name)
+            .ok_or_else(|| {
+                anyhow!(
+                    "CIDR '{}' does not exist or is already {}abled",
+                    name,
+                    if enable { "en" } else { "dis" }
+                )
+            })?
+    } else {
+        let cidr_selection: Vec<_> = eligible_cidrs
+            .iter()
+            .map(|cidr| format!("{} ({})", &cidr.name, &cidr.cidr))
+            .collect();
+        let (index, _) = select(
+            &format!("CIDR to {}able", if enable { "en" } else { "dis" }),
+            &cidr_selection,
+        )?;
+        eligible_cidrs[index]
+    };
+
+    Ok(
+        if args.yes
+            || confirm(&format!(
+                "{}able CIDR {}?",
+                if enable { "En" } else { "Dis" },
+                cidr.name.yellow()
+            ))?
+        {
+            Some(cidr.clone())
+        } else {
+            None
+        },
+    )
+}
+
+/// Choose a peer to delete, returning `None` if deletion isn't confirmed.
+pub fn delete_peer(peers: &[Peer], args: &DeletePeerOpts) -> Result<Option<Peer>, Error> {
+    ensure_flags(&[("--name", args.name.is_some()), ("--yes", args.yes)])?;
+
+    // The server can't delete itself.
+    let eligible_peers: Vec<_> = peers
+        .iter()
+        .filter(|peer| &*peer.name != "innernet-server")
+        .collect();
+
+    let peer = if let Some(ref name) = args.name {
+        eligible_peers
+            .into_iter()
+            .find(|p| &p.name == name)
+            .ok_or_else(|| anyhow!("Peer '{}' does not exist", name))?
+    } else {
+        let peer_selection: Vec<_> = eligible_peers
+            .iter()
+            .map(|peer| format!("{} ({})", &peer.name, &peer.ip))
+            .collect();
+        let (index, _) = select("Peer to delete", &peer_selection)?;
+        eligible_peers[index]
+    };
+
+    Ok(
+        if args.yes
+            || confirm(&format!(
+                "Delete peer {}? Its name and IP stay reserved until it's purged.",
+                peer.name.yellow()
+            ))?
+        {
+            Some(peer.clone())
+        } else {
+            None
+        },
+    )
+}
+
 /// Presents a selection and confirmation of eligible peers for either disabling or enabling,
 /// and returns back the ID of the selected peer.
 pub fn enable_or_disable_peer(
@@ -443,6 +633,8 @@ pub fn enable_or_disable_peer(
     args: &EnableDisablePeerOpts,
     enable: bool,
 ) -> Result<Option<Peer>, Error> {
+    ensure_flags(&[("--name", args.name.is_some()), ("--yes", args.yes)])?;
+
     let enabled_peers: Vec<_> = peers
         .iter()
         .filter(|peer| enable && peer.is_disabled || !enable && !peer.is_disabled)
@@ -480,51 +672,82 @@ pub fn enable_or_disable_peer(
     )
 }
 
-/// Confirm and write a innernet invitation file after a peer has been created.
+/// Write the `invitation` built by [`peer_invitation`] for `peer` to `target_file`.
 pub fn write_peer_invitation(
     target_file: (&mut File, &str),
+    peer: &Peer,
+    invitation: InterfaceConfig,
+) -> Result<(), Error> {
+    invitation.write_to(target_file.0, true, None)?;
+
+    println!(
+        "\nPeer \"{}\" added\n\
+         Peer invitation file written to {}\n\
+         Please send it to them securely (eg. via magic-wormhole) \
+         to bootstrap them onto the network.",
+        peer.name.bold(),
+        target_file.1.bold()
+    );
+
+    Ok(())
+}
+
+/// Build the invitation a newly created peer uses to join the network. Only the alternate
+/// endpoints an admin configured are included, not the server's local addresses, since the
+/// new peer tries each one in turn before giving up.
+#[allow(clippy::too_many_arguments)]
+pub fn peer_invitation(
     network_name: &InterfaceName,
     peer: &Peer,
     server_peer: &Peer,
+    alternate_endpoints: &[Endpoint],
     root_cidr: &Cidr,
     keypair: KeyPair,
     server_api_addr: &SocketAddr,
-) -> Result<(), Error> {
-    let peer_invitation = InterfaceConfig {
+    preshared_key: Option<String>,
+) -> Result<InterfaceConfig, Error> {
+    let external_endpoint = server_peer
+        .endpoint
+        .clone()
+        .expect("The innernet server should have a WireGuard endpoint");
+    let alternate_endpoints = alternate_endpoints
+        .iter()
+        .filter(|endpoint| **endpoint != external_endpoint)
+        .cloned()
+        .collect();
+    Ok(InterfaceConfig {
         interface: InterfaceInfo {
             network_name: network_name.to_string(),
             private_key: keypair.private.to_base64(),
             address: IpNet::new(peer.ip, root_cidr.prefix_len())?,
             listen_port: None,
+            mtu: None,
+            backend: None,
         },
         server: ServerInfo {
-            external_endpoint: server_peer
-                .endpoint
-                .clone()
-                .expect("The innernet server should have a WireGuard endpoint"),
+            external_endpoint,
+            alternate_endpoints,
             internal_endpoint: *server_api_addr,
+            join_token: None,
             public_key: server_peer.public_key.clone(),
+            preshared_key,
         },
-    };
-
-    peer_invitation.write_to(target_file.0, true, None)?;
-
-    println!(
-        "\nPeer \"{}\" added\n\
-         Peer invitation file written to {}\n\
-         Please send it to them securely (eg. via magic-wormhole) \
-         to bootstrap them onto the network.",
-        peer.name.bold(),
-        target_file.1.bold()
-    );
-
-    Ok(())
+        metadata: Default::default(),
+    })
 }
 
 pub fn set_listen_port(
     interface: &InterfaceInfo,
     args: ListenPortOpts,
 ) -> Result<Option<Option<u16>>, Error> {
+    ensure_flags(&[
+        (
+            "--listen-port or --unset",
+            args.listen_port.is_some() || args.unset,
+        ),
+        ("--yes", args.yes),
+    ])?;
+
     let listen_port = if let Some(listen_port) = args.listen_port {
         Some(listen_port)
     } else if !args.unset {
@@ -558,13 +781,42 @@ pub fn set_listen_port(
     }
 }
 
-pub fn ask_endpoint(listen_port: u16) -> Result<Endpoint, Error> {
+pub fn set_keepalive(args: &KeepaliveOpts) -> Result<Option<u16>, Error> {
+    ensure_flags(&[("--seconds", args.seconds.is_some()), ("--yes", args.yes)])?;
+
+    let seconds = if let Some(seconds) = args.seconds {
+        seconds
+    } else {
+        input(
+            "Persistent keepalive interval in seconds (0 to disable)",
+            Prefill::Default(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
+        )?
+    };
+
+    let targets = if args.all { "all peers" } else { "the server" };
+    let prompt = if seconds == 0 {
+        format!("Disable persistent keepalive for {targets}?")
+    } else {
+        format!("Set persistent keepalive for {targets} to {seconds}s?")
+    };
+
+    if args.yes || confirm(&prompt)? {
+        Ok(Some(seconds))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Ask for an external endpoint, optionally auto-detecting the public IP, preferring the address
+/// family in `preference` if both are available.
+pub fn ask_endpoint(listen_port: u16, preference: Preference) -> Result<Endpoint, Error> {
+    ensure_interactive("External endpoint")?;
     let external_ip = if Confirm::with_theme(&*THEME)
         .wait_for_newline(true)
-        .with_prompt("Auto-detect external endpoint IP address (via a DNS query to 1.1.1.1)?")
+        .with_prompt("Auto-detect external endpoint IP address (via a DNS query to Cloudflare)?")
         .interact()?
     {
-        publicip::get_any(Preference::Ipv4)
+        publicip::get_any(preference)
     } else {
         None
     };
@@ -581,10 +833,13 @@ pub fn ask_endpoint(listen_port: u16) -> Result<Endpoint, Error> {
 pub fn override_endpoint(
     args: &OverrideEndpointOpts,
     listen_port: u16,
+    preference: Preference,
 ) -> Result<Option<Endpoint>, Error> {
+    ensure_flags(&[("--endpoint", args.endpoint.is_some()), ("--yes", args.yes)])?;
+
     let endpoint = match &args.endpoint {
         Some(endpoint) => endpoint.clone(),
-        None => ask_endpoint(listen_port)?,
+        None => ask_endpoint(listen_port, preference)?,
     };
     if args.yes || confirm(&format!("Set external endpoint to {endpoint}?"))? {
         Ok(Some(endpoint))
@@ -594,5 +849,21 @@ pub fn override_endpoint(
 }
 
 pub fn unset_override_endpoint(args: &OverrideEndpointOpts) -> Result<bool, Error> {
+    ensure_flags(&[("--yes", args.yes)])?;
     Ok(args.yes || confirm("Unset external endpoint to enable automatic endpoint discovery?")?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_flags_lists_every_missing_flag() {
+        let flags = [("--name", false), ("--cidr", true), ("--yes", false)];
+        assert!(check_flags(true, &flags).is_ok());
+        assert!(check_flags(false, &[("--name", true), ("--yes", true)]).is_ok());
+
+        let message = check_flags(false, &flags).unwrap_err().to_string();
+        assert!(message.ends_with("--name, --yes"), "{}", message);
+    }
+}
@@ -1,7 +1,7 @@
-use anyhow::{anyhow, Error};
+use anyhow::{anyhow, bail, Error};
 use clap::{
     builder::{PossibleValuesParser, TypedValueParser},
-    Args,
+    Args, ValueEnum,
 };
 use ipnet::IpNet;
 use once_cell::sync::Lazy;
@@ -44,6 +44,48 @@ impl FromStr for Interface {
     }
 }
 
+/// Names the system or other software commonly give their own interfaces, e.g. `lo`, `eth0`,
+/// `enp0s3` or `wg0`.
+static SYSTEM_INTERFACE_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"^(lo[0-9]*|(eth|wlan|wg|tun|tap|utun|br|docker|virbr|bond|vlan)[0-9]+|(en|wl|ww)[opsx][0-9a-f].*|veth.*)$",
+    )
+    .unwrap()
+});
+
+impl Interface {
+    /// Whether a network interface with this name already exists on the system.
+    pub fn exists(&self) -> bool {
+        std::ffi::CString::new(self.to_string())
+            .map(|name| unsafe { libc::if_nametoindex(name.as_ptr()) } != 0)
+            .unwrap_or(false)
+    }
+
+    /// Whether the name follows a scheme the system or other software uses for its own
+    /// interfaces, so an interface by that name may show up later and be mistaken for this one.
+    pub fn looks_like_system_interface(&self) -> bool {
+        SYSTEM_INTERFACE_REGEX.is_match(&self.to_string())
+    }
+
+    /// Refuse names of interfaces that already exist on the system, and warn about names that
+    /// look like they belong to one.
+    pub fn check_reserved(&self) -> Result<(), Error> {
+        if self.exists() {
+            bail!(
+                "a network interface named \"{}\" already exists on this system, pick another name.",
+                self
+            );
+        }
+        if self.looks_like_system_interface() {
+            log::warn!(
+                "\"{}\" looks like the name of a system interface, consider picking another name.",
+                self
+            );
+        }
+        Ok(())
+    }
+}
+
 impl Deref for Interface {
     type Target = InterfaceName;
 
@@ -58,7 +100,7 @@ impl Display for Interface {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 /// An external endpoint that supports both IP and domain name hosts.
 pub struct Endpoint {
     host: Host,
@@ -137,8 +179,28 @@ impl Display for Endpoint {
 }
 
 impl Endpoint {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The same host, on a different port.
+    pub fn with_port(&self, port: u16) -> Self {
+        Self {
+            host: self.host.clone(),
+            port,
+        }
+    }
+
+    /// Resolve the endpoint to an address. A failed DNS lookup is reported as
+    /// [`io::ErrorKind::AddrNotAvailable`], like a name without any addresses.
     pub fn resolve(&self) -> Result<SocketAddr, io::Error> {
-        let mut addrs = self.to_string().to_socket_addrs()?;
+        let mut addrs = self.to_string().to_socket_addrs().map_err(|e| {
+            if e.kind() == io::ErrorKind::InvalidInput {
+                e
+            } else {
+                io::Error::new(io::ErrorKind::AddrNotAvailable, e)
+            }
+        })?;
         addrs.next().ok_or_else(|| {
             io::Error::new(
                 io::ErrorKind::AddrNotAvailable,
@@ -155,6 +217,15 @@ pub enum EndpointContents {
     Unset,
 }
 
+/// The fields a peer may change about itself, sent to `PUT /user/self`.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PeerSelfContents {
+    pub description: Option<String>,
+}
+
+/// The longest description a peer can have, in characters.
+pub const MAX_PEER_DESCRIPTION_LEN: usize = 256;
+
 impl From<EndpointContents> for Option<Endpoint> {
     fn from(endpoint: EndpointContents) -> Self {
         match endpoint {
@@ -179,6 +250,18 @@ pub struct AssociationContents {
     pub cidr_id_2: i64,
 }
 
+/// A change an admin made to the network, as recorded in the server's audit log.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub id: i64,
+    /// Seconds since the UNIX epoch.
+    pub timestamp: u64,
+    pub actor_ip: IpAddr,
+    pub actor_public_key: String,
+    pub action: String,
+    pub target: String,
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Association {
     pub id: i64,
@@ -200,6 +283,14 @@ pub struct CidrContents {
     pub name: String,
     pub cidr: IpNet,
     pub parent: Option<i64>,
+    /// The persistent keepalive interval (in seconds) for peers in this CIDR, where 0 disables
+    /// keepalive. If unset, [`crate::PERSISTENT_KEEPALIVE_INTERVAL_SECS`] is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_keepalive: Option<u16>,
+    /// A disabled CIDR's peers (and those of its children) are left out of every peer's view
+    /// of the network until it's enabled again.
+    #[serde(default)]
+    pub is_disabled: bool,
 }
 
 impl Deref for CidrContents {
@@ -248,11 +339,25 @@ impl<'a> std::ops::Deref for CidrTree<'a> {
 
 impl<'a> CidrTree<'a> {
     pub fn new(cidrs: &'a [Cidr]) -> Self {
+        Self::try_new(cidrs).expect("failed to find root CIDR")
+    }
+
+    /// Like [`CidrTree::new`], but fails instead of panicking if there's no single root CIDR,
+    /// i.e. the one with the shortest prefix.
+    pub fn try_new(cidrs: &'a [Cidr]) -> Result<Self, Error> {
         let root = cidrs
             .iter()
             .min_by_key(|c| c.cidr.prefix_len())
-            .expect("failed to find root CIDR");
-        Self::with_root(cidrs, root)
+            .ok_or_else(|| anyhow!("no CIDRs to build a tree from"))?;
+        let roots: Vec<_> = cidrs
+            .iter()
+            .filter(|c| c.cidr.prefix_len() == root.cidr.prefix_len())
+            .map(|c| format!("{} ({})", c.name, c.cidr))
+            .collect();
+        if roots.len() > 1 {
+            bail!("ambiguous root CIDR, could be any of {}", roots.join(", "));
+        }
+        Ok(Self::with_root(cidrs, root))
     }
 
     pub fn with_root(cidrs: &'a [Cidr], root: &'a Cidr) -> Self {
@@ -279,11 +384,113 @@ impl<'a> CidrTree<'a> {
             self.children().flat_map(|child| child.leaves()).collect()
         }
     }
+
+    /// The IDs of the CIDR with `id` and each of its ancestors, nearest first.
+    fn ancestry(&self, id: i64) -> Vec<i64> {
+        let mut ancestry = vec![id];
+        while let Some(parent) = self
+            .cidrs
+            .iter()
+            .find(|cidr| Some(cidr.id) == ancestry.last().copied())
+            .and_then(|cidr| cidr.parent)
+        {
+            if ancestry.contains(&parent) {
+                break;
+            }
+            ancestry.push(parent);
+        }
+        ancestry
+    }
+
+    /// Why `from` can reach `to`, mirroring the server's rules for which peers it hands out in
+    /// `/user/state`, or `None` if it can't.
+    ///
+    /// Peers don't get to see associations, so without them (`None`) any peer that isn't
+    /// reachable some other way is assumed to be reachable through an association, which holds
+    /// for the peers a client was sent by the server.
+    pub fn reachability(
+        &self,
+        associations: Option<&[Association]>,
+        from: &Peer,
+        to: &Peer,
+    ) -> Option<Reachability> {
+        let to_ancestry = self.ancestry(to.cidr_id);
+        if to_ancestry.contains(&from.cidr_id) {
+            return Some(Reachability::SameCidr);
+        }
+        if to_ancestry.contains(&INFRA_CIDR_ID) {
+            return Some(Reachability::Infrastructure);
+        }
+
+        let Some(associations) = associations else {
+            return Some(Reachability::Associated);
+        };
+        let from_ancestry = self.ancestry(from.cidr_id);
+        associations
+            .iter()
+            .any(|association| {
+                let (a, b) = (association.cidr_id_1, association.cidr_id_2);
+                (from_ancestry.contains(&a) && to_ancestry.contains(&b))
+                    || (from_ancestry.contains(&b) && to_ancestry.contains(&a))
+            })
+            .then_some(Reachability::Associated)
+    }
+
+    /// The peers among `peers` that `from` can reach, along with why.
+    pub fn reachable_peers<'p>(
+        &self,
+        associations: Option<&[Association]>,
+        from: &Peer,
+        peers: &'p [Peer],
+    ) -> Vec<(&'p Peer, Reachability)> {
+        peers
+            .iter()
+            .filter(|peer| peer.id != from.id)
+            .filter_map(|peer| Some((peer, self.reachability(associations, from, peer)?)))
+            .collect()
+    }
+}
+
+/// The ID of the CIDR the server creates for itself, whose peers every peer can reach.
+pub const INFRA_CIDR_ID: i64 = 2;
+
+/// Why one peer is allowed to reach another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reachability {
+    /// The other peer is in the same CIDR, or one nested in it.
+    SameCidr,
+    /// The other peer is part of the network's infrastructure, like the server.
+    Infrastructure,
+    /// The other peer is in a CIDR associated with this peer's CIDR or one of its parents.
+    Associated,
+}
+
+impl Display for Reachability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::SameCidr => "same cidr",
+            Self::Infrastructure => "infrastructure",
+            Self::Associated => "associated",
+        })
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub struct RedeemContents {
     pub public_key: String,
+
+    /// The network's join token, if it requires one for redemption.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub join_token: Option<String>,
+}
+
+/// A request to replace the current peer's public key, signed with the old one.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RotateKeyContents {
+    pub public_key: String,
+
+    /// The old private key's signature over the rotation, from [`KeyRotation::sign`].
+    pub signature: String,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Args)]
@@ -299,6 +506,32 @@ pub struct InstallOpts {
     /// Delete the invitation after a successful install
     #[clap(short, long)]
     pub delete_invite: bool,
+
+    /// The join token required by the network, if it wasn't included in the invitation
+    #[clap(long)]
+    pub join_token: Option<String>,
+
+    /// The maximum number of seconds to wait for the first handshake with the server after
+    /// registering the new keypair. The server takes up to 5 seconds to switch to the new key,
+    /// so this should leave room beyond that
+    #[clap(long, default_value = "10")]
+    pub redeem_wait: u64,
+
+    /// Install even if the network's address range overlaps that of an installed interface
+    #[clap(long)]
+    pub allow_overlap: bool,
+
+    /// Write the innernet@.service systemd unit if it isn't installed yet (Linux only)
+    #[clap(long)]
+    pub install_service: bool,
+
+    /// The refresh interval in seconds written into a newly installed systemd unit
+    #[clap(long, default_value = "60")]
+    pub service_interval: u64,
+
+    /// Don't check that the server is reachable through the new interface after installing
+    #[clap(long)]
+    pub no_self_test: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Args)]
@@ -323,6 +556,10 @@ pub struct AddPeerOpts {
     #[clap(long)]
     pub admin: Option<bool>,
 
+    /// Give the new peer read-only access to the admin API, instead of asking about --admin
+    #[clap(long)]
+    pub viewer: bool,
+
     /// Bypass confirmation
     #[clap(long)]
     pub yes: bool,
@@ -362,6 +599,17 @@ pub struct EnableDisablePeerOpts {
     pub yes: bool,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct DeletePeerOpts {
+    /// Name of peer to delete
+    #[clap(long)]
+    pub name: Option<Hostname>,
+
+    /// Bypass confirmation
+    #[clap(long, requires("name"))]
+    pub yes: bool,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Args)]
 pub struct AddCidrOpts {
     /// The CIDR name (eg. 'engineers')
@@ -376,6 +624,11 @@ pub struct AddCidrOpts {
     #[clap(long)]
     pub parent: Option<String>,
 
+    /// The default persistent keepalive interval in seconds for peers in this CIDR
+    /// (0 to disable)
+    #[clap(long)]
+    pub default_keepalive: Option<u16>,
+
     /// Bypass confirmation
     #[clap(long)]
     pub yes: bool,
@@ -396,6 +649,17 @@ pub struct RenameCidrOpts {
     pub yes: bool,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct EnableDisableCidrOpts {
+    /// Name of CIDR to enable/disable
+    #[clap(long)]
+    pub name: Option<String>,
+
+    /// Bypass confirmation
+    #[clap(long, requires("name"))]
+    pub yes: bool,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Args)]
 pub struct DeleteCidrOpts {
     /// The CIDR name (eg. 'engineers')
@@ -435,6 +699,21 @@ pub struct ListenPortOpts {
     pub yes: bool,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct KeepaliveOpts {
+    /// The persistent keepalive interval in seconds, or 0 to disable keepalives
+    #[clap(short, long)]
+    pub seconds: Option<u16>,
+
+    /// Apply the interval to every peer instead of only the server
+    #[clap(long)]
+    pub all: bool,
+
+    /// Bypass confirmation
+    #[clap(long)]
+    pub yes: bool,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Args)]
 pub struct OverrideEndpointOpts {
     /// The listen port you'd like to set for the interface
@@ -466,6 +745,35 @@ pub struct NatOpts {
     /// Don't report any candidates to coordinating server.
     /// Shorthand for --exclude-nat-candidates '0.0.0.0/0'.
     pub no_nat_candidates: bool,
+
+    #[clap(long, value_enum, value_name = "FAMILY")]
+    /// Only report NAT candidates of this address family, e.g. to have peers in the same
+    /// datacenter reach each other over IPv6 ULAs rather than RFC1918 IPv4 addresses.
+    pub candidates_family: Option<AddressFamily>,
+
+    #[clap(long, value_name = "SECONDS", default_value = "5", value_parser = clap::value_parser!(u64).range(1..))]
+    /// How long to wait for a handshake on each candidate endpoint before trying the next.
+    pub nat_step_interval: u64,
+
+    #[clap(long, value_name = "SECONDS")]
+    /// Give up on NAT traversal after this long, leaving the remaining peers unconnected until
+    /// the next fetch. By default, every candidate of every peer is tried.
+    pub nat_traversal_timeout: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    pub fn contains(self, ip: IpAddr) -> bool {
+        match self {
+            Self::Ipv4 => ip.is_ipv4(),
+            Self::Ipv6 => ip.is_ipv6(),
+        }
+    }
 }
 
 impl NatOpts {
@@ -474,12 +782,18 @@ impl NatOpts {
             no_nat_traversal: true,
             exclude_nat_candidates: vec![],
             no_nat_candidates: true,
+            candidates_family: None,
+            nat_step_interval: 5,
+            nat_traversal_timeout: None,
         }
     }
 
     /// Check if an IP is allowed to be reported as a candidate.
     pub fn is_excluded(&self, ip: IpAddr) -> bool {
         self.no_nat_candidates
+            || self
+                .candidates_family
+                .is_some_and(|family| !family.contains(ip))
             || self
                 .exclude_nat_candidates
                 .iter()
@@ -487,7 +801,7 @@ impl NatOpts {
     }
 }
 
-#[derive(Debug, Clone, Copy, Args)]
+#[derive(Debug, Default, Clone, Copy, Args)]
 pub struct NetworkOpts {
     #[clap(long)]
     /// Whether the routing should be done by innernet or is done by an
@@ -500,8 +814,105 @@ pub struct NetworkOpts {
     pub backend: Backend,
 
     #[clap(long)]
-    /// Specify the desired MTU for your interface (default: 1280).
-    pub mtu: Option<u32>,
+    /// Specify the desired MTU for your interface (default: 1280), or "auto" to pick the
+    /// largest one the path to the server supports.
+    pub mtu: Option<Mtu>,
+}
+
+/// An interface MTU, either given outright or probed from the path to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mtu {
+    Auto,
+    Fixed(u32),
+}
+
+impl FromStr for Mtu {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            mtu => mtu
+                .parse()
+                .map(Self::Fixed)
+                .map_err(|_| "MTU must be a number or \"auto\""),
+        }
+    }
+}
+
+impl Display for Mtu {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Auto => f.write_str("auto"),
+            Self::Fixed(mtu) => mtu.fmt(f),
+        }
+    }
+}
+
+/// The hostname a peer is written to the hosts file under, where `{name}`, `{interface}` and
+/// `{cidr}` stand for the peer's name, the interface and the name of the peer's CIDR.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostnameFormat(String);
+
+impl HostnameFormat {
+    /// The hostname for a peer, or `None` if the format needs a CIDR the peer doesn't have, or
+    /// the result isn't a valid hostname (CIDR names in particular may contain anything).
+    pub fn hostname(&self, name: &str, interface: &str, cidr: Option<&str>) -> Option<String> {
+        let cidr = match cidr {
+            Some(cidr) => cidr,
+            None if self.0.contains("{cidr}") => return None,
+            None => "",
+        };
+        let hostname = self
+            .0
+            .replace("{name}", name)
+            .replace("{interface}", interface)
+            .replace("{cidr}", cidr);
+        let is_valid_label = |label: &str| {
+            !label.is_empty()
+                && label.len() < 64
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        };
+        (hostname.len() <= 253 && hostname.split('.').all(is_valid_label)).then_some(hostname)
+    }
+}
+
+impl Default for HostnameFormat {
+    fn default() -> Self {
+        Self("{name}.{interface}.wg".into())
+    }
+}
+
+impl FromStr for HostnameFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.contains("{name}") {
+            return Err("hostname format must include {name}");
+        }
+        let rest = s
+            .replace("{name}", "")
+            .replace("{interface}", "")
+            .replace("{cidr}", "");
+        if !rest
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        {
+            return Err(
+                "hostname format may only contain letters, digits, '-', '.' and the placeholders \
+                 {name}, {interface} and {cidr}",
+            );
+        }
+        Ok(Self(s.into()))
+    }
+}
+
+impl Display for HostnameFormat {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
@@ -513,11 +924,17 @@ pub struct PeerContents {
     pub endpoint: Option<Endpoint>,
     pub persistent_keepalive_interval: Option<u16>,
     pub is_admin: bool,
+    /// Viewers can read everything admins can, but can't change anything.
+    #[serde(default)]
+    pub is_viewer: bool,
     pub is_disabled: bool,
     pub is_redeemed: bool,
     pub invite_expires: Option<SystemTime>,
     #[serde(default)]
     pub candidates: Vec<Endpoint>,
+    /// A free-form note about the peer, which the peer can set itself.
+    #[serde(default)]
+    pub description: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
@@ -528,6 +945,109 @@ pub struct Peer {
     pub contents: PeerContents,
 }
 
+impl PeerContents {
+    /// Start building the contents of a new peer. See [`PeerContentsBuilder`] for the defaults.
+    pub fn builder(
+        name: Hostname,
+        ip: IpAddr,
+        cidr_id: i64,
+        public_key: String,
+    ) -> PeerContentsBuilder {
+        PeerContentsBuilder::new(name, ip, cidr_id, public_key)
+    }
+
+    /// The IPs the peer is allowed to send from and receive traffic for on the WireGuard
+    /// interface.
+    pub fn allowed_ips(&self) -> Vec<AllowedIp> {
+        vec![AllowedIp {
+            address: self.ip,
+            cidr: if self.ip.is_ipv4() { 32 } else { 128 },
+        }]
+    }
+}
+
+/// Builds [`PeerContents`], so callers only spell out what differs from the defaults: not an
+/// admin or viewer, enabled, not yet redeemed, with no invite expiration, endpoint or
+/// candidates, and the default persistent keepalive interval. Invitations should set an
+/// expiration with [`PeerContentsBuilder::set_invite_expires`].
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct PeerContentsBuilder {
+    contents: PeerContents,
+}
+
+impl PeerContentsBuilder {
+    pub fn new(name: Hostname, ip: IpAddr, cidr_id: i64, public_key: String) -> Self {
+        Self {
+            contents: PeerContents {
+                name,
+                ip,
+                cidr_id,
+                public_key,
+                endpoint: None,
+                persistent_keepalive_interval: crate::persistent_keepalive_interval(None),
+                is_admin: false,
+                is_viewer: false,
+                is_disabled: false,
+                is_redeemed: false,
+                invite_expires: None,
+                candidates: vec![],
+                description: None,
+            },
+        }
+    }
+
+    pub fn set_endpoint(mut self, endpoint: Endpoint) -> Self {
+        self.contents.endpoint = Some(endpoint);
+        self
+    }
+
+    /// `None` disables keepalive for the peer.
+    pub fn set_persistent_keepalive_interval(mut self, interval: Option<u16>) -> Self {
+        self.contents.persistent_keepalive_interval = interval;
+        self
+    }
+
+    pub fn set_admin(mut self, is_admin: bool) -> Self {
+        self.contents.is_admin = is_admin;
+        self
+    }
+
+    pub fn set_viewer(mut self, is_viewer: bool) -> Self {
+        self.contents.is_viewer = is_viewer;
+        self
+    }
+
+    pub fn set_disabled(mut self, is_disabled: bool) -> Self {
+        self.contents.is_disabled = is_disabled;
+        self
+    }
+
+    pub fn set_redeemed(mut self, is_redeemed: bool) -> Self {
+        self.contents.is_redeemed = is_redeemed;
+        self
+    }
+
+    pub fn set_invite_expires(mut self, expires: SystemTime) -> Self {
+        self.contents.invite_expires = Some(expires);
+        self
+    }
+
+    pub fn set_candidates(mut self, candidates: Vec<Endpoint>) -> Self {
+        self.contents.candidates = candidates;
+        self
+    }
+
+    pub fn set_description(mut self, description: String) -> Self {
+        self.contents.description = Some(description);
+        self
+    }
+
+    pub fn build(self) -> PeerContents {
+        self.contents
+    }
+}
+
 impl Deref for Peer {
     type Target = PeerContents;
 
@@ -562,6 +1082,12 @@ pub enum PeerChange {
         old: Option<SocketAddr>,
         new: Option<SocketAddr>,
     },
+    /// Whether a preshared key was set before and after; the keys themselves are never
+    /// included so they don't end up in logs.
+    PresharedKey {
+        old: bool,
+        new: bool,
+    },
     NatTraverseReattempt,
 }
 
@@ -581,6 +1107,16 @@ impl Display for PeerChange {
                 old.display_string(),
                 new.display_string()
             ),
+            Self::PresharedKey { old, new } => write!(
+                f,
+                "Preshared Key: {} => {}",
+                if *old { "[set]" } else { "[none]" },
+                match (old, new) {
+                    (true, true) => "[changed]",
+                    (false, true) => "[set]",
+                    _ => "[none]",
+                }
+            ),
             Self::NatTraverseReattempt => write!(f, "NAT Traversal Reattempt"),
         }
     }
@@ -615,6 +1151,16 @@ impl<'a> PeerDiff<'a> {
     pub fn new(
         old_info: Option<&'a PeerInfo>,
         new: Option<&'a Peer>,
+    ) -> Result<Option<Self>, Error> {
+        Self::with_preshared_key(old_info, new, None)
+    }
+
+    /// Like [`PeerDiff::new`], but the peer should also end up with `preshared_key` (or no
+    /// preshared key at all if it's `None`).
+    pub fn with_preshared_key(
+        old_info: Option<&'a PeerInfo>,
+        new: Option<&'a Peer>,
+        preshared_key: Option<&Key>,
     ) -> Result<Option<Self>, Error> {
         let old = old_info.map(|p| &p.config);
         match (old_info, new) {
@@ -622,14 +1168,14 @@ impl<'a> PeerDiff<'a> {
                 anyhow!("old and new peer configs have different public keys"),
             ),
             (None, None) => Ok(None),
-            _ => Ok(
-                Self::peer_config_builder(old_info, new).map(|(builder, changes)| Self {
+            _ => Ok(Self::peer_config_builder(old_info, new, preshared_key).map(
+                |(builder, changes)| Self {
                     old,
                     new,
                     builder,
                     changes,
-                }),
-            ),
+                },
+            )),
         }
     }
 
@@ -644,6 +1190,7 @@ impl<'a> PeerDiff<'a> {
     fn peer_config_builder(
         old_info: Option<&PeerInfo>,
         new: Option<&Peer>,
+        preshared_key: Option<&Key>,
     ) -> Option<(PeerConfigBuilder, Vec<PeerChange>)> {
         let old = old_info.map(|p| &p.config);
         let public_key = match (old, new) {
@@ -661,17 +1208,39 @@ impl<'a> PeerDiff<'a> {
         // diff.new is now guaranteed to be a Some(_) variant.
         let new = new.unwrap();
 
-        let new_allowed_ips = &[AllowedIp {
-            address: new.ip,
-            cidr: if new.ip.is_ipv4() { 32 } else { 128 },
-        }];
-        if old.is_none() || matches!(old, Some(old) if old.allowed_ips != new_allowed_ips) {
+        // The interface doesn't necessarily report allowed IPs in the order they were added,
+        // so compare them as sets.
+        let sorted = |ips: &[AllowedIp]| {
+            let mut ips: Vec<_> = ips.iter().map(|ip| (ip.address, ip.cidr)).collect();
+            ips.sort_unstable();
+            ips.dedup();
+            ips
+        };
+        let new_allowed_ips = new.allowed_ips();
+        if old.is_none()
+            || matches!(old, Some(old) if sorted(&old.allowed_ips) != sorted(&new_allowed_ips))
+        {
             builder = builder
                 .replace_allowed_ips()
-                .add_allowed_ips(new_allowed_ips);
+                .add_allowed_ips(&new_allowed_ips);
             changes.push(PeerChange::AllowedIPs {
                 old: old.map(|o| o.allowed_ips.clone()).unwrap_or_default(),
-                new: new_allowed_ips.to_vec(),
+                new: new_allowed_ips,
+            });
+        }
+
+        // Some backends report an unset preshared key as all zeroes.
+        let old_preshared_key = old
+            .and_then(|old| old.preshared_key.as_ref())
+            .filter(|key| **key != Key::zero());
+        if old_preshared_key != preshared_key {
+            builder = match preshared_key {
+                Some(key) => builder.set_preshared_key(key.clone()),
+                None => builder.unset_preshared_key(),
+            };
+            changes.push(PeerChange::PresharedKey {
+                old: old_preshared_key.is_some(),
+                new: preshared_key.is_some(),
             });
         }
 
@@ -735,6 +1304,17 @@ impl<'a> From<PeerDiff<'a>> for PeerConfigBuilder {
     }
 }
 
+/// This model is sent as a response to the /info endpoint.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Info {
+    /// The version of innernet-server.
+    pub version: String,
+
+    /// Bumped on every change to the network's peers, CIDRs or associations. If it's the same
+    /// as at the last fetch, /state only differs in the endpoints the server has observed.
+    pub epoch: u64,
+}
+
 /// This model is sent as a response to the /state endpoint, and is meant
 /// to include all the data a client needs to update its WireGuard interface.
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -747,6 +1327,69 @@ pub struct State {
     /// At the moment, this is all CIDRs, regardless of whether the peer is
     /// eligible to communicate with them or not.
     pub cidrs: Vec<Cidr>,
+
+    /// The key rotations of the peers in `peers` newer than the ones the client said it had
+    /// seen, oldest first, so a client can follow a peer it has already pinned to its current
+    /// public key.
+    #[serde(default)]
+    pub key_rotations: Vec<KeyRotation>,
+}
+
+/// A peer replacing its public key with a new one, as recorded by the server.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct KeyRotation {
+    /// Increases with every rotation the server records.
+    #[serde(default)]
+    pub id: i64,
+    pub old_public_key: String,
+    pub new_public_key: String,
+    /// The old private key's signature over the rotation (base64), which is what lets a client
+    /// trust it without trusting the server.
+    #[serde(default)]
+    pub signature: String,
+}
+
+impl KeyRotation {
+    fn message(old_public_key: &str, new_public_key: &str) -> Vec<u8> {
+        format!("innernet key rotation\n{old_public_key}\n{new_public_key}").into_bytes()
+    }
+
+    /// Sign the rotation from `old_private_key` to `new_public_key`, returning the signature in
+    /// base64.
+    pub fn sign(old_private_key: &Key, new_public_key: &str) -> String {
+        let message = Self::message(&old_private_key.get_public().to_base64(), new_public_key);
+        base64::encode(old_private_key.sign(&message))
+    }
+
+    /// Whether the rotation was signed by the holder of the old key.
+    pub fn is_signed(&self) -> bool {
+        let Ok(old_public_key) = Key::from_base64(&self.old_public_key) else {
+            return false;
+        };
+        let Ok(signature) = base64::decode(&self.signature) else {
+            return false;
+        };
+        let Ok(signature) = <[u8; 64]>::try_from(signature) else {
+            return false;
+        };
+        old_public_key.verify(
+            &Self::message(&self.old_public_key, &self.new_public_key),
+            &signature,
+        )
+    }
+}
+
+/// A peer's traffic as seen by the server's WireGuard interface. The byte counts are relative to
+/// the interface's current session, so they reset whenever the server restarts.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PeerUsage {
+    pub id: i64,
+    pub public_key: String,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// Seconds since the UNIX epoch, or `None` if the server has never had a handshake with the
+    /// peer this session.
+    pub last_handshake_time: Option<u64>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -852,6 +1495,25 @@ impl<T> IoErrorContext<T> for Result<T, std::io::Error> {
     }
 }
 
+/// A new peer was given an IP that another peer already has.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpInUse {
+    pub ip: IpAddr,
+    pub peer: String,
+}
+
+impl Display for IpInUse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "IP {} is already assigned to peer {}",
+            self.ip, self.peer
+        )
+    }
+}
+
+impl std::error::Error for IpInUse {}
+
 #[derive(Debug)]
 pub struct WrappedIoError {
     io_error: std::io::Error,
@@ -864,6 +1526,13 @@ impl Display for WrappedIoError {
     }
 }
 
+impl WrappedIoError {
+    /// What the error happened to, usually a path or an interface name.
+    pub fn context(&self) -> &str {
+        &self.context
+    }
+}
+
 impl Deref for WrappedIoError {
     type Target = std::io::Error;
 
@@ -880,26 +1549,28 @@ mod tests {
     use std::net::IpAddr;
     use wireguard_control::{Key, PeerConfigBuilder, PeerStats};
 
+    #[test]
+    fn test_interface_looks_like_system_interface() {
+        for name in [
+            "lo", "eth0", "wlan1", "wg0", "enp0s3", "ens33", "wlp2s0", "docker0",
+        ] {
+            let interface: Interface = name.parse().unwrap();
+            assert!(interface.looks_like_system_interface(), "{name}");
+        }
+        for name in ["evilcorp", "engineering", "wg", "tonari", "loft", "eth-net"] {
+            let interface: Interface = name.parse().unwrap();
+            assert!(!interface.looks_like_system_interface(), "{name}");
+        }
+
+        #[cfg(target_os = "linux")]
+        assert!("lo".parse::<Interface>().unwrap().exists());
+    }
+
     #[test]
     fn test_peer_no_diff() {
         const PUBKEY: &str = "4CNZorWVtohO64n6AAaH/JyFjIIgBFrfJK2SGtKjzEE=";
         let ip: IpAddr = "10.0.0.1".parse().unwrap();
-        let peer = Peer {
-            id: 1,
-            contents: PeerContents {
-                name: "peer1".parse().unwrap(),
-                ip,
-                cidr_id: 1,
-                public_key: PUBKEY.to_owned(),
-                endpoint: None,
-                persistent_keepalive_interval: None,
-                is_admin: false,
-                is_disabled: false,
-                is_redeemed: true,
-                invite_expires: None,
-                candidates: vec![],
-            },
-        };
+        let peer = peer_with_ip(PUBKEY, ip);
         let builder =
             PeerConfigBuilder::new(&Key::from_base64(PUBKEY).unwrap()).add_allowed_ip(ip, 32);
 
@@ -921,19 +1592,10 @@ mod tests {
         let ip: IpAddr = "10.0.0.1".parse().unwrap();
         let peer = Peer {
             id: 1,
-            contents: PeerContents {
-                name: "peer1".parse().unwrap(),
-                ip,
-                cidr_id: 1,
-                public_key: PUBKEY.to_owned(),
-                endpoint: None,
-                persistent_keepalive_interval: Some(15),
-                is_admin: false,
-                is_disabled: false,
-                is_redeemed: true,
-                invite_expires: None,
-                candidates: vec![],
-            },
+            contents: PeerContents::builder("peer1".parse().unwrap(), ip, 1, PUBKEY.into())
+                .set_persistent_keepalive_interval(Some(15))
+                .set_redeemed(true)
+                .build(),
         };
         let builder =
             PeerConfigBuilder::new(&Key::from_base64(PUBKEY).unwrap()).add_allowed_ip(ip, 32);
@@ -956,19 +1618,11 @@ mod tests {
         let ip: IpAddr = "10.0.0.1".parse().unwrap();
         let peer = Peer {
             id: 1,
-            contents: PeerContents {
-                name: "peer1".parse().unwrap(),
-                ip,
-                cidr_id: 1,
-                public_key: PUBKEY.to_owned(),
-                endpoint: Some("1.1.1.1:1111".parse().unwrap()),
-                persistent_keepalive_interval: None,
-                is_admin: false,
-                is_disabled: false,
-                is_redeemed: true,
-                invite_expires: None,
-                candidates: vec![],
-            },
+            contents: PeerContents::builder("peer1".parse().unwrap(), ip, 1, PUBKEY.into())
+                .set_endpoint("1.1.1.1:1111".parse().unwrap())
+                .set_persistent_keepalive_interval(None)
+                .set_redeemed(true)
+                .build(),
         };
         let builder =
             PeerConfigBuilder::new(&Key::from_base64(PUBKEY).unwrap()).add_allowed_ip(ip, 32);
@@ -992,4 +1646,242 @@ mod tests {
         info.stats.last_handshake_time = Some(SystemTime::now());
         assert!(matches!(PeerDiff::new(Some(&info), Some(&peer)), Ok(None)));
     }
+
+    fn peer_with_ip(public_key: &str, ip: IpAddr) -> Peer {
+        Peer {
+            id: 1,
+            contents: PeerContents::builder("peer1".parse().unwrap(), ip, 1, public_key.into())
+                .set_persistent_keepalive_interval(None)
+                .set_redeemed(true)
+                .build(),
+        }
+    }
+
+    #[test]
+    fn test_peer_contents_builder_defaults() {
+        let contents = PeerContents::builder(
+            "peer1".parse().unwrap(),
+            "10.0.0.1".parse().unwrap(),
+            1,
+            String::new(),
+        )
+        .build();
+        assert!(!contents.is_admin && !contents.is_viewer);
+        assert!(!contents.is_disabled && !contents.is_redeemed);
+        assert_eq!(
+            contents.persistent_keepalive_interval,
+            Some(crate::PERSISTENT_KEEPALIVE_INTERVAL_SECS)
+        );
+        assert_eq!(contents.invite_expires, None);
+    }
+
+    #[test]
+    fn test_peer_diff_allowed_ips() {
+        const PUBKEY: &str = "4CNZorWVtohO64n6AAaH/JyFjIIgBFrfJK2SGtKjzEE=";
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let peer = peer_with_ip(PUBKEY, ip);
+
+        // An extra allowed IP on the interface that the peer shouldn't have gets replaced.
+        let info = PeerInfo {
+            config: PeerConfigBuilder::new(&Key::from_base64(PUBKEY).unwrap())
+                .add_allowed_ip("10.0.1.0".parse().unwrap(), 24)
+                .add_allowed_ip(ip, 32)
+                .into_peer_config(),
+            stats: Default::default(),
+        };
+        let diff = PeerDiff::new(Some(&info), Some(&peer)).unwrap().unwrap();
+        assert_eq!(
+            diff.changes(),
+            &[PeerChange::AllowedIPs {
+                old: info.config.allowed_ips.clone(),
+                new: peer.allowed_ips(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_peer_diff_preshared_key() {
+        const PUBKEY: &str = "4CNZorWVtohO64n6AAaH/JyFjIIgBFrfJK2SGtKjzEE=";
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let peer = peer_with_ip(PUBKEY, ip);
+        let psk = Key::generate_preshared();
+        let builder =
+            PeerConfigBuilder::new(&Key::from_base64(PUBKEY).unwrap()).add_allowed_ip(ip, 32);
+
+        let mut info = PeerInfo {
+            // How the userspace backend reports a peer without a preshared key.
+            config: builder.clone().unset_preshared_key().into_peer_config(),
+            stats: Default::default(),
+        };
+        assert_eq!(PeerDiff::new(Some(&info), Some(&peer)).unwrap(), None);
+
+        let diff = PeerDiff::with_preshared_key(Some(&info), Some(&peer), Some(&psk))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            diff.changes(),
+            &[PeerChange::PresharedKey {
+                old: false,
+                new: true
+            }]
+        );
+
+        info.config = builder.set_preshared_key(psk.clone()).into_peer_config();
+        assert_eq!(
+            PeerDiff::with_preshared_key(Some(&info), Some(&peer), Some(&psk)).unwrap(),
+            None
+        );
+        let diff = PeerDiff::new(Some(&info), Some(&peer)).unwrap().unwrap();
+        assert_eq!(
+            diff.changes(),
+            &[PeerChange::PresharedKey {
+                old: true,
+                new: false
+            }]
+        );
+    }
+
+    #[test]
+    fn test_cidr_tree_try_new_empty() {
+        assert!(CidrTree::try_new(&[]).is_err());
+    }
+
+    #[test]
+    fn test_cidr_tree_try_new_ambiguous_root() {
+        let cidr = |id, name: &str, cidr: &str| Cidr {
+            id,
+            contents: CidrContents {
+                name: name.into(),
+                cidr: cidr.parse().unwrap(),
+                parent: None,
+                default_keepalive: None,
+                is_disabled: false,
+            },
+        };
+        let cidrs = [cidr(1, "a", "10.0.0.0/16"), cidr(2, "b", "10.1.0.0/16")];
+        let err = CidrTree::try_new(&cidrs).err().unwrap();
+        assert!(err.to_string().contains("a (10.0.0.0/16), b (10.1.0.0/16)"));
+
+        let cidrs = [cidr(1, "root", "10.0.0.0/8"), cidr(2, "a", "10.0.0.0/16")];
+        assert_eq!(CidrTree::try_new(&cidrs).unwrap().name, "root");
+    }
+
+    #[test]
+    fn test_reachability() {
+        let cidr = |id, parent, cidr: &str| Cidr {
+            id,
+            contents: CidrContents {
+                name: format!("cidr{id}"),
+                cidr: cidr.parse().unwrap(),
+                parent,
+                default_keepalive: None,
+                is_disabled: false,
+            },
+        };
+        let peer = |id, cidr_id, ip: &str| {
+            let mut peer = peer_with_ip("", ip.parse().unwrap());
+            peer.id = id;
+            peer.cidr_id = cidr_id;
+            peer
+        };
+        let cidrs = [
+            cidr(1, None, "10.0.0.0/8"),
+            cidr(INFRA_CIDR_ID, Some(1), "10.0.0.0/24"),
+            cidr(3, Some(1), "10.1.0.0/16"),
+            cidr(4, Some(3), "10.1.1.0/24"),
+            cidr(5, Some(1), "10.2.0.0/16"),
+            cidr(6, Some(1), "10.3.0.0/16"),
+        ];
+        let tree = CidrTree::new(&cidrs);
+        let me = peer(1, 3, "10.1.0.1");
+        let peers = [
+            me.clone(),
+            peer(2, INFRA_CIDR_ID, "10.0.0.1"),
+            peer(3, 4, "10.1.1.1"),
+            peer(4, 5, "10.2.0.1"),
+            peer(5, 6, "10.3.0.1"),
+        ];
+        // Associating the root's child 3 with 5 lets peers in 3's subtree reach 5's.
+        let associations = [Association {
+            id: 1,
+            contents: AssociationContents {
+                cidr_id_1: 5,
+                cidr_id_2: 3,
+            },
+        }];
+
+        let reachable = tree.reachable_peers(Some(&associations), &me, &peers);
+        let reachable: Vec<_> = reachable.iter().map(|(p, r)| (p.id, *r)).collect();
+        assert_eq!(
+            reachable,
+            [
+                (2, Reachability::Infrastructure),
+                (3, Reachability::SameCidr),
+                (4, Reachability::Associated),
+            ]
+        );
+
+        // Peers in a nested CIDR inherit the associations of their parents, but the
+        // association doesn't reach up from a child CIDR to the parent's peers.
+        let nested = peer(3, 4, "10.1.1.1");
+        assert_eq!(
+            tree.reachability(Some(&associations), &nested, &peers[3]),
+            Some(Reachability::Associated)
+        );
+        assert_eq!(tree.reachability(Some(&associations), &nested, &me), None);
+
+        // Without associations, whatever the server handed out is assumed reachable.
+        assert_eq!(tree.reachable_peers(None, &me, &peers).len(), 4);
+    }
+
+    #[test]
+    fn test_hostname_format() {
+        assert_eq!(
+            HostnameFormat::default().hostname("laptop", "evilcorp", Some("humans")),
+            Some("laptop.evilcorp.wg".into())
+        );
+        let format: HostnameFormat = "{name}.{cidr}.internal.example.com".parse().unwrap();
+        assert_eq!(
+            format.hostname("laptop", "evilcorp", Some("humans")),
+            Some("laptop.humans.internal.example.com".into())
+        );
+        assert_eq!(
+            "{name}"
+                .parse::<HostnameFormat>()
+                .unwrap()
+                .hostname("laptop", "evilcorp", None),
+            Some("laptop".into())
+        );
+        // CIDR names aren't restricted to hostname characters, and a peer may have no CIDR.
+        assert_eq!(
+            format.hostname("laptop", "evilcorp", Some("Field Ops")),
+            None
+        );
+        assert_eq!(format.hostname("laptop", "evilcorp", Some("-ops")), None);
+        assert_eq!(format.hostname("laptop", "evilcorp", None), None);
+        assert_eq!(format.hostname("laptop", "evilcorp", Some("")), None);
+
+        assert!("{interface}.wg".parse::<HostnameFormat>().is_err());
+        assert!("{name}.{owner}.wg".parse::<HostnameFormat>().is_err());
+        assert!("{name} .wg".parse::<HostnameFormat>().is_err());
+    }
+
+    #[test]
+    fn test_nat_candidates_family() {
+        let v4: IpAddr = "10.0.0.1".parse().unwrap();
+        let v6: IpAddr = "fd00::1".parse().unwrap();
+        let mut nat = NatOpts {
+            no_nat_traversal: false,
+            exclude_nat_candidates: vec![],
+            no_nat_candidates: false,
+            candidates_family: Some(AddressFamily::Ipv6),
+            nat_step_interval: 5,
+            nat_traversal_timeout: None,
+        };
+        assert!(nat.is_excluded(v4));
+        assert!(!nat.is_excluded(v6));
+
+        nat.exclude_nat_candidates = vec!["fd00::/64".parse().unwrap()];
+        assert!(nat.is_excluded(v6));
+    }
 }
@@ -1,9 +1,10 @@
-use crate::{Error, IoErrorContext, NetworkOpts, Peer, PeerDiff};
+use crate::{Error, IoErrorContext, Mtu, NetworkOpts, Peer, PeerDiff};
 use ipnet::IpNet;
 use std::{
     io,
     net::{IpAddr, SocketAddr},
-    time::Duration,
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 use wireguard_control::{
     Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder, PeerInfo,
@@ -69,16 +70,142 @@ pub use super::netlink::set_addr;
 #[cfg(target_os = "linux")]
 pub use super::netlink::set_up;
 
+/// The MTU interfaces get unless told otherwise, which fits inside nearly every path.
+pub const DEFAULT_MTU: u32 = 1280;
+
+/// The largest interface MTU whose WireGuard packets fit through the path to `endpoint`, or
+/// `None` if the path MTU couldn't be determined or leaves too little room for the tunnel.
+pub fn probe_mtu(endpoint: SocketAddr, inner_ipv6: bool) -> Option<u32> {
+    match path_mtu(endpoint) {
+        Ok(path_mtu) => tunnel_mtu(path_mtu, endpoint, inner_ipv6),
+        Err(e) => {
+            log::debug!("path MTU probe to {} failed: {}", endpoint, e);
+            None
+        },
+    }
+}
+
+/// The interface MTU that leaves room for WireGuard's outer IP, UDP and WireGuard headers
+/// within `path_mtu`, or `None` if that's below the minimum the tunnel's traffic requires
+/// (1280 bytes for IPv6, 576 for IPv4).
+fn tunnel_mtu(path_mtu: u32, endpoint: SocketAddr, inner_ipv6: bool) -> Option<u32> {
+    const UDP_AND_WIREGUARD_OVERHEAD: u32 = 8 + 32;
+    let ip_overhead = if endpoint.is_ipv4() { 20 } else { 40 };
+    let minimum = if inner_ipv6 { 1280 } else { 576 };
+    path_mtu
+        .checked_sub(ip_overhead + UDP_AND_WIREGUARD_OVERHEAD)
+        .filter(|mtu| *mtu >= minimum)
+}
+
+/// Ask the kernel for its path MTU estimate to `endpoint`, after sending a probe as large as
+/// the route allows with fragmentation disabled, so that a smaller hop along the way gets a
+/// chance to report its MTU. If the estimate doesn't drop, nothing confirmed the probe made
+/// it through (WireGuard never answers it, and ICMP may be filtered), so that's an error
+/// rather than a guess that the whole route MTU fits.
+#[cfg(target_os = "linux")]
+fn path_mtu(endpoint: SocketAddr) -> Result<u32, io::Error> {
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr, UdpSocket},
+        os::fd::AsRawFd,
+    };
+
+    let (level, discover, discover_do, mtu_option, headers) = if endpoint.is_ipv4() {
+        (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+            libc::IP_MTU,
+            28,
+        )
+    } else {
+        (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+            libc::IPV6_MTU,
+            48,
+        )
+    };
+    let socket = if endpoint.is_ipv4() {
+        UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?
+    } else {
+        UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?
+    };
+    socket.connect(endpoint)?;
+    let fd = socket.as_raw_fd();
+
+    let get_mtu = || -> Result<u32, io::Error> {
+        let mut mtu: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: `mtu` and `len` are valid for writes of the sizes given.
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                level,
+                mtu_option,
+                &mut mtu as *mut _ as *mut _,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(mtu as u32)
+    };
+
+    // SAFETY: `discover_do` lives for the duration of the call, with its size given.
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            discover,
+            &discover_do as *const _ as *const _,
+            std::mem::size_of_val(&discover_do) as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // WireGuard drops datagrams it can't authenticate, so probing its port is harmless.
+    let route_mtu = get_mtu()?;
+    let probe = vec![0; route_mtu.saturating_sub(headers) as usize];
+    match socket.send(&probe) {
+        Ok(_) => {},
+        Err(e) if e.raw_os_error() == Some(libc::EMSGSIZE) => {},
+        Err(e) => return Err(e),
+    }
+    thread::sleep(Duration::from_millis(500));
+    let path_mtu = get_mtu()?;
+    if path_mtu >= route_mtu {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "no hop reported a smaller MTU than the route's {route_mtu}, so it's unverified"
+            ),
+        ));
+    }
+    Ok(path_mtu)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn path_mtu(_endpoint: SocketAddr) -> Result<u32, io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "path MTU probing is only supported on Linux",
+    ))
+}
+
 pub fn up(
     interface: &InterfaceName,
     private_key: &str,
     address: IpNet,
     listen_port: Option<u16>,
-    peer: Option<(&str, IpAddr, SocketAddr)>,
+    peer: Option<(&str, IpAddr, SocketAddr, Option<&Key>)>,
     network: NetworkOpts,
 ) -> Result<(), io::Error> {
     let mut device = DeviceUpdate::new();
-    if let Some((public_key, address, endpoint)) = peer {
+    if let Some((public_key, address, endpoint, preshared_key)) = peer {
         let prefix = if address.is_ipv4() { 32 } else { 128 };
         let peer_config = PeerConfigBuilder::new(
             &wireguard_control::Key::from_base64(public_key).map_err(|_| {
@@ -91,6 +218,10 @@ pub fn up(
         .add_allowed_ip(address, prefix)
         .set_persistent_keepalive_interval(25)
         .set_endpoint(endpoint);
+        let peer_config = match preshared_key {
+            Some(key) => peer_config.set_preshared_key(key.clone()),
+            None => peer_config,
+        };
         device = device.add_peer(peer_config);
     }
     if let Some(listen_port) = listen_port {
@@ -100,7 +231,20 @@ pub fn up(
         .set_private_key(wireguard_control::Key::from_base64(private_key).unwrap())
         .apply(interface, network.backend)?;
     set_addr(interface, address)?;
-    set_up(interface, network.mtu.unwrap_or(1280))?;
+    let mtu = match network.mtu {
+        Some(Mtu::Fixed(mtu)) => mtu,
+        Some(Mtu::Auto) => {
+            let endpoint = peer.map(|(_, _, endpoint, _)| endpoint);
+            let mtu = endpoint.and_then(|endpoint| probe_mtu(endpoint, address.addr().is_ipv6()));
+            match mtu {
+                Some(mtu) => log::info!("using probed MTU {}", mtu),
+                None => log::warn!("couldn't probe the path MTU, using {}", DEFAULT_MTU),
+            }
+            mtu.unwrap_or(DEFAULT_MTU)
+        },
+        None => DEFAULT_MTU,
+    };
+    set_up(interface, mtu)?;
     if !network.no_routing {
         add_route(interface, address)?;
     }
@@ -123,11 +267,105 @@ pub fn set_listen_port(
     Ok(())
 }
 
+pub fn set_persistent_keepalive(
+    interface: &InterfaceName,
+    peers: &[Key],
+    seconds: u16,
+    backend: Backend,
+) -> Result<(), Error> {
+    let updates = peers
+        .iter()
+        .map(|key| PeerConfigBuilder::new(key).set_persistent_keepalive_interval(seconds))
+        .collect::<Vec<_>>();
+    DeviceUpdate::new()
+        .add_peers(&updates)
+        .apply(interface, backend)?;
+
+    Ok(())
+}
+
 pub fn down(interface: &InterfaceName, backend: Backend) -> Result<(), Error> {
-    Ok(Device::get(interface, backend)
-        .with_str(interface.as_str_lossy())?
-        .delete()
-        .with_str(interface.as_str_lossy())?)
+    let device = Device::get(interface, backend).with_str(interface.as_str_lossy())?;
+
+    // The kernel normally drops an interface's routes along with it, but we've seen them
+    // survive crashes, so clean up the ones we added explicitly before deleting the device.
+    #[cfg(target_os = "linux")]
+    match super::netlink::get_routes(interface) {
+        Ok(routes) => {
+            for route in routes {
+                if let Err(e) = delete_route(interface, route) {
+                    log::warn!("failed to delete route {}: {}", route, e);
+                }
+            }
+        },
+        Err(e) => log::warn!("failed to list routes for {}: {}", interface, e),
+    }
+
+    Ok(device.delete().with_str(interface.as_str_lossy())?)
+}
+
+/// Wait up to `timeout` for a handshake with `peer` newer than `since`, returning whether one
+/// happened. WireGuard only initiates a handshake when it has something to send, so the caller
+/// is responsible for sending traffic to the peer.
+pub fn wait_for_handshake(
+    interface: &InterfaceName,
+    peer: &Key,
+    since: SystemTime,
+    timeout: Duration,
+    backend: Backend,
+) -> Result<bool, io::Error> {
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    let start = Instant::now();
+    loop {
+        let handshaked = Device::peers_iter(interface, backend)?
+            .find_by_key(peer)?
+            .and_then(|info| info.stats.last_handshake_time)
+            .is_some_and(|time| time >= since);
+        if handshaked {
+            return Ok(true);
+        }
+        if start.elapsed() >= timeout {
+            return Ok(false);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Remove the routes [`add_route`] added through this interface for CIDRs within `address`'s
+/// network, returning the ones removed. Routes to the same CIDRs through other interfaces are
+/// left alone, and an interface that no longer exists has no routes left to remove, so this
+/// has to run before the interface is taken down.
+#[cfg(target_os = "linux")]
+pub fn flush_routes(interface: &InterfaceName, address: IpNet) -> Result<Vec<IpNet>, io::Error> {
+    let network = address.trunc();
+    let routes = match super::netlink::get_routes(interface) {
+        Ok(routes) => routes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut removed = vec![];
+    for route in routes.into_iter().filter(|route| network.contains(route)) {
+        if delete_route(interface, route)? {
+            removed.push(route);
+        }
+    }
+    Ok(removed)
+}
+
+/// Remove the route [`add_route`] added for `address` through this interface, returning it if
+/// it was there. Routes to the same CIDR through other interfaces are left alone, and an
+/// interface that no longer exists has no routes left to remove.
+#[cfg(target_os = "macos")]
+pub fn flush_routes(interface: &InterfaceName, address: IpNet) -> Result<Vec<IpNet>, io::Error> {
+    if wireguard_control::backends::userspace::resolve_tun(interface).is_err() {
+        return Ok(vec![]);
+    }
+    Ok(if delete_route(interface, address)? {
+        vec![address.trunc()]
+    } else {
+        vec![]
+    })
 }
 
 /// Add a route in the OS's routing table to get traffic flowing through this interface.
@@ -168,16 +406,47 @@ pub fn add_route(interface: &InterfaceName, cidr: IpNet) -> Result<bool, io::Err
 #[cfg(target_os = "linux")]
 pub use super::netlink::add_route;
 
+/// Remove the route through this interface added by [`add_route`], leaving any other
+/// interface's route to the same CIDR alone.
+/// Returns true if the route was removed, false if it didn't exist.
+#[cfg(target_os = "macos")]
+pub fn delete_route(interface: &InterfaceName, cidr: IpNet) -> Result<bool, io::Error> {
+    let family = if matches!(cidr, IpNet::V4(_)) {
+        "-inet"
+    } else {
+        "-inet6"
+    };
+    let cidr = cidr.trunc().to_string();
+    let real_interface = wireguard_control::backends::userspace::resolve_tun(interface)?;
+    let args = [
+        "-n",
+        "delete",
+        family,
+        cidr.as_str(),
+        "-interface",
+        real_interface.as_str(),
+    ];
+    match cmd("route", &args) {
+        Ok(_) => Ok(true),
+        Err(e) if e.to_string().contains("not in table") => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use super::netlink::delete_route;
+
 pub trait DeviceExt {
-    /// Diff the output of a wgctrl device with a list of server-reported peers.
-    fn diff<'a>(&'a self, peers: &'a [Peer]) -> Vec<PeerDiff<'a>>;
+    /// Diff the output of a wgctrl device with a list of server-reported peers, which should
+    /// all end up with `preshared_key` (or none at all if it's `None`).
+    fn diff<'a>(&'a self, peers: &'a [Peer], preshared_key: Option<&Key>) -> Vec<PeerDiff<'a>>;
 
     // /// Get a peer by their public key, a helper function.
     fn get_peer(&self, public_key: &str) -> Option<&PeerInfo>;
 }
 
 impl DeviceExt for Device {
-    fn diff<'a>(&'a self, peers: &'a [Peer]) -> Vec<PeerDiff<'a>> {
+    fn diff<'a>(&'a self, peers: &'a [Peer], preshared_key: Option<&Key>) -> Vec<PeerDiff<'a>> {
         let interface_public_key = self
             .public_key
             .as_ref()
@@ -185,27 +454,25 @@ impl DeviceExt for Device {
             .unwrap_or_default();
         let existing_peers = &self.peers;
 
-        // Match existing peers (by pubkey) to new peer information from the server.
+        // Match existing peers (by pubkey) to new peer information from the server. A disabled
+        // peer that's still on the interface gets a diff that removes it.
         let modifications = peers.iter().filter_map(|peer| {
-            if peer.is_disabled || peer.public_key == interface_public_key {
+            let existing_peer = existing_peers
+                .iter()
+                .find(|p| p.config.public_key.to_base64() == peer.public_key);
+            if peer.public_key == interface_public_key
+                || (peer.is_disabled && existing_peer.is_none())
+            {
                 None
             } else {
-                let existing_peer = existing_peers
-                    .iter()
-                    .find(|p| p.config.public_key.to_base64() == peer.public_key);
-                PeerDiff::new(existing_peer, Some(peer)).unwrap()
+                PeerDiff::with_preshared_key(existing_peer, Some(peer), preshared_key).unwrap()
             }
         });
 
-        // Remove any peers on the interface that aren't in the server's peer list any more.
-        let removals = existing_peers.iter().filter_map(|existing| {
-            let public_key = existing.config.public_key.to_base64();
-            if peers.iter().any(|p| p.public_key == public_key) {
-                None
-            } else {
-                PeerDiff::new(Some(existing), None).unwrap()
-            }
-        });
+        // Remove any peers on the interface that the server doesn't list any more.
+        let removals = stale_peers(existing_peers, peers)
+            .into_iter()
+            .filter_map(|existing| PeerDiff::new(Some(existing), None).unwrap());
 
         modifications.chain(removals).collect::<Vec<_>>()
     }
@@ -217,6 +484,19 @@ impl DeviceExt for Device {
     }
 }
 
+/// The peers in `existing` (typically an interface's) that are missing from `peers`
+/// altogether, such as ones deleted from the server while this peer was offline. Disabled
+/// peers are left out, since diffing against `peers` already removes them.
+pub fn stale_peers<'a>(existing: &'a [PeerInfo], peers: &[Peer]) -> Vec<&'a PeerInfo> {
+    existing
+        .iter()
+        .filter(|info| {
+            let public_key = info.config.public_key.to_base64();
+            !peers.iter().any(|peer| peer.public_key == public_key)
+        })
+        .collect()
+}
+
 pub trait PeerInfoExt {
     /// WireGuard rejects any communication after REJECT_AFTER_TIME, so we can use this
     /// as a heuristic for "currentness" without relying on heavier things like ICMP.
@@ -235,3 +515,69 @@ impl PeerInfoExt for PeerInfo {
         last_handshake <= REJECT_AFTER_TIME
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PeerContents;
+
+    fn peer(name: &str, public_key: &Key, ip: &str) -> Peer {
+        Peer {
+            id: 1,
+            contents: PeerContents::builder(
+                name.parse().unwrap(),
+                ip.parse().unwrap(),
+                1,
+                public_key.to_base64(),
+            )
+            .set_redeemed(true)
+            .build(),
+        }
+    }
+
+    #[test]
+    fn test_tunnel_mtu() {
+        let v4: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:51820".parse().unwrap();
+        assert_eq!(tunnel_mtu(1500, v4, false), Some(1440));
+        assert_eq!(tunnel_mtu(1500, v6, false), Some(1420));
+        assert_eq!(tunnel_mtu(9000, v4, true), Some(8940));
+        // Too small a path is rejected instead of being rounded up to a size that won't fit.
+        assert_eq!(tunnel_mtu(1340, v4, true), Some(1280));
+        assert_eq!(tunnel_mtu(1280, v4, true), None);
+        assert_eq!(tunnel_mtu(1280, v4, false), Some(1220));
+        assert_eq!(tunnel_mtu(600, v4, false), None);
+        assert_eq!(tunnel_mtu(40, v4, false), None);
+    }
+
+    #[test]
+    fn test_stale_peers() {
+        let keys: Vec<_> = (0..3)
+            .map(|_| Key::generate_private().get_public())
+            .collect();
+        let mut peers = vec![
+            peer("peer1", &keys[0], "10.0.0.1"),
+            peer("peer2", &keys[1], "10.0.0.2"),
+            peer("peer3", &keys[2], "10.0.0.3"),
+        ];
+        let existing: Vec<_> = peers
+            .iter()
+            .map(|peer| PeerInfo {
+                config: PeerConfigBuilder::from(PeerDiff::new(None, Some(peer)).unwrap().unwrap())
+                    .into_peer_config(),
+                stats: Default::default(),
+            })
+            .collect();
+        assert!(stale_peers(&existing, &peers).is_empty());
+
+        // peer3 disappears from the server's state while peer2 is disabled, which the diff
+        // takes care of instead.
+        peers.pop();
+        peers[1].is_disabled = true;
+        let stale: Vec<_> = stale_peers(&existing, &peers)
+            .into_iter()
+            .map(|info| info.config.public_key.clone())
+            .collect();
+        assert_eq!(stale, [keys[2].clone()]);
+    }
+}
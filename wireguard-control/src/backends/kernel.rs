@@ -22,7 +22,7 @@ use netlink_packet_wireguard::{
 };
 use netlink_request::{max_genl_payload_length, netlink_request_genl, netlink_request_rtnl};
 
-use std::{convert::TryFrom, io};
+use std::{collections::HashMap, convert::TryFrom, io};
 
 macro_rules! get_nla_value {
     ($nlas:expr, $e:ident, $v:ident) => {
@@ -356,18 +356,18 @@ impl ApplyPayload {
     }
 }
 
-pub fn get_by_name(name: &InterfaceName) -> Result<Device, io::Error> {
+fn get_device_nlas(name: &InterfaceName) -> Result<Vec<WgDeviceAttrs>, io::Error> {
     let genlmsg: GenlMessage<Wireguard> = GenlMessage::from_payload(Wireguard {
         cmd: WireguardCmd::GetDevice,
         nlas: vec![WgDeviceAttrs::IfName(name.as_str_lossy().to_string())],
     });
     let responses = netlink_request_genl(genlmsg, Some(NLM_F_REQUEST | NLM_F_DUMP | NLM_F_ACK))?;
     log::debug!(
-        "get_by_name: got {} response message(s) from netlink request",
+        "get_device_nlas: got {} response message(s) from netlink request",
         responses.len()
     );
 
-    let nlas = responses.into_iter().try_fold(vec![], |mut nlas, nlmsg| {
+    responses.into_iter().try_fold(vec![], |mut nlas, nlmsg| {
         let mut message = match nlmsg {
             NetlinkMessage {
                 payload: NetlinkPayload::InnerMessage(message),
@@ -382,7 +382,11 @@ pub fn get_by_name(name: &InterfaceName) -> Result<Device, io::Error> {
         };
         nlas.append(&mut message.payload.nlas);
         Ok(nlas)
-    })?;
+    })
+}
+
+pub fn get_by_name(name: &InterfaceName) -> Result<Device, io::Error> {
+    let nlas = get_device_nlas(name)?;
     let device = Device::try_from(&nlas[..])?;
     log::debug!(
         "get_by_name: parsed wireguard device {} with {} peer(s)",
@@ -392,6 +396,50 @@ pub fn get_by_name(name: &InterfaceName) -> Result<Device, io::Error> {
     Ok(device)
 }
 
+/// The kernel sends the whole device in one dump, but each peer is only parsed into a
+/// [`PeerInfo`] once the iterator reaches it.
+pub fn peers_by_name(
+    name: &InterfaceName,
+) -> Result<impl Iterator<Item = io::Result<PeerInfo>>, io::Error> {
+    let nlas = get_device_nlas(name)?;
+    Ok(nlas
+        .into_iter()
+        .filter_map(|nla| match nla {
+            WgDeviceAttrs::Peers(peers) => Some(peers),
+            _ => None,
+        })
+        .flatten()
+        .map(PeerInfo::try_from))
+}
+
+/// The kernel has no stats-only query, so this still dumps the whole device, but it skips
+/// building the peer configs and allowed IPs.
+pub fn get_stats_by_name(name: &InterfaceName) -> Result<HashMap<Key, PeerStats>, io::Error> {
+    let nlas = get_device_nlas(name)?;
+    let stats = nlas
+        .iter()
+        .filter_map(|nla| match nla {
+            WgDeviceAttrs::Peers(peers) => Some(peers),
+            _ => None,
+        })
+        .flatten()
+        .filter_map(|peer| {
+            let public_key = get_nla_value!(peer, WgPeerAttrs, PublicKey).map(|key| Key(*key))?;
+            let stats = PeerStats {
+                last_handshake_time: get_nla_value!(peer, WgPeerAttrs, LastHandshake).cloned(),
+                rx_bytes: get_nla_value!(peer, WgPeerAttrs, RxBytes)
+                    .cloned()
+                    .unwrap_or_default(),
+                tx_bytes: get_nla_value!(peer, WgPeerAttrs, TxBytes)
+                    .cloned()
+                    .unwrap_or_default(),
+            };
+            Some((public_key, stats))
+        })
+        .collect();
+    Ok(stats)
+}
+
 pub fn delete_interface(iface: &InterfaceName) -> io::Result<()> {
     add_del(iface, false)
 }
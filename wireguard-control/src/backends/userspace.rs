@@ -1,6 +1,7 @@
 use crate::{Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfig, PeerInfo, PeerStats};
 
 use std::{
+    collections::HashMap,
     fmt::Write as _,
     fs,
     io::{self, prelude::*, BufReader},
@@ -9,6 +10,7 @@ use std::{
     process::{Command, Output},
     time::{Duration, SystemTime},
 };
+use zeroize::Zeroizing;
 
 static VAR_RUN_PATH: &str = "/var/run/wireguard";
 static RUN_PATH: &str = "/run/wireguard";
@@ -38,14 +40,34 @@ fn get_socketfile(name: &InterfaceName) -> io::Result<PathBuf> {
     }
 }
 
+/// Connect to the interface's UAPI control socket, keeping the socket path and the underlying
+/// cause in the error since a bare `ENOENT`/`ECONNREFUSED` gives users nothing to go on.
 fn open_socket(name: &InterfaceName) -> io::Result<UnixStream> {
-    UnixStream::connect(get_socketfile(name)?)
+    let path = get_socketfile(name)?;
+    UnixStream::connect(&path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "failed to connect to control socket {} ({e}), is {} running?",
+                path.display(),
+                get_userspace_implementation()
+            ),
+        )
+    })
 }
 
 pub fn resolve_tun(name: &InterfaceName) -> io::Result<String> {
     let namefile = get_namefile(name)?;
-    Ok(fs::read_to_string(namefile)
-        .map_err(|_| io::Error::new(io::ErrorKind::NotFound, "WireGuard name file can't be read"))?
+    Ok(fs::read_to_string(&namefile)
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "WireGuard name file {} can't be read ({e})",
+                    namefile.display()
+                ),
+            )
+        })?
         .trim()
         .to_string())
 }
@@ -238,27 +260,140 @@ impl ConfigParser {
     }
 }
 
-pub fn get_by_name(name: &InterfaceName) -> Result<Device, io::Error> {
+/// Send a `get` request to the interface's UAPI socket and call `handle_line` on each line of
+/// the response.
+fn read_device<F>(name: &InterfaceName, mut handle_line: F) -> Result<(), io::Error>
+where
+    F: FnMut(&str) -> Result<(), io::Error>,
+{
     let mut sock = open_socket(name)?;
     sock.write_all(b"get=1\n\n")?;
     let mut reader = BufReader::new(sock);
     let mut buf = String::new();
 
-    let mut parser = ConfigParser::new(name);
-
     loop {
         match reader.read_line(&mut buf)? {
             0 | 1 if buf == "\n" => break,
             _ => {
-                parser.add_line(buf.trim_end())?;
+                handle_line(buf.trim_end())?;
                 buf.clear();
             },
         };
     }
 
+    Ok(())
+}
+
+pub fn get_by_name(name: &InterfaceName) -> Result<Device, io::Error> {
+    let mut parser = ConfigParser::new(name);
+    read_device(name, |line| parser.add_line(line))?;
     Ok(parser.into())
 }
 
+/// Parses a `get` response one line at a time, yielding each peer as soon as the line starting
+/// the next one (or the final `errno`) has been read.
+struct PeerIter<R> {
+    reader: R,
+    parser: ConfigParser,
+    buf: String,
+    done: bool,
+}
+
+impl<R: BufRead> Iterator for PeerIter<R> {
+    type Item = io::Result<PeerInfo>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(peer) = self.parser.device_info.peers.pop() {
+                return Some(Ok(peer));
+            }
+            if self.done {
+                return None;
+            }
+
+            self.buf.clear();
+            let result = match self.reader.read_line(&mut self.buf) {
+                Ok(0 | 1) if self.buf == "\n" => {
+                    self.done = true;
+                    Ok(())
+                },
+                Ok(_) => self.parser.add_line(self.buf.trim_end()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+pub fn peers_by_name(
+    name: &InterfaceName,
+) -> Result<impl Iterator<Item = io::Result<PeerInfo>>, io::Error> {
+    let mut sock = open_socket(name)?;
+    sock.write_all(b"get=1\n\n")?;
+    Ok(PeerIter {
+        reader: BufReader::new(sock),
+        parser: ConfigParser::new(name),
+        buf: String::new(),
+        done: false,
+    })
+}
+
+/// Collects only the per-peer statistics from a `get` response, skipping everything else.
+#[derive(Default)]
+struct StatsParser {
+    stats: HashMap<Key, PeerStats>,
+    current_peer: Option<Key>,
+}
+
+impl StatsParser {
+    fn add_line(&mut self, line: &str) -> Result<(), io::Error> {
+        use io::ErrorKind::InvalidData;
+
+        let (key, value) = line.split_once('=').ok_or(InvalidData)?;
+        if key == "public_key" {
+            let public_key = Key::from_hex(value).map_err(|_| InvalidData)?;
+            self.stats.insert(public_key.clone(), PeerStats::default());
+            self.current_peer = Some(public_key);
+            return Ok(());
+        } else if key == "errno" && value != "0" {
+            return Err(io::Error::from_raw_os_error(
+                value.parse().map_err(|_| InvalidData)?,
+            ));
+        }
+
+        let Some(stats) = self
+            .current_peer
+            .as_ref()
+            .and_then(|key| self.stats.get_mut(key))
+        else {
+            return Ok(());
+        };
+        match key {
+            "rx_bytes" => stats.rx_bytes = value.parse().map_err(|_| InvalidData)?,
+            "tx_bytes" => stats.tx_bytes = value.parse().map_err(|_| InvalidData)?,
+            "last_handshake_time_sec" => {
+                let handshake_seconds: u64 = value.parse().map_err(|_| InvalidData)?;
+                if handshake_seconds > 0 {
+                    stats.last_handshake_time =
+                        Some(SystemTime::UNIX_EPOCH + Duration::from_secs(handshake_seconds));
+                }
+            },
+            _ => {},
+        }
+
+        Ok(())
+    }
+}
+
+pub fn get_stats_by_name(name: &InterfaceName) -> Result<HashMap<Key, PeerStats>, io::Error> {
+    let mut parser = StatsParser::default();
+    read_device(name, |line| parser.add_line(line))?;
+    Ok(parser.stats)
+}
+
 /// Following the rough logic of wg-quick(8), use the wireguard-go userspace
 /// implementation by default, but allow for an environment variable to choose
 /// a different implementation.
@@ -283,7 +418,15 @@ fn start_userspace_wireguard(iface: &InterfaceName) -> io::Result<Output> {
             .output()?
     };
     if !output.status.success() {
-        Err(io::ErrorKind::AddrNotAvailable.into())
+        Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!(
+                "{} exited with {}: {}",
+                get_userspace_implementation(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ))
     } else {
         Ok(output)
     }
@@ -298,16 +441,17 @@ pub fn apply(builder: &DeviceUpdate, iface: &InterfaceName) -> io::Result<()> {
             let _ = fs::remove_file(get_namefile(iface)?);
             start_userspace_wireguard(iface)?;
             std::thread::sleep(Duration::from_millis(100));
-            open_socket(iface)
-                .map_err(|e| io::Error::new(e.kind(), format!("failed to open socket ({e})")))?
+            open_socket(iface)?
         },
         Ok(sock) => sock,
     };
 
-    let mut request = String::from("set=1\n");
+    // The request contains the private key, so make sure it doesn't linger in memory.
+    let mut request = Zeroizing::new(String::from("set=1\n"));
 
     if let Some(ref k) = builder.private_key {
-        writeln!(request, "private_key={}", hex::encode(k.as_bytes())).ok();
+        let private_key = Zeroizing::new(hex::encode(k.as_bytes()));
+        writeln!(request, "private_key={}", *private_key).ok();
     }
 
     if let Some(f) = builder.fwmark {
@@ -339,7 +483,8 @@ pub fn apply(builder: &DeviceUpdate, iface: &InterfaceName) -> io::Result<()> {
         }
 
         if let Some(ref k) = peer.preshared_key {
-            writeln!(request, "preshared_key={}", hex::encode(k.as_bytes())).ok();
+            let preshared_key = Zeroizing::new(hex::encode(k.as_bytes()));
+            writeln!(request, "preshared_key={}", *preshared_key).ok();
         }
 
         if let Some(endpoint) = peer.endpoint {
@@ -382,3 +527,82 @@ pub fn apply(builder: &DeviceUpdate, iface: &InterfaceName) -> io::Result<()> {
         _ => Err(io::ErrorKind::Other.into()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_parser() {
+        let peer = Key::generate_private().get_public();
+        let response = format!(
+            "private_key={}\n\
+             listen_port=51820\n\
+             public_key={}\n\
+             endpoint=10.0.0.1:51820\n\
+             last_handshake_time_sec=1700000000\n\
+             last_handshake_time_nsec=0\n\
+             rx_bytes=1024\n\
+             tx_bytes=2048\n\
+             allowed_ip=10.42.0.1/32\n\
+             errno=0",
+            hex::encode(Key::generate_private().as_bytes()),
+            hex::encode(peer.as_bytes()),
+        );
+
+        let mut parser = StatsParser::default();
+        for line in response.lines() {
+            parser.add_line(line).unwrap();
+        }
+
+        assert_eq!(
+            parser.stats,
+            HashMap::from([(
+                peer,
+                PeerStats {
+                    last_handshake_time: Some(
+                        SystemTime::UNIX_EPOCH + Duration::from_secs(1700000000)
+                    ),
+                    rx_bytes: 1024,
+                    tx_bytes: 2048,
+                }
+            )])
+        );
+    }
+
+    #[test]
+    fn test_peer_iter() {
+        let name: InterfaceName = "wg-test".parse().unwrap();
+        let peers = [
+            Key::generate_private().get_public(),
+            Key::generate_private().get_public(),
+        ];
+        let response = format!(
+            "private_key={}\n\
+             listen_port=51820\n\
+             public_key={}\n\
+             allowed_ip=10.42.0.1/32\n\
+             public_key={}\n\
+             rx_bytes=1024\n\
+             errno=0\n\
+             \n",
+            hex::encode(Key::generate_private().as_bytes()),
+            hex::encode(peers[0].as_bytes()),
+            hex::encode(peers[1].as_bytes()),
+        );
+
+        let mut iter = PeerIter {
+            reader: io::Cursor::new(response),
+            parser: ConfigParser::new(&name),
+            buf: String::new(),
+            done: false,
+        };
+        let first = iter.next().unwrap().unwrap();
+        assert_eq!(first.config.public_key, peers[0]);
+        assert_eq!(first.config.allowed_ips.len(), 1);
+        let second = iter.next().unwrap().unwrap();
+        assert_eq!(second.config.public_key, peers[1]);
+        assert_eq!(second.stats.rx_bytes, 1024);
+        assert!(iter.next().is_none());
+    }
+}
@@ -1,9 +1,13 @@
 use crate::{
     device::{AllowedIp, PeerConfig},
     key::Key,
+    Device, DeviceUpdate,
 };
 
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::{
+    fmt::{self, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
 
 /// Builds and represents a single peer in a WireGuard interface configuration.
 ///
@@ -179,3 +183,303 @@ impl PeerConfigBuilder {
         self
     }
 }
+
+/// Represents an error in parsing a `wg-quick` style configuration.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct InvalidWgQuickConfig {
+    /// The 1-based line the error was found on.
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::error::Error for InvalidWgQuickConfig {}
+
+impl fmt::Display for InvalidWgQuickConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Keys `wg-quick` understands in `[Interface]` that configure the host rather than the
+/// WireGuard device, and so are ignored when parsing.
+const WG_QUICK_ONLY_KEYS: &[&str] = &[
+    "address",
+    "dns",
+    "mtu",
+    "table",
+    "preup",
+    "postup",
+    "predown",
+    "postdown",
+    "saveconfig",
+];
+
+enum Section {
+    None,
+    Interface,
+    Peer,
+}
+
+impl DeviceUpdate {
+    /// Parses a `wg-quick` style `[Interface]`/`[Peer]` configuration.
+    ///
+    /// Like `wg setconf`, the resulting update replaces all existing peers on the interface
+    /// when applied. Settings that `wg-quick` applies to the host instead of the device
+    /// (`Address`, `DNS`, `MTU`, ...) are ignored, and endpoints must be IP addresses rather
+    /// than hostnames.
+    pub fn from_wg_quick(config: &str) -> Result<Self, InvalidWgQuickConfig> {
+        let mut update = DeviceUpdate::new().replace_peers();
+        let mut peer: Option<PeerConfigBuilder> = None;
+        let mut section = Section::None;
+
+        for (i, line) in config.lines().enumerate() {
+            let error = |message: String| InvalidWgQuickConfig {
+                line: i + 1,
+                message,
+            };
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                update = finish_section(update, &section, peer.take()).map_err(error)?;
+                section = match line.to_ascii_lowercase().as_str() {
+                    "[interface]" => Section::Interface,
+                    "[peer]" => Section::Peer,
+                    _ => return Err(error(format!("unknown section {line}"))),
+                };
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error(format!("expected \"Key = Value\", got \"{line}\"")))?;
+            let (key, value) = (key.trim().to_ascii_lowercase(), value.trim());
+            let parse_key =
+                |value: &str| Key::from_base64(value).map_err(|e| error(format!("{key}: {e}")));
+
+            match section {
+                Section::None => {
+                    return Err(error(format!("\"{line}\" is outside of a section")));
+                },
+                Section::Interface => match key.as_str() {
+                    "privatekey" => update = update.set_private_key(parse_key(value)?),
+                    "listenport" => {
+                        update = update.set_listen_port(
+                            value
+                                .parse()
+                                .map_err(|_| error(format!("invalid port \"{value}\"")))?,
+                        )
+                    },
+                    "fwmark" => {
+                        let fwmark = match value.strip_prefix("0x") {
+                            _ if value == "off" => Ok(0),
+                            Some(hex) => u32::from_str_radix(hex, 16),
+                            None => value.parse(),
+                        }
+                        .map_err(|_| error(format!("invalid fwmark \"{value}\"")))?;
+                        update = update.set_fwmark(fwmark);
+                    },
+                    key if WG_QUICK_ONLY_KEYS.contains(&key) => {},
+                    _ => return Err(error(format!("unknown [Interface] key \"{key}\""))),
+                },
+                Section::Peer => {
+                    if key == "publickey" {
+                        if peer.is_some() {
+                            return Err(error("duplicate PublicKey in [Peer]".into()));
+                        }
+                        peer =
+                            Some(PeerConfigBuilder::new(&parse_key(value)?).replace_allowed_ips());
+                        continue;
+                    }
+                    let builder = peer
+                        .take()
+                        .ok_or_else(|| error("[Peer] must start with its PublicKey".into()))?;
+                    peer = Some(match key.as_str() {
+                        "presharedkey" => builder.set_preshared_key(parse_key(value)?),
+                        "allowedips" => value
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|ip| !ip.is_empty())
+                            .try_fold(builder, |builder, ip| {
+                                let ip: AllowedIp = ip
+                                    .parse()
+                                    .map_err(|_| error(format!("invalid allowed IP \"{ip}\"")))?;
+                                Ok(builder.add_allowed_ip(ip.address, ip.cidr))
+                            })?,
+                        "endpoint" => builder.set_endpoint(value.parse().map_err(|_| {
+                            error(format!("invalid endpoint \"{value}\" (must be IP:port)"))
+                        })?),
+                        "persistentkeepalive" => {
+                            let interval = match value {
+                                "off" => 0,
+                                _ => value.parse().map_err(|_| {
+                                    error(format!("invalid keepalive interval \"{value}\""))
+                                })?,
+                            };
+                            builder.set_persistent_keepalive_interval(interval)
+                        },
+                        _ => return Err(error(format!("unknown [Peer] key \"{key}\""))),
+                    });
+                },
+            }
+        }
+
+        finish_section(update, &section, peer).map_err(|message| InvalidWgQuickConfig {
+            line: config.lines().count(),
+            message,
+        })
+    }
+}
+
+fn finish_section(
+    update: DeviceUpdate,
+    section: &Section,
+    peer: Option<PeerConfigBuilder>,
+) -> Result<DeviceUpdate, String> {
+    match (section, peer) {
+        (Section::Peer, None) => Err("[Peer] is missing its PublicKey".into()),
+        (_, Some(peer)) => Ok(update.add_peer(peer)),
+        _ => Ok(update),
+    }
+}
+
+impl Device {
+    /// Serializes the device's configuration in the `wg-quick` style `[Interface]`/`[Peer]`
+    /// format, as understood by [`DeviceUpdate::from_wg_quick`].
+    pub fn to_wg_quick(&self) -> String {
+        let mut config = String::from("[Interface]\n");
+        // Writing to a String can't fail.
+        if let Some(private_key) = &self.private_key {
+            let _ = writeln!(
+                config,
+                "PrivateKey = {}",
+                *private_key.to_base64_zeroizing()
+            );
+        }
+        if let Some(listen_port) = self.listen_port {
+            let _ = writeln!(config, "ListenPort = {listen_port}");
+        }
+        if let Some(fwmark) = self.fwmark.filter(|fwmark| *fwmark != 0) {
+            let _ = writeln!(config, "FwMark = {fwmark:#x}");
+        }
+
+        for peer in &self.peers {
+            let peer = &peer.config;
+            let _ = writeln!(config, "\n[Peer]");
+            let _ = writeln!(config, "PublicKey = {}", peer.public_key.to_base64());
+            if let Some(preshared_key) = &peer.preshared_key {
+                let _ = writeln!(config, "PresharedKey = {}", preshared_key.to_base64());
+            }
+            if !peer.allowed_ips.is_empty() {
+                let allowed_ips = peer
+                    .allowed_ips
+                    .iter()
+                    .map(|ip| format!("{}/{}", ip.address, ip.cidr))
+                    .collect::<Vec<_>>();
+                let _ = writeln!(config, "AllowedIPs = {}", allowed_ips.join(", "));
+            }
+            if let Some(endpoint) = peer.endpoint {
+                let _ = writeln!(config, "Endpoint = {endpoint}");
+            }
+            if let Some(interval) = peer.persistent_keepalive_interval.filter(|i| *i != 0) {
+                let _ = writeln!(config, "PersistentKeepalive = {interval}");
+            }
+        }
+
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Backend, KeyPair, PeerInfo, PeerStats};
+
+    #[test]
+    fn test_from_wg_quick() {
+        let interface = KeyPair::generate();
+        let peer = KeyPair::generate();
+        let config = format!(
+            "
+            [Interface]
+            # Host settings like Address are left to wg-quick.
+            Address = 10.0.0.1/24
+            PrivateKey = {}
+            ListenPort = 51820
+
+            [Peer]
+            PublicKey = {}
+            AllowedIPs = 10.0.0.2/32, fd00::2/128
+            Endpoint = 192.0.2.1:51820
+            PersistentKeepalive = 25
+            ",
+            interface.private.to_base64(),
+            peer.public.to_base64()
+        );
+
+        let update = DeviceUpdate::from_wg_quick(&config).unwrap();
+        assert_eq!(update.private_key, Some(interface.private));
+        assert_eq!(update.listen_port, Some(51820));
+        assert!(update.replace_peers);
+        assert_eq!(
+            update.peers,
+            vec![PeerConfigBuilder::new(&peer.public)
+                .replace_allowed_ips()
+                .add_allowed_ip("10.0.0.2".parse().unwrap(), 32)
+                .add_allowed_ip("fd00::2".parse().unwrap(), 128)
+                .set_endpoint("192.0.2.1:51820".parse().unwrap())
+                .set_persistent_keepalive_interval(25)]
+        );
+    }
+
+    #[test]
+    fn test_from_wg_quick_errors() {
+        let key = KeyPair::generate().public.to_base64();
+        let invalid = [
+            ("ListenPort = 1", 1),
+            ("[Interface]\nBogus = 1", 2),
+            ("[Peer]\nAllowedIPs = 10.0.0.1/32", 2),
+            (&format!("[Peer]\nPublicKey = {key}\nEndpoint = host:1"), 3),
+            ("[Peer]\n", 1),
+        ];
+        for (config, line) in invalid {
+            let err = DeviceUpdate::from_wg_quick(config).unwrap_err();
+            assert_eq!(err.line, line, "{config:?}: {err}");
+        }
+    }
+
+    #[test]
+    fn test_wg_quick_roundtrip() {
+        let keypair = KeyPair::generate();
+        let peer = PeerConfigBuilder::new(&KeyPair::generate().public)
+            .set_preshared_key(Key::generate_preshared())
+            .add_allowed_ip("10.0.0.0".parse().unwrap(), 24)
+            .set_endpoint("[2001:db8::1]:1234".parse().unwrap())
+            .into_peer_config();
+        let device = Device {
+            name: "wg0".parse().unwrap(),
+            public_key: Some(keypair.public),
+            private_key: Some(keypair.private.clone()),
+            fwmark: Some(0x51820),
+            listen_port: Some(1234),
+            peers: vec![PeerInfo {
+                config: peer.clone(),
+                stats: PeerStats::default(),
+            }],
+            linked_name: None,
+            backend: Backend::Userspace,
+            __cant_construct_me: (),
+        };
+
+        let update = DeviceUpdate::from_wg_quick(&device.to_wg_quick()).unwrap();
+        assert_eq!(update.private_key, Some(keypair.private));
+        assert_eq!(update.listen_port, Some(1234));
+        assert_eq!(update.fwmark, Some(0x51820));
+        assert_eq!(
+            update.peers,
+            vec![PeerConfigBuilder::from_peer_config(peer)]
+        );
+    }
+}
@@ -4,6 +4,7 @@ use crate::{backends, key::Key, Backend, KeyPair, PeerConfigBuilder};
 
 use std::{
     borrow::Cow,
+    collections::HashMap,
     ffi::CStr,
     fmt, io,
     net::{IpAddr, SocketAddr},
@@ -236,6 +237,34 @@ impl Device {
         }
     }
 
+    /// Iterates over the peers of an interface without collecting them into a [`Device`].
+    ///
+    /// Callers looking for a single peer can stop as soon as they find it instead of building a
+    /// `Vec` of every peer first, which matters on interfaces with thousands of them.
+    pub fn peers_iter(name: &InterfaceName, backend: Backend) -> Result<PeerIter, std::io::Error> {
+        Ok(PeerIter(match backend {
+            #[cfg(target_os = "linux")]
+            Backend::Kernel => Box::new(backends::kernel::peers_by_name(name)?),
+            Backend::Userspace => Box::new(backends::userspace::peers_by_name(name)?),
+        }))
+    }
+
+    /// Reads only the constantly-changing [`PeerStats`] of each peer, keyed by public key.
+    ///
+    /// This is meant for monitoring loops. Neither backend can ask for the stats alone, so the
+    /// whole device is still read, but peer configs and allowed IPs aren't parsed into a
+    /// [`Device`], which adds up on interfaces with many peers.
+    pub fn get_stats(
+        name: &InterfaceName,
+        backend: Backend,
+    ) -> Result<HashMap<Key, PeerStats>, std::io::Error> {
+        match backend {
+            #[cfg(target_os = "linux")]
+            Backend::Kernel => backends::kernel::get_stats_by_name(name),
+            Backend::Userspace => backends::userspace::get_stats_by_name(name),
+        }
+    }
+
     pub fn delete(self) -> Result<(), std::io::Error> {
         match self.backend {
             #[cfg(target_os = "linux")]
@@ -245,6 +274,30 @@ impl Device {
     }
 }
 
+/// The peers of an interface, parsed one at a time. Returned by [`Device::peers_iter`].
+pub struct PeerIter(Box<dyn Iterator<Item = Result<PeerInfo, std::io::Error>>>);
+
+impl PeerIter {
+    /// Reads peers until the one with `public_key`, stopping at the first error.
+    pub fn find_by_key(&mut self, public_key: &Key) -> Result<Option<PeerInfo>, std::io::Error> {
+        for info in self {
+            let info = info?;
+            if &info.config.public_key == public_key {
+                return Ok(Some(info));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for PeerIter {
+    type Item = Result<PeerInfo, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
 /// Builds and represents a configuration that can be applied to a WireGuard interface.
 ///
 /// This is the primary way of changing the settings of an interface.
@@ -410,6 +463,17 @@ impl DeviceUpdate {
         self
     }
 
+    /// Specifies the complete set of peers the interface should have, removing any others.
+    ///
+    /// This is [`replace_peers`](Self::replace_peers) plus [`add_peers`](Self::add_peers), so
+    /// the interface ends up with exactly `peers` instead of converging on them through
+    /// individual additions and removals. Replaced peers lose their current sessions and any
+    /// endpoints they roamed to, and handshake again.
+    #[must_use]
+    pub fn set_peers(self, peers: &[PeerConfigBuilder]) -> Self {
+        self.replace_peers().add_peers(peers)
+    }
+
     /// Specifies that the peer with this public key should be removed from the interface.
     #[must_use]
     pub fn remove_peer_by_key(self, public_key: &Key) -> Self {
@@ -469,6 +533,18 @@ mod tests {
         device.delete().unwrap();
     }
 
+    #[test]
+    fn test_set_peers() {
+        let keypairs: Vec<_> = (0..3).map(|_| KeyPair::generate()).collect();
+        let peers: Vec<_> = keypairs
+            .iter()
+            .map(|keypair| PeerConfigBuilder::new(&keypair.public))
+            .collect();
+        let update = DeviceUpdate::new().set_peers(&peers);
+        assert!(update.replace_peers);
+        assert_eq!(update.peers, peers);
+    }
+
     #[test]
     fn test_interface_names() {
         assert_eq!(
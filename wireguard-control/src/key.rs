@@ -1,22 +1,45 @@
 use std::{ffi::NulError, fmt};
 
+use curve25519_dalek::{
+    edwards::{CompressedEdwardsY, EdwardsPoint},
+    montgomery::MontgomeryPoint,
+    scalar::Scalar,
+};
+use sha2::{Digest, Sha512};
 use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
-/// Represents an error in base64 key parsing.
+/// Represents an error in key parsing, saying what was wrong with the input.
 #[derive(Eq, PartialEq, Debug, Clone)]
-pub struct InvalidKey;
+#[non_exhaustive]
+pub enum InvalidKey {
+    /// The input isn't valid base64.
+    Base64,
+    /// The input isn't valid hex.
+    Hex,
+    /// The input decoded (or, for hex of an odd length, would decode) to this many bytes,
+    /// rather than the 32 of a key.
+    Length(usize),
+    /// The input contains a NUL byte.
+    Nul,
+}
 
 impl std::error::Error for InvalidKey {}
 
 impl fmt::Display for InvalidKey {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Invalid key format")
+        match self {
+            Self::Base64 => write!(f, "Invalid key format: not valid base64"),
+            Self::Hex => write!(f, "Invalid key format: not valid hex"),
+            Self::Length(len) => write!(f, "Invalid key length: {len} bytes instead of 32"),
+            Self::Nul => write!(f, "Invalid key format: contains a NUL byte"),
+        }
     }
 }
 
 impl From<NulError> for InvalidKey {
     fn from(_: NulError) -> Self {
-        InvalidKey {}
+        InvalidKey::Nul
     }
 }
 
@@ -28,9 +51,19 @@ impl From<NulError> for InvalidKey {
 ///
 /// This means that you need to be careful when working with
 /// `Key`s, especially ones created from external data.
-#[derive(PartialEq, Eq, Clone)]
+///
+/// Since any key may be a private one, every `Key` is zeroed out when it's dropped.
+#[derive(PartialEq, Eq, Hash, Clone)]
 pub struct Key(pub [u8; 32]);
 
+impl Drop for Key {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for Key {}
+
 impl Key {
     /// Generates and returns a new private key.
     pub fn generate_private() -> Self {
@@ -80,29 +113,111 @@ impl Key {
         base64::encode(self.0)
     }
 
+    /// Like [`Key::to_base64`], but the string is zeroed out when it's dropped. Use this for
+    /// private keys that only need to be in text form temporarily.
+    pub fn to_base64_zeroizing(&self) -> Zeroizing<String> {
+        Zeroizing::new(self.to_base64())
+    }
+
     /// Converts a base64 representation of the key to the raw bytes.
     ///
     /// This can fail, as not all text input is valid base64 - in this case
-    /// `Err(InvalidKey)` is returned.
+    /// `Err(InvalidKey::Base64)` is returned. Input that decodes to anything
+    /// other than 32 bytes is rejected with `Err(InvalidKey::Length(_))`.
     pub fn from_base64(key: &str) -> Result<Self, crate::InvalidKey> {
         let mut key_bytes = [0u8; 32];
-        let decoded_bytes = base64::decode(key).map_err(|_| InvalidKey)?;
+        let decoded_bytes = Zeroizing::new(base64::decode(key).map_err(|_| InvalidKey::Base64)?);
 
         if decoded_bytes.len() != 32 {
-            return Err(InvalidKey);
+            return Err(InvalidKey::Length(decoded_bytes.len()));
         }
 
         key_bytes.copy_from_slice(&decoded_bytes[..]);
         Ok(Self(key_bytes))
     }
 
+    /// Signs `message` with this private key, so that anyone with the matching public key can
+    /// check that its holder vouched for the message.
+    ///
+    /// WireGuard keys are X25519 keys, which can't sign on their own, so this uses XEdDSA (as
+    /// specified by Signal) with a deterministic nonce to sign with the equivalent Ed25519 key.
+    #[must_use]
+    pub fn sign(&self, message: &[u8]) -> [u8; 64] {
+        let mut clamped = Zeroizing::new(self.0);
+        clamped[0] &= 248;
+        clamped[31] &= 127;
+        clamped[31] |= 64;
+        let mut a = Zeroizing::new(Scalar::from_bytes_mod_order(*clamped));
+        // XEdDSA public keys always have a sign bit of zero, so flip the private scalar if the
+        // Edwards point it gives is negative.
+        let mut public = EdwardsPoint::mul_base(&a);
+        if public.compress().as_bytes()[31] >> 7 == 1 {
+            *a = -*a;
+            public = -public;
+        }
+        let public = public.compress();
+
+        let r = Zeroizing::new(hash_to_scalar(&[
+            &[0xfe],
+            &[0xff; 31],
+            a.as_bytes(),
+            message,
+        ]));
+        let big_r = EdwardsPoint::mul_base(&r).compress();
+        let h = hash_to_scalar(&[big_r.as_bytes(), public.as_bytes(), message]);
+        let s = *r + h * *a;
+
+        let mut signature = [0u8; 64];
+        signature[..32].copy_from_slice(big_r.as_bytes());
+        signature[32..].copy_from_slice(s.as_bytes());
+        signature
+    }
+
+    /// Checks that `signature` was made by [`Key::sign`] over `message` with the private key
+    /// matching this public key.
+    pub fn verify(&self, message: &[u8], signature: &[u8; 64]) -> bool {
+        let mut u = self.0;
+        u[31] &= 127;
+        let Some(public) = MontgomeryPoint(u).to_edwards(0) else {
+            return false;
+        };
+        if public.is_small_order() {
+            return false;
+        }
+        let mut big_r = [0u8; 32];
+        big_r.copy_from_slice(&signature[..32]);
+        let mut s = [0u8; 32];
+        s.copy_from_slice(&signature[32..]);
+        let Some(s) = Option::<Scalar>::from(Scalar::from_canonical_bytes(s)) else {
+            return false;
+        };
+
+        let big_r = CompressedEdwardsY(big_r);
+        let h = hash_to_scalar(&[big_r.as_bytes(), public.compress().as_bytes(), message]);
+        EdwardsPoint::vartime_double_scalar_mul_basepoint(&h, &-public, &s).compress() == big_r
+    }
+
     pub fn from_hex(hex_str: &str) -> Result<Self, crate::InvalidKey> {
         let mut sized_bytes = [0u8; 32];
-        hex::decode_to_slice(hex_str, &mut sized_bytes).map_err(|_| InvalidKey)?;
+        if hex_str.len() != 64 {
+            return Err(InvalidKey::Length(hex_str.len() / 2));
+        }
+        hex::decode_to_slice(hex_str, &mut sized_bytes).map_err(|_| InvalidKey::Hex)?;
         Ok(Self(sized_bytes))
     }
 }
 
+/// SHA-512 over the concatenation of `parts`, reduced to a scalar.
+fn hash_to_scalar(parts: &[&[u8]]) -> Scalar {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    let mut hash = [0u8; 64];
+    hash.copy_from_slice(&hasher.finalize());
+    Scalar::from_bytes_mod_order_wide(&hash)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -185,6 +300,20 @@ mod tests {
         assert_ne!(key.as_bytes(), &[0u8; 32]);
     }
 
+    #[test]
+    fn test_key_zeroized_on_drop() {
+        use crate::key::Key;
+
+        let mut key = std::mem::ManuallyDrop::new(Key::generate_private());
+        let bytes: *const [u8; 32] = &key.0;
+        // SAFETY: `key` is never used again after being dropped here, and `bytes` still
+        // points into its (not deallocated) storage.
+        unsafe {
+            std::mem::ManuallyDrop::drop(&mut key);
+            assert_eq!(*bytes, [0u8; 32]);
+        }
+    }
+
     #[test]
     fn test_key_base64() {
         use crate::key::Key;
@@ -206,7 +335,41 @@ mod tests {
             .rev()
             .collect();
 
-        assert_eq!(Key::from_base64(&key_b64), Err(InvalidKey));
+        assert_eq!(Key::from_base64(&key_b64), Err(InvalidKey::Base64));
+    }
+
+    #[test]
+    fn test_invalid_key_length() {
+        use crate::key::{InvalidKey, Key};
+
+        assert_eq!(
+            Key::from_base64("AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=="),
+            Err(InvalidKey::Length(31))
+        );
+        assert_eq!(Key::from_base64(""), Err(InvalidKey::Length(0)));
+        assert_eq!(Key::from_hex("00"), Err(InvalidKey::Length(1)));
+        assert_eq!(Key::from_hex(&"0".repeat(63)), Err(InvalidKey::Length(31)));
+        assert_eq!(Key::from_hex(&"zz".repeat(32)), Err(InvalidKey::Hex));
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        use crate::key::{Key, KeyPair};
+
+        let pair = KeyPair::generate();
+        let signature = pair.private.sign(b"message");
+        assert!(pair.public.verify(b"message", &signature));
+        assert!(!pair.public.verify(b"other message", &signature));
+        assert!(!KeyPair::generate().public.verify(b"message", &signature));
+
+        let mut tampered = signature;
+        tampered[40] ^= 1;
+        assert!(!pair.public.verify(b"message", &tampered));
+
+        // Keys from elsewhere, e.g. `wg genkey`, aren't necessarily clamped.
+        let private = Key::from_base64("SGb+ojrRNDuMePufwtIYhXzA//k6wF3R21tEBgKlzlM=").unwrap();
+        let signature = private.sign(b"message");
+        assert!(private.get_public().verify(b"message", &signature));
     }
 
     #[test]
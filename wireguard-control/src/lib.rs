@@ -67,3 +67,21 @@ impl Backend {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_backend() {
+        #[cfg(target_os = "linux")]
+        assert_eq!(Backend::default(), Backend::Kernel);
+        #[cfg(not(target_os = "linux"))]
+        assert_eq!(Backend::default(), Backend::Userspace);
+
+        // The default must always be one of the backends that can be picked by name.
+        let default = Backend::default().to_string();
+        assert!(Backend::variants().contains(&default.as_str()));
+        assert_eq!(default.parse(), Ok(Backend::default()));
+    }
+}